@@ -0,0 +1,170 @@
+//! Compare the file trees of two snapshots using only their metadata
+
+use std::{collections::BTreeMap, io::Write, path::PathBuf};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    error::Result,
+    formats::{
+        tree::{Node, NodeKind},
+        Backup,
+    },
+};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug, Default)]
+pub enum DiffFormat {
+    /// One line per change, prefixed with `+`, `-`, `M` or `m`
+    #[default]
+    Text,
+    /// A single JSON array
+    Json,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    /// The contents or the type of the entry changed
+    Modified,
+    /// Same contents, only metadata such as the mode or mtime changed
+    Metadata,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Change {
+    pub path: PathBuf,
+    pub change: ChangeKind,
+    /// Metadata fields that differ, empty for added and removed entries
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<&'static str>,
+}
+
+/// Whether two files have the same contents. Content hashes are used if both sides have one,
+/// otherwise the chunk lists are compared - chunks are content addressed, so the same chunks
+/// mean the same contents.
+fn same_contents(a: &Node, b: &Node) -> bool {
+    if a.size.is_some() && b.size.is_some() && a.size != b.size {
+        return false;
+    }
+
+    match (&a.hash, &b.hash) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.chunks == b.chunks,
+    }
+}
+
+/// Metadata fields that differ between two versions of an entry
+/// Extended attributes have no inherent order, formats keeping them in maps list them randomly
+fn sorted_xattrs(node: &Node) -> Vec<&(String, Vec<u8>)> {
+    let mut xattrs: Vec<_> = node.xattrs.iter().collect();
+    xattrs.sort();
+    xattrs
+}
+
+fn changed_fields(a: &Node, b: &Node) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    if a.mode != b.mode {
+        fields.push("mode");
+    }
+    if a.uid != b.uid || a.user != b.user {
+        fields.push("owner");
+    }
+    if a.gid != b.gid || a.group != b.group {
+        fields.push("group");
+    }
+    if a.mtime != b.mtime {
+        fields.push("mtime");
+    }
+    if sorted_xattrs(a) != sorted_xattrs(b) {
+        fields.push("xattrs");
+    }
+
+    fields
+}
+
+/// Compare two versions of an entry, `None` if nothing changed
+pub fn compare(a: &Node, b: &Node) -> Option<Change> {
+    let modified = match (&a.kind, &b.kind) {
+        (NodeKind::File, NodeKind::File) => !same_contents(a, b),
+        (kind_a, kind_b) => kind_a != kind_b,
+    };
+
+    let fields = changed_fields(a, b);
+    let change = if modified {
+        ChangeKind::Modified
+    } else if !fields.is_empty() {
+        ChangeKind::Metadata
+    } else {
+        return None;
+    };
+
+    Some(Change {
+        path: b.path.clone(),
+        change,
+        fields,
+    })
+}
+
+/// List the changes from snapshot `from` to snapshot `to`, sorted by path
+pub fn diff(backup: &dyn Backup, from: &str, to: &str) -> Result<Vec<Change>> {
+    let old: BTreeMap<PathBuf, Node> = backup
+        .tree(from)?
+        .into_iter()
+        .map(|node| (node.path.clone(), node))
+        .collect();
+    let mut new: BTreeMap<PathBuf, Node> = backup
+        .tree(to)?
+        .into_iter()
+        .map(|node| (node.path.clone(), node))
+        .collect();
+
+    let mut changes = Vec::new();
+    for (path, old_node) in old {
+        match new.remove(&path) {
+            Some(new_node) => changes.extend(compare(&old_node, &new_node)),
+            None => changes.push(Change {
+                path,
+                change: ChangeKind::Removed,
+                fields: Vec::new(),
+            }),
+        }
+    }
+    changes.extend(new.into_keys().map(|path| Change {
+        path,
+        change: ChangeKind::Added,
+        fields: Vec::new(),
+    }));
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(changes)
+}
+
+pub fn write_diff(changes: &[Change], format: DiffFormat, mut writer: impl Write) -> Result<()> {
+    match format {
+        DiffFormat::Text => {
+            for change in changes {
+                let prefix = match change.change {
+                    ChangeKind::Added => "+",
+                    ChangeKind::Removed => "-",
+                    ChangeKind::Modified => "M",
+                    ChangeKind::Metadata => "m",
+                };
+                write!(writer, "{prefix} {}", change.path.display())?;
+                if !change.fields.is_empty() {
+                    write!(writer, " ({})", change.fields.join(", "))?;
+                }
+                writeln!(writer)?;
+            }
+        }
+        DiffFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, changes)?;
+            writeln!(writer)?;
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
@@ -26,12 +26,77 @@ pub enum Error {
     ChronoParse(#[from] chrono::ParseError),
     #[error(transparent)]
     Xz2Stream(#[from] xz2::stream::Error),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+    #[error(transparent)]
+    Glob(#[from] globset::Error),
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
 
     // Format errors
     #[error(transparent)]
     Duplicacy(#[from] crate::formats::duplicacy::error::Error),
     #[error(transparent)]
     Restic(#[from] crate::formats::restic::error::Error),
+    #[error(transparent)]
+    Borg(#[from] crate::formats::borg::error::Error),
+    #[error(transparent)]
+    Kopia(#[from] crate::formats::kopia::error::Error),
+    #[error(transparent)]
+    Duplicati(#[from] crate::formats::duplicati::error::Error),
+    #[error(transparent)]
+    Pbs(#[from] crate::formats::pbs::error::Error),
+    #[error(transparent)]
+    Duplicity(#[from] crate::formats::duplicity::error::Error),
+    #[error(transparent)]
+    Bupstash(#[from] crate::formats::bupstash::error::Error),
+    #[error(transparent)]
+    Casync(#[from] crate::formats::casync::error::Error),
+    #[error(transparent)]
+    Bup(#[from] crate::formats::bup::error::Error),
+
+    // Tool errors
+    #[error("Unrecognized repository layout: {0:?}")]
+    #[diagnostic(help("Check the repository path or pass --format explicitly"))]
+    UnrecognizedFormat(std::path::PathBuf),
+    #[error("Repository layout of {0:?} matches several formats: {1}")]
+    #[diagnostic(help("Pass --format to pick one"))]
+    AmbiguousFormat(std::path::PathBuf, String),
+    #[error("Unsupported repository location: {0}")]
+    #[diagnostic(help(
+        "Repositories are local paths, or sftp:// URLs when built with the `sftp` feature"
+    ))]
+    UnsupportedLocation(String),
+    #[cfg(feature = "sftp")]
+    #[error("Invalid repository location: {0}")]
+    #[diagnostic(help("SFTP locations look like sftp://[user@]host[:port]/path"))]
+    InvalidLocation(String),
+    #[cfg(feature = "sftp")]
+    #[error("Couldn't connect to {0} over SFTP")]
+    #[diagnostic(help(
+        "The host key has to be in known_hosts and a key has to be in the agent or configured for the host"
+    ))]
+    SftpConnection(String),
+    #[error("Snapshot not found: {0}")]
+    SnapshotNotFound(String),
+    #[error("The repository doesn't contain any snapshots")]
+    NoSnapshots,
+    #[error("Path not found in snapshot: {0:?}")]
+    PathNotFound(std::path::PathBuf),
+    #[error("Not a regular file: {0:?}")]
+    NotAFile(std::path::PathBuf),
+    #[error("Version {0} doesn't exist")]
+    VersionNotFound(usize),
+    #[error("Mismatched hash: {0:?}")]
+    MismatchedHash(std::path::PathBuf),
+    #[error("Contents don't match the recorded size: {0:?}")]
+    MismatchedSize(std::path::PathBuf),
+    #[error("Chunk {0} is too short for the file contents it should hold ({1} bytes)")]
+    TruncatedChunk(String, usize),
 
     #[error("Unknown error")]
     _Unknown,
@@ -0,0 +1,274 @@
+//! Export a snapshot as a single archive, streamed to a file or stdout
+
+use std::io::Write;
+
+use clap::ValueEnum;
+
+use crate::{error::Result, formats::Backup};
+
+mod tar;
+mod zip;
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// Only used by formats that compress their entries
+    pub compression: Compression,
+    /// Store the files of a hard link group as links to the first one, zip always stores
+    /// them in full
+    pub hard_links: bool,
+    /// Also group files without inodes by their contents and metadata
+    pub guess_hard_links: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum ExportFormat {
+    /// POSIX tar with pax extended headers
+    Tar,
+    /// Zip, with zip64 extensions for large files
+    Zip,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug, Default)]
+pub enum Compression {
+    Store,
+    #[default]
+    Deflate,
+}
+
+pub fn export(
+    backup: &dyn Backup,
+    snapshot: &str,
+    writer: impl Write,
+    options: &ExportOptions,
+) -> Result<()> {
+    let nodes = backup.tree(snapshot)?;
+
+    match options.format {
+        ExportFormat::Tar => tar::export(backup, &nodes, writer, options),
+        ExportFormat::Zip => zip::export(backup, &nodes, writer, options),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        io::{Cursor, Read},
+        path::{Path, PathBuf},
+    };
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::{
+        formats::tree::{ChunkRef, Inode, Node, NodeKind, SnapshotInfo},
+        storage::{local::Local, Storage},
+    };
+
+    const ZERO_CHUNK_SIZE: usize = 64 * 1024;
+    const ZERO_CHUNKS: usize = 16;
+
+    /// A snapshot held in memory, the chunk IDs are the chunk contents except for `zero`
+    struct MemoryBackup {
+        nodes: Vec<Node>,
+    }
+
+    impl Backup for MemoryBackup {
+        fn snapshots(&self) -> Vec<SnapshotInfo> {
+            Vec::new()
+        }
+
+        fn tree(&self, _snapshot: &str) -> Result<Vec<Node>> {
+            Ok(self.nodes.clone())
+        }
+
+        fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+            match id {
+                "zero" => Ok(vec![0; ZERO_CHUNK_SIZE]),
+                _ => Ok(id.as_bytes().to_vec()),
+            }
+        }
+
+        fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+            Ok(self.chunk(id)?.len() as u64)
+        }
+
+        fn object_dirs(&self) -> Vec<PathBuf> {
+            Vec::new()
+        }
+
+        fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+            Ok(HashSet::new())
+        }
+
+        fn storage(&self) -> &dyn Storage {
+            &Local
+        }
+    }
+
+    fn file(path: &str, chunks: &[&str], size: Option<u64>) -> Node {
+        let mut node = Node::new(path, NodeKind::File);
+        node.mode = Some(0o640);
+        node.size = size;
+        node.chunks = chunks.iter().map(|id| ChunkRef::whole(*id)).collect();
+        node
+    }
+
+    /// A regular file, a symlink, two hard links, a file of unknown size and a file that's
+    /// mostly zeros, like a sparse file restored from a backup
+    fn backup() -> MemoryBackup {
+        let mut dir = Node::new("dir", NodeKind::Dir);
+        dir.mode = Some(0o750);
+
+        let mut regular = file("dir/regular", &["hello ", "world"], Some(11));
+        regular.mtime = Utc.timestamp_opt(1_700_000_000, 250_000_000).single();
+        regular.uid = Some(1000);
+        regular.gid = Some(100);
+
+        let link = Node::new(
+            "dir/link",
+            NodeKind::Symlink {
+                target: "regular".to_string(),
+            },
+        );
+
+        let inode = Inode {
+            device: 1,
+            inode: 42,
+            links: 2,
+        };
+        let mut first = file("first", &["linked"], Some(6));
+        first.inode = Some(inode);
+        let mut second = file("dir/second", &["linked"], Some(6));
+        second.inode = Some(inode);
+
+        let mut unknown = file("unknown", &["no size"], None);
+        unknown.mtime = Utc.timestamp_opt(-2, 500_000_000).single();
+
+        let mut chunks = vec!["zero"; ZERO_CHUNKS];
+        chunks.push("end");
+        let sparse = file(
+            "sparse",
+            &chunks,
+            Some((ZERO_CHUNK_SIZE * ZERO_CHUNKS + 3) as u64),
+        );
+
+        MemoryBackup {
+            nodes: vec![dir, regular, link, first, second, unknown, sparse],
+        }
+    }
+
+    fn sparse_contents() -> Vec<u8> {
+        let mut data = vec![0; ZERO_CHUNK_SIZE * ZERO_CHUNKS];
+        data.extend(b"end");
+        data
+    }
+
+    fn export_to_vec(format: ExportFormat) -> Vec<u8> {
+        let options = ExportOptions {
+            format,
+            compression: Compression::Deflate,
+            hard_links: true,
+            guess_hard_links: false,
+        };
+        let mut out = Vec::new();
+        export(&backup(), "snapshot", &mut out, &options).unwrap();
+        out
+    }
+
+    #[test]
+    fn tar_round_trip() {
+        let data = export_to_vec(ExportFormat::Tar);
+        let mut archive = ::tar::Archive::new(&data[..]);
+
+        let mut entries = HashMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_path_buf();
+            let header = entry.header().clone();
+            let link_name = entry.link_name().unwrap().map(|name| name.to_path_buf());
+            let mtime = entry.pax_extensions().unwrap().and_then(|mut records| {
+                records
+                    .find(|record| record.as_ref().unwrap().key().unwrap() == "mtime")
+                    .map(|record| record.unwrap().value().unwrap().to_string())
+            });
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            entries.insert(path, (header, link_name, mtime, contents));
+        }
+        assert_eq!(entries.len(), 7);
+
+        let (header, _, _, _) = &entries[Path::new("dir")];
+        assert_eq!(header.entry_type(), ::tar::EntryType::Directory);
+        assert_eq!(header.mode().unwrap(), 0o750);
+
+        let (header, _, mtime, contents) = &entries[Path::new("dir/regular")];
+        assert_eq!(header.entry_type(), ::tar::EntryType::Regular);
+        assert_eq!(contents, b"hello world");
+        assert_eq!(header.mode().unwrap(), 0o640);
+        assert_eq!((header.uid().unwrap(), header.gid().unwrap()), (1000, 100));
+        assert_eq!(header.mtime().unwrap(), 1_700_000_000);
+        assert_eq!(mtime.as_deref(), Some("1700000000.250000000"));
+
+        let (header, link_name, _, _) = &entries[Path::new("dir/link")];
+        assert_eq!(header.entry_type(), ::tar::EntryType::Symlink);
+        assert_eq!(link_name.as_deref(), Some(Path::new("regular")));
+
+        // the second file of the hard link group links to the first one
+        let (header, _, _, contents) = &entries[Path::new("first")];
+        assert_eq!(header.entry_type(), ::tar::EntryType::Regular);
+        assert_eq!(contents, b"linked");
+        let (header, link_name, _, contents) = &entries[Path::new("dir/second")];
+        assert_eq!(header.entry_type(), ::tar::EntryType::Link);
+        assert_eq!(link_name.as_deref(), Some(Path::new("first")));
+        assert!(contents.is_empty());
+
+        // the size is only known once the contents have been read
+        let (header, _, mtime, contents) = &entries[Path::new("unknown")];
+        assert_eq!(contents, b"no size");
+        assert_eq!(header.size().unwrap(), 7);
+        assert_eq!(header.mtime().unwrap(), 0);
+        assert_eq!(mtime.as_deref(), Some("-1.500000000"));
+
+        let (_, _, _, contents) = &entries[Path::new("sparse")];
+        assert_eq!(*contents, sparse_contents());
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        let data = export_to_vec(ExportFormat::Zip);
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(&data)).unwrap();
+        assert_eq!(archive.len(), 7);
+
+        let mut read = |name: &str| {
+            let mut file = archive.by_name(name).unwrap();
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).unwrap();
+            (file.unix_mode().unwrap(), file.header_start(), contents)
+        };
+
+        let (mode, _, _) = read("dir/");
+        assert_eq!(mode, 0o040750);
+
+        let (mode, _, contents) = read("dir/regular");
+        assert_eq!((mode, &contents[..]), (0o100640, &b"hello world"[..]));
+
+        // symlinks store their target as contents
+        let (mode, _, contents) = read("dir/link");
+        assert_eq!((mode, &contents[..]), (0o120777, &b"regular"[..]));
+
+        // zip has no hard links, both files are stored in full
+        assert_eq!(read("first").2, b"linked");
+        assert_eq!(read("dir/second").2, b"linked");
+
+        // unknown sizes might be large, the local header and descriptor use zip64
+        let (_, header_start, contents) = read("unknown");
+        assert_eq!(contents, b"no size");
+        let header = &data[header_start as usize..];
+        assert_eq!(header[4..6], 45u16.to_le_bytes());
+        assert_eq!(header[18..26], [0xFF; 8]);
+
+        assert_eq!(read("sparse").2, sparse_contents());
+    }
+}
@@ -0,0 +1,289 @@
+//! Write a snapshot as a POSIX tar archive
+//!
+//! Anything that doesn't fit into the ustar header (long paths and names, sub-second mtimes,
+//! huge files) and the extended attributes are stored in pax extended headers, see
+//! https://pubs.opengroup.org/onlinepubs/9699919799/utilities/pax.html#tag_20_92_13_03
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use tar::{Builder, EntryType, Header};
+
+use super::ExportOptions;
+use crate::{
+    error::{Error, Result},
+    formats::{
+        tree::{HardLinkKey, Node, NodeKind},
+        Backup,
+    },
+};
+
+/// Largest size that fits into the 11 octal digits of the ustar size field
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+/// Records of a pax extended header, each is formatted as `<length> <key>=<value>\n`
+#[derive(Default)]
+struct PaxRecords(Vec<u8>);
+
+impl PaxRecords {
+    fn add(&mut self, key: &str, value: impl AsRef<[u8]>) {
+        let value = value.as_ref();
+
+        // the length includes the digits of the length itself
+        let base = key.len() + value.len() + 3;
+        let mut len = base + 1;
+        while len != base + len.to_string().len() {
+            len = base + len.to_string().len();
+        }
+
+        self.0.extend(format!("{len} {key}=").as_bytes());
+        self.0.extend(value);
+        self.0.push(b'\n');
+    }
+}
+
+pub fn export(
+    backup: &dyn Backup,
+    nodes: &[Node],
+    writer: impl Write,
+    options: &ExportOptions,
+) -> Result<()> {
+    let mut builder = Builder::new(writer);
+
+    // path of the first file of each hard link group, the others link to it
+    let mut hard_links: HashMap<HardLinkKey, PathBuf> = HashMap::new();
+
+    for node in nodes {
+        let mut header = Header::new_ustar();
+        let mut pax = PaxRecords::default();
+
+        set_path(&mut header, &mut pax, &node.path);
+        set_metadata(&mut header, &mut pax, node);
+
+        match &node.kind {
+            NodeKind::Dir => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_size(0);
+                append(&mut builder, header, pax, std::io::empty())?;
+            }
+            NodeKind::Symlink { target } => {
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                set_link_name(&mut header, &mut pax, Path::new(target));
+                append(&mut builder, header, pax, std::io::empty())?;
+            }
+            NodeKind::File => {
+                let key = node
+                    .hard_link_key(options.guess_hard_links && backup.guesses_hard_links())
+                    .filter(|_| options.hard_links);
+                if let Some(key) = key {
+                    if let Some(original) = hard_links.get(&key) {
+                        header.set_entry_type(EntryType::Link);
+                        header.set_size(0);
+                        set_link_name(&mut header, &mut pax, original);
+                        append(&mut builder, header, pax, std::io::empty())?;
+                        continue;
+                    }
+
+                    hard_links.insert(key, node.path.clone());
+                }
+
+                header.set_entry_type(EntryType::Regular);
+
+                debug!("Exporting {:?}", node.path);
+                match node.size {
+                    Some(size) => {
+                        set_size(&mut header, &mut pax, size);
+                        let reader = ExactReader {
+                            inner: node.reader(backup),
+                            path: &node.path,
+                            remaining: size,
+                        };
+                        append(&mut builder, header, pax, reader)?;
+                    }
+                    None => {
+                        // the header comes first, so the contents have to be read to know the size
+                        let mut data = Vec::new();
+                        node.reader(backup).read_to_end(&mut data)?;
+                        set_size(&mut header, &mut pax, data.len() as u64);
+                        append(&mut builder, header, pax, &data[..])?;
+                    }
+                }
+            }
+            NodeKind::Special => {
+                trace!("Skipping special file {:?}", node.path);
+            }
+        }
+    }
+
+    builder.into_inner()?.flush()?;
+
+    Ok(())
+}
+
+/// Fails if the contents don't have the size written into the header beforehand, everything
+/// after the entry would be misaligned
+struct ExactReader<'a, R> {
+    inner: R,
+    path: &'a Path,
+    remaining: u64,
+}
+
+impl<R: Read> Read for ExactReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mismatched = || std::io::Error::other(Error::MismatchedSize(self.path.to_path_buf()));
+
+        if self.remaining == 0 {
+            return match self.inner.read(&mut [0u8; 1])? {
+                0 => Ok(0),
+                _ => Err(mismatched()),
+            };
+        }
+
+        let len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        match self.inner.read(&mut buf[..len])? {
+            0 if len > 0 => Err(mismatched()),
+            read => {
+                self.remaining -= read as u64;
+                Ok(read)
+            }
+        }
+    }
+}
+
+/// Write the pax extended header if there is one, followed by the entry itself
+fn append<W: Write>(
+    builder: &mut Builder<W>,
+    mut header: Header,
+    pax: PaxRecords,
+    data: impl Read,
+) -> Result<()> {
+    if !pax.0.is_empty() {
+        let mut pax_header = Header::new_ustar();
+        pax_header.set_entry_type(EntryType::XHeader);
+        pax_header.set_path("././@PaxHeader")?;
+        pax_header.set_mode(0o644);
+        pax_header.set_size(pax.0.len() as u64);
+        pax_header.set_cksum();
+        builder.append(&pax_header, &pax.0[..])?;
+    }
+
+    header.set_cksum();
+    builder.append(&header, data)?;
+
+    Ok(())
+}
+
+/// Copy as much of a string as fits into a header field, the full value lives in a pax record
+fn truncate_into(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len() - 1);
+    field.fill(0);
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+fn set_path(header: &mut Header, pax: &mut PaxRecords, path: &Path) {
+    if header.set_path(path).is_err() {
+        let path = path.to_string_lossy();
+        pax.add("path", path.as_bytes());
+        truncate_into(&mut header.as_old_mut().name, path.as_bytes());
+        if let Some(ustar) = header.as_ustar_mut() {
+            ustar.prefix.fill(0);
+        }
+    }
+}
+
+fn set_link_name(header: &mut Header, pax: &mut PaxRecords, target: &Path) {
+    if header.set_link_name(target).is_err() {
+        let target = target.to_string_lossy();
+        pax.add("linkpath", target.as_bytes());
+        truncate_into(&mut header.as_old_mut().linkname, target.as_bytes());
+    }
+}
+
+fn set_size(header: &mut Header, pax: &mut PaxRecords, size: u64) {
+    header.set_size(size);
+    if size > USTAR_MAX_SIZE {
+        pax.add("size", size.to_string());
+    }
+}
+
+fn set_metadata(header: &mut Header, pax: &mut PaxRecords, node: &Node) {
+    let default_mode = match node.kind {
+        NodeKind::Dir => 0o755,
+        NodeKind::Symlink { .. } => 0o777,
+        _ => 0o644,
+    };
+    header.set_mode(node.mode.unwrap_or(default_mode));
+    header.set_uid(node.uid.unwrap_or(0) as u64);
+    header.set_gid(node.gid.unwrap_or(0) as u64);
+
+    if let Some(user) = &node.user {
+        if header.set_username(user).is_err() {
+            pax.add("uname", user);
+        }
+    }
+    if let Some(group) = &node.group {
+        if header.set_groupname(group).is_err() {
+            pax.add("gname", group);
+        }
+    }
+
+    if let Some(mtime) = node.mtime {
+        header.set_mtime(mtime.timestamp().max(0) as u64);
+        if mtime.timestamp_subsec_nanos() != 0 {
+            // sign and absolute value, the fraction of a negative timestamp counts towards zero
+            let nanos =
+                mtime.timestamp() as i128 * 1_000_000_000 + mtime.timestamp_subsec_nanos() as i128;
+            let sign = if nanos < 0 { "-" } else { "" };
+            let nanos = nanos.unsigned_abs();
+            pax.add(
+                "mtime",
+                format!(
+                    "{sign}{}.{:09}",
+                    nanos / 1_000_000_000,
+                    nanos % 1_000_000_000
+                ),
+            );
+        }
+    }
+
+    // same keys as GNU tar and bsdtar
+    for (name, value) in &node.xattrs {
+        pax.add(&format!("SCHILY.xattr.{name}"), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn pax_records() {
+        let mut header = Header::new_ustar();
+        let mut pax = PaxRecords::default();
+        set_size(&mut header, &mut pax, USTAR_MAX_SIZE);
+        assert!(pax.0.is_empty());
+        set_size(&mut header, &mut pax, USTAR_MAX_SIZE + 1);
+        assert_eq!(pax.0, b"19 size=8589934592\n");
+
+        // the length counts its own digits, which can push it to the next power of ten
+        let mut pax = PaxRecords::default();
+        pax.add("path", "a".repeat(91));
+        assert_eq!(&pax.0[..9], b"101 path=");
+        assert_eq!(pax.0.len(), 101);
+
+        let mut pax = PaxRecords::default();
+        let mut node = Node::new("file", NodeKind::File);
+        node.mtime = Utc.timestamp_opt(-1, 500_000_000).single();
+        set_metadata(&mut header, &mut pax, &node);
+        assert_eq!(pax.0, b"22 mtime=-0.500000000\n");
+        assert_eq!(header.mtime().unwrap(), 0);
+    }
+}
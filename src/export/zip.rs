@@ -0,0 +1,392 @@
+//! Write a snapshot as a zip archive without seeking, so it can be streamed to stdout
+//!
+//! Every entry is followed by a data descriptor holding its CRC and sizes, since they're only
+//! known once the contents have been written. Large files, offsets and entry counts switch to the
+//! zip64 fields. See https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+//!
+//! Zip has no notion of hard links, every file of a hard link group is stored in full.
+
+use std::io::{Read, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use chrono::{Datelike, Timelike};
+use flate2::{write::DeflateEncoder, Compression as DeflateLevel};
+
+use super::{Compression, ExportOptions};
+use crate::{
+    error::{Error, Result},
+    formats::{
+        tree::{Node, NodeKind},
+        Backup,
+    },
+};
+
+const LOCAL_FILE_HEADER: u32 = 0x04034b50;
+const DATA_DESCRIPTOR: u32 = 0x08074b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x02014b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR: u32 = 0x07064b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+
+// extra field header IDs
+const ZIP64_EXTRA: u16 = 0x0001;
+const EXTENDED_TIMESTAMP: u16 = 0x5455;
+const INFO_ZIP_UNIX: u16 = 0x7875;
+
+// general purpose flags
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+
+const METHOD_STORE: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+/// Made by unix, spec version 6.3
+const VERSION_MADE_BY: u16 = (3 << 8) | 63;
+const VERSION_NEEDED: u16 = 20;
+const VERSION_NEEDED_ZIP64: u16 = 45;
+
+/// Files that might not fit into the 32 bit fields use a zip64 data descriptor, leave some room
+/// for deflate making incompressible data slightly larger
+const ZIP64_THRESHOLD: u64 = 0xF000_0000;
+
+// unix file type bits stored in the upper half of the external attributes
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+/// MS-DOS directory attribute
+const DOS_DIRECTORY: u32 = 0x10;
+
+/// Keeps track of the offset into the archive
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Calculates the CRC and size of the uncompressed contents while they're read
+struct CrcReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+    count: u64,
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// Everything the central directory needs to know about an entry that has been written
+struct CentralEntry {
+    name: Vec<u8>,
+    flags: u16,
+    method: u16,
+    dos_time: u16,
+    dos_date: u16,
+    crc: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    offset: u64,
+    external_attributes: u32,
+    extra: Vec<u8>,
+}
+
+pub fn export(
+    backup: &dyn Backup,
+    nodes: &[Node],
+    writer: impl Write,
+    options: &ExportOptions,
+) -> Result<()> {
+    let mut out = CountingWriter {
+        inner: writer,
+        count: 0,
+    };
+    let mut entries = Vec::new();
+
+    for node in nodes {
+        let mut name = node.path.to_string_lossy().to_string();
+
+        let (file_type, method) = match &node.kind {
+            NodeKind::Dir => {
+                name.push('/');
+                (S_IFDIR, METHOD_STORE)
+            }
+            NodeKind::Symlink { .. } => (S_IFLNK, METHOD_STORE),
+            NodeKind::File => match options.compression {
+                Compression::Store => (S_IFREG, METHOD_STORE),
+                Compression::Deflate => (S_IFREG, METHOD_DEFLATE),
+            },
+            NodeKind::Special => {
+                trace!("Skipping special file {:?}", node.path);
+                continue;
+            }
+        };
+
+        let default_mode = match node.kind {
+            NodeKind::Dir => 0o755,
+            NodeKind::Symlink { .. } => 0o777,
+            _ => 0o644,
+        };
+        let mut external_attributes = (file_type | node.mode.unwrap_or(default_mode)) << 16;
+        if node.kind == NodeKind::Dir {
+            external_attributes |= DOS_DIRECTORY;
+        }
+
+        // unknown sizes might end up being large
+        let zip64 = node.kind == NodeKind::File && node.size.unwrap_or(u64::MAX) > ZIP64_THRESHOLD;
+
+        let (dos_time, dos_date) = dos_date_time(node);
+        let flags = FLAG_DATA_DESCRIPTOR | FLAG_UTF8;
+        let offset = out.count;
+
+        // local file header, the CRC and sizes follow the data in the descriptor
+        out.write_u32::<LittleEndian>(LOCAL_FILE_HEADER)?;
+        out.write_u16::<LittleEndian>(if zip64 {
+            VERSION_NEEDED_ZIP64
+        } else {
+            VERSION_NEEDED
+        })?;
+        out.write_u16::<LittleEndian>(flags)?;
+        out.write_u16::<LittleEndian>(method)?;
+        out.write_u16::<LittleEndian>(dos_time)?;
+        out.write_u16::<LittleEndian>(dos_date)?;
+        out.write_u32::<LittleEndian>(0)?; // crc
+        out.write_u32::<LittleEndian>(if zip64 { u32::MAX } else { 0 })?; // compressed size
+        out.write_u32::<LittleEndian>(if zip64 { u32::MAX } else { 0 })?; // uncompressed size
+
+        let mut local_extra = Vec::new();
+        if zip64 {
+            // the sizes are unknown, but the field signals the descriptor uses 64 bit sizes
+            write_extra(&mut local_extra, ZIP64_EXTRA, &[0; 16])?;
+        }
+        let metadata_extra = metadata_extra(node)?;
+        local_extra.extend(&metadata_extra);
+
+        out.write_u16::<LittleEndian>(name.len() as u16)?;
+        out.write_u16::<LittleEndian>(local_extra.len() as u16)?;
+        out.write_all(name.as_bytes())?;
+        out.write_all(&local_extra)?;
+
+        // contents
+        let data_start = out.count;
+        let (crc, uncompressed_size) = match &node.kind {
+            NodeKind::File => {
+                debug!("Exporting {:?}", node.path);
+                let reader = CrcReader {
+                    inner: node.reader(backup),
+                    hasher: crc32fast::Hasher::new(),
+                    count: 0,
+                };
+                write_data(&mut out, reader, method)?
+            }
+            NodeKind::Symlink { target } => {
+                let reader = CrcReader {
+                    inner: target.as_bytes(),
+                    hasher: crc32fast::Hasher::new(),
+                    count: 0,
+                };
+                write_data(&mut out, reader, method)?
+            }
+            _ => (0, 0),
+        };
+        let compressed_size = out.count - data_start;
+
+        // data descriptor
+        out.write_u32::<LittleEndian>(DATA_DESCRIPTOR)?;
+        out.write_u32::<LittleEndian>(crc)?;
+        if zip64 {
+            out.write_u64::<LittleEndian>(compressed_size)?;
+            out.write_u64::<LittleEndian>(uncompressed_size)?;
+        } else {
+            // only possible if the contents are larger than the size the node claims
+            let (Ok(compressed_size), Ok(uncompressed_size)) = (
+                u32::try_from(compressed_size),
+                u32::try_from(uncompressed_size),
+            ) else {
+                return Err(Error::MismatchedSize(node.path.clone()));
+            };
+            out.write_u32::<LittleEndian>(compressed_size)?;
+            out.write_u32::<LittleEndian>(uncompressed_size)?;
+        }
+
+        entries.push(CentralEntry {
+            name: name.into_bytes(),
+            flags,
+            method,
+            dos_time,
+            dos_date,
+            crc,
+            compressed_size,
+            uncompressed_size,
+            offset,
+            external_attributes,
+            extra: metadata_extra,
+        });
+    }
+
+    write_central_directory(&mut out, &entries)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Copy the contents, compressing them if needed, and return their CRC and uncompressed size
+fn write_data<W: Write, R: Read>(
+    out: &mut W,
+    mut reader: CrcReader<R>,
+    method: u16,
+) -> Result<(u32, u64)> {
+    if method == METHOD_DEFLATE {
+        let mut encoder = DeflateEncoder::new(&mut *out, DeflateLevel::default());
+        std::io::copy(&mut reader, &mut encoder)?;
+        encoder.finish()?;
+    } else {
+        std::io::copy(&mut reader, out)?;
+    }
+
+    Ok((reader.hasher.finalize(), reader.count))
+}
+
+fn write_central_directory<W: Write>(
+    out: &mut CountingWriter<W>,
+    entries: &[CentralEntry],
+) -> Result<()> {
+    let central_directory_start = out.count;
+
+    for entry in entries {
+        // anything that doesn't fit is moved to the zip64 extra field, in this order
+        let mut zip64 = Vec::new();
+        if entry.uncompressed_size >= u32::MAX as u64 {
+            zip64.write_u64::<LittleEndian>(entry.uncompressed_size)?;
+        }
+        if entry.compressed_size >= u32::MAX as u64 {
+            zip64.write_u64::<LittleEndian>(entry.compressed_size)?;
+        }
+        if entry.offset >= u32::MAX as u64 {
+            zip64.write_u64::<LittleEndian>(entry.offset)?;
+        }
+
+        let mut extra = Vec::new();
+        if !zip64.is_empty() {
+            write_extra(&mut extra, ZIP64_EXTRA, &zip64)?;
+        }
+        extra.extend(&entry.extra);
+
+        out.write_u32::<LittleEndian>(CENTRAL_DIRECTORY_HEADER)?;
+        out.write_u16::<LittleEndian>(VERSION_MADE_BY)?;
+        out.write_u16::<LittleEndian>(if zip64.is_empty() {
+            VERSION_NEEDED
+        } else {
+            VERSION_NEEDED_ZIP64
+        })?;
+        out.write_u16::<LittleEndian>(entry.flags)?;
+        out.write_u16::<LittleEndian>(entry.method)?;
+        out.write_u16::<LittleEndian>(entry.dos_time)?;
+        out.write_u16::<LittleEndian>(entry.dos_date)?;
+        out.write_u32::<LittleEndian>(entry.crc)?;
+        out.write_u32::<LittleEndian>(entry.compressed_size.min(u32::MAX as u64) as u32)?;
+        out.write_u32::<LittleEndian>(entry.uncompressed_size.min(u32::MAX as u64) as u32)?;
+        out.write_u16::<LittleEndian>(entry.name.len() as u16)?;
+        out.write_u16::<LittleEndian>(extra.len() as u16)?;
+        out.write_u16::<LittleEndian>(0)?; // comment length
+        out.write_u16::<LittleEndian>(0)?; // disk number
+        out.write_u16::<LittleEndian>(0)?; // internal attributes
+        out.write_u32::<LittleEndian>(entry.external_attributes)?;
+        out.write_u32::<LittleEndian>(entry.offset.min(u32::MAX as u64) as u32)?;
+        out.write_all(&entry.name)?;
+        out.write_all(&extra)?;
+    }
+
+    let central_directory_end = out.count;
+    let central_directory_size = central_directory_end - central_directory_start;
+
+    let needs_zip64 = entries.len() >= u16::MAX as usize
+        || central_directory_size >= u32::MAX as u64
+        || central_directory_start >= u32::MAX as u64;
+
+    if needs_zip64 {
+        out.write_u32::<LittleEndian>(ZIP64_END_OF_CENTRAL_DIRECTORY)?;
+        out.write_u64::<LittleEndian>(44)?; // size of the remaining record
+        out.write_u16::<LittleEndian>(VERSION_MADE_BY)?;
+        out.write_u16::<LittleEndian>(VERSION_NEEDED_ZIP64)?;
+        out.write_u32::<LittleEndian>(0)?; // disk number
+        out.write_u32::<LittleEndian>(0)?; // disk with the central directory
+        out.write_u64::<LittleEndian>(entries.len() as u64)?; // entries on this disk
+        out.write_u64::<LittleEndian>(entries.len() as u64)?; // total entries
+        out.write_u64::<LittleEndian>(central_directory_size)?;
+        out.write_u64::<LittleEndian>(central_directory_start)?;
+
+        out.write_u32::<LittleEndian>(ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR)?;
+        out.write_u32::<LittleEndian>(0)?; // disk with the zip64 end of central directory
+        out.write_u64::<LittleEndian>(central_directory_end)?;
+        out.write_u32::<LittleEndian>(1)?; // total number of disks
+    }
+
+    out.write_u32::<LittleEndian>(END_OF_CENTRAL_DIRECTORY)?;
+    out.write_u16::<LittleEndian>(0)?; // disk number
+    out.write_u16::<LittleEndian>(0)?; // disk with the central directory
+    out.write_u16::<LittleEndian>(entries.len().min(u16::MAX as usize) as u16)?;
+    out.write_u16::<LittleEndian>(entries.len().min(u16::MAX as usize) as u16)?;
+    out.write_u32::<LittleEndian>(central_directory_size.min(u32::MAX as u64) as u32)?;
+    out.write_u32::<LittleEndian>(central_directory_start.min(u32::MAX as u64) as u32)?;
+    out.write_u16::<LittleEndian>(0)?; // comment length
+
+    Ok(())
+}
+
+fn write_extra(extra: &mut Vec<u8>, id: u16, data: &[u8]) -> Result<()> {
+    extra.write_u16::<LittleEndian>(id)?;
+    extra.write_u16::<LittleEndian>(data.len() as u16)?;
+    extra.extend(data);
+
+    Ok(())
+}
+
+/// Extended timestamp and Info-ZIP unix ownership fields, identical in the local and central
+/// headers since only the mtime is stored
+fn metadata_extra(node: &Node) -> Result<Vec<u8>> {
+    let mut extra = Vec::new();
+
+    if let Some(mtime) = node.mtime {
+        let mut timestamp = vec![1]; // flags: mtime present
+        timestamp.write_i32::<LittleEndian>(mtime.timestamp() as i32)?;
+        write_extra(&mut extra, EXTENDED_TIMESTAMP, &timestamp)?;
+    }
+
+    if let (Some(uid), Some(gid)) = (node.uid, node.gid) {
+        let mut unix = vec![1, 4]; // version, uid size
+        unix.write_u32::<LittleEndian>(uid)?;
+        unix.push(4); // gid size
+        unix.write_u32::<LittleEndian>(gid)?;
+        write_extra(&mut extra, INFO_ZIP_UNIX, &unix)?;
+    }
+
+    Ok(extra)
+}
+
+/// MS-DOS time and date, with a 2 second resolution and no dates before 1980
+fn dos_date_time(node: &Node) -> (u16, u16) {
+    let Some(mtime) = node.mtime.filter(|mtime| mtime.year() >= 1980) else {
+        // 1980-01-01 00:00:00
+        return (0, (1 << 5) | 1);
+    };
+
+    let time = (mtime.hour() << 11) | (mtime.minute() << 5) | (mtime.second() / 2);
+    let date = (((mtime.year() - 1980) as u32).min(127) << 9) | (mtime.month() << 5) | mtime.day();
+
+    (time as u16, date as u16)
+}
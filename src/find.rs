@@ -0,0 +1,81 @@
+//! Search the metadata of every snapshot for matching paths
+
+use std::{io::Write, path::Path};
+
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+
+use crate::{error::Result, formats::Backup};
+
+/// Pattern matched against the paths of a snapshot
+#[derive(Debug)]
+pub enum PathPattern {
+    /// Globs without a `/` only match the file name, like `find -name`
+    Glob {
+        matcher: GlobMatcher,
+        name_only: bool,
+    },
+    /// Matched anywhere in the full path
+    Regex(Regex),
+}
+
+impl PathPattern {
+    pub fn glob(pattern: &str) -> Result<Self> {
+        let pattern = pattern.trim_start_matches('/');
+
+        Ok(Self::Glob {
+            // `*` stays within a directory, `**` crosses them
+            matcher: GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()?
+                .compile_matcher(),
+            name_only: !pattern.contains('/'),
+        })
+    }
+
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Ok(Self::Regex(Regex::new(pattern)?))
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        match self {
+            PathPattern::Glob { matcher, name_only } if *name_only => {
+                path.file_name().is_some_and(|name| matcher.is_match(name))
+            }
+            PathPattern::Glob { matcher, .. } => matcher.is_match(path),
+            PathPattern::Regex(regex) => regex.is_match(&path.to_string_lossy()),
+        }
+    }
+}
+
+/// Walk every snapshot and write one line per matching entry: snapshot, snapshot time, size,
+/// mtime and path. Returns the number of matches.
+pub fn find(backup: &dyn Backup, pattern: &PathPattern, mut writer: impl Write) -> Result<usize> {
+    let mut matches = 0;
+
+    for snapshot in backup.snapshots() {
+        debug!("Searching snapshot {}", snapshot.id);
+
+        for node in backup.tree(&snapshot.id)? {
+            if !pattern.is_match(&node.path) {
+                continue;
+            }
+
+            matches += 1;
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}",
+                snapshot.id,
+                snapshot.time.to_rfc3339(),
+                node.size.map(|size| size.to_string()).unwrap_or_default(),
+                node.mtime
+                    .map(|mtime| mtime.to_rfc3339())
+                    .unwrap_or_default(),
+                node.path.display()
+            )?;
+        }
+    }
+
+    writer.flush()?;
+    Ok(matches)
+}
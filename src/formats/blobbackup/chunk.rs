@@ -1,7 +1,7 @@
 use std::path::Path;
 
 use super::{decoder::Decoder, keys::Keys};
-use crate::error::Result;
+use crate::{error::Result, storage::Storage};
 
 #[derive(Debug)]
 pub struct Chunk {
@@ -9,8 +9,8 @@ pub struct Chunk {
 }
 
 impl Chunk {
-    pub fn from_file(keys: &Keys, path: impl AsRef<Path>) -> Result<Self> {
-        let file = std::fs::read(path)?;
+    pub fn from_file(storage: &dyn Storage, keys: &Keys, path: impl AsRef<Path>) -> Result<Self> {
+        let file = storage.read(path.as_ref())?;
 
         let decoder = Decoder::new(&keys.master_key);
         let data = decoder.decrypt_and_decompress(&file)?;
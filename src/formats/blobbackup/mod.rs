@@ -1,17 +1,20 @@
 use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    rc::Rc,
 };
 
 use chrono::prelude::*;
 
-use crate::error::Result;
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, SnapshotInfo},
+    storage::Storage,
+};
 use chunk::Chunk;
 use keys::Keys;
 use snapshot::Snapshot;
 
-use self::snapshot::ItemType;
-
 mod chunk;
 mod decoder;
 mod keys;
@@ -20,41 +23,39 @@ mod snapshot;
 #[derive(Debug)]
 pub struct BlobBackup {
     pub path: PathBuf,
+    storage: Rc<dyn Storage>,
     pub keys: Keys,
-    pub password: String,
 
     pub snapshots: HashMap<String, Snapshot>,
 }
 
 impl BlobBackup {
-    pub fn from_folder(path: impl Into<PathBuf>, password: impl Into<String>) -> Result<Self> {
+    pub fn from_folder(
+        storage: Rc<dyn Storage>,
+        path: impl Into<PathBuf>,
+        password: impl Into<String>,
+    ) -> Result<Self> {
         let path = path.into();
         let password = password.into();
 
         // load keys
-        let keys = Keys::from_folder(path.join("keys"), &password)?;
+        let keys = Keys::from_folder(&*storage, path.join("keys"), &password)?;
 
         Ok(Self {
             path,
+            storage,
             keys,
-            password,
 
             snapshots: HashMap::new(),
         })
     }
 
     pub fn load_all_snapshots(&mut self) -> Result<()> {
-        for entry in std::fs::read_dir(self.path.join("snapshots"))? {
-            let entry = entry?;
-            let path = entry.path();
+        for entry in self.storage.list(&self.path.join("snapshots"))? {
+            if !entry.stat.is_dir {
+                let snapshot = Snapshot::from_file(&*self.storage, &self.keys, &entry.path)?;
 
-            if path.is_file() {
-                let snapshot = Snapshot::from_file(&self.keys, &path)?;
-
-                self.snapshots.insert(
-                    path.file_name().unwrap().to_string_lossy().to_string(),
-                    snapshot,
-                );
+                self.snapshots.insert(entry.name, snapshot);
             }
         }
 
@@ -67,74 +68,70 @@ impl BlobBackup {
         Ok(())
     }
 
-    pub fn dump_all_files(&mut self, output_dir: impl AsRef<Path>) -> Result<()> {
-        // find the latest snapshot
-        let latest_name = self
+    pub fn resolve_path(&self, hash: &str) -> PathBuf {
+        self.path.join("chunks").join(hash)
+    }
+}
+
+impl Backup for BlobBackup {
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        let mut snapshots: Vec<SnapshotInfo> = self
             .snapshots
             .keys()
-            .max_by_key(|x| {
+            .map(|name| SnapshotInfo {
+                id: name.clone(),
+                // there's only one source per repository
+                group: String::new(),
                 // parse the timestamp (e.g. 2023-08-15-18-36-19)
-                Utc.datetime_from_str(x, "%Y-%m-%d-%H-%M-%S")
-                    .expect("Failed to parse datetime from snapshot name")
+                time: Utc
+                    .datetime_from_str(name, "%Y-%m-%d-%H-%M-%S")
+                    .expect("Failed to parse datetime from snapshot name"),
             })
-            .expect("Failed to find the latest snapshot");
-
-        let latest = self.snapshots.get(latest_name).unwrap();
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.time);
 
-        // create the output_dir folder
-        std::fs::create_dir_all(output_dir.as_ref())?;
+        snapshots
+    }
 
-        // read all the data chunks referenced by the snapshot
-        let mut chunks = Vec::new();
-        for hash in &latest.chunks {
-            let chunk = Chunk::from_file(&self.keys, self.resolve_path(hash))?;
-            chunks.push(chunk);
-        }
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let id = self.find_snapshot(snapshot)?.id;
+        let snapshot = &self.snapshots[&id];
 
-        // ignore the folder structure, iterate only over the files
-        for (name, item) in latest
+        let mut nodes: Vec<tree::Node> = snapshot
             .snapshot
             .iter()
-            .filter(|(_, item)| item.item_type == ItemType::File)
-        {
-            let filename = Path::new(name)
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .to_string();
-
-            let range = item.range.unwrap();
-
-            let mut data: Vec<u8> = Vec::new();
-
-            // satisfy clippy - i'm not sure what to think of this
-            for (index, chunk) in chunks
-                .iter()
-                .enumerate()
-                .take(range.end_chunk + 1)
-                .skip(range.start_chunk)
-            {
-                let buf = if range.start_chunk == range.end_chunk {
-                    // read it all in one go
-                    &chunk.data[range.start_offset..range.end_offset]
-                } else if index == range.start_chunk {
-                    &chunk.data[range.start_offset..]
-                } else if index == range.end_chunk {
-                    &chunk.data[..range.end_offset]
-                } else {
-                    &chunk.data
-                };
-
-                data.extend(buf);
-            }
+            .map(|(path, item)| item.to_tree_node(path, &snapshot.chunks))
+            .collect();
 
-            std::fs::write(output_dir.as_ref().join(filename), data)?;
-        }
+        // items are stored in a map, sorting by path lists parents before their children
+        nodes.sort_by(|a, b| a.path.cmp(&b.path));
 
-        Ok(())
+        Ok(nodes)
     }
 
-    pub fn resolve_path(&self, hash: &str) -> PathBuf {
-        self.path.join("chunks").join(hash)
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        let chunk = Chunk::from_file(&*self.storage, &self.keys, self.resolve_path(id))?;
+        Ok(chunk.data)
+    }
+
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        Ok(self.storage.stat(&self.resolve_path(id))?.size)
+    }
+
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        vec![self.path.join("chunks")]
+    }
+
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        Ok(self
+            .snapshots
+            .values()
+            .flat_map(|snapshot| &snapshot.chunks)
+            .map(|id| self.resolve_path(id))
+            .collect())
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        &*self.storage
     }
 }
@@ -1,10 +1,16 @@
 use std::{collections::HashMap, path::Path};
 
+use chrono::{TimeZone, Utc};
 use serde::{Deserialize, Deserializer};
 
 use super::{decoder::Decoder, keys::Keys};
-use crate::error::Result;
+use crate::{
+    error::Result,
+    formats::tree::{self, ChunkRef, NodeKind},
+    storage::Storage,
+};
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Snapshot {
@@ -38,9 +44,53 @@ pub struct ChunkRange {
     pub end_offset: usize,
 }
 
+impl Item {
+    /// Convert into the format independent representation, `chunks` is the list of chunks of the
+    /// snapshot the item belongs to
+    pub fn to_tree_node(&self, path: &str, chunks: &[String]) -> tree::Node {
+        let kind = match self.item_type {
+            ItemType::Dir => NodeKind::Dir,
+            ItemType::File => NodeKind::File,
+        };
+
+        let mut node = tree::Node::new(path.trim_start_matches('/'), kind);
+        node.mtime = Utc
+            .timestamp_opt(
+                self.mtime.trunc() as i64,
+                (self.mtime.fract() * 1_000_000_000.0) as u32,
+            )
+            .single();
+
+        if let Some(range) = self.range {
+            node.chunks = (range.start_chunk..=range.end_chunk)
+                .map(|chunk| ChunkRef {
+                    id: chunks[chunk].clone(),
+                    start: if chunk == range.start_chunk {
+                        range.start_offset
+                    } else {
+                        0
+                    },
+                    end: if chunk == range.end_chunk {
+                        Some(range.end_offset)
+                    } else {
+                        None
+                    },
+                    size: None,
+                })
+                .collect();
+        }
+
+        node
+    }
+}
+
 impl Snapshot {
-    pub fn from_file(keys: &Keys, path: impl AsRef<Path>) -> Result<Snapshot> {
-        let file = std::fs::read(path)?;
+    pub fn from_file(
+        storage: &dyn Storage,
+        keys: &Keys,
+        path: impl AsRef<Path>,
+    ) -> Result<Snapshot> {
+        let file = storage.read(path.as_ref())?;
 
         let decoder = Decoder::new(&keys.master_key);
         let data = decoder.decrypt_and_decompress(&file)?;
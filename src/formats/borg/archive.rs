@@ -0,0 +1,236 @@
+//! The manifest, archive metadata and the item streams of the archives
+
+use std::{collections::HashMap, ffi::OsStr, os::unix::ffi::OsStrExt};
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use rmpv::Value;
+
+use super::{error::Error, msgpack};
+use crate::{
+    error::Result,
+    formats::tree::{self, ChunkRef, Inode, NodeKind},
+};
+
+const S_IFMT: u64 = 0o170000;
+const S_IFDIR: u64 = 0o040000;
+const S_IFREG: u64 = 0o100000;
+const S_IFLNK: u64 = 0o120000;
+
+#[derive(Debug, Clone)]
+pub struct ArchiveRef {
+    pub name: String,
+    pub id: Vec<u8>,
+}
+
+/// List the archives of a decoded manifest
+pub fn parse_manifest(data: &[u8]) -> Result<Vec<ArchiveRef>> {
+    let manifest = msgpack::decode(data)?;
+    let archives = msgpack::get(&manifest, "archives")
+        .and_then(Value::as_map)
+        .ok_or_else(|| Error::InvalidMsgpack("manifest without archives".into()))?;
+
+    archives
+        .iter()
+        .map(|(name, archive)| {
+            Ok(ArchiveRef {
+                name: String::from_utf8_lossy(msgpack::bytes(name).unwrap_or_default()).to_string(),
+                id: msgpack::require_bytes(archive, "id")?.to_vec(),
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub name: String,
+    pub hostname: String,
+    pub time: DateTime<Utc>,
+    /// Chunks holding the msgpack encoded items
+    pub items: Vec<Vec<u8>>,
+    /// Borg 2 lists the item chunks in these objects instead
+    pub item_ptrs: Vec<Vec<u8>>,
+}
+
+impl Archive {
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        let archive = msgpack::decode(data)?;
+
+        // naive UTC timestamps, borg 2 adds the offset
+        let time = msgpack::get_string(&archive, "time").unwrap_or_default();
+        let time = match DateTime::parse_from_rfc3339(&time) {
+            Ok(time) => time.with_timezone(&Utc),
+            Err(_) => Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(
+                &time,
+                "%Y-%m-%dT%H:%M:%S%.f",
+            )?),
+        };
+
+        let ids = |key: &str| -> Vec<Vec<u8>> {
+            msgpack::get(&archive, key)
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|id| msgpack::bytes(id).map(<[u8]>::to_vec))
+                .collect()
+        };
+        if msgpack::get(&archive, "items").is_none()
+            && msgpack::get(&archive, "item_ptrs").is_none()
+        {
+            return Err(Error::InvalidMsgpack("archive without items".into()).into());
+        }
+
+        Ok(Self {
+            name: msgpack::get_string(&archive, "name").unwrap_or_default(),
+            hostname: msgpack::get_string(&archive, "hostname").unwrap_or_default(),
+            time,
+            items: ids("items"),
+            item_ptrs: ids("item_ptrs"),
+        })
+    }
+}
+
+/// IDs of the item chunks listed by an item pointer object
+pub fn parse_item_ptrs(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    msgpack::decode(data)?
+        .as_array()
+        .ok_or_else(|| Error::InvalidMsgpack("item pointers aren't a list".into()))?
+        .iter()
+        .map(|id| {
+            msgpack::bytes(id)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| Error::InvalidMsgpack("invalid item pointer".into()).into())
+        })
+        .collect()
+}
+
+/// Decode the concatenated item chunks of an archive, items can span chunk boundaries
+pub fn parse_items(data: &[u8]) -> Result<Vec<Value>> {
+    let mut items = Vec::new();
+    let mut cursor = data;
+    while !cursor.is_empty() {
+        let item = rmpv::decode::read_value(&mut cursor)
+            .map_err(|e| Error::InvalidMsgpack(e.to_string()))?;
+        items.push(item);
+    }
+
+    Ok(items)
+}
+
+/// Convert the items of an archive. Borg 1 stores hard links as a master item holding the chunks
+/// and items pointing to it via `source`, borg 2 gives every item of the group the same `hlid`.
+/// The group shares a made up inode.
+pub fn to_tree_nodes(items: &[Value]) -> Vec<tree::Node> {
+    let path = |item: &Value| {
+        msgpack::get_bytes(item, "path")
+            .unwrap_or_default()
+            .to_vec()
+    };
+    let is_link = |item: &Value| {
+        let mode = msgpack::get_u64(item, "mode").unwrap_or_default();
+        mode & S_IFMT == S_IFREG && msgpack::get(item, "source").is_some()
+    };
+    let group = |item: &Value| match msgpack::get_bytes(item, "hlid") {
+        Some(hlid) => hlid.to_vec(),
+        None => path(item),
+    };
+
+    // group the hard links by their master, or the first item with the same ID
+    let mut groups: HashMap<Vec<u8>, (usize, u64)> = HashMap::new();
+    for (i, item) in items.iter().enumerate() {
+        if msgpack::get(item, "hardlink_master").and_then(Value::as_bool) == Some(true)
+            || msgpack::get(item, "hlid").is_some()
+        {
+            groups.entry(group(item)).or_insert((i, 0)).1 += 1;
+        }
+    }
+    for item in items.iter().filter(|item| is_link(item)) {
+        if let Some(group) = msgpack::get_bytes(item, "source").and_then(|s| groups.get_mut(s)) {
+            group.1 += 1;
+        }
+    }
+
+    items
+        .iter()
+        .map(|item| {
+            let master = match is_link(item) {
+                true => msgpack::get_bytes(item, "source").and_then(|s| groups.get(s)),
+                false => groups.get(&group(item)),
+            };
+            let mut node = to_tree_node(item, master.map(|(i, _)| &items[*i]));
+            if let Some((inode, links)) = master {
+                node.inode = Some(Inode {
+                    device: 0,
+                    inode: *inode as u64,
+                    links: *links,
+                });
+            }
+
+            node
+        })
+        .collect()
+}
+
+/// Convert a single item, hard links take their contents from `master`
+fn to_tree_node(item: &Value, master: Option<&Value>) -> tree::Node {
+    let mode = msgpack::get_u64(item, "mode").unwrap_or_default();
+    let kind = match mode & S_IFMT {
+        S_IFDIR => NodeKind::Dir,
+        S_IFREG => NodeKind::File,
+        // borg 2 renamed `source` to `target`
+        S_IFLNK => NodeKind::Symlink {
+            target: msgpack::get_string(item, "target")
+                .or_else(|| msgpack::get_string(item, "source"))
+                .unwrap_or_default(),
+        },
+        _ => NodeKind::Special,
+    };
+
+    let path = msgpack::get_bytes(item, "path").unwrap_or_default();
+    let path = OsStr::from_bytes(path.strip_prefix(b"/").unwrap_or(path));
+
+    let mut node = tree::Node::new(path, kind);
+    node.mode = Some((mode & 0o7777) as u32);
+    node.uid = msgpack::get_u64(item, "uid").map(|uid| uid as u32);
+    node.gid = msgpack::get_u64(item, "gid").map(|gid| gid as u32);
+    node.user = msgpack::get_string(item, "user");
+    node.group = msgpack::get_string(item, "group");
+    node.mtime = msgpack::get_i64(item, "mtime").map(|ns| Utc.timestamp_nanos(ns));
+    node.xattrs = msgpack::get(item, "xattrs")
+        .and_then(Value::as_map)
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, value)| {
+            Some((
+                String::from_utf8_lossy(msgpack::bytes(name)?).to_string(),
+                msgpack::bytes(value).unwrap_or_default().to_vec(),
+            ))
+        })
+        .collect();
+
+    if node.kind == NodeKind::File {
+        let contents = master.unwrap_or(item);
+
+        // each entry is id | size | stored size, borg 2 drops the stored size
+        node.chunks = msgpack::get(contents, "chunks")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|chunk| {
+                let chunk = chunk.as_array()?;
+                Some(ChunkRef {
+                    size: chunk.get(1).and_then(Value::as_u64).map(|s| s as usize),
+                    ..ChunkRef::whole(hex::encode(msgpack::bytes(chunk.first()?)?))
+                })
+            })
+            .collect();
+
+        node.size = msgpack::get_u64(contents, "size").or_else(|| {
+            node.chunks
+                .iter()
+                .map(|chunk| chunk.size.map(|size| size as u64))
+                .sum()
+        });
+    }
+
+    node
+}
@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use super::error::Error;
+use crate::{error::Result, storage::Storage};
+
+#[derive(Debug, Default)]
+pub struct Config {
+    pub version: u32,
+    pub segments_per_dir: u64,
+    pub id: Vec<u8>,
+    /// Encrypted key for repokey mode, base64 encoded
+    pub key: Option<String>,
+}
+
+impl Config {
+    /// Parse the INI style config, values can continue on indented lines
+    pub fn from_file(storage: &dyn Storage, path: impl AsRef<Path>) -> Result<Self> {
+        let file = storage.read_to_string(path.as_ref())?;
+
+        let mut values: Vec<(String, String)> = Vec::new();
+        let mut section = String::new();
+        for line in file.lines() {
+            if line.trim().is_empty() || line.trim_start().starts_with(['#', ';']) {
+                continue;
+            }
+
+            if line.starts_with([' ', '\t']) {
+                // continuation of the previous value
+                if let Some((_, value)) = values.last_mut() {
+                    value.push_str(line.trim());
+                }
+            } else if let Some(name) = line.trim().strip_prefix('[') {
+                section = name.trim_end_matches(']').to_string();
+            } else if let Some((key, value)) = line.split_once('=') {
+                if section == "repository" {
+                    values.push((key.trim().to_string(), value.trim().to_string()));
+                }
+            }
+        }
+
+        let get = |key: &str| {
+            values
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.as_str())
+        };
+        let parse = |key: &str| -> Result<u64> {
+            get(key)
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| Error::InvalidConfig(format!("missing or invalid {key}")).into())
+        };
+
+        let config = Config {
+            version: parse("version")? as u32,
+            segments_per_dir: parse("segments_per_dir")?,
+            id: hex::decode(get("id").unwrap_or_default())?,
+            key: get("key").filter(|key| !key.is_empty()).map(str::to_string),
+        };
+        trace!("Config: {config:?}");
+
+        Ok(config)
+    }
+}
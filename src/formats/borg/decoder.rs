@@ -0,0 +1,470 @@
+//! Decrypt, decompress and verify repository objects
+
+use std::io::Read;
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    ChaCha20Poly1305,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+
+use super::{
+    error::Error,
+    keys::{Aes256Ctr, Key},
+    msgpack,
+    ocb::Ocb,
+};
+use crate::error::Result;
+
+/// The first byte of every object identifies the key type it was written with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    Keyfile,
+    Plaintext,
+    Repokey,
+    Blake2Keyfile,
+    Blake2Repokey,
+    Blake2Authenticated,
+    Authenticated,
+    AesOcbKeyfile,
+    AesOcbRepokey,
+    ChaCha20Poly1305Keyfile,
+    ChaCha20Poly1305Repokey,
+    Blake2AesOcbKeyfile,
+    Blake2AesOcbRepokey,
+    Blake2ChaCha20Poly1305Keyfile,
+    Blake2ChaCha20Poly1305Repokey,
+}
+
+impl TryFrom<u8> for KeyType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        Ok(match value {
+            0x00 => KeyType::Keyfile,
+            // keys derived from the passphrase alone, deprecated since borg 1.0
+            0x01 => return Err(Error::PassphraseMode),
+            0x02 => KeyType::Plaintext,
+            0x03 => KeyType::Repokey,
+            0x04 => KeyType::Blake2Keyfile,
+            0x05 => KeyType::Blake2Repokey,
+            0x06 => KeyType::Blake2Authenticated,
+            0x07 => KeyType::Authenticated,
+            // the AEAD modes of borg 2, the upper half names the cipher suite
+            0x10 => KeyType::AesOcbKeyfile,
+            0x11 => KeyType::AesOcbRepokey,
+            0x20 => KeyType::ChaCha20Poly1305Keyfile,
+            0x21 => KeyType::ChaCha20Poly1305Repokey,
+            0x30 => KeyType::Blake2AesOcbKeyfile,
+            0x31 => KeyType::Blake2AesOcbRepokey,
+            0x40 => KeyType::Blake2ChaCha20Poly1305Keyfile,
+            0x41 => KeyType::Blake2ChaCha20Poly1305Repokey,
+            t => return Err(Error::UnsupportedKeyType(t)),
+        })
+    }
+}
+
+/// Cipher suites of the AEAD modes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Aead {
+    AesOcb,
+    ChaCha20Poly1305,
+}
+
+impl Aead {
+    /// Name of borg's cipher class, part of the session key derivation
+    fn name(&self) -> &'static str {
+        match self {
+            Aead::AesOcb => "AES256_OCB",
+            Aead::ChaCha20Poly1305 => "CHACHA20_POLY1305",
+        }
+    }
+}
+
+impl KeyType {
+    pub fn needs_key(&self) -> bool {
+        *self != KeyType::Plaintext
+    }
+
+    fn is_encrypted(&self) -> bool {
+        !matches!(
+            self,
+            KeyType::Plaintext | KeyType::Authenticated | KeyType::Blake2Authenticated
+        )
+    }
+
+    fn is_blake2(&self) -> bool {
+        matches!(
+            self,
+            KeyType::Blake2Keyfile
+                | KeyType::Blake2Repokey
+                | KeyType::Blake2Authenticated
+                | KeyType::Blake2AesOcbKeyfile
+                | KeyType::Blake2AesOcbRepokey
+                | KeyType::Blake2ChaCha20Poly1305Keyfile
+                | KeyType::Blake2ChaCha20Poly1305Repokey
+        )
+    }
+
+    fn aead(&self) -> Option<Aead> {
+        match self {
+            KeyType::AesOcbKeyfile
+            | KeyType::AesOcbRepokey
+            | KeyType::Blake2AesOcbKeyfile
+            | KeyType::Blake2AesOcbRepokey => Some(Aead::AesOcb),
+            KeyType::ChaCha20Poly1305Keyfile
+            | KeyType::ChaCha20Poly1305Repokey
+            | KeyType::Blake2ChaCha20Poly1305Keyfile
+            | KeyType::Blake2ChaCha20Poly1305Repokey => Some(Aead::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+/// Borg 2 objects are the length of the encrypted metadata (u16) | encrypted metadata |
+/// encrypted data
+fn split_object(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = data.split_first_chunk::<2>()?;
+    let len = u16::from_le_bytes(*len) as usize;
+    (len <= rest.len()).then(|| rest.split_at(len))
+}
+
+/// The byte naming the key type an object was written with
+pub fn key_type_byte(data: &[u8], version: u32) -> Option<u8> {
+    match version {
+        1 => data.first().copied(),
+        _ => split_object(data)?.0.first().copied(),
+    }
+}
+
+/// Borg's keyed BLAKE2b prepends the key instead of using BLAKE2b's keyed mode
+fn blake2b_256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hasher = Blake2b::<U32>::new();
+    hasher.update(key);
+    hasher.update(data);
+    hasher.finalize().to_vec()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[derive(Debug)]
+pub struct Decoder {
+    key_type: KeyType,
+    key: Option<Key>,
+    /// Repository version, borg 2 encrypts the metadata of objects separately
+    version: u32,
+}
+
+impl Decoder {
+    pub fn new(key_type: KeyType, key: Option<Key>, version: u32) -> Self {
+        Self {
+            key_type,
+            key,
+            version,
+        }
+    }
+
+    fn key(&self) -> Result<&Key> {
+        Ok(self.key.as_ref().ok_or(Error::PasswordRequired)?)
+    }
+
+    /// ID of an object with the given plaintext
+    fn id_hash(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self.key_type {
+            KeyType::Plaintext => Sha256::digest(data).to_vec(),
+            t if t.is_blake2() => blake2b_256(&self.key()?.id_key, data),
+            _ => hmac_sha256(&self.key()?.id_key, data),
+        })
+    }
+
+    /// Decode an object, its ID is checked unless it's the manifest
+    pub fn decode(&self, id: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let plaintext = match self.version {
+            1 => decompress(&self.decrypt(id, data)?)?,
+            _ => self.decode_v2(id, data)?,
+        };
+
+        // the manifest has a fixed ID
+        if id.iter().any(|b| *b != 0) && self.id_hash(&plaintext)? != id {
+            return Err(Error::IntegrityCheckFailed(hex::encode(id)).into());
+        }
+
+        Ok(plaintext)
+    }
+
+    /// The metadata names the compression, the data has no header of its own
+    fn decode_v2(&self, id: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let integrity_error = || Error::IntegrityCheckFailed(hex::encode(id));
+
+        let (meta, data) = split_object(data).ok_or_else(integrity_error)?;
+        let meta = msgpack::decode(&self.decrypt(id, meta)?)?;
+        let compressed = self.decrypt(id, data)?;
+
+        let compression = ["ctype", "clevel"]
+            .map(|key| msgpack::get_u64(&meta, key).map_or(0xff, |value| value.min(0xff) as u8));
+        // obfuscated objects are padded after the payload
+        let payload = match msgpack::get_u64(&meta, "psize") {
+            Some(size) => compressed
+                .get(..size as usize)
+                .ok_or_else(integrity_error)?,
+            None => &compressed,
+        };
+
+        decompress_as(compression, payload)
+    }
+
+    /// Authenticate and decrypt an object, or just strip the key type of unencrypted ones
+    fn decrypt(&self, id: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let integrity_error = || Error::IntegrityCheckFailed(hex::encode(id));
+
+        let (&key_type, payload) = data.split_first().ok_or_else(integrity_error)?;
+        let key_type = KeyType::try_from(key_type)?;
+        if key_type != self.key_type {
+            return Err(integrity_error().into());
+        }
+
+        if let Some(aead) = key_type.aead() {
+            return self.decrypt_aead(aead, id, data);
+        }
+        if !key_type.is_encrypted() {
+            return Ok(payload.to_vec());
+        }
+
+        // type | MAC (32) | IV (lower 8 bytes of the counter) | ciphertext
+        if payload.len() < 40 {
+            return Err(integrity_error().into());
+        }
+        let (mac, authenticated) = payload.split_at(32);
+        let key = self.key()?;
+
+        let expected = if key_type.is_blake2() {
+            blake2b_256(&key.enc_hmac_key, authenticated)
+        } else {
+            hmac_sha256(&key.enc_hmac_key, authenticated)
+        };
+        if expected != mac {
+            return Err(integrity_error().into());
+        }
+
+        let (iv, ciphertext) = authenticated.split_at(8);
+        let mut counter = [0u8; 16];
+        counter[8..].copy_from_slice(iv);
+
+        let mut plaintext = ciphertext.to_vec();
+        Aes256Ctr::new_from_slices(&key.enc_key, &counter)
+            .map_err(|_| integrity_error())?
+            .apply_keystream(&mut plaintext);
+
+        Ok(plaintext)
+    }
+
+    /// type | reserved | message IV (6) | session ID (24) | tag (16) | ciphertext, the header
+    /// before the tag is authenticated along with the object ID
+    fn decrypt_aead(&self, aead: Aead, id: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let integrity_error = || Error::IntegrityCheckFailed(hex::encode(id));
+
+        if data.len() < 48 {
+            return Err(integrity_error().into());
+        }
+        let (header, rest) = data.split_at(32);
+        let (tag, ciphertext) = rest.split_at(16);
+
+        // every session derives its own key, its messages are counted by the IV
+        let mut key = [0u8; 32];
+        Hkdf::<Sha512>::new(Some(&header[8..]), &self.key()?.crypt_key())
+            .expand(
+                format!("borg-session-key-{}", aead.name()).as_bytes(),
+                &mut key,
+            )
+            .unwrap();
+        let mut nonce = [0u8; 12];
+        nonce[6..].copy_from_slice(&header[2..8]);
+        let aad = [id, header].concat();
+
+        let mut plaintext = ciphertext.to_vec();
+        match aead {
+            Aead::AesOcb => {
+                Ocb::new(&key).decrypt(&nonce, &aad, &mut plaintext, tag.try_into().unwrap())
+            }
+            Aead::ChaCha20Poly1305 => ChaCha20Poly1305::new(&key.into()).decrypt_in_place_detached(
+                &nonce.into(),
+                &aad,
+                &mut plaintext,
+                tag.into(),
+            ),
+        }
+        .map_err(|_| integrity_error())?;
+
+        Ok(plaintext)
+    }
+}
+
+/// Every compressed object starts with a two byte header naming the algorithm, except zlib
+/// which is recognized by its own header
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < 2 {
+        return Err(Error::UnsupportedCompression([0, 0]).into());
+    }
+
+    let header = [data[0], data[1]];
+    let (cmf, flg) = (data[0] as u16, data[1] as u16);
+    if cmf & 0x0f == 8 && (cmf * 256 + flg) % 31 == 0 {
+        let mut decompressed = Vec::new();
+        flate2::read::ZlibDecoder::new(data).read_to_end(&mut decompressed)?;
+        return Ok(decompressed);
+    }
+
+    let payload = &data[2..];
+    match header {
+        // obfuscated size: length | compressed object | padding
+        [0x04, 0x00] => {
+            if payload.len() < 4 {
+                return Err(Error::UnsupportedCompression(header).into());
+            }
+            let (len, rest) = payload.split_at(4);
+            let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+            decompress(
+                rest.get(..len)
+                    .ok_or(Error::UnsupportedCompression(header))?,
+            )
+        }
+        [_, 0x00] => decompress_as(header, payload),
+        _ => Err(Error::UnsupportedCompression(header))?,
+    }
+}
+
+/// Decompress with the algorithm named by the compression type and level, borg 2 stores them
+/// in the object metadata
+fn decompress_as(compression: [u8; 2], data: &[u8]) -> Result<Vec<u8>> {
+    match compression[0] {
+        0x00 => Ok(data.to_vec()),
+        0x01 => decompress_lz4(data),
+        0x02 => {
+            let mut decompressed = Vec::new();
+            xz2::read::XzDecoder::new(data).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        0x03 => Ok(zstd::stream::decode_all(data)?),
+        // borg 2 names zlib too
+        0x05 => {
+            let mut decompressed = Vec::new();
+            flate2::read::ZlibDecoder::new(data).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        _ => Err(Error::UnsupportedCompression(compression))?,
+    }
+}
+
+/// Raw LZ4 blocks don't store their decompressed size, grow the buffer until it fits
+fn decompress_lz4(data: &[u8]) -> Result<Vec<u8>> {
+    let mut size = (data.len() * 4).max(1024);
+    loop {
+        let mut buffer = vec![0; size];
+        match lz4_flex::block::decompress_into(data, &mut buffer) {
+            Ok(len) => {
+                buffer.truncate(len);
+                return Ok(buffer);
+            }
+            Err(lz4_flex::block::DecompressError::OutputTooSmall { .. }) if size < 1 << 30 => {
+                size *= 4
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoder(key_type: KeyType, version: u32) -> Decoder {
+        let key = Key {
+            repository_id: vec![0x11; 32],
+            enc_key: (0..32).collect(),
+            enc_hmac_key: (32..64).collect(),
+            id_key: (64..96).collect(),
+        };
+        Decoder::new(key_type, Some(key), version)
+    }
+
+    /// "borg known answer" compressed with zlib, encrypted with the counter starting at 5
+    const CHUNK: &str = concat!(
+        "00a693a3733aabfe1ff9baba4ea466caf89881a1161f9346ca3218a1ee794f3928",
+        "0000000000000005d19b0a2c563b116d9e7d10e9192882cfc720130891eb8fe47b",
+    );
+    const ID: &str = "2206942aa1a67afc5ebcd55855c11a1cca5b84e657a15de8a8d2949a46931d27";
+
+    #[test]
+    fn aes_ctr_hmac_sha256() {
+        let data = hex::decode(CHUNK).unwrap();
+        let id = hex::decode(ID).unwrap();
+        assert_eq!(
+            decoder(KeyType::Keyfile, 1).decode(&id, &data).unwrap(),
+            b"borg known answer"
+        );
+    }
+
+    #[test]
+    fn tampered_chunk() {
+        let mut data = hex::decode(CHUNK).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        let id = hex::decode(ID).unwrap();
+        assert!(matches!(
+            decoder(KeyType::Keyfile, 1).decode(&id, &data),
+            Err(crate::error::Error::Borg(Error::IntegrityCheckFailed(_)))
+        ));
+    }
+
+    /// Borg 2 objects of "borg 2 known answer" compressed with zlib, the same key is used as
+    /// crypt key, the session ID counts up from 0xc0 and the metadata and data have IVs 7 and 8
+    const CHACHA20_POLY1305_OBJECT: &str = concat!(
+        "4d002000000000000007c0c1c2c3c4c5c6c7c8c9cacbcccdcecfd0d1d2d3d4d5d6d7a22f0916da2c45c956f315",
+        "bc70659e25a8aa1adb81075d5bcdd12ad78e937a3986d70fd9a6dc7f752c4902b7a82000000000000008c0c1c2",
+        "c3c4c5c6c7c8c9cacbcccdcecfd0d1d2d3d4d5d6d7e5c338f3f190669262c41675575937381a78cf650319b8c0",
+        "db5b63d5bc7236a7563e58b7728fca5524c96d",
+    );
+    const AES_OCB_OBJECT: &str = concat!(
+        "4d001100000000000007c0c1c2c3c4c5c6c7c8c9cacbcccdcecfd0d1d2d3d4d5d6d724f19bb7a1e227cabe22be",
+        "68879932f6556cf12cbed5723b93e4535c1c41328a39541a02b89bddcbb7fa5e40031100000000000008c0c1c2",
+        "c3c4c5c6c7c8c9cacbcccdcecfd0d1d2d3d4d5d6d7ce60c55fa9f331dd78f821d2a7cd8f4ea93a999c29898319",
+        "c69c7da06d705d87abe4688d2d2e93c0464f01",
+    );
+    const V2_ID: &str = "6ef18577dd80066168566bb8a239889eae491cb703d6f265e7ef6e3fded2c013";
+
+    #[test]
+    fn aead() {
+        let id = hex::decode(V2_ID).unwrap();
+        for (key_type, object) in [
+            (KeyType::ChaCha20Poly1305Keyfile, CHACHA20_POLY1305_OBJECT),
+            (KeyType::AesOcbRepokey, AES_OCB_OBJECT),
+        ] {
+            let mut data = hex::decode(object).unwrap();
+            assert_eq!(
+                KeyType::try_from(key_type_byte(&data, 2).unwrap()).unwrap(),
+                key_type
+            );
+
+            let decoder = decoder(key_type, 2);
+            assert_eq!(decoder.decode(&id, &data).unwrap(), b"borg 2 known answer");
+
+            // the ID is authenticated too
+            let mut other_id = id.clone();
+            other_id[0] ^= 1;
+            assert!(decoder.decode(&other_id, &data).is_err());
+
+            *data.last_mut().unwrap() ^= 1;
+            assert!(matches!(
+                decoder.decode(&id, &data),
+                Err(crate::error::Error::Borg(Error::IntegrityCheckFailed(_)))
+            ));
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Password required")]
+    PasswordRequired,
+    #[error("Invalid password")]
+    InvalidPassword,
+    #[error("No keyfile found for repository {0}, set BORG_KEY_FILE to point to it")]
+    KeyfileNotFound(String),
+    #[error("Unsupported key type: {0:#04x}")]
+    UnsupportedKeyType(u8),
+    #[error("The deprecated passphrase key mode isn't supported, only keyfile and repokey")]
+    PassphraseMode,
+    #[error("Unsupported key algorithm: {0}")]
+    UnsupportedKeyAlgorithm(String),
+    #[error("Unsupported compression: {0:02x?}")]
+    UnsupportedCompression([u8; 2]),
+    #[error("Integrity check failed for object {0}")]
+    IntegrityCheckFailed(String),
+    #[error("Object not found in repository: {0}")]
+    ObjectNotFound(String),
+    #[error("Invalid segment entry in {0}")]
+    InvalidSegment(String),
+    #[error("Invalid msgpack data: {0}")]
+    InvalidMsgpack(String),
+    #[error("Unsupported repository version: {0}, only segment based repositories are supported")]
+    UnsupportedVersion(u32),
+    #[error("The key belongs to a different repository")]
+    KeyMismatch,
+    #[error("Invalid repository config: {0}")]
+    InvalidConfig(String),
+}
@@ -0,0 +1,224 @@
+use std::path::PathBuf;
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use base64::{engine::general_purpose, Engine as _};
+use chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    ChaCha20Poly1305,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{config::Config, error::Error, msgpack};
+use crate::error::Result;
+
+pub type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+/// The decrypted key material
+#[derive(Debug)]
+pub struct Key {
+    pub repository_id: Vec<u8>,
+    pub enc_key: Vec<u8>,
+    /// 32 bytes for HMAC-SHA256, 128 bytes (64 random, 64 zero) for BLAKE2b
+    pub enc_hmac_key: Vec<u8>,
+    pub id_key: Vec<u8>,
+}
+
+impl Key {
+    /// Key material of the AEAD modes, borg 2 keeps the legacy keys in one value
+    pub fn crypt_key(&self) -> Vec<u8> {
+        [&self.enc_key[..], &self.enc_hmac_key].concat()
+    }
+
+    /// Load the key of a repository, either from its config (repokey mode) or from a keyfile
+    pub fn load(config: &Config, password: Option<&str>) -> Result<Self> {
+        let password = password.ok_or(Error::PasswordRequired)?;
+
+        let blob = match &config.key {
+            Some(key) => key.clone(),
+            None => Self::find_keyfile(&hex::encode(&config.id))?,
+        };
+
+        let key = Self::from_blob(&blob, password)?;
+        if key.repository_id != config.id {
+            return Err(Error::KeyMismatch.into());
+        }
+
+        Ok(key)
+    }
+
+    /// Keyfiles start with `BORG_KEY <repository id>`, followed by the base64 encoded key
+    fn find_keyfile(repository_id: &str) -> Result<String> {
+        let header = format!("BORG_KEY {repository_id}");
+        let parse = |path: &PathBuf| -> Option<String> {
+            let file = std::fs::read_to_string(path).ok()?;
+            let (first, rest) = file.split_once('\n')?;
+            (first.trim() == header).then(|| rest.split_whitespace().collect())
+        };
+
+        if let Some(path) = std::env::var_os("BORG_KEY_FILE") {
+            return parse(&PathBuf::from(path))
+                .ok_or_else(|| Error::KeyfileNotFound(repository_id.to_string()).into());
+        }
+
+        let keys_dir = match std::env::var_os("BORG_KEYS_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| {
+                    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+                })
+                .unwrap_or_default()
+                .join("borg")
+                .join("keys"),
+        };
+        trace!("Looking for keyfiles in {keys_dir:?}");
+
+        for entry in std::fs::read_dir(&keys_dir).into_iter().flatten() {
+            if let Some(key) = parse(&entry?.path()) {
+                return Ok(key);
+            }
+        }
+
+        Err(Error::KeyfileNotFound(repository_id.to_string()))?
+    }
+
+    fn from_blob(blob: &str, password: &str) -> Result<Self> {
+        let encrypted = msgpack::decode(&general_purpose::STANDARD.decode(blob)?)?;
+        let algorithm = msgpack::get_string(&encrypted, "algorithm").unwrap_or_default();
+        let salt = msgpack::require_bytes(&encrypted, "salt")?;
+        let mut data = msgpack::require_bytes(&encrypted, "data")?.to_vec();
+
+        let data = match algorithm.as_str() {
+            "sha256" => {
+                let iterations = msgpack::get_u64(&encrypted, "iterations").unwrap_or(100_000);
+                let mut key = [0u8; 32];
+                pbkdf2::pbkdf2_hmac::<Sha256>(
+                    password.as_bytes(),
+                    salt,
+                    iterations as u32,
+                    &mut key,
+                );
+
+                Aes256Ctr::new(&key.into(), &[0u8; 16].into()).apply_keystream(&mut data);
+
+                // the hash is the only way to tell a wrong password apart
+                let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+                mac.update(&data);
+                mac.verify_slice(msgpack::require_bytes(&encrypted, "hash")?)
+                    .map_err(|_| Error::InvalidPassword)?;
+
+                data
+            }
+            "argon2 chacha20-poly1305" => {
+                let variant = match msgpack::get_string(&encrypted, "argon2_type").as_deref() {
+                    Some("i") => argon2::Algorithm::Argon2i,
+                    Some("d") => argon2::Algorithm::Argon2d,
+                    _ => argon2::Algorithm::Argon2id,
+                };
+                let params = argon2::Params::new(
+                    msgpack::get_u64(&encrypted, "argon2_memory_cost").unwrap_or(65536) as u32,
+                    msgpack::get_u64(&encrypted, "argon2_time_cost").unwrap_or(3) as u32,
+                    msgpack::get_u64(&encrypted, "argon2_parallelism").unwrap_or(4) as u32,
+                    Some(32),
+                )
+                .map_err(|e| Error::UnsupportedKeyAlgorithm(e.to_string()))?;
+
+                let mut key = [0u8; 32];
+                argon2::Argon2::new(variant, argon2::Version::V0x13, params)
+                    .hash_password_into(password.as_bytes(), salt, &mut key)
+                    .map_err(|e| Error::UnsupportedKeyAlgorithm(e.to_string()))?;
+
+                // MAC | ciphertext, the key is only used once so the nonce is zero
+                if data.len() < 16 {
+                    return Err(Error::InvalidPassword.into());
+                }
+                let mut ciphertext = data.split_off(16);
+                ChaCha20Poly1305::new(&key.into())
+                    .decrypt_in_place_detached(
+                        &[0u8; 12].into(),
+                        b"",
+                        &mut ciphertext,
+                        data[..].into(),
+                    )
+                    .map_err(|_| Error::InvalidPassword)?;
+
+                ciphertext
+            }
+            algorithm => return Err(Error::UnsupportedKeyAlgorithm(algorithm.to_string()).into()),
+        };
+
+        let key = msgpack::decode(&data)?;
+        let (enc_key, enc_hmac_key) = match msgpack::get_bytes(&key, "crypt_key") {
+            Some(crypt_key) if crypt_key.len() == 64 => crypt_key.split_at(32),
+            Some(_) => return Err(Error::InvalidMsgpack("invalid crypt_key".into()).into()),
+            None => (
+                msgpack::require_bytes(&key, "enc_key")?,
+                msgpack::require_bytes(&key, "enc_hmac_key")?,
+            ),
+        };
+
+        Ok(Self {
+            repository_id: msgpack::require_bytes(&key, "repository_id")?.to_vec(),
+            enc_key: enc_key.to_vec(),
+            enc_hmac_key: enc_hmac_key.to_vec(),
+            id_key: msgpack::require_bytes(&key, "id_key")?.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keyfile of a key with counting bytes, using 1000 PBKDF2 iterations and "password"
+    const KEYFILE: &str = concat!(
+        "hqd2ZXJzaW9uAaRzYWx0xCCgoaKjpKWmp6ipqqusra6vsLGys7S1tre4ubq7vL2+v6ppdGVyYXRpb25z",
+        "zgAAA+ipYWxnb3JpdGhtpnNoYTI1NqRoYXNoxCBFY5JtrK+E5NvkBeVTi+ZwUD5WtpCq2SHHKRsp28u4",
+        "gaRkYXRhxNY2K46cMy7aHaDPfXI57VmLMfPAS721Ae+rKU73P7WzIdc564Zi5DVp4LnIUd3VCSWCEyMc",
+        "UDFG2lp5g9POItRDk/E3UvxulTPQVCp5fivJdEVfXkGvWm66miCx7AxmOYtIW6PAiIDTPGiUiBmFzfDq",
+        "tdJzDYvylZsay56pzAeD5j5VUz9pMur5BSYh1P3EFqM73dB8mSPbwHZDYkizT+llkUEW+GOoaoR49R67",
+        "fZmifwK4aAnzVCO3013fJDzB/0sLtI76MS6Y71IDf5GsmBglM4Nh/HHO",
+    );
+
+    #[test]
+    fn sha256_keyfile() {
+        let key = Key::from_blob(KEYFILE, "password").unwrap();
+        assert_eq!(key.repository_id, [0x11; 32]);
+        assert_eq!(key.enc_key, (0..32).collect::<Vec<u8>>());
+        assert_eq!(key.enc_hmac_key, (32..64).collect::<Vec<u8>>());
+        assert_eq!(key.id_key, (64..96).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn wrong_password() {
+        assert!(matches!(
+            Key::from_blob(KEYFILE, "wrong"),
+            Err(crate::error::Error::Borg(Error::InvalidPassword))
+        ));
+    }
+
+    /// Borg 2 keyfile with the same keys as one crypt key, using argon2id with a single pass over
+    /// 64 KiB and "password"
+    const ARGON2_KEYFILE: &str = concat!(
+        "iKd2ZXJzaW9uAalhbGdvcml0aG24YXJnb24yIGNoYWNoYTIwLXBvbHkxMzA1pHNhbHTEEODh4uPk5ebn6Onq6+zt",
+        "7u+wYXJnb24yX3RpbWVfY29zdAGyYXJnb24yX21lbW9yeV9jb3N0QLJhcmdvbjJfcGFyYWxsZWxpc20Bq2FyZ29u",
+        "Ml90eXBlomlkpGRhdGHE2RDD0XhDIvjbGKtkhwwCtHFnnXNI+zfqxYBmUFlcYMKT0xr11OzUoPFS3251SwenyPAr",
+        "fXHYgHkii0zbzCgLrV3ufVhZ/vHoAPlwJTgGCmB7yzv2rlciQujc4EjGgBH4rEAYsarhU6qKMYrbGrJ3kRnTJTmO",
+        "6FVddLkOFitYync5ssf6veWDnO4DhdCbj8IUkIOjhP3MT1+KKXPizNyq2pSvdCztD1J+KBVw4fCl8nlCMwUwT8CW",
+        "b1MGJqHJIe8VvxW1sVRsdG4+TGWLJMrJjVuDdiL+1hdwEnY=",
+    );
+
+    #[test]
+    fn argon2_keyfile() {
+        let key = Key::from_blob(ARGON2_KEYFILE, "password").unwrap();
+        assert_eq!(key.repository_id, [0x22; 32]);
+        assert_eq!(key.crypt_key(), (0..64).collect::<Vec<u8>>());
+        assert_eq!(key.id_key, (64..96).collect::<Vec<u8>>());
+
+        assert!(matches!(
+            Key::from_blob(ARGON2_KEYFILE, "wrong"),
+            Err(crate::error::Error::Borg(Error::InvalidPassword))
+        ));
+    }
+}
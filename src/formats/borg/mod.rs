@@ -0,0 +1,182 @@
+use std::{collections::HashSet, path::PathBuf, rc::Rc};
+
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, SnapshotInfo},
+    storage::Storage,
+};
+use archive::Archive;
+use config::Config;
+use decoder::{Decoder, KeyType};
+use error::Error;
+use keys::Key;
+use repository::Repository;
+
+mod archive;
+mod config;
+mod decoder;
+mod keys;
+mod msgpack;
+mod ocb;
+mod repository;
+
+pub mod error;
+
+/// The manifest is stored under an all zero ID
+const MANIFEST_ID: [u8; 32] = [0; 32];
+
+#[derive(Debug)]
+pub struct Borg {
+    pub path: PathBuf,
+
+    repository: Repository,
+    decoder: Decoder,
+
+    /// Archives by ID
+    archives: Vec<(Vec<u8>, Archive)>,
+}
+
+impl Borg {
+    pub fn from_folder(
+        storage: Rc<dyn Storage>,
+        path: impl Into<PathBuf>,
+        password: impl Into<Option<String>>,
+    ) -> Result<Self> {
+        let path = path.into();
+        let password = password.into();
+
+        // version 2 is written by the borg 2 betas still storing objects in segments
+        let config = Config::from_file(&*storage, path.join("config"))?;
+        if !matches!(config.version, 1 | 2) {
+            return Err(Error::UnsupportedVersion(config.version).into());
+        }
+        let repository = Repository::open(storage, &path, config.segments_per_dir)?;
+
+        // the manifest tells us which kind of key the repository uses
+        let manifest = repository.get(&MANIFEST_ID)?;
+        let key_type = KeyType::try_from(
+            decoder::key_type_byte(&manifest, config.version)
+                .ok_or(Error::IntegrityCheckFailed(hex::encode(MANIFEST_ID)))?,
+        )?;
+        debug!("Key type: {key_type:?}");
+
+        let key = match key_type.needs_key() {
+            true => Some(Key::load(&config, password.as_deref())?),
+            false => None,
+        };
+
+        Ok(Self {
+            path,
+            repository,
+            decoder: Decoder::new(key_type, key, config.version),
+            archives: Vec::new(),
+        })
+    }
+
+    /// Load the metadata of every archive listed in the manifest
+    pub fn load_all_archives(&mut self) -> Result<()> {
+        let manifest = self.load_object(&MANIFEST_ID)?;
+
+        for archive_ref in archive::parse_manifest(&manifest)? {
+            trace!("Loading archive {}", archive_ref.name);
+            let mut archive = Archive::from_slice(&self.load_object(&archive_ref.id)?)?;
+            for id in &archive.item_ptrs {
+                archive
+                    .items
+                    .extend(archive::parse_item_ptrs(&self.load_object(id)?)?);
+            }
+            self.archives.push((archive_ref.id, archive));
+        }
+
+        Ok(())
+    }
+
+    /// Load everything
+    pub fn load_all(&mut self) -> Result<()> {
+        self.load_all_archives()?;
+        Ok(())
+    }
+
+    fn load_object(&self, id: &[u8]) -> Result<Vec<u8>> {
+        let data = self.repository.get(id)?;
+        self.decoder.decode(id, &data)
+    }
+
+    fn find_archive(&self, name: &str) -> Result<&(Vec<u8>, Archive)> {
+        self.archives
+            .iter()
+            .find(|(_, archive)| archive.name == name)
+            .ok_or_else(|| crate::error::Error::SnapshotNotFound(name.to_string()))
+    }
+}
+
+impl Backup for Borg {
+    /// Archives are identified by name, grouped by the host that created them
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        let mut snapshots: Vec<SnapshotInfo> = self
+            .archives
+            .iter()
+            .map(|(_, archive)| SnapshotInfo {
+                id: archive.name.clone(),
+                group: archive.hostname.clone(),
+                time: archive.time,
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+
+        snapshots
+    }
+
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let id = self.find_snapshot(snapshot)?.id;
+        let (_, archive) = self.find_archive(&id)?;
+
+        let mut stream = Vec::new();
+        for chunk in &archive.items {
+            stream.extend(self.load_object(chunk)?);
+        }
+
+        let items = archive::parse_items(&stream)?;
+        Ok(archive::to_tree_nodes(&items))
+    }
+
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        self.load_object(&hex::decode(id)?)
+    }
+
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        self.repository.stored_size(&hex::decode(id)?)
+    }
+
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        vec![self.path.join("data")]
+    }
+
+    /// Segments mix objects of every archive, a segment is referenced as long as one of its
+    /// objects is
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        let mut ids: Vec<Vec<u8>> = vec![MANIFEST_ID.to_vec()];
+        for (id, archive) in &self.archives {
+            ids.push(id.clone());
+            ids.extend(archive.item_ptrs.iter().cloned());
+            ids.extend(archive.items.iter().cloned());
+
+            for node in self.tree(&archive.name)? {
+                for chunk in node.chunks {
+                    ids.push(hex::decode(chunk.id)?);
+                }
+            }
+        }
+
+        ids.iter()
+            .map(|id| {
+                let location = self.repository.location(id)?;
+                Ok(self.repository.segment_path(location.segment))
+            })
+            .collect()
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        self.repository.storage()
+    }
+}
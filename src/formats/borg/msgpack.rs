@@ -0,0 +1,51 @@
+//! Helpers for the loosely typed msgpack borg uses - older versions encode every string as raw
+//! bytes, newer ones distinguish str and bin, so both are accepted wherever text is expected
+
+use rmpv::Value;
+
+use super::error::Error;
+use crate::error::Result;
+
+/// Decode a single msgpack value
+pub fn decode(data: &[u8]) -> Result<Value> {
+    rmpv::decode::read_value(&mut &data[..])
+        .map_err(|e| Error::InvalidMsgpack(e.to_string()).into())
+}
+
+/// Look up a key in a msgpack map
+pub fn get<'a>(map: &'a Value, key: &str) -> Option<&'a Value> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| bytes(k) == Some(key.as_bytes()))
+        .map(|(_, value)| value)
+}
+
+/// Raw contents of a str or bin value
+pub fn bytes(value: &Value) -> Option<&[u8]> {
+    match value {
+        Value::Binary(bytes) => Some(bytes),
+        Value::String(string) => Some(string.as_bytes()),
+        _ => None,
+    }
+}
+
+pub fn get_bytes<'a>(map: &'a Value, key: &str) -> Option<&'a [u8]> {
+    bytes(get(map, key)?)
+}
+
+pub fn get_string(map: &Value, key: &str) -> Option<String> {
+    get_bytes(map, key).map(|bytes| String::from_utf8_lossy(bytes).to_string())
+}
+
+pub fn get_u64(map: &Value, key: &str) -> Option<u64> {
+    get(map, key)?.as_u64()
+}
+
+pub fn get_i64(map: &Value, key: &str) -> Option<i64> {
+    get(map, key)?.as_i64()
+}
+
+/// Like [`get_bytes`], but the key has to be present
+pub fn require_bytes<'a>(map: &'a Value, key: &str) -> Result<&'a [u8]> {
+    get_bytes(map, key).ok_or_else(|| Error::InvalidMsgpack(format!("missing {key}")).into())
+}
@@ -0,0 +1,195 @@
+//! AES-256-OCB decryption with 96 bit nonces and 128 bit tags, one of the AEAD modes of borg 2.
+//! See https://www.rfc-editor.org/rfc/rfc7253, blocks are handled as big endian integers.
+
+use aes::{
+    cipher::{BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes256, Block,
+};
+use chacha20poly1305::aead;
+
+pub struct Ocb {
+    cipher: Aes256,
+    l_star: u128,
+    l_dollar: u128,
+    /// `L_i`, enough for any object borg stores
+    l: [u128; 32],
+}
+
+/// Multiplication by x in GF(2^128)
+fn double(block: u128) -> u128 {
+    (block << 1) ^ if block >> 127 == 1 { 0x87 } else { 0 }
+}
+
+/// A partial block followed by a single set bit and zeros
+fn pad(data: &[u8]) -> u128 {
+    let mut block = [0u8; 16];
+    block[..data.len()].copy_from_slice(data);
+    block[data.len()] = 0x80;
+    u128::from_be_bytes(block)
+}
+
+impl Ocb {
+    pub fn new(key: &[u8; 32]) -> Self {
+        let cipher = Aes256::new(key.into());
+        let mut ocb = Self {
+            cipher,
+            l_star: 0,
+            l_dollar: 0,
+            l: [0; 32],
+        };
+
+        ocb.l_star = ocb.encipher(0);
+        ocb.l_dollar = double(ocb.l_star);
+        ocb.l[0] = double(ocb.l_dollar);
+        for i in 1..ocb.l.len() {
+            ocb.l[i] = double(ocb.l[i - 1]);
+        }
+
+        ocb
+    }
+
+    fn encipher(&self, block: u128) -> u128 {
+        let mut block = Block::from(block.to_be_bytes());
+        self.cipher.encrypt_block(&mut block);
+        u128::from_be_bytes(block.into())
+    }
+
+    fn decipher(&self, block: u128) -> u128 {
+        let mut block = Block::from(block.to_be_bytes());
+        self.cipher.decrypt_block(&mut block);
+        u128::from_be_bytes(block.into())
+    }
+
+    /// Offset of the i-th block (counting from 1), following the one before it
+    fn next_offset(&self, offset: u128, i: usize) -> u128 {
+        offset ^ self.l[i.trailing_zeros() as usize]
+    }
+
+    /// Sum over the associated data
+    fn hash(&self, aad: &[u8]) -> u128 {
+        let mut offset = 0;
+        let mut sum = 0;
+
+        let mut blocks = aad.chunks_exact(16);
+        for (i, block) in (&mut blocks).enumerate() {
+            offset = self.next_offset(offset, i + 1);
+            sum ^= self.encipher(u128::from_be_bytes(block.try_into().unwrap()) ^ offset);
+        }
+
+        let rest = blocks.remainder();
+        if !rest.is_empty() {
+            offset ^= self.l_star;
+            sum ^= self.encipher(pad(rest) ^ offset);
+        }
+
+        sum
+    }
+
+    /// Initial offset, derived from the nonce encrypted with its lowest six bits cleared
+    fn initial_offset(&self, nonce: &[u8; 12]) -> u128 {
+        // tag length (128 mod 128 = 0) | zeros | 1 | nonce
+        let mut block = [0u8; 16];
+        block[3] = 1;
+        block[4..].copy_from_slice(nonce);
+        let nonce = u128::from_be_bytes(block);
+
+        let bottom = (nonce & 0x3f) as u32;
+        let top = self.encipher(nonce & !0x3f);
+        if bottom == 0 {
+            return top;
+        }
+
+        // the 192 bit stretch is the top followed by its first 64 bits xored with bits 9 to 72
+        let stretch = ((top >> 64) ^ (top >> 56)) as u64;
+        (top << bottom) | (stretch >> (64 - bottom)) as u128
+    }
+
+    /// Decrypt in place, fails if the tag doesn't match
+    pub fn decrypt(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; 16],
+    ) -> Result<(), aead::Error> {
+        let mut offset = self.initial_offset(nonce);
+        let mut checksum = 0;
+
+        let mut blocks = data.chunks_exact_mut(16);
+        for (i, block) in (&mut blocks).enumerate() {
+            offset = self.next_offset(offset, i + 1);
+            let plain =
+                self.decipher(u128::from_be_bytes((&*block).try_into().unwrap()) ^ offset) ^ offset;
+            block.copy_from_slice(&plain.to_be_bytes());
+            checksum ^= plain;
+        }
+
+        let rest = blocks.into_remainder();
+        if !rest.is_empty() {
+            offset ^= self.l_star;
+            for (byte, key) in rest.iter_mut().zip(self.encipher(offset).to_be_bytes()) {
+                *byte ^= key;
+            }
+            checksum ^= pad(rest);
+        }
+
+        let expected = self.encipher(checksum ^ offset ^ self.l_dollar) ^ self.hash(aad);
+        match expected == u128::from_be_bytes(*tag) {
+            true => Ok(()),
+            false => Err(aead::Error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Known answers from OpenSSL, with counting bytes as key, nonce, plaintext and associated
+    /// data (starting at 100)
+    #[test]
+    fn known_answers() {
+        let ocb = Ocb::new(&std::array::from_fn(|i| i as u8));
+        let nonce = std::array::from_fn(|i| i as u8);
+        let cases = [
+            (0, 0, "", "5be1796471504759b8255f7512ba58e8"),
+            // partial blocks in both
+            (
+                33,
+                41,
+                "b271bb69c3e1b79629cb362807319a03d4439c9923f10f8dbad35e2b3d8aa1ee96",
+                "56fb60e0c06382b3bd488f6475740705",
+            ),
+            (
+                64,
+                32,
+                concat!(
+                    "b271bb69c3e1b79629cb362807319a03d4439c9923f10f8dbad35e2b3d8aa1ee",
+                    "898e14edc1043d15a958741a7cb46f165a16941dc195a75ad602cb10d2b5fc4a",
+                ),
+                "0e1f2e144c0afca9e0cfbf61de83276b",
+            ),
+        ];
+
+        for (len, aad_len, ciphertext, tag) in cases {
+            let aad: Vec<u8> = (100..100 + aad_len).collect();
+            let mut tag: [u8; 16] = hex::decode(tag).unwrap().try_into().unwrap();
+            let mut data = hex::decode(ciphertext).unwrap();
+            ocb.decrypt(&nonce, &aad, &mut data, &tag).unwrap();
+            assert_eq!(data, (0..len).collect::<Vec<u8>>());
+
+            tag[15] ^= 1;
+            let mut data = hex::decode(ciphertext).unwrap();
+            assert!(ocb.decrypt(&nonce, &aad, &mut data, &tag).is_err());
+        }
+
+        // the lowest six bits of the nonce block are zero, the offset is the encrypted nonce
+        let mut nonce = [0u8; 12];
+        nonce[11] = 0x40;
+        let mut data = hex::decode("5d4628").unwrap();
+        let tag = hex::decode("46108c411830a35df5ca208f126a220f").unwrap();
+        ocb.decrypt(&nonce, b"", &mut data, &tag.try_into().unwrap())
+            .unwrap();
+        assert_eq!(data, b"abc");
+    }
+}
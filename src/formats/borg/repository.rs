@@ -0,0 +1,339 @@
+//! Locate objects in the segment files, using the hashindex written on every commit
+
+use std::{
+    collections::HashMap,
+    hash::Hasher,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use byteorder::{ReadBytesExt, LE};
+use twox_hash::XxHash64;
+
+use super::error::Error;
+use crate::{error::Result, storage::Storage};
+
+const SEGMENT_MAGIC: &[u8] = b"BORG_SEG";
+const INDEX_MAGIC: &[u8] = b"BORG_IDX";
+
+/// crc32 | size | tag
+const HEADER_SIZE: u32 = 9;
+const TAG_PUT: u8 = 0;
+const TAG_DELETE: u8 = 1;
+const TAG_COMMIT: u8 = 2;
+/// Written by borg 2, the key is followed by a hash of the entry
+const TAG_PUT2: u8 = 3;
+
+/// Buckets whose segment is one of these don't hold an entry
+const EMPTY: u32 = 0xffff_ffff;
+const DELETED: u32 = 0xffff_fffe;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Location {
+    pub segment: u64,
+    pub offset: u64,
+}
+
+#[derive(Debug)]
+pub struct Repository {
+    storage: Rc<dyn Storage>,
+    path: PathBuf,
+    segments_per_dir: u64,
+    objects: HashMap<Vec<u8>, Location>,
+}
+
+impl Repository {
+    pub fn open(
+        storage: Rc<dyn Storage>,
+        path: impl Into<PathBuf>,
+        segments_per_dir: u64,
+    ) -> Result<Self> {
+        let mut repository = Self {
+            storage,
+            path: path.into(),
+            segments_per_dir,
+            objects: HashMap::new(),
+        };
+
+        let segments = repository.segments()?;
+        let last_segment = segments.last().map(|(segment, _)| *segment);
+
+        // the index is only valid if nothing was written after it
+        let objects = match repository.latest_index()? {
+            Some((transaction, path)) if Some(transaction) >= last_segment => {
+                debug!("Loading index {path:?}");
+                repository.load_index(&path)?
+            }
+            _ => None,
+        };
+        match objects {
+            Some(objects) => repository.objects = objects,
+            None => {
+                debug!("No usable index, replaying {} segments", segments.len());
+                repository.replay(&segments)?;
+            }
+        }
+
+        Ok(repository)
+    }
+
+    /// Every segment file, sorted by number
+    fn segments(&self) -> Result<Vec<(u64, PathBuf)>> {
+        let mut segments = Vec::new();
+        for dir in self.storage.list(&self.path.join("data"))? {
+            if !dir.stat.is_dir {
+                continue;
+            }
+
+            for segment in self.storage.list(&dir.path)? {
+                if let Ok(number) = segment.name.parse() {
+                    segments.push((number, segment.path));
+                }
+            }
+        }
+        segments.sort();
+
+        Ok(segments)
+    }
+
+    /// `index.<transaction>` with the highest transaction number
+    fn latest_index(&self) -> Result<Option<(u64, PathBuf)>> {
+        let mut latest = None;
+        for entry in self.storage.list(&self.path)? {
+            let Some(transaction) = entry
+                .name
+                .strip_prefix("index.")
+                .and_then(|n| n.parse::<u64>().ok())
+            else {
+                continue;
+            };
+
+            if latest
+                .as_ref()
+                .is_none_or(|(latest, _)| transaction > *latest)
+            {
+                latest = Some((transaction, entry.path));
+            }
+        }
+
+        Ok(latest)
+    }
+
+    /// Parse a hashindex: magic | entries | buckets | key size | value size, followed by the
+    /// buckets. Values start with the segment and offset of the object. Indexes in other formats
+    /// are skipped, the segments hold the same information.
+    fn load_index(&self, path: &Path) -> Result<Option<HashMap<Vec<u8>, Location>>> {
+        let file = self.storage.read(path)?;
+        let invalid = || Error::InvalidSegment(path.to_string_lossy().to_string());
+
+        if !file.starts_with(INDEX_MAGIC) {
+            debug!("Unknown index format in {path:?}");
+            return Ok(None);
+        }
+        let mut header = &file[INDEX_MAGIC.len()..];
+        let entries = header.read_i32::<LE>()?;
+        let buckets = header.read_i32::<LE>()? as usize;
+        let key_size = header.read_u8()? as usize;
+        let value_size = header.read_u8()? as usize;
+        if value_size < 8 {
+            return Err(invalid().into());
+        }
+
+        let bucket_size = key_size + value_size;
+        let data = &file[INDEX_MAGIC.len() + 10..];
+        if data.len() < buckets * bucket_size {
+            return Err(invalid().into());
+        }
+
+        let mut objects = HashMap::with_capacity(entries.max(0) as usize);
+        for bucket in data.chunks_exact(bucket_size).take(buckets) {
+            let (key, mut value) = bucket.split_at(key_size);
+            let segment = value.read_u32::<LE>()?;
+            if segment == EMPTY || segment == DELETED {
+                continue;
+            }
+
+            let offset = value.read_u32::<LE>()?;
+            objects.insert(
+                key.to_vec(),
+                Location {
+                    segment: segment as u64,
+                    offset: offset as u64,
+                },
+            );
+        }
+
+        Ok(Some(objects))
+    }
+
+    /// Rebuild the index from the segments, changes only count once they're committed
+    fn replay(&mut self, segments: &[(u64, PathBuf)]) -> Result<()> {
+        let mut pending: Vec<(Vec<u8>, Option<Location>)> = Vec::new();
+
+        for (segment, path) in segments {
+            let file = self.storage.read(path)?;
+            let invalid = || Error::InvalidSegment(path.to_string_lossy().to_string());
+            if !file.starts_with(SEGMENT_MAGIC) {
+                return Err(invalid().into());
+            }
+
+            let mut offset = SEGMENT_MAGIC.len();
+            while offset + HEADER_SIZE as usize <= file.len() {
+                let mut header = &file[offset..];
+                let _crc = header.read_u32::<LE>()?;
+                let size = header.read_u32::<LE>()? as usize;
+                let tag = header.read_u8()?;
+                // puts and deletes are followed by the key of the object
+                let min_size = match tag {
+                    TAG_PUT | TAG_DELETE => HEADER_SIZE + 32,
+                    TAG_PUT2 => HEADER_SIZE + 32 + 8,
+                    _ => HEADER_SIZE,
+                };
+                if size < min_size as usize || offset + size > file.len() {
+                    // a torn write at the end of the last segment
+                    warn!("Truncated entry in segment {path:?} at offset {offset}");
+                    break;
+                }
+
+                let key = || file[offset + 9..offset + 41].to_vec();
+                match tag {
+                    TAG_PUT | TAG_PUT2 => pending.push((
+                        key(),
+                        Some(Location {
+                            segment: *segment,
+                            offset: offset as u64,
+                        }),
+                    )),
+                    TAG_DELETE => pending.push((key(), None)),
+                    TAG_COMMIT => {
+                        for (key, location) in pending.drain(..) {
+                            match location {
+                                Some(location) => self.objects.insert(key, location),
+                                None => self.objects.remove(&key),
+                            };
+                        }
+                    }
+                    _ => return Err(invalid().into()),
+                }
+
+                offset += size;
+            }
+        }
+
+        if !pending.is_empty() {
+            warn!("Ignoring {} uncommitted segment entries", pending.len());
+        }
+
+        Ok(())
+    }
+
+    pub fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+
+    pub fn segment_path(&self, segment: u64) -> PathBuf {
+        self.path
+            .join("data")
+            .join((segment / self.segments_per_dir).to_string())
+            .join(segment.to_string())
+    }
+
+    pub fn location(&self, id: &[u8]) -> Result<Location> {
+        Ok(*self
+            .objects
+            .get(id)
+            .ok_or_else(|| Error::ObjectNotFound(hex::encode(id)))?)
+    }
+
+    /// Read the raw, still encrypted contents of an object
+    pub fn get(&self, id: &[u8]) -> Result<Vec<u8>> {
+        let location = self.location(id)?;
+        let path = self.segment_path(location.segment);
+        let invalid = || Error::InvalidSegment(path.to_string_lossy().to_string());
+
+        let mut header = &self.storage.read_range(&path, location.offset, 8)?[..];
+        let crc = header.read_u32::<LE>()?;
+        let size = header.read_u32::<LE>()?;
+        if size < HEADER_SIZE + 32 {
+            return Err(invalid().into());
+        }
+        let mut entry = size.to_le_bytes().to_vec();
+        entry.extend(
+            self.storage
+                .read_range(&path, location.offset + 8, size as u64 - 8)?,
+        );
+        if entry.len() != size as usize - 4 {
+            return Err(invalid().into());
+        }
+
+        if entry[5..37] != *id {
+            return Err(invalid().into());
+        }
+
+        match entry[4] {
+            // tag | key | data, the checksum covers everything after itself
+            TAG_PUT if crc32fast::hash(&entry) == crc => Ok(entry.split_off(37)),
+            // tag | key | hash | data, the checksum stops after the xxh64 hash of the size, tag,
+            // key and data
+            TAG_PUT2 if entry.len() >= 45 && crc32fast::hash(&entry[..45]) == crc => {
+                let data = entry.split_off(45);
+                let mut hasher = XxHash64::with_seed(0);
+                hasher.write(&entry[..37]);
+                hasher.write(&data);
+                if hasher.finish().to_be_bytes() != entry[37..] {
+                    return Err(invalid().into());
+                }
+
+                Ok(data)
+            }
+            _ => Err(invalid().into()),
+        }
+    }
+
+    /// Size of an object's segment entry
+    pub fn stored_size(&self, id: &[u8]) -> Result<u64> {
+        let location = self.location(id)?;
+
+        let size = self.storage.read_range(
+            &self.segment_path(location.segment),
+            location.offset + 4,
+            4,
+        )?;
+
+        Ok((&size[..]).read_u32::<LE>()? as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::local::Local;
+
+    /// A borg 2 put of "borg 2 segment entry" under counting bytes, followed by a commit
+    const ENTRIES: &str = concat!(
+        "ec44cae24500000003000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f49cb04",
+        "79a5fbd504626f72672032207365676d656e7420656e74727940f43c250900000002",
+    );
+
+    #[test]
+    fn put2() {
+        let dir = tempfile::tempdir().unwrap();
+        let segment = dir.path().join("data").join("0").join("1");
+        std::fs::create_dir_all(segment.parent().unwrap()).unwrap();
+        let mut data = SEGMENT_MAGIC.to_vec();
+        data.extend(hex::decode(ENTRIES).unwrap());
+        std::fs::write(&segment, &data).unwrap();
+
+        let id: Vec<u8> = (0..32).collect();
+        let repository = Repository::open(Rc::new(Local), dir.path(), 1000).unwrap();
+        assert_eq!(repository.get(&id).unwrap(), b"borg 2 segment entry");
+
+        // the data is only covered by the hash
+        data[SEGMENT_MAGIC.len() + 50] ^= 1;
+        std::fs::write(&segment, &data).unwrap();
+        assert!(matches!(
+            repository.get(&id),
+            Err(crate::error::Error::Borg(Error::InvalidSegment(_)))
+        ));
+    }
+}
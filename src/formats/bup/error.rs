@@ -0,0 +1,23 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Object {0} not found")]
+    ObjectNotFound(String),
+    #[error("Invalid object ID: {0}")]
+    InvalidObjectId(String),
+    #[error("Invalid object {0}")]
+    InvalidObject(String),
+    #[error("Object {0} is not a {1}")]
+    UnexpectedObjectType(String, &'static str),
+    #[error("Invalid pack index: {0}")]
+    InvalidPackIndex(String),
+    #[error("Invalid pack {0}: {1}")]
+    InvalidPack(String, String),
+    #[error("Integrity check failed for {0}")]
+    IntegrityCheckFailed(String),
+    #[error("Invalid metadata in {0}")]
+    InvalidMetadata(String),
+    #[error("Directory {0} was saved with split trees, which aren't supported")]
+    UnsupportedSplitTree(String),
+}
@@ -0,0 +1,356 @@
+//! A read-only git object store: packed and loose objects, branches, trees and commits.
+//! Nothing here is specific to bup.
+
+use std::{collections::BTreeMap, io::Read, path::PathBuf, rc::Rc};
+
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::read::ZlibDecoder;
+use sha1::{Digest, Sha1};
+
+use super::{
+    error::Error,
+    pack::{self, Entry, Pack},
+};
+use crate::{error::Result, storage::Storage};
+
+/// Objects are addressed by the SHA-1 of their type, size and contents
+pub type Oid = [u8; 20];
+
+// modes of tree entries, other entries are blobs with the mode of a file or symlink
+pub const MODE_TREE: u32 = 0o040000;
+/// A commit of a submodule
+pub const MODE_GITLINK: u32 = 0o160000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+}
+
+impl ObjectKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectKind::Commit => "commit",
+            ObjectKind::Tree => "tree",
+            ObjectKind::Blob => "blob",
+            ObjectKind::Tag => "tag",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [
+            ObjectKind::Commit,
+            ObjectKind::Tree,
+            ObjectKind::Blob,
+            ObjectKind::Tag,
+        ]
+        .into_iter()
+        .find(|kind| kind.name() == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeEntry {
+    pub mode: u32,
+    pub name: String,
+    pub id: Oid,
+}
+
+#[derive(Debug, Clone)]
+pub struct Commit {
+    pub tree: Oid,
+    pub parents: Vec<Oid>,
+    /// When it was committed, not authored
+    pub time: DateTime<Utc>,
+}
+
+pub fn parse_id(id: &str) -> Result<Oid> {
+    hex::decode(id)
+        .ok()
+        .and_then(|id| Oid::try_from(id).ok())
+        .ok_or_else(|| Error::InvalidObjectId(id.to_string()).into())
+}
+
+#[derive(Debug)]
+pub struct Repository {
+    pub path: PathBuf,
+    storage: Rc<dyn Storage>,
+
+    packs: Vec<Pack>,
+}
+
+impl Repository {
+    /// Open a bare repository and load the index of every pack
+    pub fn open(storage: Rc<dyn Storage>, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+
+        let mut packs = Vec::new();
+        for entry in storage.list(&path.join("objects").join("pack"))? {
+            let idx = entry.path;
+            if idx.extension().is_some_and(|ext| ext == "idx") {
+                trace!("Loading pack index {idx:?}");
+                packs.push(Pack::open(&*storage, &idx)?);
+            }
+        }
+        packs.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(Self {
+            path,
+            storage,
+            packs,
+        })
+    }
+
+    /// Every branch and the commit it points to, loose refs take precedence over packed ones
+    pub fn branches(&self) -> Result<BTreeMap<String, Oid>> {
+        let mut branches = BTreeMap::new();
+
+        if let Ok(packed) = self.storage.read_to_string(&self.path.join("packed-refs")) {
+            // `^` lines are the targets of the annotated tag above them
+            for line in packed.lines() {
+                if let Some((id, name)) = line.split_once(' ') {
+                    if let Some(branch) = name.strip_prefix("refs/heads/") {
+                        branches.insert(branch.to_string(), parse_id(id)?);
+                    }
+                }
+            }
+        }
+
+        let heads = self.path.join("refs").join("heads");
+        let mut dirs = vec![heads.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in self.storage.list(&dir).into_iter().flatten() {
+                let path = entry.path;
+                if entry.stat.is_dir {
+                    dirs.push(path);
+                    continue;
+                }
+
+                let branch = path
+                    .strip_prefix(&heads)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string();
+                let id = self.storage.read_to_string(&path)?;
+                branches.insert(branch, parse_id(id.trim())?);
+            }
+        }
+
+        Ok(branches)
+    }
+
+    /// `objects/<first 2 digits of the ID>/<remaining 38>`
+    fn loose_path(&self, id: &Oid) -> PathBuf {
+        let id = hex::encode(id);
+        self.path.join("objects").join(&id[..2]).join(&id[2..])
+    }
+
+    fn find_packed(&self, id: &Oid) -> Option<(&Pack, u64)> {
+        self.packs
+            .iter()
+            .find_map(|pack| Some((pack, pack.find(id)?)))
+    }
+
+    /// The pack or loose object file holding an object
+    pub fn location(&self, id: &Oid) -> Option<PathBuf> {
+        match self.find_packed(id) {
+            Some((pack, _)) => Some(pack.path.clone()),
+            None => Some(self.loose_path(id)).filter(|path| self.storage.is_file(path)),
+        }
+    }
+
+    /// Read, resolve and verify an object
+    pub fn read(&self, id: &Oid) -> Result<(ObjectKind, Vec<u8>)> {
+        let (kind, data) = self.read_unverified(id)?;
+
+        let mut hasher = Sha1::new();
+        hasher.update(format!("{} {}\0", kind.name(), data.len()));
+        hasher.update(&data);
+        if hasher.finalize()[..] != id[..] {
+            return Err(Error::IntegrityCheckFailed(hex::encode(id)).into());
+        }
+
+        Ok((kind, data))
+    }
+
+    /// Read an object that has to be of a given type
+    pub fn read_kind(&self, id: &Oid, expected: ObjectKind) -> Result<Vec<u8>> {
+        match self.read(id)? {
+            (kind, data) if kind == expected => Ok(data),
+            _ => Err(Error::UnexpectedObjectType(hex::encode(id), expected.name()).into()),
+        }
+    }
+
+    fn read_unverified(&self, id: &Oid) -> Result<(ObjectKind, Vec<u8>)> {
+        match self.find_packed(id) {
+            Some((pack, offset)) => self.read_packed(pack, offset),
+            None => {
+                let (kind, _, data) = self.read_loose(id, None)?;
+                Ok((kind, data))
+            }
+        }
+    }
+
+    /// Deltas are resolved recursively, bases may be deltas themselves
+    fn read_packed(&self, pack: &Pack, offset: u64) -> Result<(ObjectKind, Vec<u8>)> {
+        let (entry, _, data) = pack.read_at(&*self.storage, offset, None)?;
+        let name = || format!("{}:{offset}", pack.path.display());
+
+        match entry {
+            Entry::Object(kind) => Ok((kind, data)),
+            Entry::OfsDelta(base) => {
+                let (kind, base) = self.read_packed(pack, base)?;
+                Ok((kind, pack::apply_delta(&base, &data, &name())?))
+            }
+            Entry::RefDelta(base) => {
+                let (kind, base) = self.read_unverified(&base)?;
+                Ok((kind, pack::apply_delta(&base, &data, &name())?))
+            }
+        }
+    }
+
+    /// Loose objects are a zlib stream of `<type> <size>\0<contents>`. With a `limit`, only
+    /// that many bytes of the stream are inflated and the contents may be incomplete.
+    fn read_loose(&self, id: &Oid, limit: Option<u64>) -> Result<(ObjectKind, u64, Vec<u8>)> {
+        let name = hex::encode(id);
+        let file = self
+            .storage
+            .read(&self.loose_path(id))
+            .map_err(|_| Error::ObjectNotFound(name.clone()))?;
+
+        let mut data = Vec::new();
+        ZlibDecoder::new(&file[..])
+            .take(limit.unwrap_or(u64::MAX))
+            .read_to_end(&mut data)?;
+
+        let invalid = || Error::InvalidObject(name.clone());
+        let separator = data.iter().position(|b| *b == 0).ok_or_else(invalid)?;
+        let header = std::str::from_utf8(&data[..separator]).map_err(|_| invalid())?;
+        let (kind, size) = header.split_once(' ').ok_or_else(invalid)?;
+        let kind = ObjectKind::from_name(kind).ok_or_else(invalid)?;
+        let size: u64 = size.parse().map_err(|_| invalid())?;
+
+        let contents = data.split_off(separator + 1);
+        if limit.is_none() && contents.len() as u64 != size {
+            return Err(invalid().into());
+        }
+
+        Ok((kind, size, contents))
+    }
+
+    /// Size of an object, only the headers are read. The size of a delta is the size of its
+    /// instructions, the size of the object is at their start.
+    pub fn object_size(&self, id: &Oid) -> Result<u64> {
+        let Some((pack, offset)) = self.find_packed(id) else {
+            return Ok(self.read_loose(id, Some(64))?.1);
+        };
+
+        match pack.read_at(&*self.storage, offset, Some(20))? {
+            (Entry::Object(_), size, _) => Ok(size),
+            (_, _, delta) => pack::delta_sizes(&delta)
+                .map(|(_, size)| size)
+                .ok_or_else(|| Error::InvalidObject(hex::encode(id)).into()),
+        }
+    }
+
+    /// Space an object takes up: its compressed entry in a pack, or its loose file
+    pub fn stored_size(&self, id: &Oid) -> Result<u64> {
+        match self.find_packed(id) {
+            Some((pack, offset)) => Ok(pack.stored_size(offset)),
+            None => Ok(self
+                .storage
+                .stat(&self.loose_path(id))
+                .map_err(|_| Error::ObjectNotFound(hex::encode(id)))?
+                .size),
+        }
+    }
+
+    /// Entries are `<octal mode> <name>\0<binary ID>`
+    pub fn tree(&self, id: &Oid) -> Result<Vec<TreeEntry>> {
+        let data = self.read_kind(id, ObjectKind::Tree)?;
+        let invalid = || Error::InvalidObject(hex::encode(id));
+
+        let mut entries = Vec::new();
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let space = rest.iter().position(|b| *b == b' ').ok_or_else(invalid)?;
+            let nul = rest.iter().position(|b| *b == 0).ok_or_else(invalid)?;
+            if nul < space || rest.len() < nul + 21 {
+                return Err(invalid().into());
+            }
+
+            let mode = std::str::from_utf8(&rest[..space])
+                .ok()
+                .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+                .ok_or_else(invalid)?;
+            entries.push(TreeEntry {
+                mode,
+                name: String::from_utf8_lossy(&rest[space + 1..nul]).to_string(),
+                id: rest[nul + 1..nul + 21].try_into().unwrap(),
+            });
+            rest = &rest[nul + 21..];
+        }
+
+        Ok(entries)
+    }
+
+    /// Only the headers are parsed, the message is ignored
+    pub fn commit(&self, id: &Oid) -> Result<Commit> {
+        let data = self.read_kind(id, ObjectKind::Commit)?;
+        let text = String::from_utf8_lossy(&data);
+        let invalid = || Error::InvalidObject(hex::encode(id));
+
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut time = None;
+        for line in text.lines().take_while(|line| !line.is_empty()) {
+            match line.split_once(' ') {
+                Some(("tree", id)) => tree = Some(parse_id(id)?),
+                Some(("parent", id)) => parents.push(parse_id(id)?),
+                // `<name> <<email>> <seconds> <timezone>`
+                Some(("committer", committer)) => {
+                    time = committer
+                        .rsplit(' ')
+                        .nth(1)
+                        .and_then(|seconds| seconds.parse().ok())
+                        .and_then(|seconds| Utc.timestamp_opt(seconds, 0).single());
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Commit {
+            tree: tree.ok_or_else(invalid)?,
+            parents,
+            time: time.ok_or_else(invalid)?,
+        })
+    }
+
+    pub fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+
+    /// Where packs and their indexes are
+    pub fn pack_dir(&self) -> PathBuf {
+        self.path.join("objects").join("pack")
+    }
+
+    /// The directories of loose objects, named after the first 2 digits of their IDs
+    pub fn loose_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = self
+            .storage
+            .list(&self.path.join("objects"))
+            .into_iter()
+            .flatten()
+            .filter(|entry| {
+                entry.name.len() == 2 && entry.name.bytes().all(|b| b.is_ascii_hexdigit())
+            })
+            .map(|entry| entry.path)
+            .collect();
+        dirs.sort();
+
+        dirs
+    }
+}
@@ -0,0 +1,242 @@
+//! `.bupm` files: the metadata of a directory, followed by the metadata of its other entries.
+//! Each is a sequence of tagged records, ended by an empty tag.
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use super::error::Error;
+use crate::error::Result;
+
+const REC_END: u64 = 0;
+const REC_COMMON_V1: u64 = 2;
+const REC_SYMLINK_TARGET: u64 = 3;
+const REC_POSIX1E_ACL: u64 = 4;
+const REC_LINUX_XATTR: u64 = 7;
+const REC_HARDLINK_TARGET: u64 = 8;
+const REC_COMMON_V2: u64 = 9;
+const REC_COMMON_V3: u64 = 10;
+
+// tags of the entries of `system.posix_acl_*` xattrs
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// `st_mode`, including the file type
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub mtime: Option<DateTime<Utc>>,
+    /// Only recorded by newer versions
+    pub size: Option<u64>,
+    pub symlink_target: Option<String>,
+    /// Path of the first link to the same inode, as it was on the saved filesystem
+    pub hardlink_target: Option<String>,
+    /// ACLs included
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Unsigned varints are LEB128, signed ones keep the sign in bit 6 of their first byte
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    name: &'a str,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8], name: &'a str) -> Self {
+        Self { data, pos: 0, name }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn invalid(&self) -> Error {
+        Error::InvalidMetadata(self.name.to_string())
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self.data.get(self.pos).ok_or_else(|| self.invalid())?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn vuint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift > 63 {
+                return Err(self.invalid().into());
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn vint(&mut self) -> Result<i64> {
+        let byte = self.byte()?;
+        let mut value = (byte & 0x3f) as i64;
+        if byte & 0x80 != 0 {
+            value |= (self.vuint()? as i64) << 6;
+        }
+
+        Ok(match byte & 0x40 {
+            0 => value,
+            _ => -value,
+        })
+    }
+
+    /// Length prefixed bytes
+    fn bvec(&mut self) -> Result<&'a [u8]> {
+        let len = self.vuint()? as usize;
+        let data = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| self.invalid())?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(self.bvec()?).to_string())
+    }
+
+    /// Read the next metadata, unknown records are skipped
+    pub fn metadata(&mut self) -> Result<Metadata> {
+        let mut metadata = Metadata::default();
+        let mut acls = Vec::new();
+
+        loop {
+            let tag = self.vuint()?;
+            if tag == REC_END {
+                break;
+            }
+
+            let mut r = Reader::new(self.bvec()?, self.name);
+            match tag {
+                // the first version stored IDs unsigned, the third added the size
+                REC_COMMON_V1 | REC_COMMON_V2 | REC_COMMON_V3 => {
+                    let signed = |r: &mut Reader| match tag {
+                        REC_COMMON_V1 => r.vuint().map(|value| value as i64),
+                        _ => r.vint(),
+                    };
+                    let mode = signed(&mut r)?;
+                    let uid = signed(&mut r)?;
+                    let user = r.string()?;
+                    let gid = signed(&mut r)?;
+                    let group = r.string()?;
+                    let _rdev = signed(&mut r)?;
+                    let (_atime, _atime_ns) = (r.vint()?, r.vuint()?);
+                    let (mtime, mtime_ns) = (r.vint()?, r.vuint()?);
+                    let (_ctime, _ctime_ns) = (r.vint()?, r.vuint()?);
+
+                    metadata.mode = Some(mode as u32);
+                    metadata.uid = Some(uid as u32);
+                    metadata.gid = Some(gid as u32);
+                    metadata.user = Some(user).filter(|user| !user.is_empty());
+                    metadata.group = Some(group).filter(|group| !group.is_empty());
+                    metadata.mtime = Utc.timestamp_opt(mtime, mtime_ns as u32).single();
+                    if tag == REC_COMMON_V3 {
+                        metadata.size = u64::try_from(r.vint()?).ok();
+                    }
+                }
+                REC_SYMLINK_TARGET => metadata.symlink_target = Some(r.string()?),
+                REC_HARDLINK_TARGET => metadata.hardlink_target = Some(r.string()?),
+                REC_POSIX1E_ACL => {
+                    while !r.is_empty() {
+                        acls.push(r.string()?);
+                    }
+                }
+                REC_LINUX_XATTR => {
+                    let count = r.vuint()?;
+                    for _ in 0..count {
+                        let name = r.string()?;
+                        metadata.xattrs.push((name, r.bvec()?.to_vec()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // the text of the access ACL and of the default ACL, each also with numeric IDs
+        let access = acls.get(1).and_then(|text| acl_xattr(text, true));
+        let default = acls.get(3).and_then(|text| acl_xattr(text, false));
+        if let Some(access) = access {
+            metadata
+                .xattrs
+                .push(("system.posix_acl_access".to_string(), access));
+        }
+        if let Some(default) = default {
+            metadata
+                .xattrs
+                .push(("system.posix_acl_default".to_string(), default));
+        }
+
+        Ok(metadata)
+    }
+}
+
+/// Encode an ACL in text form (`u::rwx,u:1000:r--,g::r-x,m::r-x,o::---`) the way the kernel
+/// stores it: version, then tag | permissions | ID. Access ACLs without named entries are
+/// left out, the mode already says it all.
+fn acl_xattr(text: &str, access: bool) -> Option<Vec<u8>> {
+    let mut entries = Vec::new();
+    for entry in text.split([',', '\n']) {
+        let entry = entry.split('#').next().unwrap_or_default().trim();
+        if entry.is_empty() {
+            continue;
+        }
+
+        let mut fields = entry.split(':');
+        let (tag, qualifier, permissions) = (fields.next()?, fields.next()?, fields.next()?);
+        let id = match qualifier {
+            "" => ACL_UNDEFINED_ID,
+            id => id.parse().ok()?,
+        };
+        let tag = match (tag, qualifier.is_empty()) {
+            ("u" | "user", true) => ACL_USER_OBJ,
+            ("u" | "user", false) => ACL_USER,
+            ("g" | "group", true) => ACL_GROUP_OBJ,
+            ("g" | "group", false) => ACL_GROUP,
+            ("m" | "mask", _) => ACL_MASK,
+            ("o" | "other", _) => ACL_OTHER,
+            _ => return None,
+        };
+        let permissions = permissions
+            .chars()
+            .map(|c| match c {
+                'r' => 4,
+                'w' => 2,
+                'x' => 1,
+                _ => 0,
+            })
+            .sum::<u16>();
+
+        entries.push((tag, id, permissions));
+    }
+    if entries.is_empty() || (access && entries.len() <= 3) {
+        return None;
+    }
+    entries.sort();
+
+    let mut xattr = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+    for (tag, id, permissions) in entries {
+        xattr.extend_from_slice(&tag.to_le_bytes());
+        xattr.extend_from_slice(&permissions.to_le_bytes());
+        xattr.extend_from_slice(&id.to_le_bytes());
+    }
+
+    Some(xattr)
+}
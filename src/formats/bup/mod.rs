@@ -0,0 +1,407 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, ChunkRef, Inode, NodeKind, SnapshotInfo},
+    storage::Storage,
+};
+use error::Error;
+use git::{ObjectKind, Oid, Repository, TreeEntry, MODE_GITLINK, MODE_TREE};
+use metadata::Metadata;
+
+mod git;
+mod metadata;
+mod pack;
+
+pub mod error;
+
+/// Metadata of the entries of a directory, in a file of the directory's tree
+const BUPM: &str = ".bupm";
+/// Files next to a pack that belong to it: its index, reverse index, bitmap and lock
+const PACK_FILES: &[&str] = &["idx", "rev", "bitmap", "keep"];
+/// Marks directories whose tree was split like a large file
+const SPLIT_TREE_PREFIX: &str = ".bupd.";
+
+// file types of `st_mode`
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// A save is a commit on the branch it was saved to
+#[derive(Debug)]
+struct Save {
+    id: Oid,
+    branch: String,
+    time: DateTime<Utc>,
+    tree: Oid,
+}
+
+#[derive(Debug)]
+pub struct Bup {
+    repository: Repository,
+    saves: Vec<Save>,
+}
+
+/// Undo the name mangling of bup: files split into chunks are trees named `<name>.bup`, and
+/// files whose name would be ambiguous get a `.bupl` suffix. Returns the name and whether it's
+/// a chunked file.
+fn demangle(entry: &TreeEntry) -> (&str, bool) {
+    let name = entry.name.as_str();
+    if let Some(name) = name.strip_suffix(".bupl") {
+        (name, false)
+    } else if let Some(name) = name.strip_suffix(".bup") {
+        (name, true)
+    } else if let Some(name) = name.strip_suffix(".bupm") {
+        (name, entry.mode == MODE_TREE)
+    } else {
+        (name, false)
+    }
+}
+
+impl Bup {
+    /// Open a repository, it's a bare git repository and nothing is encrypted
+    pub fn from_folder(storage: Rc<dyn Storage>, path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(Self {
+            repository: Repository::open(storage, path)?,
+            saves: Vec::new(),
+        })
+    }
+
+    /// Every commit of every branch is a save
+    pub fn load_all_snapshots(&mut self) -> Result<()> {
+        let mut seen = HashSet::new();
+        for (branch, head) in self.repository.branches()? {
+            let mut commits = vec![head];
+            while let Some(id) = commits.pop() {
+                if !seen.insert(id) {
+                    continue;
+                }
+
+                trace!("Loading commit {} of {branch}", hex::encode(id));
+                let commit = self.repository.commit(&id)?;
+                commits.extend(&commit.parents);
+                self.saves.push(Save {
+                    id,
+                    branch: branch.clone(),
+                    time: commit.time,
+                    tree: commit.tree,
+                });
+            }
+        }
+        self.saves.sort_by_key(|save| save.time);
+
+        Ok(())
+    }
+
+    /// Load everything
+    pub fn load_all(&mut self) -> Result<()> {
+        self.load_all_snapshots()?;
+        Ok(())
+    }
+
+    /// The blobs of a chunked file, in order. Entries of the trees are named after their offset
+    /// in the file, subtrees hold further chunks.
+    fn hashsplit_chunks(&self, id: &Oid, chunks: &mut Vec<ChunkRef>) -> Result<()> {
+        for entry in self.repository.tree(id)? {
+            match entry.mode {
+                MODE_TREE => self.hashsplit_chunks(&entry.id, chunks)?,
+                _ => chunks.push(ChunkRef {
+                    id: hex::encode(entry.id),
+                    start: 0,
+                    end: None,
+                    size: Some(self.repository.object_size(&entry.id)? as usize),
+                }),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Contents of a blob or of a chunked file
+    fn read_file(&self, entry: &TreeEntry) -> Result<Vec<u8>> {
+        if entry.mode != MODE_TREE {
+            return self.repository.read_kind(&entry.id, ObjectKind::Blob);
+        }
+
+        let mut chunks = Vec::new();
+        self.hashsplit_chunks(&entry.id, &mut chunks)?;
+        let mut data = Vec::new();
+        for chunk in chunks {
+            data.extend(self.chunk(&chunk.id)?);
+        }
+
+        Ok(data)
+    }
+
+    /// List a directory and everything below it. Its `.bupm` holds the metadata of the
+    /// directory itself, then of every other entry except subdirectories, sorted by their
+    /// demangled names.
+    fn walk_dir(
+        &self,
+        id: &Oid,
+        path: &Path,
+        nodes: &mut Vec<tree::Node>,
+        hardlinks: &mut Vec<(usize, String)>,
+    ) -> Result<()> {
+        let mut entries = self.repository.tree(id)?;
+        if entries
+            .iter()
+            .any(|entry| entry.name.starts_with(SPLIT_TREE_PREFIX))
+        {
+            return Err(Error::UnsupportedSplitTree(path.display().to_string()).into());
+        }
+        entries.sort_by(|a, b| demangle(a).0.cmp(demangle(b).0));
+
+        let bupm = match entries.iter().find(|entry| entry.name == BUPM) {
+            Some(entry) => Some(self.read_file(entry)?),
+            None => None,
+        };
+        let name = format!("{}/{BUPM}", path.display());
+        let mut bupm = bupm
+            .as_deref()
+            .map(|bupm| metadata::Reader::new(bupm, &name));
+        let mut next_metadata = || match &mut bupm {
+            Some(bupm) if !bupm.is_empty() => bupm.metadata().map(Some),
+            _ => Ok(None),
+        };
+
+        // the root itself isn't listed
+        let metadata = next_metadata()?;
+        if !path.as_os_str().is_empty() {
+            let mut node = tree::Node::new(path, NodeKind::Dir);
+            if let Some(metadata) = metadata {
+                apply_metadata(&mut node, metadata);
+            }
+            nodes.push(node);
+        }
+
+        for entry in &entries {
+            let (name, chunked) = demangle(entry);
+            if entry.name == BUPM || entry.mode == MODE_GITLINK {
+                continue;
+            }
+
+            let path = path.join(name);
+            if entry.mode == MODE_TREE && !chunked {
+                self.walk_dir(&entry.id, &path, nodes, hardlinks)?;
+                continue;
+            }
+
+            let metadata = next_metadata()?.unwrap_or_default();
+            if let Some(target) = &metadata.hardlink_target {
+                hardlinks.push((nodes.len(), target.clone()));
+            }
+            nodes.push(self.entry_node(path, entry, chunked, metadata)?);
+        }
+
+        Ok(())
+    }
+
+    /// A file, symlink or special file. The type is taken from the metadata if there is any,
+    /// special files are stored as empty blobs.
+    fn entry_node(
+        &self,
+        path: PathBuf,
+        entry: &TreeEntry,
+        chunked: bool,
+        metadata: Metadata,
+    ) -> Result<tree::Node> {
+        let mode = metadata.mode.unwrap_or(match entry.mode {
+            MODE_TREE => S_IFREG | 0o644,
+            mode => mode,
+        });
+        let kind = match mode & S_IFMT {
+            S_IFREG => NodeKind::File,
+            S_IFLNK => NodeKind::Symlink {
+                target: match &metadata.symlink_target {
+                    Some(target) => target.clone(),
+                    None => String::from_utf8_lossy(&self.read_file(entry)?).to_string(),
+                },
+            },
+            S_IFDIR => return Err(Error::InvalidObject(hex::encode(entry.id)).into()),
+            _ => NodeKind::Special,
+        };
+
+        let mut node = tree::Node::new(path, kind);
+        node.mode = Some(mode & 0o7777);
+        if node.kind == NodeKind::File {
+            match chunked {
+                true => self.hashsplit_chunks(&entry.id, &mut node.chunks)?,
+                false => node.chunks.push(ChunkRef {
+                    size: Some(self.repository.object_size(&entry.id)? as usize),
+                    ..ChunkRef::whole(hex::encode(entry.id))
+                }),
+            }
+            node.size = Some(
+                node.chunks
+                    .iter()
+                    .map(|chunk| chunk.size.unwrap_or_default() as u64)
+                    .sum(),
+            );
+        }
+        apply_metadata(&mut node, metadata);
+
+        Ok(node)
+    }
+
+    fn find(&self, snapshot: &str) -> Result<&Save> {
+        let id = self.find_snapshot(snapshot)?.id;
+        Ok(self
+            .saves
+            .iter()
+            .find(|save| hex::encode(save.id) == id)
+            .unwrap())
+    }
+
+    /// Every tree and blob below a tree
+    fn collect_tree(&self, id: &Oid, objects: &mut HashSet<Oid>) -> Result<()> {
+        if !objects.insert(*id) {
+            return Ok(());
+        }
+
+        for entry in self.repository.tree(id)? {
+            match entry.mode {
+                MODE_TREE => self.collect_tree(&entry.id, objects)?,
+                MODE_GITLINK => {}
+                _ => {
+                    objects.insert(entry.id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn apply_metadata(node: &mut tree::Node, metadata: Metadata) {
+    if let Some(mode) = metadata.mode {
+        node.mode = Some(mode & 0o7777);
+    }
+    node.uid = metadata.uid;
+    node.gid = metadata.gid;
+    node.user = metadata.user;
+    node.group = metadata.group;
+    node.mtime = metadata.mtime;
+    node.xattrs = metadata.xattrs;
+}
+
+impl Backup for Bup {
+    /// Saves are identified by their commit and grouped by branch
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        self.saves
+            .iter()
+            .map(|save| SnapshotInfo {
+                id: hex::encode(save.id),
+                group: save.branch.clone(),
+                time: save.time,
+            })
+            .collect()
+    }
+
+    /// Hard links are recorded by bup, so every file gets an inode of its own unless it's a
+    /// link to an earlier one
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let save = self.find(snapshot)?;
+
+        let mut nodes = Vec::new();
+        let mut hardlinks = Vec::new();
+        self.walk_dir(&save.tree, Path::new(""), &mut nodes, &mut hardlinks)?;
+
+        for (index, node) in nodes.iter_mut().enumerate() {
+            if node.kind == NodeKind::File {
+                node.inode = Some(Inode {
+                    device: 0,
+                    inode: index as u64,
+                    links: 1,
+                });
+            }
+        }
+
+        // targets are absolute paths on the saved filesystem, which are only known if nothing
+        // was stripped from them
+        let indexes: HashMap<PathBuf, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (Path::new("/").join(&node.path), index))
+            .collect();
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (index, target) in hardlinks {
+            if let Some(target) = indexes.get(Path::new(&target)) {
+                groups.entry(*target).or_default().push(index);
+            }
+        }
+        for (target, mut links) in groups {
+            links.push(target);
+            for index in &links {
+                nodes[*index].inode = Some(Inode {
+                    device: 0,
+                    inode: target as u64,
+                    links: links.len() as u64,
+                });
+            }
+        }
+
+        Ok(nodes)
+    }
+
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        self.repository
+            .read_kind(&git::parse_id(id)?, ObjectKind::Blob)
+    }
+
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        self.repository.stored_size(&git::parse_id(id)?)
+    }
+
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = vec![self.repository.pack_dir()];
+        dirs.extend(self.repository.loose_dirs());
+        dirs
+    }
+
+    /// Packs holding a reachable object and the files git keeps next to them, and reachable
+    /// loose objects. The
+    /// midx and bloom files bup keeps next to the packs are always referenced.
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        let mut objects = HashSet::new();
+        for save in &self.saves {
+            objects.insert(save.id);
+            self.collect_tree(&save.tree, &mut objects)?;
+        }
+
+        let mut paths = HashSet::new();
+        for id in &objects {
+            let path = self
+                .repository
+                .location(id)
+                .ok_or_else(|| Error::ObjectNotFound(hex::encode(id)))?;
+            if path.extension().is_some_and(|ext| ext == "pack") {
+                paths.extend(PACK_FILES.iter().map(|ext| path.with_extension(ext)));
+            }
+            paths.insert(path);
+        }
+
+        for entry in self.storage().list(&self.repository.pack_dir())? {
+            let path = entry.path;
+            if path
+                .extension()
+                .is_some_and(|ext| ext == "midx" || ext == "bloom")
+            {
+                paths.insert(path);
+            }
+        }
+
+        Ok(paths)
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        self.repository.storage()
+    }
+}
@@ -0,0 +1,395 @@
+//! Git packfiles: zlib compressed objects, possibly stored as deltas against other objects, and
+//! the `.idx` files listing where each object starts
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use flate2::read::ZlibDecoder;
+
+use super::{
+    error::Error,
+    git::{ObjectKind, Oid},
+};
+use crate::{error::Result, storage::Storage};
+
+const PACK_MAGIC: &[u8] = b"PACK";
+const IDX_V2_MAGIC: &[u8] = b"\xfftOc";
+
+/// Fanout table: the number of objects whose ID starts with a byte up to each value
+const FANOUT_SIZE: usize = 256 * 4;
+/// Trailing SHA-1 of the pack, and of the index itself
+const CHECKSUMS_SIZE: usize = 2 * 20;
+
+// object types of the entry headers
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+/// How an object is stored: whole or as a delta against a base object
+#[derive(Debug)]
+pub enum Entry {
+    Object(ObjectKind),
+    /// The base is earlier in the same pack, at this offset
+    OfsDelta(u64),
+    RefDelta(Oid),
+}
+
+#[derive(Debug)]
+pub struct Pack {
+    pub path: PathBuf,
+
+    /// Sorted, as listed by the index
+    ids: Vec<Oid>,
+    offsets: Vec<u64>,
+    /// Where each entry ends, for the stored size of objects
+    sorted_offsets: Vec<u64>,
+    size: u64,
+}
+
+fn be_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_byte(reader: &mut impl Read) -> std::io::Result<u8> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+impl Pack {
+    /// Load the index of a pack, version 1 indexes have no header and interleave offsets and
+    /// IDs
+    pub fn open(storage: &dyn Storage, idx_path: &Path) -> Result<Self> {
+        let name = idx_path.display().to_string();
+        let invalid = || Error::InvalidPackIndex(name.clone());
+        let data = storage.read(idx_path)?;
+
+        let (ids, offsets) = match data.starts_with(IDX_V2_MAGIC) {
+            true => {
+                if data.len() < 8 + FANOUT_SIZE || be_u32(&data, 4) != 2 {
+                    return Err(invalid().into());
+                }
+                let count = be_u32(&data, 8 + FANOUT_SIZE - 4) as usize;
+
+                // IDs | CRC32s | 32 bit offsets | 64 bit offsets, for the ones that don't fit
+                let ids_start = 8 + FANOUT_SIZE;
+                let offsets_start = ids_start + count * 24;
+                let large_start = offsets_start + count * 4;
+                if data.len() < large_start + CHECKSUMS_SIZE {
+                    return Err(invalid().into());
+                }
+
+                let mut ids = Vec::with_capacity(count);
+                let mut offsets = Vec::with_capacity(count);
+                for i in 0..count {
+                    ids.push(data[ids_start + i * 20..][..20].try_into().unwrap());
+
+                    let offset = be_u32(&data, offsets_start + i * 4);
+                    offsets.push(match offset & 0x8000_0000 {
+                        0 => offset as u64,
+                        _ => {
+                            let large = large_start + (offset & 0x7fff_ffff) as usize * 8;
+                            if data.len() < large + 8 + CHECKSUMS_SIZE {
+                                return Err(invalid().into());
+                            }
+                            be_u64(&data, large)
+                        }
+                    });
+                }
+
+                (ids, offsets)
+            }
+            false => {
+                if data.len() < FANOUT_SIZE {
+                    return Err(invalid().into());
+                }
+                let count = be_u32(&data, FANOUT_SIZE - 4) as usize;
+                if data.len() < FANOUT_SIZE + count * 24 + CHECKSUMS_SIZE {
+                    return Err(invalid().into());
+                }
+
+                data[FANOUT_SIZE..FANOUT_SIZE + count * 24]
+                    .chunks(24)
+                    .map(|entry| (Oid::try_from(&entry[4..]).unwrap(), be_u32(entry, 0) as u64))
+                    .unzip()
+            }
+        };
+        if ids.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(invalid().into());
+        }
+
+        let path = idx_path.with_extension("pack");
+        let header = storage.read_range(&path, 0, 12)?;
+        if header.len() < 12
+            || &header[..4] != PACK_MAGIC
+            || !matches!(be_u32(&header, 4), 2 | 3)
+            || be_u32(&header, 8) as usize != ids.len()
+        {
+            return Err(
+                Error::InvalidPack(path.display().to_string(), "header".to_string()).into(),
+            );
+        }
+
+        let mut sorted_offsets = offsets.clone();
+        sorted_offsets.sort_unstable();
+
+        Ok(Self {
+            size: storage.stat(&path)?.size,
+            path,
+            ids,
+            offsets,
+            sorted_offsets,
+        })
+    }
+
+    /// Offset of an object in the pack
+    pub fn find(&self, id: &Oid) -> Option<u64> {
+        let index = self.ids.binary_search(id).ok()?;
+        Some(self.offsets[index])
+    }
+
+    /// Size of the compressed entry, up to the next one or the trailing checksum
+    pub fn stored_size(&self, offset: u64) -> u64 {
+        let index = self.sorted_offsets.partition_point(|o| *o <= offset);
+        let end = self
+            .sorted_offsets
+            .get(index)
+            .copied()
+            .unwrap_or(self.size.saturating_sub(20));
+
+        end.saturating_sub(offset)
+    }
+
+    /// Read the entry at an offset: how it's stored, its size and, inflated, the first `limit`
+    /// bytes of its data or all of it
+    pub fn read_at(
+        &self,
+        storage: &dyn Storage,
+        offset: u64,
+        limit: Option<u64>,
+    ) -> Result<(Entry, u64, Vec<u8>)> {
+        let invalid = |reason: &str| {
+            Error::InvalidPack(
+                self.path.display().to_string(),
+                format!("{reason} at {offset}"),
+            )
+        };
+
+        // deflate needs at most 2 bytes for every byte it inflates to, plus the block headers
+        let stored = self.stored_size(offset);
+        let length = limit.map_or(stored, |limit| stored.min(limit * 2 + 1024));
+        let stored_entry = storage.read_range(&self.path, offset, length)?;
+        let mut reader = &stored_entry[..];
+
+        // type in bits 4-6 of the first byte, the size in the other bits of a varint
+        let mut byte = read_byte(&mut reader)?;
+        let kind = (byte >> 4) & 7;
+        let mut size = (byte & 0x0f) as u64;
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            if shift > 57 {
+                return Err(invalid("oversized entry").into());
+            }
+            byte = read_byte(&mut reader)?;
+            size |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+        }
+
+        let entry = match kind {
+            OBJ_COMMIT => Entry::Object(ObjectKind::Commit),
+            OBJ_TREE => Entry::Object(ObjectKind::Tree),
+            OBJ_BLOB => Entry::Object(ObjectKind::Blob),
+            OBJ_TAG => Entry::Object(ObjectKind::Tag),
+            // big endian varint, each continuation adds one so there's one encoding per value
+            OBJ_OFS_DELTA => {
+                let mut byte = read_byte(&mut reader)?;
+                let mut distance = (byte & 0x7f) as u64;
+                while byte & 0x80 != 0 {
+                    byte = read_byte(&mut reader)?;
+                    distance = ((distance + 1) << 7) | (byte & 0x7f) as u64;
+                }
+                Entry::OfsDelta(
+                    offset
+                        .checked_sub(distance)
+                        .ok_or_else(|| invalid("delta base outside of the pack"))?,
+                )
+            }
+            OBJ_REF_DELTA => {
+                let mut base = [0u8; 20];
+                reader.read_exact(&mut base)?;
+                Entry::RefDelta(base)
+            }
+            _ => return Err(invalid(&format!("unknown type {kind}")).into()),
+        };
+
+        let limit = limit.unwrap_or(size).min(size);
+        let mut data = Vec::new();
+        ZlibDecoder::new(reader)
+            .take(limit)
+            .read_to_end(&mut data)?;
+        if data.len() as u64 != limit {
+            return Err(invalid("truncated entry").into());
+        }
+
+        Ok((entry, size, data))
+    }
+}
+
+/// Little endian base 128 varint, as used by deltas
+fn delta_varint(delta: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+        if shift > 63 {
+            return None;
+        }
+    }
+}
+
+/// Size of the base and of the result, at the start of a delta
+pub fn delta_sizes(delta: &[u8]) -> Option<(u64, u64)> {
+    let mut pos = 0;
+    Some((
+        delta_varint(delta, &mut pos)?,
+        delta_varint(delta, &mut pos)?,
+    ))
+}
+
+/// Rebuild an object from its base and a delta: a sequence of instructions to either copy a
+/// range of the base or insert new data
+pub fn apply_delta(base: &[u8], delta: &[u8], name: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::InvalidObject(name.to_string());
+
+    let mut pos = 0;
+    let base_size = delta_varint(delta, &mut pos).ok_or_else(invalid)?;
+    let size = delta_varint(delta, &mut pos).ok_or_else(invalid)?;
+    if base_size != base.len() as u64 {
+        return Err(invalid().into());
+    }
+
+    let mut result = Vec::with_capacity(size as usize);
+    while pos < delta.len() {
+        let instruction = delta[pos];
+        pos += 1;
+
+        match instruction {
+            // the low 7 bits say which bytes of the offset and size follow
+            0x80.. => {
+                let mut read = |bits: std::ops::Range<u8>| {
+                    let mut value = 0usize;
+                    for (i, bit) in bits.enumerate() {
+                        if instruction & (1 << bit) != 0 {
+                            value |= (*delta.get(pos)? as usize) << (i * 8);
+                            pos += 1;
+                        }
+                    }
+                    Some(value)
+                };
+                let offset = read(0..4).ok_or_else(invalid)?;
+                let length = match read(4..7).ok_or_else(invalid)? {
+                    0 => 0x10000,
+                    length => length,
+                };
+                let data = base
+                    .get(offset..offset.saturating_add(length))
+                    .ok_or_else(invalid)?;
+                result.extend_from_slice(data);
+            }
+            0 => return Err(invalid().into()),
+            length => {
+                let data = delta.get(pos..pos + length as usize).ok_or_else(invalid)?;
+                result.extend_from_slice(data);
+                pos += length as usize;
+            }
+        }
+    }
+    if result.len() as u64 != size {
+        return Err(invalid().into());
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::storage::Local;
+
+    /// `git pack-objects` of the blobs "bup known answer\n" and "second blob\n"
+    const PACK: &str = concat!(
+        "5041434b0000000200000002b101789c4b2a2d50c8cecb2fcf5348cc2b2e4f2d",
+        "e202003a86064f3c789c2b4e4dcecf4b5148cac94fe202001e2d0446ac0373b4",
+        "b1d6f41bb7e6bafaa4299db61e39b8f9",
+    );
+    const IDS: [&str; 2] = [
+        "ddc93d17f1ae1cd1c1f5b3caa32f0edb356098ed",
+        "e78157d2b2c18ad114cb7e34ea55fbf09268de41",
+    ];
+    const OFFSETS: [u32; 2] = [12, 39];
+    /// SHA-1 of the `.idx` git wrote along with the pack
+    const IDX_CHECKSUM: &str = "d519f3dac8acb43ba203d8409e9768dac63ef29b";
+
+    /// The version 2 index of the pack, rebuilt the way git writes it
+    fn index(pack: &[u8]) -> Vec<u8> {
+        let ids: Vec<Vec<u8>> = IDS.iter().map(|id| hex::decode(id).unwrap()).collect();
+        let mut idx = [IDX_V2_MAGIC, &2u32.to_be_bytes()].concat();
+        for byte in 0..=255 {
+            let count = ids.iter().filter(|id| id[0] <= byte).count() as u32;
+            idx.extend(count.to_be_bytes());
+        }
+        ids.iter().for_each(|id| idx.extend(id));
+        let ends = [OFFSETS[1], pack.len() as u32 - 20];
+        for (offset, end) in OFFSETS.iter().zip(ends) {
+            idx.extend(crc32fast::hash(&pack[*offset as usize..end as usize]).to_be_bytes());
+        }
+        OFFSETS
+            .iter()
+            .for_each(|offset| idx.extend(offset.to_be_bytes()));
+        idx.extend(&pack[pack.len() - 20..]);
+        let checksum = Sha1::digest(&idx);
+        idx.extend(checksum);
+        idx
+    }
+
+    #[test]
+    fn index_v2() {
+        let pack = hex::decode(PACK).unwrap();
+        let idx = index(&pack);
+        assert_eq!(hex::encode(Sha1::digest(&idx)), IDX_CHECKSUM);
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("pack-test.pack"), &pack).unwrap();
+        std::fs::write(dir.path().join("pack-test.idx"), &idx).unwrap();
+        let pack = Pack::open(&Local, &dir.path().join("pack-test.idx")).unwrap();
+
+        let id: Oid = hex::decode(IDS[0]).unwrap().try_into().unwrap();
+        let offset = pack.find(&id).unwrap();
+        assert_eq!(offset, 12);
+        assert_eq!(pack.stored_size(offset), 27);
+
+        let (entry, size, data) = pack.read_at(&Local, offset, None).unwrap();
+        assert!(matches!(entry, Entry::Object(ObjectKind::Blob)));
+        assert_eq!(size, 17);
+        assert_eq!(data, b"bup known answer\n");
+
+        assert_eq!(pack.find(&[0xff; 20]), None);
+    }
+}
@@ -0,0 +1,94 @@
+//! Decoding of the BARE encoding (`serde_bare`) bupstash serializes everything with
+
+use std::collections::BTreeMap;
+
+use super::error::Error;
+use crate::error::Result;
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pub pos: usize,
+    /// What's being decoded, only used for errors
+    what: &'a str,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8], what: &'a str) -> Self {
+        Self { data, pos: 0, what }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| Error::InvalidData(self.what.to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    /// Fixed size integers are little endian
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    /// Variable length integers (`serde_bare::Uint`, lengths and enum tags), 7 bits per byte
+    pub fn uint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(Error::InvalidData(self.what.to_string()))?
+    }
+
+    pub fn data(&mut self) -> Result<&'a [u8]> {
+        let len = self.uint()? as usize;
+        self.bytes(len)
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(self.data()?).to_string())
+    }
+
+    pub fn option<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<Option<T>> {
+        match self.bool()? {
+            true => Ok(Some(f(self)?)),
+            false => Ok(None),
+        }
+    }
+
+    pub fn map<K: Ord, V>(
+        &mut self,
+        mut key: impl FnMut(&mut Self) -> Result<K>,
+        mut value: impl FnMut(&mut Self) -> Result<V>,
+    ) -> Result<BTreeMap<K, V>> {
+        let mut map = BTreeMap::new();
+        for _ in 0..self.uint()? {
+            let k = key(self)?;
+            map.insert(k, value(self)?);
+        }
+
+        Ok(map)
+    }
+}
@@ -0,0 +1,189 @@
+//! Keys, the libsodium compatible boxes data is encrypted with and the keyed BLAKE3 hashes
+//! chunks are addressed by
+
+use std::{cell::RefCell, path::PathBuf};
+
+use base64::{engine::general_purpose, Engine as _};
+use chacha20::{
+    cipher::{consts::U10, KeyIvInit, StreamCipher},
+    XChaCha20,
+};
+use poly1305::{universal_hash::KeyInit, Poly1305};
+
+use super::{bare::Reader, error::Error};
+use crate::error::Result;
+
+const KEY_HEADER: &str = "-----BEGIN BUPSTASH KEY-----";
+const KEY_FOOTER: &str = "-----END BUPSTASH KEY-----";
+
+const NONCE_SIZE: usize = 24;
+const MAC_SIZE: usize = 16;
+const PUBLIC_KEY_SIZE: usize = 32;
+
+// the last byte of compressed data
+const COMPRESS_FOOTER_NONE: u8 = 0;
+const COMPRESS_FOOTER_LZ4: u8 = 1;
+const COMPRESS_FOOTER_ZSTD: u8 = 2;
+
+pub type HashKey = [u8; 32];
+
+/// The secret half of a box key pair and the pre-shared key mixed into every box key
+#[derive(Debug)]
+pub struct BoxSecret {
+    sk: [u8; 32],
+    psk: [u8; 32],
+    /// Senders use one ephemeral key per item, the key derived for the last one is kept
+    last_key: RefCell<Option<([u8; PUBLIC_KEY_SIZE], [u8; 32])>>,
+}
+
+/// One of the three parts of a key: data, index or metadata
+#[derive(Debug)]
+pub struct KeyPart {
+    /// First half of the hash key, the second is stored in each item. Metadata isn't hashed.
+    pub hash_key_part_1: Option<[u8; 32]>,
+    pub secret: BoxSecret,
+}
+
+/// A primary key or a sub key, sub keys may lack some of the parts
+#[derive(Debug)]
+pub struct Key {
+    pub id: [u8; 16],
+    pub data: Option<KeyPart>,
+    pub index: Option<KeyPart>,
+    pub metadata: Option<KeyPart>,
+}
+
+impl Key {
+    /// Load the key file `BUPSTASH_KEY` points to, keys aren't protected by a password
+    pub fn load() -> Result<Self> {
+        let path = std::env::var_os("BUPSTASH_KEY")
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .ok_or(Error::KeyRequired)?;
+        trace!("Loading key {path:?}");
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// The base64 of the BARE encoded key between a header and a footer
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidKey(reason.to_string());
+        let start = text
+            .find(KEY_HEADER)
+            .ok_or_else(|| invalid("missing header"))?;
+        let end = text
+            .find(KEY_FOOTER)
+            .ok_or_else(|| invalid("missing footer"))?;
+        let base64: String = text
+            .get(start + KEY_HEADER.len()..end)
+            .ok_or_else(|| invalid("missing key"))?
+            .split_whitespace()
+            .collect();
+        let data = general_purpose::STANDARD.decode(base64)?;
+
+        let mut r = Reader::new(&data, "key");
+        // primary keys have every part, sub keys only the ones they were created with
+        let optional = match r.uint()? {
+            0 => false,
+            1 => true,
+            tag => return Err(invalid(&format!("unknown key type {tag}")).into()),
+        };
+        let field = |r: &mut Reader| match optional {
+            true => r.option(|r| r.array::<32>()),
+            false => r.array::<32>().map(Some),
+        };
+
+        let id = r.array()?;
+        let _rollsum_key = field(&mut r)?;
+        let mut parts = Vec::new();
+        for hashed in [true, true, false] {
+            let (hash_key_part_1, _hash_key_part_2) = match hashed {
+                true => (field(&mut r)?, field(&mut r)?),
+                false => (None, None),
+            };
+            let (_pk, sk, psk) = (field(&mut r)?, field(&mut r)?, field(&mut r)?);
+            parts.push(sk.zip(psk).map(|(sk, psk)| KeyPart {
+                hash_key_part_1,
+                secret: BoxSecret {
+                    sk,
+                    psk,
+                    last_key: RefCell::default(),
+                },
+            }));
+        }
+
+        let mut parts = parts.into_iter();
+        Ok(Self {
+            id,
+            data: parts.next().unwrap(),
+            index: parts.next().unwrap(),
+            metadata: parts.next().unwrap(),
+        })
+    }
+}
+
+impl BoxSecret {
+    /// `crypto_box_curve25519xchacha20poly1305_beforenm`, mixed with the pre-shared key
+    fn box_key(&self, pk: &[u8; PUBLIC_KEY_SIZE]) -> [u8; 32] {
+        if let Some((last_pk, key)) = *self.last_key.borrow() {
+            if last_pk == *pk {
+                return key;
+            }
+        }
+
+        let shared = x25519_dalek::StaticSecret::from(self.sk)
+            .diffie_hellman(&x25519_dalek::PublicKey::from(*pk));
+        let unmixed = chacha20::hchacha::<U10>(shared.as_bytes().into(), &[0u8; 16].into());
+        let key = *blake3::keyed_hash(&self.psk, &unmixed).as_bytes();
+
+        *self.last_key.borrow_mut() = Some((*pk, key));
+        key
+    }
+
+    /// Decrypt and decompress: nonce | MAC | ciphertext | ephemeral public key. `what` is only
+    /// used for errors.
+    pub fn decrypt(&self, data: &[u8], what: &str) -> Result<Vec<u8>> {
+        if data.len() < NONCE_SIZE + MAC_SIZE + PUBLIC_KEY_SIZE {
+            return Err(Error::InvalidData(what.to_string()).into());
+        }
+        let (data, pk) = data.split_at(data.len() - PUBLIC_KEY_SIZE);
+        let (nonce, data) = data.split_at(NONCE_SIZE);
+        let (mac, ciphertext) = data.split_at(MAC_SIZE);
+
+        // NaCl secretbox: the first 32 bytes of the key stream are the Poly1305 key
+        let key = self.box_key(pk.try_into().unwrap());
+        let mut cipher = XChaCha20::new(&key.into(), nonce.into());
+        let mut mac_key = [0u8; 32];
+        cipher.apply_keystream(&mut mac_key);
+        if Poly1305::new(&mac_key.into()).compute_unpadded(ciphertext)[..] != *mac {
+            return Err(Error::DecryptionFailed(what.to_string()).into());
+        }
+
+        let mut plaintext = ciphertext.to_vec();
+        cipher.apply_keystream(&mut plaintext);
+        decompress(plaintext, what)
+    }
+}
+
+/// Undo the compression marked by the footer byte
+pub fn decompress(mut data: Vec<u8>, what: &str) -> Result<Vec<u8>> {
+    let footer = data
+        .pop()
+        .ok_or_else(|| Error::InvalidData(what.to_string()))?;
+
+    match footer {
+        COMPRESS_FOOTER_NONE => Ok(data),
+        COMPRESS_FOOTER_LZ4 => Ok(lz4_flex::decompress_size_prepended(&data)?),
+        COMPRESS_FOOTER_ZSTD => Ok(zstd::stream::decode_all(&data[..])?),
+        footer => Err(Error::UnsupportedCompression(footer))?,
+    }
+}
+
+/// Combine the two halves of a hash key
+pub fn derive_hash_key(part_1: &[u8; 32], part_2: &[u8; 32]) -> HashKey {
+    *blake3::keyed_hash(part_1, part_2).as_bytes()
+}
+
+/// Address of a data or index chunk
+pub fn keyed_address(key: &HashKey, data: &[u8]) -> [u8; 32] {
+    *blake3::keyed_hash(key, data).as_bytes()
+}
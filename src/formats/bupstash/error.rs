@@ -0,0 +1,25 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("No key given, set BUPSTASH_KEY to the path of the key file")]
+    KeyRequired,
+    #[error("Invalid key file: {0}")]
+    InvalidKey(String),
+    #[error("The key can't read {0}")]
+    MissingKeyPart(String),
+    #[error("Item {0} was created with a different key")]
+    KeyMismatch(String),
+    #[error("Unsupported item metadata version {1} in {0}")]
+    UnsupportedItemVersion(String, u64),
+    #[error("Unsupported index entry version {0}")]
+    UnsupportedIndexVersion(u64),
+    #[error("Unsupported compression: {0}")]
+    UnsupportedCompression(u8),
+    #[error("Truncated or invalid {0}")]
+    InvalidData(String),
+    #[error("Decryption failed for {0}")]
+    DecryptionFailed(String),
+    #[error("Integrity check failed for {0}")]
+    IntegrityCheckFailed(String),
+}
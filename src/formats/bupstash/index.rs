@@ -0,0 +1,166 @@
+//! The index of a directory: every entry and where its contents are in the data stream
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use chrono::{TimeZone, Utc};
+
+use super::{bare::Reader, error::Error};
+use crate::{
+    error::Result,
+    formats::tree::{self, ChunkRef, Inode, NodeKind},
+};
+
+const INDEX_ENTRY_V3: u64 = 2;
+
+// file types of `st_mode`
+const S_IFMT: u64 = 0o170000;
+const S_IFDIR: u64 = 0o040000;
+const S_IFREG: u64 = 0o100000;
+const S_IFLNK: u64 = 0o120000;
+
+/// The chunks of the data stream a file's contents start and end in, and the offsets inside
+/// them
+#[derive(Debug)]
+pub struct IndexEntryOffsets {
+    pub data_chunk_idx: u64,
+    pub data_chunk_end_idx: u64,
+    pub data_chunk_offset: u64,
+    pub data_chunk_end_offset: u64,
+}
+
+#[derive(Debug)]
+pub struct IndexEntry {
+    /// Relative to the directory that was saved, `.` is the directory itself
+    pub path: String,
+    pub mode: u64,
+    pub size: u64,
+    pub uid: u64,
+    pub gid: u64,
+    pub mtime: u64,
+    pub mtime_nsec: u64,
+    pub norm_dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    pub link_target: Option<String>,
+    pub xattrs: Option<BTreeMap<String, Vec<u8>>>,
+    pub offsets: IndexEntryOffsets,
+}
+
+impl IndexEntry {
+    fn read(r: &mut Reader) -> Result<Self> {
+        let version = r.uint()?;
+        if version != INDEX_ENTRY_V3 {
+            return Err(Error::UnsupportedIndexVersion(version).into());
+        }
+
+        let path = r.string()?;
+        let mode = r.uint()?;
+        let size = r.uint()?;
+        let uid = r.uint()?;
+        let gid = r.uint()?;
+        let mtime = r.uint()?;
+        let mtime_nsec = r.uint()?;
+        let _ctime = r.uint()?;
+        let _ctime_nsec = r.uint()?;
+        let norm_dev = r.uint()?;
+        let ino = r.uint()?;
+        let nlink = r.uint()?;
+        let link_target = r.option(Reader::string)?;
+        let _dev_major = r.uint()?;
+        let _dev_minor = r.uint()?;
+        let xattrs = r.option(|r| r.map(Reader::string, |r| Ok(r.data()?.to_vec())))?;
+        let _sparse = r.bool()?;
+        // the unkeyed BLAKE3 of the contents, if it was computed
+        if r.uint()? != 0 {
+            r.array::<32>()?;
+        }
+        let offsets = IndexEntryOffsets {
+            data_chunk_idx: r.uint()?,
+            data_chunk_end_idx: r.uint()?,
+            data_chunk_offset: r.uint()?,
+            data_chunk_end_offset: r.uint()?,
+        };
+
+        Ok(Self {
+            path,
+            mode,
+            size,
+            uid,
+            gid,
+            mtime,
+            mtime_nsec,
+            norm_dev,
+            ino,
+            nlink,
+            link_target,
+            xattrs,
+            offsets,
+        })
+    }
+
+    /// Convert to a tree node, `data_chunks` are the addresses of the chunks of the data stream
+    pub fn to_tree_node(&self, data_chunks: &[String]) -> Result<tree::Node> {
+        let kind = match self.mode & S_IFMT {
+            S_IFDIR => NodeKind::Dir,
+            S_IFREG => NodeKind::File,
+            S_IFLNK => NodeKind::Symlink {
+                target: self.link_target.clone().unwrap_or_default(),
+            },
+            _ => NodeKind::Special,
+        };
+
+        let mut node = tree::Node::new(PathBuf::from(&self.path), kind);
+        node.mode = Some((self.mode & 0o7777) as u32);
+        node.uid = Some(self.uid as u32);
+        node.gid = Some(self.gid as u32);
+        node.mtime = Utc
+            .timestamp_opt(self.mtime as i64, self.mtime_nsec as u32)
+            .single();
+        node.xattrs = self
+            .xattrs
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        node.inode = Some(Inode {
+            device: self.norm_dev,
+            inode: self.ino,
+            links: self.nlink,
+        });
+
+        if node.kind == NodeKind::File {
+            node.size = Some(self.size);
+            if self.size > 0 {
+                let offsets = &self.offsets;
+                for i in offsets.data_chunk_idx..=offsets.data_chunk_end_idx {
+                    let id = data_chunks.get(i as usize).ok_or_else(|| {
+                        Error::InvalidData(format!("data offsets of {}", self.path))
+                    })?;
+                    node.chunks.push(ChunkRef {
+                        id: id.clone(),
+                        start: match i == offsets.data_chunk_idx {
+                            true => offsets.data_chunk_offset as usize,
+                            false => 0,
+                        },
+                        end: (i == offsets.data_chunk_end_idx)
+                            .then_some(offsets.data_chunk_end_offset as usize),
+                        size: None,
+                    });
+                }
+            }
+        }
+
+        Ok(node)
+    }
+}
+
+/// Parse the concatenated chunks of an index tree
+pub fn read_entries(data: &[u8]) -> Result<Vec<IndexEntry>> {
+    let mut r = Reader::new(data, "index");
+    let mut entries = Vec::new();
+    while !r.is_empty() {
+        entries.push(IndexEntry::read(&mut r)?);
+    }
+
+    Ok(entries)
+}
@@ -0,0 +1,121 @@
+//! Item metadata: the roots of the data and index trees in plain text, everything else
+//! encrypted with the metadata key
+
+use std::{collections::BTreeMap, path::Path};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use super::{
+    bare::Reader,
+    crypto::{self, HashKey, Key},
+    error::Error,
+};
+use crate::{error::Result, storage::Storage};
+
+/// Only the current item format is supported, older repositories are upgraded on open
+const ITEM_METADATA_V3: u64 = 2;
+
+/// Chunks are addressed by the BLAKE3 of their plaintext
+pub type Address = [u8; 32];
+
+/// Root of a tree of chunks, a tree of height 0 is a single data chunk
+#[derive(Debug, Clone)]
+pub struct HTreeMetadata {
+    pub height: u64,
+    pub data_chunk_count: u64,
+    pub address: Address,
+}
+
+impl HTreeMetadata {
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            height: r.uint()?,
+            data_chunk_count: r.uint()?,
+            address: r.array()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Item {
+    /// Hex of the item's random ID, also its file name
+    pub id: String,
+    pub time: DateTime<Utc>,
+    pub data_tree: HTreeMetadata,
+    /// Only directories have an index, it lists the files and where they are in the data
+    pub index_tree: Option<HTreeMetadata>,
+    pub data_hash_key: Option<HashKey>,
+    pub index_hash_key: Option<HashKey>,
+    pub data_size: u64,
+    /// `name` is set by `bupstash put`, the other tags by the user
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Item {
+    /// Read and decrypt `items/<id>`, the plain text part is authenticated by a hash in the
+    /// encrypted part
+    pub fn load(storage: &dyn Storage, path: &Path, key: &Key) -> Result<Self> {
+        let id = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let data = storage.read(path)?;
+
+        let mut r = Reader::new(&data, &id);
+        let version = r.uint()?;
+        if version != ITEM_METADATA_V3 {
+            return Err(Error::UnsupportedItemVersion(id, version).into());
+        }
+
+        let plain_text_start = r.pos;
+        let primary_key_id: [u8; 16] = r.array()?;
+        let unix_timestamp_millis = r.u64()?;
+        let data_tree = HTreeMetadata::read(&mut r)?;
+        let index_tree = r.option(HTreeMetadata::read)?;
+        let plain_text = &data[plain_text_start..r.pos];
+        let encrypted = r.data()?;
+
+        if primary_key_id != key.id {
+            return Err(Error::KeyMismatch(id).into());
+        }
+        let metadata = key
+            .metadata
+            .as_ref()
+            .ok_or_else(|| Error::MissingKeyPart("item metadata".into()))?;
+        let decrypted = metadata.secret.decrypt(encrypted, &id)?;
+
+        let mut r = Reader::new(&decrypted, &id);
+        let plain_text_hash: [u8; 32] = r.array()?;
+        if *blake3::hash(plain_text).as_bytes() != plain_text_hash {
+            return Err(Error::IntegrityCheckFailed(id).into());
+        }
+        let _send_key_id: [u8; 16] = r.array()?;
+        let index_hash_key_part_2: [u8; 32] = r.array()?;
+        let data_hash_key_part_2: [u8; 32] = r.array()?;
+        let _timestamp = r.string()?;
+        let data_size = r.uint()?;
+        let _index_size = r.uint()?;
+        let tags = r.map(Reader::string, Reader::string)?;
+
+        let hash_key = |part: &Option<crypto::KeyPart>, part_2| {
+            part.as_ref()
+                .and_then(|part| part.hash_key_part_1.as_ref())
+                .map(|part_1| crypto::derive_hash_key(part_1, part_2))
+        };
+
+        Ok(Self {
+            time: Utc
+                .timestamp_millis_opt(unix_timestamp_millis as i64)
+                .single()
+                .unwrap_or_default(),
+            data_tree,
+            index_tree,
+            data_hash_key: hash_key(&key.data, &data_hash_key_part_2),
+            index_hash_key: hash_key(&key.index, &index_hash_key_part_2),
+            data_size,
+            tags,
+            id,
+        })
+    }
+}
@@ -0,0 +1,228 @@
+use std::{collections::HashSet, path::PathBuf, rc::Rc};
+
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, ChunkCache, ChunkRef, NodeKind, SnapshotInfo},
+    storage::Storage,
+};
+use crypto::{HashKey, Key};
+use error::Error;
+use item::{Address, HTreeMetadata, Item};
+
+mod bare;
+mod crypto;
+mod index;
+mod item;
+
+pub mod error;
+
+/// Entries of tree blocks: the number of data chunks below it and its address
+const TREE_ENTRY_SIZE: usize = 8 + 32;
+
+#[derive(Debug)]
+pub struct Bupstash {
+    pub path: PathBuf,
+    storage: Rc<dyn Storage>,
+
+    key: Key,
+    items: Vec<Item>,
+    /// Leaves of the data tree hold the contents of many small files
+    chunks: ChunkCache,
+}
+
+impl Bupstash {
+    /// Open a repository, the key is read from the file `BUPSTASH_KEY` points to
+    pub fn from_folder(storage: Rc<dyn Storage>, path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(Self {
+            path: path.into(),
+            storage,
+            key: Key::load()?,
+            items: Vec::new(),
+            chunks: ChunkCache::default(),
+        })
+    }
+
+    /// Load the metadata of every item
+    pub fn load_all_snapshots(&mut self) -> Result<()> {
+        for entry in self.storage.list(&self.path.join("items"))? {
+            trace!("Loading item {:?}", entry.path);
+            self.items
+                .push(Item::load(&*self.storage, &entry.path, &self.key)?);
+        }
+        self.items.sort_by_key(|item| item.time);
+
+        Ok(())
+    }
+
+    /// Load everything
+    pub fn load_all(&mut self) -> Result<()> {
+        self.load_all_snapshots()?;
+        Ok(())
+    }
+
+    fn chunk_path(&self, address: &[u8]) -> PathBuf {
+        self.path.join("data").join(hex::encode(address))
+    }
+
+    /// The addresses of the leaves of a tree and of the tree blocks above them. Tree blocks are
+    /// only compressed and addressed by their unkeyed hash.
+    fn walk_tree(&self, tree: &HTreeMetadata) -> Result<(Vec<Address>, Vec<Address>)> {
+        let mut level = vec![tree.address];
+        let mut blocks = Vec::new();
+
+        for _ in 0..tree.height {
+            let mut next = Vec::new();
+            for address in &level {
+                let name = hex::encode(address);
+                let data =
+                    crypto::decompress(self.storage.read(&self.chunk_path(address))?, &name)?;
+                if blake3::hash(&data).as_bytes() != address {
+                    return Err(Error::IntegrityCheckFailed(name).into());
+                }
+                if data.len() % TREE_ENTRY_SIZE != 0 {
+                    return Err(Error::InvalidData(name).into());
+                }
+
+                next.extend(
+                    data.chunks(TREE_ENTRY_SIZE)
+                        .map(|entry| Address::try_from(&entry[8..]).unwrap()),
+                );
+            }
+            blocks.append(&mut level);
+            level = next;
+        }
+        if level.len() as u64 != tree.data_chunk_count {
+            return Err(Error::InvalidData(hex::encode(tree.address)).into());
+        }
+
+        Ok((level, blocks))
+    }
+
+    /// Read and decrypt a leaf of a data or index tree, checking it against its address
+    fn read_leaf(
+        &self,
+        address: &[u8],
+        part: &Option<crypto::KeyPart>,
+        hash_keys: &[HashKey],
+        what: &str,
+    ) -> Result<Vec<u8>> {
+        let name = hex::encode(address);
+        let part = part
+            .as_ref()
+            .ok_or_else(|| Error::MissingKeyPart(what.to_string()))?;
+        let data = part
+            .secret
+            .decrypt(&self.storage.read(&self.chunk_path(address))?, &name)?;
+
+        if !hash_keys
+            .iter()
+            .any(|key| crypto::keyed_address(key, &data) == address)
+        {
+            return Err(Error::IntegrityCheckFailed(name).into());
+        }
+
+        Ok(data)
+    }
+
+    fn item(&self, snapshot: &str) -> Result<&Item> {
+        let id = self.find_snapshot(snapshot)?.id;
+        Ok(self.items.iter().find(|item| item.id == id).unwrap())
+    }
+}
+
+impl Backup for Bupstash {
+    /// Items are identified by their ID and grouped by their name
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        self.items
+            .iter()
+            .map(|item| SnapshotInfo {
+                id: item.id.clone(),
+                group: item.tags.get("name").cloned().unwrap_or_default(),
+                time: item.time,
+            })
+            .collect()
+    }
+
+    /// Directories are listed by their index, anything else is a single file named after the
+    /// item
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let item = self.item(snapshot)?;
+        let data_chunks: Vec<String> = self
+            .walk_tree(&item.data_tree)?
+            .0
+            .iter()
+            .map(hex::encode)
+            .collect();
+
+        let Some(index_tree) = &item.index_tree else {
+            let name = item.tags.get("name").map_or("data", String::as_str);
+            let mut node = tree::Node::new(name, NodeKind::File);
+            node.size = Some(item.data_size);
+            node.chunks = data_chunks.into_iter().map(ChunkRef::whole).collect();
+            return Ok(vec![node]);
+        };
+
+        let hash_keys: Vec<HashKey> = item.index_hash_key.into_iter().collect();
+        let mut index = Vec::new();
+        for address in self.walk_tree(index_tree)?.0 {
+            index.extend(self.read_leaf(&address, &self.key.index, &hash_keys, "the index")?);
+        }
+
+        let mut nodes = Vec::new();
+        for entry in index::read_entries(&index)? {
+            if entry.path == "." {
+                continue;
+            }
+            nodes.push(entry.to_tree_node(&data_chunks)?);
+        }
+        nodes.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(nodes)
+    }
+
+    /// Data chunks are addressed by a keyed hash, the key usually is the same for every item
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        self.chunks.get_or_load(id, || {
+            let hash_keys: Vec<HashKey> = self
+                .items
+                .iter()
+                .filter_map(|item| item.data_hash_key)
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect();
+
+            self.read_leaf(&hex::decode(id)?, &self.key.data, &hash_keys, "data")
+        })
+    }
+
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        Ok(self.storage.stat(&self.chunk_path(&hex::decode(id)?))?.size)
+    }
+
+    /// Chunks of every item are stored in `data/<address>`
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        vec![self.path.join("data")]
+    }
+
+    /// Data and index chunks of every item and the tree blocks above them
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        let mut referenced = HashSet::new();
+        for item in &self.items {
+            for tree in std::iter::once(&item.data_tree).chain(&item.index_tree) {
+                let (leaves, blocks) = self.walk_tree(tree)?;
+                referenced.extend(
+                    leaves
+                        .iter()
+                        .chain(&blocks)
+                        .map(|address| self.chunk_path(address)),
+                );
+            }
+        }
+
+        Ok(referenced)
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+}
@@ -0,0 +1,339 @@
+//! catar archives: a depth first stream of file entries, each followed by its metadata and
+//! contents. ACLs, file capabilities and SELinux labels become the xattrs the kernel exposes
+//! them as.
+
+use std::{
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use chrono::{TimeZone, Utc};
+
+use super::{error::Error, index::ChunkIndex};
+use crate::{
+    error::Result,
+    formats::tree::{self, NodeKind},
+};
+
+const CA_FORMAT_ENTRY: u64 = 0x1396fabcea5bbb51;
+const CA_FORMAT_USER: u64 = 0xf453131aaeeaccb3;
+const CA_FORMAT_GROUP: u64 = 0x25eb6ac969396a52;
+const CA_FORMAT_XATTR: u64 = 0xb8157091f80bc486;
+const CA_FORMAT_ACL_USER: u64 = 0x297dc88b2ef12faf;
+const CA_FORMAT_ACL_GROUP: u64 = 0x36f2acb56cb3dd0b;
+const CA_FORMAT_ACL_GROUP_OBJ: u64 = 0x23047110441f38f3;
+const CA_FORMAT_ACL_DEFAULT: u64 = 0xfe3eeda6823c8cd0;
+const CA_FORMAT_ACL_DEFAULT_USER: u64 = 0xbdf03df9bd010a91;
+const CA_FORMAT_ACL_DEFAULT_GROUP: u64 = 0xa0cb1168782d1f51;
+const CA_FORMAT_FCAPS: u64 = 0xf7267db0afed0629;
+const CA_FORMAT_QUOTA_PROJID: u64 = 0x161baf2d8772a72b;
+const CA_FORMAT_SELINUX: u64 = 0x46faf0602fd26c59;
+const CA_FORMAT_SYMLINK: u64 = 0x664a6fb6830e0d6c;
+const CA_FORMAT_DEVICE: u64 = 0xac3dace369dfe643;
+const CA_FORMAT_PAYLOAD: u64 = 0x8b9e1d93d6dcffc9;
+const CA_FORMAT_FILENAME: u64 = 0x6dbb6ebcb3161f0b;
+const CA_FORMAT_GOODBYE: u64 = 0xdfd35c5e8327c403;
+
+/// Every item starts with its size, including the header, and its type
+const HEADER_SIZE: u64 = 16;
+
+const S_IFMT: u64 = 0o170000;
+const S_IFDIR: u64 = 0o040000;
+const S_IFREG: u64 = 0o100000;
+const S_IFLNK: u64 = 0o120000;
+
+// tags of the entries of `system.posix_acl_*` xattrs
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+
+/// The ACL items of an entry, named users and groups are `(tag, id, permissions)`
+#[derive(Default)]
+struct Acls {
+    access: Vec<(u16, u32, u16)>,
+    group_obj: Option<u16>,
+    /// Permissions of the owner, group, others and the mask
+    default: Option<[u64; 4]>,
+    default_entries: Vec<(u16, u32, u16)>,
+}
+
+impl Acls {
+    /// Encode an ACL the way the kernel stores it: version, then tag | permissions | ID
+    fn encode(entries: &[(u16, u32, u16)]) -> Vec<u8> {
+        let mut entries = entries.to_vec();
+        entries.sort();
+
+        let mut xattr = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for (tag, id, permissions) in entries {
+            xattr.extend_from_slice(&tag.to_le_bytes());
+            xattr.extend_from_slice(&permissions.to_le_bytes());
+            xattr.extend_from_slice(&id.to_le_bytes());
+        }
+
+        xattr
+    }
+
+    /// The group bits of the mode are the mask if there's an access ACL
+    fn into_xattrs(self, mode: u64) -> Vec<(String, Vec<u8>)> {
+        let mut xattrs = Vec::new();
+        let bits = |shift: u64| ((mode >> shift) & 7) as u16;
+
+        if !self.access.is_empty() || self.group_obj.is_some() {
+            let mut entries = self.access;
+            entries.extend([
+                (ACL_USER_OBJ, ACL_UNDEFINED_ID, bits(6)),
+                (
+                    ACL_GROUP_OBJ,
+                    ACL_UNDEFINED_ID,
+                    self.group_obj.unwrap_or(bits(3)),
+                ),
+                (ACL_MASK, ACL_UNDEFINED_ID, bits(3)),
+                (ACL_OTHER, ACL_UNDEFINED_ID, bits(0)),
+            ]);
+            xattrs.push((
+                "system.posix_acl_access".to_string(),
+                Self::encode(&entries),
+            ));
+        }
+
+        if let Some([user_obj, group_obj, other, mask]) = self.default {
+            let mut entries = self.default_entries;
+            entries.extend([
+                (ACL_USER_OBJ, ACL_UNDEFINED_ID, user_obj as u16),
+                (ACL_GROUP_OBJ, ACL_UNDEFINED_ID, group_obj as u16),
+                (ACL_OTHER, ACL_UNDEFINED_ID, other as u16),
+            ]);
+            // `u64::MAX` if there's no mask
+            if mask <= 7 {
+                entries.push((ACL_MASK, ACL_UNDEFINED_ID, mask as u16));
+            }
+            xattrs.push((
+                "system.posix_acl_default".to_string(),
+                Self::encode(&entries),
+            ));
+        }
+
+        xattrs
+    }
+}
+
+pub struct Decoder<'a, R> {
+    reader: R,
+    name: &'a str,
+    index: &'a ChunkIndex,
+}
+
+impl<'a, R: Read + Seek> Decoder<'a, R> {
+    pub fn new(reader: R, name: &'a str, index: &'a ChunkIndex) -> Self {
+        Self {
+            reader,
+            name,
+            index,
+        }
+    }
+
+    /// Decode the whole archive, paths are relative to its root directory
+    pub fn decode(mut self, nodes: &mut Vec<tree::Node>) -> Result<()> {
+        self.decode_entry(Path::new(""), nodes)
+    }
+
+    fn invalid(&self, reason: &str) -> Error {
+        Error::InvalidArchive(self.name.to_string(), reason.to_string())
+    }
+
+    /// Type and size of the contents of the next item
+    fn read_header(&mut self) -> Result<(u64, u64)> {
+        let size = self.read_u64()?;
+        let kind = self.read_u64()?;
+        if size < HEADER_SIZE {
+            return Err(self.invalid("item smaller than its header").into());
+        }
+
+        Ok((kind, size - HEADER_SIZE))
+    }
+
+    /// Go back to the start of the header that was just read
+    fn unread(&mut self) -> Result<()> {
+        self.reader.seek(SeekFrom::Current(-(HEADER_SIZE as i64)))?;
+        Ok(())
+    }
+
+    fn skip(&mut self, size: u64) -> Result<()> {
+        self.reader.seek(SeekFrom::Current(size as i64))?;
+        Ok(())
+    }
+
+    fn read_vec(&mut self, size: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; size as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Names and link targets are NUL terminated
+    fn read_string(&mut self, size: u64) -> Result<String> {
+        let mut data = self.read_vec(size)?;
+        if data.pop() != Some(0) {
+            return Err(self.invalid("string without NUL terminator").into());
+        }
+
+        Ok(String::from_utf8_lossy(&data).to_string())
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut data = [0u8; 8];
+        self.reader.read_exact(&mut data)?;
+        Ok(u64::from_le_bytes(data))
+    }
+
+    /// ID | permissions | name of the user or group
+    fn read_acl_entry(&mut self, tag: u16, size: u64) -> Result<(u16, u32, u16)> {
+        let id = self.read_u64()?;
+        let permissions = self.read_u64()?;
+        self.skip(size.saturating_sub(16))?;
+
+        Ok((tag, id as u32, permissions as u16))
+    }
+
+    /// Decode an entry, its metadata, its contents and, for directories, everything below it
+    fn decode_entry(&mut self, path: &Path, nodes: &mut Vec<tree::Node>) -> Result<()> {
+        let (kind, size) = self.read_header()?;
+        if kind != CA_FORMAT_ENTRY {
+            return Err(Error::UnexpectedItem(self.name.to_string(), kind).into());
+        }
+        if size < 48 {
+            return Err(self.invalid("truncated entry").into());
+        }
+
+        // feature flags | mode | flags | uid | gid | mtime in nanoseconds
+        let _feature_flags = self.read_u64()?;
+        let mode = self.read_u64()?;
+        let _flags = self.read_u64()?;
+        let uid = self.read_u64()?;
+        let gid = self.read_u64()?;
+        let mtime = self.read_u64()?;
+        self.skip(size - 48)?;
+
+        let mut node = tree::Node::new(
+            path,
+            match mode & S_IFMT {
+                S_IFDIR => NodeKind::Dir,
+                S_IFREG => NodeKind::File,
+                S_IFLNK => NodeKind::Symlink {
+                    target: String::new(),
+                },
+                _ => NodeKind::Special,
+            },
+        );
+        node.mode = Some((mode & 0o7777) as u32);
+        node.uid = Some(uid as u32);
+        node.gid = Some(gid as u32);
+        node.mtime = Some(Utc.timestamp_nanos(mtime as i64));
+
+        // metadata items, followed by the contents unless it's a directory, fifo or socket
+        let mut acls = Acls::default();
+        loop {
+            let (kind, size) = self.read_header()?;
+            match kind {
+                CA_FORMAT_USER => node.user = Some(self.read_string(size)?),
+                CA_FORMAT_GROUP => node.group = Some(self.read_string(size)?),
+                CA_FORMAT_XATTR => {
+                    let xattr = self.read_vec(size)?;
+                    let Some(separator) = xattr.iter().position(|b| *b == 0) else {
+                        return Err(self.invalid("xattr without name").into());
+                    };
+                    node.xattrs.push((
+                        String::from_utf8_lossy(&xattr[..separator]).to_string(),
+                        xattr[separator + 1..].to_vec(),
+                    ));
+                }
+                CA_FORMAT_ACL_USER => acls.access.push(self.read_acl_entry(ACL_USER, size)?),
+                CA_FORMAT_ACL_GROUP => acls.access.push(self.read_acl_entry(ACL_GROUP, size)?),
+                CA_FORMAT_ACL_GROUP_OBJ => {
+                    acls.group_obj = Some(self.read_u64()? as u16);
+                    self.skip(size.saturating_sub(8))?;
+                }
+                CA_FORMAT_ACL_DEFAULT => {
+                    let mut permissions = [0u64; 4];
+                    for permission in &mut permissions {
+                        *permission = self.read_u64()?;
+                    }
+                    acls.default = Some(permissions);
+                    self.skip(size.saturating_sub(32))?;
+                }
+                CA_FORMAT_ACL_DEFAULT_USER => acls
+                    .default_entries
+                    .push(self.read_acl_entry(ACL_USER, size)?),
+                CA_FORMAT_ACL_DEFAULT_GROUP => acls
+                    .default_entries
+                    .push(self.read_acl_entry(ACL_GROUP, size)?),
+                CA_FORMAT_FCAPS => {
+                    let fcaps = self.read_vec(size)?;
+                    node.xattrs.push(("security.capability".to_string(), fcaps));
+                }
+                CA_FORMAT_SELINUX => {
+                    let label = self.read_vec(size)?;
+                    node.xattrs.push(("security.selinux".to_string(), label));
+                }
+                CA_FORMAT_QUOTA_PROJID => self.skip(size)?,
+                CA_FORMAT_PAYLOAD => {
+                    let start = self.reader.stream_position()?;
+                    node.size = Some(size);
+                    node.chunks = self.index.chunk_refs(start, start + size);
+                    self.skip(size)?;
+                    break;
+                }
+                CA_FORMAT_SYMLINK => {
+                    node.kind = NodeKind::Symlink {
+                        target: self.read_string(size)?,
+                    };
+                    break;
+                }
+                CA_FORMAT_DEVICE => {
+                    self.skip(size)?;
+                    break;
+                }
+                CA_FORMAT_FILENAME | CA_FORMAT_GOODBYE => {
+                    self.unread()?;
+                    break;
+                }
+                kind => return Err(Error::UnexpectedItem(self.name.to_string(), kind).into()),
+            }
+        }
+        node.xattrs.extend(acls.into_xattrs(mode));
+
+        // the root is only there for its metadata
+        let is_dir = node.kind == NodeKind::Dir;
+        if !path.as_os_str().is_empty() {
+            nodes.push(node);
+        }
+        if !is_dir {
+            return Ok(());
+        }
+
+        // the children, each preceded by its name, followed by the goodbye table of the
+        // directory, a hash table used for lookups
+        loop {
+            let (kind, size) = self.read_header()?;
+            match kind {
+                CA_FORMAT_FILENAME => {
+                    let name = self.read_string(size)?;
+                    if matches!(name.as_str(), "" | "." | "..") || name.contains('/') {
+                        return Err(self.invalid(&format!("invalid file name {name:?}")).into());
+                    }
+
+                    self.decode_entry(&path.join(name), nodes)?;
+                }
+                CA_FORMAT_GOODBYE => {
+                    self.skip(size)?;
+                    return Ok(());
+                }
+                kind => return Err(Error::UnexpectedItem(self.name.to_string(), kind).into()),
+            }
+        }
+    }
+}
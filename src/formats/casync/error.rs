@@ -0,0 +1,19 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("No chunk store (*.castr) found in {0}")]
+    StoreNotFound(String),
+    #[error("Chunk {0} not found in any store")]
+    ChunkNotFound(String),
+    #[error("Invalid index: {0}")]
+    InvalidIndex(String),
+    #[error("Unsupported compression of chunk {0}")]
+    UnsupportedCompression(String),
+    #[error("Integrity check failed for {0}")]
+    IntegrityCheckFailed(String),
+    #[error("Invalid catar archive {0}: {1}")]
+    InvalidArchive(String, String),
+    #[error("Unexpected item {1:#018x} in catar archive {0}")]
+    UnexpectedItem(String, u64),
+}
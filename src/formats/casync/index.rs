@@ -0,0 +1,110 @@
+//! Index files list the chunks of a stream: `.caidx` for catar archives, `.caibx` for blobs
+//! like disk images
+
+use std::path::Path;
+
+use super::error::Error;
+use crate::{error::Result, formats::tree::ChunkRef, storage::Storage};
+
+const CA_FORMAT_INDEX: u64 = 0x96824d9c7b129ff9;
+const CA_FORMAT_TABLE: u64 = 0xe75b9e112f17417d;
+const CA_FORMAT_TABLE_TAIL_MARKER: u64 = 0x4b4f050e5549ecd1;
+
+/// size | type | feature flags | minimum, average and maximum chunk size
+const INDEX_HEADER_SIZE: usize = 48;
+/// size (always `u64::MAX`) | type
+const TABLE_HEADER_SIZE: usize = 16;
+/// end offset | chunk ID
+const TABLE_ITEM_SIZE: usize = 40;
+
+#[derive(Debug, Clone)]
+pub struct ChunkIndex {
+    /// End offset of each chunk in the stream and its ID
+    pub entries: Vec<(u64, String)>,
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl ChunkIndex {
+    /// The table ends with an item with a zero offset, the start of its tail
+    pub fn from_file(storage: &dyn Storage, path: &Path) -> Result<Self> {
+        let name = path.display().to_string();
+        let data = storage.read(path)?;
+        let invalid = || Error::InvalidIndex(name.clone());
+
+        if data.len() < INDEX_HEADER_SIZE + TABLE_HEADER_SIZE
+            || read_u64(&data, 0) != INDEX_HEADER_SIZE as u64
+            || read_u64(&data, 8) != CA_FORMAT_INDEX
+            || read_u64(&data, INDEX_HEADER_SIZE) != u64::MAX
+            || read_u64(&data, INDEX_HEADER_SIZE + 8) != CA_FORMAT_TABLE
+            || read_u64(&data, data.len() - 8) != CA_FORMAT_TABLE_TAIL_MARKER
+        {
+            return Err(invalid().into());
+        }
+
+        let mut entries = Vec::new();
+        for item in data[INDEX_HEADER_SIZE + TABLE_HEADER_SIZE..].chunks(TABLE_ITEM_SIZE) {
+            if item.len() < TABLE_ITEM_SIZE {
+                return Err(invalid().into());
+            }
+
+            let end = read_u64(item, 0);
+            if end == 0 {
+                break;
+            }
+            if entries.last().is_some_and(|(last, _)| *last >= end) {
+                return Err(invalid().into());
+            }
+            entries.push((end, hex::encode(&item[8..])));
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Size of the whole stream
+    pub fn size(&self) -> u64 {
+        self.entries.last().map_or(0, |(end, _)| *end)
+    }
+
+    /// Start and end of a chunk in the stream
+    pub fn chunk_range(&self, index: usize) -> (u64, u64) {
+        let start = match index {
+            0 => 0,
+            _ => self.entries[index - 1].0,
+        };
+
+        (start, self.entries[index].0)
+    }
+
+    /// The chunk containing an offset of the stream
+    pub fn chunk_at(&self, offset: u64) -> Option<usize> {
+        let index = self.entries.partition_point(|(end, _)| *end <= offset);
+        (index < self.entries.len()).then_some(index)
+    }
+
+    /// The parts of the chunks holding a range of the stream
+    pub fn chunk_refs(&self, start: u64, end: u64) -> Vec<ChunkRef> {
+        let mut chunks = Vec::new();
+        let Some(first) = self.chunk_at(start).filter(|_| start < end) else {
+            return chunks;
+        };
+
+        for index in first..self.entries.len() {
+            let (chunk_start, chunk_end) = self.chunk_range(index);
+            if chunk_start >= end {
+                break;
+            }
+
+            chunks.push(ChunkRef {
+                id: self.entries[index].1.clone(),
+                start: (start.max(chunk_start) - chunk_start) as usize,
+                end: Some((end.min(chunk_end) - chunk_start) as usize),
+                size: Some((chunk_end - chunk_start) as usize),
+            });
+        }
+
+        chunks
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::HashSet,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256, Sha512_256};
+
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, ChunkCache, NodeKind, SnapshotInfo},
+    storage::Storage,
+};
+use catar::Decoder;
+use error::Error;
+use index::ChunkIndex;
+
+mod catar;
+mod index;
+
+pub mod error;
+
+// magic numbers of the compression formats chunks may be stored in
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// An index file, either of a catar archive or of a blob
+#[derive(Debug)]
+struct Snapshot {
+    /// File name of the index
+    id: String,
+    time: DateTime<Utc>,
+    index: ChunkIndex,
+}
+
+#[derive(Debug)]
+pub struct Casync {
+    pub path: PathBuf,
+    storage: Rc<dyn Storage>,
+
+    /// Every `*.castr` directory next to the indexes, chunks may be in any of them
+    stores: Vec<PathBuf>,
+    snapshots: Vec<Snapshot>,
+    /// Chunks are cut from the archive stream, small files share them
+    chunks: ChunkCache,
+}
+
+impl Casync {
+    /// Open a directory holding index files and the chunk stores they refer to, nothing is
+    /// encrypted
+    pub fn from_folder(storage: Rc<dyn Storage>, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+
+        let mut stores = Vec::new();
+        for entry in storage.list(&path)? {
+            if entry.name.ends_with(".castr") && entry.stat.is_dir {
+                stores.push(entry.path);
+            }
+        }
+        if stores.is_empty() {
+            return Err(Error::StoreNotFound(path.display().to_string()).into());
+        }
+        stores.sort();
+
+        Ok(Self {
+            path,
+            storage,
+            stores,
+            snapshots: Vec::new(),
+            chunks: ChunkCache::default(),
+        })
+    }
+
+    /// Load every `.caidx` and `.caibx` index, they're dated by their modification time
+    pub fn load_all_snapshots(&mut self) -> Result<()> {
+        for entry in self.storage.list(&self.path)? {
+            let id = entry.name;
+            if !id.ends_with(".caidx") && !id.ends_with(".caibx") {
+                continue;
+            }
+
+            trace!("Loading index {id}");
+            let time = entry.stat.modified.unwrap_or(SystemTime::UNIX_EPOCH);
+            self.snapshots.push(Snapshot {
+                index: ChunkIndex::from_file(&*self.storage, &entry.path)?,
+                time: time.into(),
+                id,
+            });
+        }
+        self.snapshots
+            .sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.id.cmp(&b.id)));
+
+        Ok(())
+    }
+
+    /// Load everything
+    pub fn load_all(&mut self) -> Result<()> {
+        self.load_all_snapshots()?;
+        Ok(())
+    }
+
+    /// Chunks are stored in `<store>/<first 4 digits of the ID>/<ID>.cacnk`
+    fn chunk_path(&self, store: &Path, id: &str) -> PathBuf {
+        store
+            .join(id.get(..4).unwrap_or_default())
+            .join(format!("{id}.cacnk"))
+    }
+
+    /// The first store holding a chunk
+    fn find_chunk(&self, id: &str) -> Result<PathBuf> {
+        self.stores
+            .iter()
+            .map(|store| self.chunk_path(store, id))
+            .find(|path| self.storage.is_file(path))
+            .ok_or_else(|| Error::ChunkNotFound(id.to_string()).into())
+    }
+
+    /// Read, decompress and verify a chunk. IDs are SHA-512/256 digests, or SHA-256 in stores
+    /// written by older versions.
+    fn read_chunk(&self, id: &str) -> Result<Vec<u8>> {
+        let data = self.storage.read(&self.find_chunk(id)?)?;
+
+        let mut decompressed = Vec::new();
+        if data.starts_with(ZSTD_MAGIC) {
+            decompressed = zstd::stream::decode_all(&data[..])?;
+        } else if data.starts_with(XZ_MAGIC) {
+            xz2::read::XzDecoder::new(&data[..]).read_to_end(&mut decompressed)?;
+        } else if data.starts_with(GZIP_MAGIC) {
+            flate2::read::GzDecoder::new(&data[..]).read_to_end(&mut decompressed)?;
+        } else {
+            return Err(Error::UnsupportedCompression(id.to_string()).into());
+        }
+
+        if hex::encode(Sha512_256::digest(&decompressed)) != id
+            && hex::encode(Sha256::digest(&decompressed)) != id
+        {
+            return Err(Error::IntegrityCheckFailed(id.to_string()).into());
+        }
+
+        Ok(decompressed)
+    }
+
+    fn find(&self, snapshot: &str) -> Result<&Snapshot> {
+        let id = self.find_snapshot(snapshot)?.id;
+        Ok(self.snapshots.iter().find(|s| s.id == id).unwrap())
+    }
+}
+
+/// Reads the stream of an index, only the chunks that are actually read are loaded
+struct IndexStream<'a> {
+    casync: &'a Casync,
+    index: &'a ChunkIndex,
+    position: u64,
+    chunk: Option<(usize, Vec<u8>)>,
+}
+
+impl<'a> IndexStream<'a> {
+    fn new(casync: &'a Casync, index: &'a ChunkIndex) -> Self {
+        Self {
+            casync,
+            index,
+            position: 0,
+            chunk: None,
+        }
+    }
+}
+
+impl Read for IndexStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(index) = self.index.chunk_at(self.position) else {
+            return Ok(0);
+        };
+
+        if self.chunk.as_ref().map(|(cached, _)| *cached) != Some(index) {
+            let data = self
+                .casync
+                .read_chunk(&self.index.entries[index].1)
+                .map_err(std::io::Error::other)?;
+            self.chunk = Some((index, data));
+        }
+
+        let (start, end) = self.index.chunk_range(index);
+        let data = &self.chunk.as_ref().unwrap().1;
+        if data.len() as u64 != end - start {
+            return Err(std::io::Error::other(Error::InvalidIndex(
+                self.index.entries[index].1.clone(),
+            )));
+        }
+
+        let offset = (self.position - start) as usize;
+        let len = buf.len().min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl Seek for IndexStream<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.index.size().checked_add_signed(offset),
+        };
+
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start")
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl Backup for Casync {
+    /// Every index is a snapshot, identified by its file name
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        self.snapshots
+            .iter()
+            .map(|snapshot| SnapshotInfo {
+                id: snapshot.id.clone(),
+                group: String::new(),
+                time: snapshot.time,
+            })
+            .collect()
+    }
+
+    /// Archives are decoded into their tree, blobs become a single file named after the index
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let snapshot = self.find(snapshot)?;
+        let index = &snapshot.index;
+
+        let mut nodes = Vec::new();
+        match snapshot.id.strip_suffix(".caibx") {
+            Some(name) => {
+                let mut node = tree::Node::new(name, NodeKind::File);
+                node.mtime = Some(snapshot.time);
+                node.size = Some(index.size());
+                node.chunks = index.chunk_refs(0, index.size());
+                nodes.push(node);
+            }
+            None => {
+                let stream = IndexStream::new(self, index);
+                Decoder::new(stream, &snapshot.id, index).decode(&mut nodes)?;
+            }
+        }
+
+        Ok(nodes)
+    }
+
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        self.chunks.get_or_load(id, || self.read_chunk(id))
+    }
+
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        Ok(self.storage.stat(&self.find_chunk(id)?)?.size)
+    }
+
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        self.stores.clone()
+    }
+
+    /// Every chunk listed in an index, in the store it's read from
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        let ids: HashSet<&str> = self
+            .snapshots
+            .iter()
+            .flat_map(|snapshot| &snapshot.index.entries)
+            .map(|(_, id)| id.as_str())
+            .collect();
+
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| self.find_chunk(id).ok())
+            .collect())
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+}
@@ -0,0 +1,119 @@
+//! Recognize the format of a repository from its layout
+
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use crate::{
+    error::{Error, Result},
+    storage::Storage,
+};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum BackupFormat {
+    Duplicacy,
+    Restic,
+    Knoxite,
+    BlobBackup,
+    Borg,
+    Kopia,
+    Duplicati,
+    Pbs,
+    Duplicity,
+    Bupstash,
+    Casync,
+    Bup,
+}
+
+impl BackupFormat {
+    /// Whether the repository looks like one of this format, only the layout is checked
+    fn matches(&self, storage: &dyn Storage, path: &Path) -> bool {
+        match self {
+            // an encrypted config starts with the `duplicacy` banner, a plain one is JSON
+            BackupFormat::Duplicacy => {
+                storage.is_dir(&path.join("chunks"))
+                    && storage.is_dir(&path.join("snapshots"))
+                    && storage
+                        .read_range(&path.join("config"), 0, 9)
+                        .is_ok_and(|header| {
+                            header.starts_with(b"duplicacy") || header.starts_with(b"{")
+                        })
+            }
+            BackupFormat::Restic => {
+                storage.is_file(&path.join("config"))
+                    && storage.is_dir(&path.join("keys"))
+                    && storage.is_dir(&path.join("data"))
+            }
+            BackupFormat::Knoxite => storage.is_file(&path.join("repository.knoxite")),
+            BackupFormat::BlobBackup => {
+                storage.is_file(&path.join("keys").join("key-salt"))
+                    && storage.is_file(&path.join("keys").join("master-key"))
+            }
+            BackupFormat::Borg => {
+                storage.is_dir(&path.join("data"))
+                    && storage
+                        .read_to_string(&path.join("config"))
+                        .is_ok_and(|config| config.trim_start().starts_with("[repository]"))
+            }
+            BackupFormat::Kopia => storage.is_file(&path.join("kopia.repository.f")),
+            // there are no repository files, only volumes
+            BackupFormat::Duplicati => storage.list(path).into_iter().flatten().any(|entry| {
+                entry.name.ends_with(".dlist.zip") || entry.name.ends_with(".dlist.zip.aes")
+            }),
+            BackupFormat::Pbs => storage.is_dir(&path.join(".chunks")),
+            // a chain always starts with a full backup
+            BackupFormat::Duplicity => storage.list(path).into_iter().flatten().any(|entry| {
+                entry.name.starts_with("duplicity-full.") && entry.name.contains(".manifest")
+            }),
+            BackupFormat::Bupstash => {
+                storage.is_file(&path.join("meta").join("schema_version"))
+                    && storage.is_dir(&path.join("items"))
+                    && storage.is_dir(&path.join("data"))
+            }
+            // indexes may be anywhere, only the ones next to a store are looked at
+            BackupFormat::Casync => {
+                let names: Vec<String> = storage
+                    .list(path)
+                    .into_iter()
+                    .flatten()
+                    .map(|entry| entry.name)
+                    .collect();
+
+                names.iter().any(|name| name.ends_with(".castr"))
+                    && names
+                        .iter()
+                        .any(|name| name.ends_with(".caidx") || name.ends_with(".caibx"))
+            }
+            // a bare git repository, nothing marks it as bup's
+            BackupFormat::Bup => {
+                storage.is_file(&path.join("HEAD"))
+                    && storage.is_dir(&path.join("refs"))
+                    && storage.is_dir(&path.join("objects").join("pack"))
+            }
+        }
+    }
+}
+
+/// Probe the repository for every known format, exactly one has to match
+pub fn detect(storage: &dyn Storage, path: impl AsRef<Path>) -> Result<BackupFormat> {
+    let path = path.as_ref();
+
+    let matches: Vec<BackupFormat> = BackupFormat::value_variants()
+        .iter()
+        .copied()
+        .filter(|format| format.matches(storage, path))
+        .collect();
+
+    match matches.as_slice() {
+        [format] => Ok(*format),
+        [] => Err(Error::UnrecognizedFormat(path.to_path_buf())),
+        formats => Err(Error::AmbiguousFormat(
+            path.to_path_buf(),
+            formats
+                .iter()
+                .map(|format| format.to_possible_value().unwrap().get_name().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        )),
+    }
+}
@@ -13,10 +13,11 @@ use serde::Deserialize;
 use sha2::Sha256;
 
 use super::{decoder::Decoder, error::Error};
-use crate::{error::Result, utils::from_hex};
+use crate::{error::Result, storage::Storage, utils::from_hex};
 
 type Blake2b256Keyed = Blake2bMac<U32>;
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug, Default)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Config {
@@ -51,9 +52,13 @@ pub struct Config {
 }
 
 impl Config {
-    pub fn from_file(path: impl AsRef<Path>, password: Option<String>) -> Result<Self> {
+    pub fn from_file(
+        storage: &dyn Storage,
+        path: impl AsRef<Path>,
+        password: Option<String>,
+    ) -> Result<Self> {
         // read config into memory - it's a small file
-        let file = std::fs::read(path)?;
+        let file = storage.read(path.as_ref())?;
 
         // check if the file is encrypted
         let encrypted = &file[0..9] == b"duplicacy";
@@ -101,7 +106,7 @@ impl Config {
             let mut buffer: Vec<u8> = Vec::new();
             buffer.extend_from_slice(b"duplicacy"); // header
             buffer.push(0); // version
-            buffer.extend_from_slice(cursor.remaining_slice()); // data
+            buffer.extend_from_slice(&cursor.get_ref()[cursor.position() as usize..]); // data
 
             let decoder = Decoder::new(key);
             decoder.decode(&buffer)?
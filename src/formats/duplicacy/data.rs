@@ -3,7 +3,7 @@ use std::path::Path;
 use serde::Deserialize;
 
 use super::{config::Config, decoder::Decoder};
-use crate::error::Result;
+use crate::{error::Result, storage::Storage};
 
 #[derive(Deserialize, Debug)]
 pub struct Data {
@@ -11,8 +11,13 @@ pub struct Data {
 }
 
 impl Data {
-    pub fn from_file(config: &Config, path: impl AsRef<Path>, hash: &[u8]) -> Result<Self> {
-        let file = std::fs::read(path.as_ref())?;
+    pub fn from_file(
+        storage: &dyn Storage,
+        config: &Config,
+        path: impl AsRef<Path>,
+        hash: &[u8],
+    ) -> Result<Self> {
+        let file = storage.read(path.as_ref())?;
 
         let decoded = if config.encrypted {
             let key = config.derive_key(&config.chunk_key, hash)?;
@@ -1,10 +1,23 @@
 use std::{collections::HashMap, io::Read, path::Path};
 
+use chrono::{TimeZone, Utc};
 use rmp::decode;
 use serde::Deserialize;
 
-use super::{config::Config, decoder::Decoder};
-use crate::error::Result;
+use super::{config::Config, decoder::Decoder, error::Error};
+use crate::{
+    error::Result,
+    formats::tree::{self, ChunkRef, ContentHash, NodeKind},
+    storage::Storage,
+    utils::from_go_mode,
+};
+
+// go's `fs.FileMode` type bits, duplicacy stores the mode as-is
+// see https://pkg.go.dev/io/fs#FileMode
+const MODE_DIR: i64 = 1 << 31;
+const MODE_SYMLINK: i64 = 1 << 27;
+// devices, named pipes, sockets and irregular files
+const MODE_SPECIAL: i64 = (1 << 26) | (1 << 25) | (1 << 24) | (1 << 21) | (1 << 19);
 
 #[derive(Deserialize, Debug)]
 pub struct Entry {
@@ -28,9 +41,90 @@ pub struct Entry {
 }
 
 impl Entry {
-    pub fn from_file(config: &Config, path: impl AsRef<Path>, hash: &[u8]) -> Result<Vec<Self>> {
+    /// Directory entries have their path suffixed with `/`, but check the mode as well
+    pub fn is_dir(&self) -> bool {
+        self.path.ends_with('/') || self.mode & MODE_DIR != 0
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & MODE_SYMLINK != 0
+    }
+
+    /// Convert into the format independent representation, `chunk_hashes` and `chunk_lengths`
+    /// describe the data chunks of the revision the entry belongs to
+    pub fn to_tree_node(
+        &self,
+        chunk_hashes: &[Vec<u8>],
+        chunk_lengths: &[usize],
+    ) -> Result<tree::Node> {
+        let kind = if self.is_dir() {
+            NodeKind::Dir
+        } else if self.is_symlink() {
+            NodeKind::Symlink {
+                target: self.link.clone(),
+            }
+        } else if self.mode & MODE_SPECIAL != 0 {
+            NodeKind::Special
+        } else {
+            NodeKind::File
+        };
+
+        let mut node = tree::Node::new(self.path.trim_end_matches('/'), kind);
+        node.mode = Some(from_go_mode(self.mode as u64));
+        node.uid = Some(self.uid as u32);
+        node.gid = Some(self.gid as u32);
+        node.mtime = Utc.timestamp_opt(self.time, 0).single();
+        node.xattrs = self
+            .attributes
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        // the attributes are a map, without sorting their order changes from one parse to the next
+        node.xattrs.sort();
+
+        if node.kind == NodeKind::File {
+            node.size = Some(self.size as u64);
+            node.hash = Some(ContentHash::Blake2b256(self.hash.clone()));
+
+            // empty files don't reference any chunks, their indexes can point past the end
+            if self.size > 0 {
+                node.chunks = (self.start_chunk..=self.end_chunk)
+                    .map(|chunk| {
+                        let hash = usize::try_from(chunk)
+                            .ok()
+                            .and_then(|index| chunk_hashes.get(index))
+                            .ok_or_else(|| Error::MissingChunk(self.path.clone(), chunk))?;
+
+                        Ok(ChunkRef {
+                            id: hex::encode(hash),
+                            start: if chunk == self.start_chunk {
+                                self.start_offset as usize
+                            } else {
+                                0
+                            },
+                            end: if chunk == self.end_chunk {
+                                Some(self.end_offset as usize)
+                            } else {
+                                None
+                            },
+                            size: chunk_lengths.get(chunk as usize).copied(),
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+            }
+        }
+
+        Ok(node)
+    }
+
+    pub fn from_file(
+        storage: &dyn Storage,
+        config: &Config,
+        path: impl AsRef<Path>,
+        hash: &[u8],
+    ) -> Result<Vec<Self>> {
         // load file
-        let file = std::fs::read(path.as_ref())?;
+        let file = storage.read(path.as_ref())?;
 
         let decoded = if config.encrypted {
             let key = config.derive_key(&config.chunk_key, hash)?;
@@ -47,7 +141,7 @@ impl Entry {
         let mut cursor = std::io::Cursor::new(decoded);
         let mut entries = Vec::new();
 
-        while !cursor.is_empty() {
+        while (cursor.position() as usize) < cursor.get_ref().len() {
             let entry = Entry {
                 path: decode::read_str(&mut cursor, &mut vec![0; 5000])
                     .expect("invalid utf-8")
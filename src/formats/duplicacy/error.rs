@@ -8,6 +8,6 @@ pub enum Error {
     InvalidHeaderVersion(u8),
     #[error("Attempting to decode header with version != 0")]
     NonzeroHeaderVersion,
-    #[error("Mismatched hash")]
-    MismatchedHash,
+    #[error("{0} references chunk {1}, which the revision doesn't list")]
+    MissingChunk(String, i32),
 }
@@ -3,7 +3,7 @@ use std::path::Path;
 use serde::Deserialize;
 
 use super::{config::Config, decoder::Decoder};
-use crate::{error::Result, utils::from_hex_vec};
+use crate::{error::Result, storage::Storage, utils::from_hex_vec};
 
 #[derive(Deserialize, Debug)]
 #[serde(transparent)]
@@ -12,18 +12,41 @@ pub struct Index {
     pub hashes: Vec<Vec<u8>>,
 }
 
+/// Sizes of the data chunks listed by the index chunks, in the same order
+#[derive(Deserialize, Debug)]
+#[serde(transparent)]
+pub struct Lengths {
+    pub lengths: Vec<usize>,
+}
+
+fn decode_metadata_chunk(
+    storage: &dyn Storage,
+    config: &Config,
+    path: impl AsRef<Path>,
+    hash: &[u8],
+) -> Result<Vec<u8>> {
+    let file = storage.read(path.as_ref())?;
+
+    let decoded = if config.encrypted {
+        let key = config.derive_key(&config.chunk_key, hash)?;
+        let decoder = Decoder::new(key);
+        decoder.decode(&file)?
+    } else {
+        let decoder = Decoder::new(None);
+        decoder.decode(&file)?
+    };
+
+    Ok(decoded)
+}
+
 impl Index {
-    pub fn from_file(config: &Config, path: impl AsRef<Path>, hash: &[u8]) -> Result<Self> {
-        let file = std::fs::read(path.as_ref())?;
-
-        let decoded = if config.encrypted {
-            let key = config.derive_key(&config.chunk_key, hash)?;
-            let decoder = Decoder::new(key);
-            decoder.decode(&file)?
-        } else {
-            let decoder = Decoder::new(None);
-            decoder.decode(&file)?
-        };
+    pub fn from_file(
+        storage: &dyn Storage,
+        config: &Config,
+        path: impl AsRef<Path>,
+        hash: &[u8],
+    ) -> Result<Self> {
+        let decoded = decode_metadata_chunk(storage, config, path, hash)?;
 
         // parse the entries
         //trace!("Index JSON: {}", String::from_utf8_lossy(&decoded));
@@ -32,3 +55,17 @@ impl Index {
         Ok(chunk)
     }
 }
+
+impl Lengths {
+    pub fn from_file(
+        storage: &dyn Storage,
+        config: &Config,
+        path: impl AsRef<Path>,
+        hash: &[u8],
+    ) -> Result<Self> {
+        let decoded = decode_metadata_chunk(storage, config, path, hash)?;
+        let lengths: Self = serde_json::from_slice(&decoded)?;
+
+        Ok(lengths)
+    }
+}
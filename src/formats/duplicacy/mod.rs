@@ -1,15 +1,21 @@
 use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    rc::Rc,
 };
 
-use blake2::{digest::consts::U32, Blake2b, Digest};
+use chrono::{TimeZone, Utc};
 
-use crate::error::Result;
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, ChunkCache, SnapshotInfo},
+    storage::Storage,
+};
 use config::Config;
 use data::Data;
 use entry::Entry;
-use index::Index;
+use index::{Index, Lengths};
 use revision::Revision;
 
 mod config;
@@ -21,30 +27,37 @@ mod revision;
 
 pub mod error;
 
-type Blake2b256 = Blake2b<U32>;
-
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct Duplicacy {
     pub path: PathBuf,
+    storage: Rc<dyn Storage>,
     pub config: Config,
 
     snapshots: HashMap<String, Vec<Revision>>,
+    /// Parsed file chunks by hash, unchanged files share them between revisions
+    file_chunks: RefCell<HashMap<Vec<u8>, Rc<Vec<Entry>>>>,
+    /// Small files are packed into the same data chunks
+    chunks: ChunkCache,
 }
 
 impl Duplicacy {
     pub fn from_folder(
+        storage: Rc<dyn Storage>,
         path: impl Into<PathBuf>,
         password: impl Into<Option<String>>,
     ) -> Result<Self> {
         let path = path.into();
 
         // load config
-        let config = Config::from_file(path.join("config"), password.into())?;
+        let config = Config::from_file(&*storage, path.join("config"), password.into())?;
 
         Ok(Duplicacy {
             path,
+            storage,
             config,
-            ..Default::default()
+            snapshots: HashMap::new(),
+            file_chunks: RefCell::default(),
+            chunks: ChunkCache::default(),
         })
     }
 
@@ -52,20 +65,16 @@ impl Duplicacy {
     pub fn load_all_snapshots(&mut self) -> Result<()> {
         let mut snapshots: HashMap<String, Vec<Revision>> = HashMap::new();
         // iterate through the snapshot IDs
-        for snapshot_id in std::fs::read_dir(self.path.join("snapshots"))? {
-            let snapshot_id = snapshot_id?;
-
+        for snapshot_id in self.storage.list(&self.path.join("snapshots"))? {
             // iterate through the revisions
             let mut revisions = Vec::new();
-            for rev in std::fs::read_dir(snapshot_id.path())? {
-                let rev = rev?;
-
-                let revision = Revision::from_file(&self.config, rev.path())?;
+            for rev in self.storage.list(&snapshot_id.path)? {
+                let revision = Revision::from_file(&*self.storage, &self.config, rev.path)?;
 
                 revisions.push(revision);
             }
 
-            snapshots.insert(snapshot_id.file_name().to_string_lossy().into(), revisions);
+            snapshots.insert(snapshot_id.name, revisions);
         }
 
         self.snapshots = snapshots;
@@ -78,87 +87,152 @@ impl Duplicacy {
         Ok(())
     }
 
-    /// Extract and recreate the files present in the latest revisions of each snapshot ID.
-    ///
-    /// Currently it will only attempt to restore file contents and verify it against the hash,
-    /// extended metadata and permissions will be ignored.
-    pub fn dump_all_files(&self, output_dir: impl AsRef<Path>) -> Result<()> {
-        // iterate through all the snapshot IDs and create a subfolder for each
-        for (snapshot, revisions) in self.snapshots.iter() {
-            // if revisions is empty, skip this snapshot
-            if revisions.is_empty() {
+    /// Find a revision by snapshot ID and revision number
+    fn find_revision(&self, snapshot_id: &str, revision: i32) -> Option<&Revision> {
+        self.snapshots
+            .get(snapshot_id)?
+            .iter()
+            .find(|rev| rev.revision == revision)
+    }
+
+    /// Load the file entries of a revision along with the hashes and sizes of the data chunks
+    /// they reference
+    fn load_revision_files(&self, revision: &Revision) -> Result<RevisionFiles> {
+        let mut entries = Vec::new();
+        let mut chunk_hashes = Vec::new();
+        let mut chunk_lengths = Vec::new();
+
+        // read file chunks
+        for hash in &revision.files {
+            if let Some(files) = self.file_chunks.borrow().get(hash) {
+                entries.push(files.clone());
                 continue;
             }
 
-            // create a subfolder
-            std::fs::create_dir_all(output_dir.as_ref().join(snapshot))?;
+            let path = self.config.resolve_path_from_hash(&self.path, hash)?;
+            let files = Rc::new(Entry::from_file(&*self.storage, &self.config, &path, hash)?);
+            self.file_chunks
+                .borrow_mut()
+                .insert(hash.clone(), files.clone());
+            entries.push(files);
+        }
+
+        // read index chunks, these list the data chunks in order
+        for hash in &revision.chunks {
+            let path = self.config.resolve_path_from_hash(&self.path, hash)?;
+            trace!("Attempting to read index chunk: {path:?}");
+            let chunk = Index::from_file(&*self.storage, &self.config, &path, hash)?;
+            chunk_hashes.extend(chunk.hashes);
+        }
 
-            // unwrap is safe, we've already checked for empty revisions
-            let latest = revisions.iter().max_by_key(|x| x.revision).unwrap();
+        // read length chunks, these list the sizes of the data chunks in the same order
+        for hash in &revision.lengths {
+            let path = self.config.resolve_path_from_hash(&self.path, hash)?;
+            let lengths = Lengths::from_file(&*self.storage, &self.config, &path, hash)?;
+            chunk_lengths.extend(lengths.lengths);
+        }
 
-            let mut file_chunks = Vec::new();
-            let mut index_chunks = Vec::new();
-            let mut data_chunks = Vec::new();
+        Ok(RevisionFiles {
+            entries,
+            chunk_hashes,
+            chunk_lengths,
+        })
+    }
+}
 
-            // read file chunks
-            for hash in &latest.files {
-                let path = self.config.resolve_path_from_hash(&self.path, hash)?;
-                let files = Entry::from_file(&self.config, &path, hash)?;
-                file_chunks.extend(files);
-            }
+/// The file entries of a revision along with the data chunks they reference
+struct RevisionFiles {
+    /// Entries of each file chunk
+    entries: Vec<Rc<Vec<Entry>>>,
+    chunk_hashes: Vec<Vec<u8>>,
+    chunk_lengths: Vec<usize>,
+}
 
-            for hash in &latest.chunks {
-                let path = self.config.resolve_path_from_hash(&self.path, hash)?;
-                trace!("Attempting to read index chunk: {path:?}");
-                let chunk = Index::from_file(&self.config, &path, hash)?;
-                index_chunks.push(chunk);
-            }
+impl Backup for Duplicacy {
+    /// Each revision is a snapshot, identified as `<snapshot id>/<revision>`
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        let mut snapshots: Vec<SnapshotInfo> = self
+            .snapshots
+            .iter()
+            .flat_map(|(snapshot_id, revisions)| {
+                revisions.iter().map(move |rev| SnapshotInfo {
+                    id: format!("{snapshot_id}/{}", rev.revision),
+                    group: snapshot_id.clone(),
+                    time: Utc.timestamp_opt(rev.start_time, 0).unwrap(),
+                })
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+
+        snapshots
+    }
 
-            for index in &index_chunks {
-                for hash in &index.hashes {
-                    let path = self.config.resolve_path_from_hash(&self.path, hash)?;
-                    let data = Data::from_file(&self.config, &path, hash)?;
-                    data_chunks.push(data);
-                }
-            }
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let id = self.find_snapshot(snapshot)?.id;
+        let (snapshot_id, revision) = id.rsplit_once('/').unwrap();
+        let revision = self
+            .find_revision(snapshot_id, revision.parse().unwrap())
+            .unwrap();
 
-            //debug!("File chunks: {:?}", file_chunks);
-            //debug!("Index chunks: {:?}", index_chunks);
-            //debug!("Data chunks: {:?}", data_chunks);
-
-            for file in file_chunks {
-                debug!("Dumping {:?}", file.path);
-
-                // build up data - not the most efficient way but it'll do
-                let mut data = Vec::new();
-                for chunk in file.start_chunk..=file.end_chunk {
-                    let buf = if file.start_chunk == file.end_chunk {
-                        // read it all in one go
-                        &data_chunks[chunk as usize].data
-                            [file.start_offset as usize..file.end_offset as usize]
-                    } else if chunk == file.start_chunk {
-                        &data_chunks[chunk as usize].data[file.start_offset as usize..]
-                    } else if chunk == file.end_chunk {
-                        &data_chunks[chunk as usize].data[..file.end_offset as usize]
-                    } else {
-                        &data_chunks[chunk as usize].data
-                    };
-
-                    data.extend(buf);
-                }
-
-                // check the hash
-                let mut hasher = Blake2b256::new();
-                hasher.update(&data);
-                let hash = hasher.finalize();
-                if hash.as_slice() == file.hash {
-                    std::fs::write(output_dir.as_ref().join(snapshot).join(file.path), data)?;
-                } else {
-                    return Err(error::Error::MismatchedHash)?;
-                }
+        let files = self.load_revision_files(revision)?;
+
+        let nodes = files
+            .entries
+            .iter()
+            .flat_map(|files| files.iter())
+            .map(|entry| entry.to_tree_node(&files.chunk_hashes, &files.chunk_lengths))
+            .collect::<Result<_>>()?;
+
+        Ok(nodes)
+    }
+
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        self.chunks.get_or_load(id, || {
+            let hash = hex::decode(id)?;
+            let path = self.config.resolve_path_from_hash(&self.path, &hash)?;
+            let data = Data::from_file(&*self.storage, &self.config, &path, &hash)?;
+
+            Ok(data.data)
+        })
+    }
+
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        let hash = hex::decode(id)?;
+        let path = self.config.resolve_path_from_hash(&self.path, &hash)?;
+
+        Ok(self.storage.stat(&path)?.size)
+    }
+
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        vec![self.path.join("chunks")]
+    }
+
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        let mut referenced = HashSet::new();
+
+        for revision in self.snapshots.values().flatten() {
+            let files = self.load_revision_files(revision)?;
+
+            // metadata chunks as well as the data chunks they list
+            let hashes = revision
+                .files
+                .iter()
+                .chain(&revision.chunks)
+                .chain(&revision.lengths)
+                .chain(&files.chunk_hashes);
+            for hash in hashes {
+                referenced.insert(self.config.resolve_path_from_hash(&self.path, hash)?);
             }
         }
 
-        Ok(())
+        Ok(referenced)
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+
+    fn guesses_hard_links(&self) -> bool {
+        true
     }
 }
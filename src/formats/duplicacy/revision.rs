@@ -3,8 +3,9 @@ use std::path::Path;
 use serde::Deserialize;
 
 use super::{config::Config, decoder::Decoder};
-use crate::{error::Result, utils::from_hex_vec};
+use crate::{error::Result, storage::Storage, utils::from_hex_vec};
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Revision {
@@ -27,9 +28,13 @@ pub struct Revision {
 }
 
 impl Revision {
-    pub fn from_file(config: &Config, path: impl AsRef<Path>) -> Result<Self> {
+    pub fn from_file(
+        storage: &dyn Storage,
+        config: &Config,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
         // read into memory
-        let file = std::fs::read(path.as_ref())?;
+        let file = storage.read(path.as_ref())?;
 
         let decoded = match config.encrypted {
             true => {
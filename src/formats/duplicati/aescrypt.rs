@@ -0,0 +1,147 @@
+//! AES Crypt file format version 2, used for `.aes` volumes
+//! see https://www.aescrypt.com/aes_file_format.html
+
+use aes::cipher::{block_padding::NoPadding, BlockDecryptMut, KeyIvInit};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::error::Error;
+use crate::error::Result;
+
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+
+/// Decrypt a whole file
+pub fn decrypt(data: &[u8], password: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::InvalidAesCrypt;
+
+    // "AES" | version | reserved
+    if data.len() < 5 || !data.starts_with(b"AES") {
+        return Err(invalid().into());
+    }
+    if data[3] != 2 {
+        return Err(Error::UnsupportedAesCryptVersion(data[3]).into());
+    }
+
+    // extensions: length | identifier \0 value, terminated by an empty one
+    let mut pos = 5;
+    loop {
+        let length = data.get(pos..pos + 2).ok_or_else(invalid)?;
+        let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+        pos += 2 + length;
+        if length == 0 {
+            break;
+        }
+    }
+
+    // IV | encrypted IV and key | HMAC, followed by the ciphertext | last block size | HMAC
+    let header = data.get(pos..pos + 96).ok_or_else(invalid)?;
+    let (iv, rest) = header.split_at(16);
+    let (encrypted_keys, keys_mac) = rest.split_at(48);
+    let body = &data[pos + 96..];
+    if body.len() < 33 || !(body.len() - 33).is_multiple_of(16) {
+        return Err(invalid().into());
+    }
+    let (ciphertext, rest) = body.split_at(body.len() - 33);
+    let (last_block_size, mac) = (rest[0] as usize, &rest[1..]);
+
+    let key = derive_key(password, iv);
+    if hmac_sha256(&key, encrypted_keys) != keys_mac {
+        return Err(Error::InvalidPassword.into());
+    }
+    let mut keys = encrypted_keys.to_vec();
+    Aes256CbcDec::new_from_slices(&key, iv)
+        .map_err(|_| invalid())?
+        .decrypt_padded_mut::<NoPadding>(&mut keys)
+        .map_err(|_| invalid())?;
+    let (data_iv, data_key) = keys.split_at(16);
+
+    if hmac_sha256(data_key, ciphertext) != mac {
+        return Err(Error::IntegrityCheckFailed("AES Crypt ciphertext".into()).into());
+    }
+    let mut plaintext = ciphertext.to_vec();
+    Aes256CbcDec::new_from_slices(data_key, data_iv)
+        .map_err(|_| invalid())?
+        .decrypt_padded_mut::<NoPadding>(&mut plaintext)
+        .map_err(|_| invalid())?;
+
+    // the last block is only partially used
+    if last_block_size != 0 && plaintext.len() >= 16 {
+        plaintext.truncate(plaintext.len() - 16 + last_block_size);
+    }
+
+    Ok(plaintext)
+}
+
+/// The IV padded to 32 bytes, hashed together with the UTF-16LE password 8192 times
+fn derive_key(password: &str, iv: &[u8]) -> [u8; 32] {
+    let password: Vec<u8> = password.encode_utf16().flat_map(u16::to_le_bytes).collect();
+
+    let mut key = [0u8; 32];
+    key[..iv.len()].copy_from_slice(iv);
+    for _ in 0..8192 {
+        let mut hasher = Sha256::new();
+        hasher.update(key);
+        hasher.update(&password);
+        key = hasher.finalize().into();
+    }
+
+    key
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "aescrypt known answer" encrypted with "password", with a `CREATED_BY` extension and the
+    /// 128 byte empty extension AES Crypt reserves for later
+    fn file() -> Vec<u8> {
+        let mut file = hex::decode("4145530200000f435245415445445f425900746573740080").unwrap();
+        file.extend([0; 128]);
+        file.extend(
+            hex::decode(concat!(
+                "0000000102030405060708090a0b0c0d0e0f1bce1ecbe8a849799a838bc77edd",
+                "1d01a3a4c20082fe3ce9fbe07bf6d4503a9362ce9aadd586014a09ac72a17ecc",
+                "2bade92b82cf609f748f9c27421530a500e49f88c433d4f1f0c78c06bef719f7",
+                "6dcbc5e6f937edbbe2bbca019d7663bd2c7c13cec2b653818612a3a6d684445c",
+                "0cad054ac7ecac4a87a19805ba82ffdea218616e4e108e26a52e22b0bfaaf59d",
+                "e67650",
+            ))
+            .unwrap(),
+        );
+        file
+    }
+
+    #[test]
+    fn version_2() {
+        assert_eq!(
+            decrypt(&file(), "password").unwrap(),
+            b"aescrypt known answer"
+        );
+    }
+
+    #[test]
+    fn wrong_password() {
+        assert!(matches!(
+            decrypt(&file(), "wrong"),
+            Err(crate::error::Error::Duplicati(Error::InvalidPassword))
+        ));
+    }
+
+    #[test]
+    fn tampered_ciphertext() {
+        let mut file = file();
+        file[24 + 128 + 2 + 96] ^= 1;
+        assert!(matches!(
+            decrypt(&file, "password"),
+            Err(crate::error::Error::Duplicati(Error::IntegrityCheckFailed(
+                _
+            )))
+        ));
+    }
+}
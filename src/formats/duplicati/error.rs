@@ -0,0 +1,25 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Password required")]
+    PasswordRequired,
+    #[error("Invalid password")]
+    InvalidPassword,
+    #[error("Not an AES Crypt file")]
+    InvalidAesCrypt,
+    #[error("Unsupported AES Crypt version: {0}")]
+    UnsupportedAesCryptVersion(u8),
+    #[error("Invalid volume name: {0}")]
+    InvalidVolumeName(String),
+    #[error("Unsupported volume encryption: {0}")]
+    UnsupportedEncryption(String),
+    #[error("Unsupported hash algorithm: {0}")]
+    UnsupportedHash(String),
+    #[error("Integrity check failed for {0}")]
+    IntegrityCheckFailed(String),
+    #[error("Block not found in any volume: {0}")]
+    BlockNotFound(String),
+    #[error("Missing {1} in volume {0}")]
+    MissingVolumeEntry(String, String),
+}
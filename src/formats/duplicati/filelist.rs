@@ -0,0 +1,152 @@
+//! The contents of file list volumes: `manifest` and `filelist.json`
+
+use std::{collections::HashMap, path::PathBuf};
+
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde::Deserialize;
+
+use crate::formats::tree::{self, ChunkRef, ContentHash, NodeKind};
+
+/// .NET ticks (100ns) at the unix epoch
+const UNIX_EPOCH_TICKS: i64 = 621_355_968_000_000_000;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Manifest {
+    pub version: u32,
+    pub blocksize: u64,
+    pub block_hash: String,
+    pub file_hash: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    File,
+    Folder,
+    Symlink,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FileEntry {
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    pub path: String,
+    /// Hash of the whole file, it's also the hash of the block of files with a single block
+    pub hash: Option<String>,
+    pub size: Option<u64>,
+    pub time: Option<String>,
+    /// Only set if the single block of a file has a different hash than the file
+    pub blockhash: Option<String>,
+    /// Blocks listing the hashes of the blocks of files with more than one block
+    #[serde(default)]
+    pub blocklists: Vec<String>,
+    pub metahash: Option<String>,
+    pub metablockhash: Option<String>,
+    #[serde(default)]
+    pub metablocklists: Vec<String>,
+}
+
+/// Metadata blocks are JSON dictionaries of strings
+pub type Metadata = HashMap<String, String>;
+
+impl FileEntry {
+    /// Paths are absolute, folders end with a separator and windows paths start with a drive
+    pub fn relative_path(&self) -> PathBuf {
+        let path = self.path.replace('\\', "/").replacen(':', "", 1);
+        PathBuf::from(path.trim_matches('/'))
+    }
+
+    /// Hashes of the blocks holding the file contents, if the file has more than one block they
+    /// come from the blocklists
+    pub fn single_block(&self) -> Option<&str> {
+        match self.size {
+            Some(0) => None,
+            _ => self.blockhash.as_deref().or(self.hash.as_deref()),
+        }
+    }
+
+    /// Convert into the format independent representation, the block hashes have to be resolved
+    /// from the blocklists first
+    pub fn to_tree_node(
+        &self,
+        metadata: &Metadata,
+        blocks: Vec<String>,
+        blocksize: u64,
+    ) -> tree::Node {
+        let kind = match self.entry_type {
+            EntryType::File => NodeKind::File,
+            EntryType::Folder => NodeKind::Dir,
+            EntryType::Symlink => NodeKind::Symlink {
+                target: metadata
+                    .get("CoreSymlinkTarget")
+                    .cloned()
+                    .unwrap_or_default(),
+            },
+            EntryType::Other => NodeKind::Special,
+        };
+
+        let mut node = tree::Node::new(self.relative_path(), kind);
+
+        // uid, gid and permissions in decimal
+        if let Some(ids) = metadata.get("unix:uid-gid-perm") {
+            let mut ids = ids.split('-').map(|id| id.parse::<u32>().ok());
+            node.uid = ids.next().flatten();
+            node.gid = ids.next().flatten();
+            node.mode = ids.next().flatten().map(|mode| mode & 0o7777);
+        }
+        node.user = metadata.get("unix:owner-name").cloned();
+        node.group = metadata.get("unix:group-name").cloned();
+        node.xattrs = metadata
+            .iter()
+            .filter_map(|(key, value)| {
+                let name = key.strip_prefix("unix-ext:")?;
+                Some((
+                    name.to_string(),
+                    general_purpose::STANDARD.decode(value).ok()?,
+                ))
+            })
+            .collect();
+        // the metadata is a map, its order changes from one parse to the next
+        node.xattrs.sort();
+
+        // ticks are more precise than the timestamp of the file list
+        node.mtime = metadata
+            .get("CoreLastWritetime")
+            .and_then(|ticks| ticks.parse::<i64>().ok())
+            .map(|ticks| Utc.timestamp_nanos((ticks - UNIX_EPOCH_TICKS) * 100))
+            .or_else(|| self.time.as_deref().and_then(parse_time));
+
+        if self.entry_type == EntryType::File {
+            node.size = self.size;
+            node.hash = self
+                .hash
+                .as_deref()
+                .and_then(|hash| general_purpose::STANDARD.decode(hash).ok())
+                .map(ContentHash::Sha256);
+
+            // every block is full, except for the last one
+            let mut remaining = self.size;
+            node.chunks = blocks
+                .into_iter()
+                .map(|id| {
+                    let mut chunk = ChunkRef::whole(id);
+                    chunk.size = remaining.map(|size| size.min(blocksize) as usize);
+                    remaining = remaining.map(|size| size.saturating_sub(blocksize));
+                    chunk
+                })
+                .collect();
+        }
+
+        node
+    }
+}
+
+/// Timestamps of file lists and their volume names, e.g. `20231114T221320Z`
+pub fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(time, "%Y%m%dT%H%M%SZ")
+        .ok()
+        .map(|time| Utc.from_utc_datetime(&time))
+}
@@ -0,0 +1,415 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    io::Read,
+    path::PathBuf,
+    rc::Rc,
+};
+
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, SnapshotInfo},
+    storage::Storage,
+};
+use error::Error;
+use filelist::{FileEntry, Manifest, Metadata};
+use volume::{Archive, Volume, VolumeType};
+
+mod aescrypt;
+mod filelist;
+mod volume;
+
+pub mod error;
+
+/// Size of the block hashes stored in blocklists
+const HASH_SIZE: usize = 32;
+
+/// `vol/<block volume>` entries of index volumes
+#[derive(Deserialize, Debug)]
+struct IndexedVolume {
+    blocks: Vec<IndexedBlock>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IndexedBlock {
+    hash: String,
+}
+
+/// The files of one backup, read from a file list volume
+#[derive(Debug)]
+struct Fileset {
+    volume: String,
+    time: DateTime<Utc>,
+    blocksize: u64,
+    entries: Vec<FileEntry>,
+}
+
+#[derive(Debug)]
+pub struct Duplicati {
+    pub path: PathBuf,
+    storage: Rc<dyn Storage>,
+    password: Option<String>,
+
+    /// Volumes by their file name
+    volumes: HashMap<String, Volume>,
+    /// Block hashes and the block volume storing them
+    blocks: HashMap<String, String>,
+    /// Index volumes and the block volumes they describe
+    indexed_volumes: HashMap<String, Vec<String>>,
+    /// Decoded blocklists, index volumes carry copies of them
+    blocklists: RefCell<HashMap<String, Vec<String>>>,
+
+    filesets: Vec<Fileset>,
+    /// The last opened volume, blocks of a file are usually stored in the same volume
+    archive: RefCell<Option<(String, Archive)>>,
+}
+
+impl Duplicati {
+    /// Read the index volumes of a backup destination, block volumes that aren't indexed are
+    /// listed instead. Duplicati's local database isn't needed.
+    pub fn from_folder(
+        storage: Rc<dyn Storage>,
+        path: impl Into<PathBuf>,
+        password: impl Into<Option<String>>,
+    ) -> Result<Self> {
+        let path = path.into();
+
+        let mut volumes = HashMap::new();
+        for entry in storage.list(&path)? {
+            if let Some(volume) = Volume::from_path(&entry.path)? {
+                volumes.insert(volume.name(), volume);
+            }
+        }
+
+        let mut duplicati = Self {
+            path,
+            storage,
+            password: password.into(),
+            volumes,
+            blocks: HashMap::new(),
+            indexed_volumes: HashMap::new(),
+            blocklists: RefCell::default(),
+            filesets: Vec::new(),
+            archive: RefCell::default(),
+        };
+        duplicati.load_indexes()?;
+
+        Ok(duplicati)
+    }
+
+    fn load_indexes(&mut self) -> Result<()> {
+        for name in self.volume_names(VolumeType::Index) {
+            trace!("Loading index volume {name}");
+            let mut archive = self.volumes[&name].open(&*self.storage, self.password.as_deref())?;
+
+            let mut indexed = Vec::new();
+            for i in 0..archive.len() {
+                let mut file = archive.by_index(i)?;
+                let entry = file.name().to_string();
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)?;
+
+                if let Some(volume) = entry.strip_prefix("vol/") {
+                    // index volumes may outlive the block volume they describe
+                    if !self.volumes.contains_key(volume) {
+                        continue;
+                    }
+
+                    let blocks: IndexedVolume = serde_json::from_slice(&data)?;
+                    for block in blocks.blocks {
+                        self.blocks.insert(block.hash, volume.to_string());
+                    }
+                    indexed.push(volume.to_string());
+                } else if let Some(hash) = entry.strip_prefix("list/") {
+                    self.blocklists
+                        .get_mut()
+                        .insert(from_url_safe(hash), split_blocklist(&data));
+                }
+            }
+
+            self.indexed_volumes.insert(name, indexed);
+        }
+
+        // block volumes without an index, their entries are the blocks
+        let indexed: HashSet<String> = self.indexed_volumes.values().flatten().cloned().collect();
+        for name in self.volume_names(VolumeType::Blocks) {
+            if indexed.contains(&name) {
+                continue;
+            }
+            trace!("Listing block volume {name}");
+
+            let archive = self.volumes[&name].open(&*self.storage, self.password.as_deref())?;
+            for entry in archive.file_names().filter(|entry| *entry != "manifest") {
+                self.blocks.insert(from_url_safe(entry), name.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load the file list of every backup
+    pub fn load_all_snapshots(&mut self) -> Result<()> {
+        for name in self.volume_names(VolumeType::Files) {
+            trace!("Loading file list {name}");
+            let volume = &self.volumes[&name];
+            let mut archive = volume.open(&*self.storage, self.password.as_deref())?;
+
+            let manifest: Manifest =
+                serde_json::from_slice(&read_entry(&mut archive, &name, "manifest")?)?;
+            for hash in [&manifest.block_hash, &manifest.file_hash] {
+                if hash != "SHA256" {
+                    return Err(Error::UnsupportedHash(hash.clone()).into());
+                }
+            }
+            trace!(
+                "Manifest: version {}, block size {}",
+                manifest.version,
+                manifest.blocksize
+            );
+
+            self.filesets.push(Fileset {
+                time: volume.time.unwrap_or_default(),
+                blocksize: manifest.blocksize,
+                entries: serde_json::from_slice(&read_entry(
+                    &mut archive,
+                    &name,
+                    "filelist.json",
+                )?)?,
+                volume: name,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Load everything
+    pub fn load_all(&mut self) -> Result<()> {
+        self.load_all_snapshots()?;
+        Ok(())
+    }
+
+    /// Names of the volumes of one type, in order
+    fn volume_names(&self, kind: VolumeType) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .volumes
+            .values()
+            .filter(|volume| volume.kind == kind)
+            .map(Volume::name)
+            .collect();
+        names.sort();
+
+        names
+    }
+
+    /// Run `f` on the archive of a volume, the last one stays open
+    fn with_archive<T>(&self, name: &str, f: impl FnOnce(&mut Archive) -> Result<T>) -> Result<T> {
+        let mut cached = self.archive.borrow_mut();
+        if cached.as_ref().map(|(cached, _)| cached.as_str()) != Some(name) {
+            let volume = self
+                .volumes
+                .get(name)
+                .ok_or_else(|| Error::BlockNotFound(name.to_string()))?;
+            *cached = Some((
+                name.to_string(),
+                volume.open(&*self.storage, self.password.as_deref())?,
+            ));
+        }
+
+        f(&mut cached.as_mut().unwrap().1)
+    }
+
+    fn block_volume(&self, hash: &str) -> Result<&String> {
+        Ok(self
+            .blocks
+            .get(hash)
+            .ok_or_else(|| Error::BlockNotFound(hash.to_string()))?)
+    }
+
+    /// Read a block from its volume and check its hash
+    fn read_block(&self, hash: &str) -> Result<Vec<u8>> {
+        let name = self.block_volume(hash)?;
+        let data = self.with_archive(name, |archive| {
+            read_entry(archive, name, &to_url_safe(hash))
+        })?;
+
+        if general_purpose::STANDARD.encode(Sha256::digest(&data)) != hash {
+            return Err(Error::IntegrityCheckFailed(hash.to_string()).into());
+        }
+
+        Ok(data)
+    }
+
+    /// The block hashes listed in a blocklist block
+    fn blocklist(&self, hash: &str) -> Result<Vec<String>> {
+        if let Some(blocks) = self.blocklists.borrow().get(hash) {
+            return Ok(blocks.clone());
+        }
+
+        let blocks = split_blocklist(&self.read_block(hash)?);
+        self.blocklists
+            .borrow_mut()
+            .insert(hash.to_string(), blocks.clone());
+        Ok(blocks)
+    }
+
+    /// Blocks of a file's contents or metadata, either listed in blocklists or a single block
+    fn blocks(&self, blocklists: &[String], single: Option<&str>) -> Result<Vec<String>> {
+        match blocklists.is_empty() {
+            true => Ok(single.map(String::from).into_iter().collect()),
+            false => Ok(blocklists
+                .iter()
+                .map(|blocklist| self.blocklist(blocklist))
+                .collect::<Result<Vec<_>>>()?
+                .concat()),
+        }
+    }
+
+    fn metadata_blocks(&self, entry: &FileEntry) -> Result<Vec<String>> {
+        let single = entry.metablockhash.as_deref().or(entry.metahash.as_deref());
+        self.blocks(&entry.metablocklists, single)
+    }
+
+    fn metadata(&self, entry: &FileEntry) -> Result<Metadata> {
+        let mut data = Vec::new();
+        for block in self.metadata_blocks(entry)? {
+            data.extend(self.read_block(&block)?);
+        }
+
+        match data.is_empty() {
+            true => Ok(Metadata::new()),
+            false => Ok(serde_json::from_slice(&data)?),
+        }
+    }
+
+    fn fileset(&self, snapshot: &str) -> Result<&Fileset> {
+        let id = self.find_snapshot(snapshot)?.id;
+        Ok(self
+            .filesets
+            .iter()
+            .find(|fileset| fileset.volume == id)
+            .unwrap())
+    }
+}
+
+/// Zip entries are named after the URL safe base64 of the block hash
+fn to_url_safe(hash: &str) -> String {
+    hash.replace('+', "-").replace('/', "_")
+}
+
+fn from_url_safe(name: &str) -> String {
+    name.replace('-', "+").replace('_', "/")
+}
+
+fn split_blocklist(data: &[u8]) -> Vec<String> {
+    data.chunks(HASH_SIZE)
+        .map(|hash| general_purpose::STANDARD.encode(hash))
+        .collect()
+}
+
+fn read_entry(archive: &mut Archive, volume: &str, name: &str) -> Result<Vec<u8>> {
+    let mut file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => {
+            return Err(Error::MissingVolumeEntry(volume.to_string(), name.to_string()).into())
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut data = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+impl Backup for Duplicati {
+    /// Backups are identified by the name of their file list volume
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        let mut snapshots: Vec<SnapshotInfo> = self
+            .filesets
+            .iter()
+            .map(|fileset| SnapshotInfo {
+                id: fileset.volume.clone(),
+                group: String::new(),
+                time: fileset.time,
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+
+        snapshots
+    }
+
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let fileset = self.fileset(snapshot)?;
+
+        let mut nodes = Vec::new();
+        for entry in &fileset.entries {
+            if entry.relative_path().as_os_str().is_empty() {
+                continue;
+            }
+
+            let blocks = self.blocks(&entry.blocklists, entry.single_block())?;
+            nodes.push(entry.to_tree_node(&self.metadata(entry)?, blocks, fileset.blocksize));
+        }
+        nodes.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(nodes)
+    }
+
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        self.read_block(id)
+    }
+
+    /// Blocks are compressed individually by the zip volume
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        let name = self.block_volume(id)?;
+        self.with_archive(name, |archive| {
+            Ok(archive.by_name(&to_url_safe(id))?.compressed_size())
+        })
+    }
+
+    /// Every volume is stored at the top of the destination
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
+    /// File lists are always referenced, block volumes as long as one of their blocks is and
+    /// index volumes as long as one of the block volumes they describe is
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        let mut blocks = HashSet::new();
+        for fileset in &self.filesets {
+            for entry in &fileset.entries {
+                blocks.extend(self.blocks(&entry.blocklists, entry.single_block())?);
+                blocks.extend(self.metadata_blocks(entry)?);
+                blocks.extend(entry.blocklists.iter().cloned());
+                blocks.extend(entry.metablocklists.iter().cloned());
+            }
+        }
+
+        let mut referenced: HashSet<&String> = self
+            .filesets
+            .iter()
+            .map(|fileset| &fileset.volume)
+            .collect();
+        for block in &blocks {
+            referenced.insert(self.block_volume(block)?);
+        }
+        for (index, volumes) in &self.indexed_volumes {
+            if volumes.iter().any(|volume| referenced.contains(volume)) {
+                referenced.insert(index);
+            }
+        }
+
+        Ok(referenced
+            .into_iter()
+            .map(|name| self.volumes[name].path.clone())
+            .collect())
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+}
@@ -0,0 +1,97 @@
+//! Remote volumes: zip files, optionally AES Crypt encrypted
+
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use zip::ZipArchive;
+
+use super::{aescrypt, error::Error, filelist::parse_time};
+use crate::{error::Result, storage::Storage};
+
+pub type Archive = ZipArchive<Cursor<Vec<u8>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeType {
+    /// `dlist`: the files of one backup
+    Files,
+    /// `dblock`: the blocks holding file contents, metadata and blocklists
+    Blocks,
+    /// `dindex`: lists the blocks of block volumes
+    Index,
+}
+
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub path: PathBuf,
+    pub kind: VolumeType,
+    /// Backup time, only set for file lists
+    pub time: Option<DateTime<Utc>>,
+    pub encrypted: bool,
+}
+
+impl Volume {
+    /// Recognize a volume by its name, `<prefix>-<timestamp>.dlist.zip`, `<prefix>-b<id>.dblock.zip`
+    /// or `<prefix>-i<id>.dindex.zip`, optionally followed by the encryption module
+    pub fn from_path(path: &Path) -> Result<Option<Self>> {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            return Ok(None);
+        };
+        if name.ends_with(".gpg") {
+            return Err(Error::UnsupportedEncryption("gpg".into()).into());
+        }
+
+        let (name, encrypted) = match name.strip_suffix(".aes") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let Some(name) = name.strip_suffix(".zip") else {
+            return Ok(None);
+        };
+
+        let (kind, name) = if let Some(name) = name.strip_suffix(".dlist") {
+            (VolumeType::Files, name)
+        } else if let Some(name) = name.strip_suffix(".dblock") {
+            (VolumeType::Blocks, name)
+        } else if let Some(name) = name.strip_suffix(".dindex") {
+            (VolumeType::Index, name)
+        } else {
+            return Ok(None);
+        };
+
+        let time = match kind {
+            VolumeType::Files => {
+                let timestamp = name.rsplit('-').next().unwrap_or_default();
+                Some(parse_time(timestamp).ok_or(Error::InvalidVolumeName(name.to_string()))?)
+            }
+            _ => None,
+        };
+
+        Ok(Some(Self {
+            path: path.to_path_buf(),
+            kind,
+            time,
+            encrypted,
+        }))
+    }
+
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Read, decrypt and open the volume
+    pub fn open(&self, storage: &dyn Storage, password: Option<&str>) -> Result<Archive> {
+        let mut data = storage.read(&self.path)?;
+        if self.encrypted {
+            data = aescrypt::decrypt(&data, password.ok_or(Error::PasswordRequired)?)?;
+        }
+
+        Ok(ZipArchive::new(Cursor::new(data))?)
+    }
+}
@@ -0,0 +1,126 @@
+//! The tarballs of volumes and signatures, entries are prefixed with what they contain:
+//! `snapshot/<path>`, `diff/<path>`, `deleted/<path>` or `signature/<path>`. Files too large for
+//! one volume are split into `multivol_snapshot/<path>/<n>` or `multivol_diff/<path>/<n>`.
+
+use std::{
+    ffi::OsStr,
+    io::Read,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use chrono::{TimeZone, Utc};
+
+use crate::{
+    error::Result,
+    formats::tree::{self, NodeKind},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// The whole contents of the path
+    Snapshot,
+    /// An rdiff delta against the previous version
+    Diff,
+    Deleted,
+    /// An rdiff signature, only in signature tarballs
+    Signature,
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub kind: EntryKind,
+    /// Block of a file split across volumes, numbered from 1
+    pub block: Option<u32>,
+    pub node: tree::Node,
+    pub data: Vec<u8>,
+}
+
+/// Split the name of an entry into its kind, path and block number
+fn parse_name(name: &[u8]) -> Option<(EntryKind, PathBuf, Option<u32>)> {
+    let name = name.strip_suffix(b"/").unwrap_or(name);
+    let (prefix, path) = match name.iter().position(|b| *b == b'/') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        // the root of the backup
+        None => (name, &b""[..]),
+    };
+
+    let (kind, multivol) = match prefix {
+        b"snapshot" => (EntryKind::Snapshot, false),
+        b"diff" => (EntryKind::Diff, false),
+        b"deleted" => (EntryKind::Deleted, false),
+        b"signature" => (EntryKind::Signature, false),
+        b"multivol_snapshot" => (EntryKind::Snapshot, true),
+        b"multivol_diff" => (EntryKind::Diff, true),
+        _ => return None,
+    };
+    if !multivol {
+        return Some((kind, PathBuf::from(OsStr::from_bytes(path)), None));
+    }
+
+    let i = path.iter().rposition(|b| *b == b'/');
+    let block = std::str::from_utf8(&path[i.map_or(0, |i| i + 1)..])
+        .ok()?
+        .parse()
+        .ok()?;
+    let path = &path[..i.unwrap_or(0)];
+    Some((kind, PathBuf::from(OsStr::from_bytes(path)), Some(block)))
+}
+
+fn to_tree_node(header: &tar::Header, path: &Path, link_name: Option<&[u8]>) -> tree::Node {
+    let kind = match header.entry_type() {
+        tar::EntryType::Directory => NodeKind::Dir,
+        tar::EntryType::Symlink => NodeKind::Symlink {
+            target: String::from_utf8_lossy(link_name.unwrap_or_default()).to_string(),
+        },
+        tar::EntryType::Regular | tar::EntryType::Continuous => NodeKind::File,
+        _ => NodeKind::Special,
+    };
+
+    let mut node = tree::Node::new(path.to_path_buf(), kind);
+    let text = |bytes: Option<&[u8]>| {
+        bytes
+            .filter(|bytes| !bytes.is_empty())
+            .map(|bytes| String::from_utf8_lossy(bytes).to_string())
+    };
+    node.mode = header.mode().ok().map(|mode| mode & 0o7777);
+    node.uid = header.uid().ok().map(|uid| uid as u32);
+    node.gid = header.gid().ok().map(|gid| gid as u32);
+    node.user = text(header.username_bytes());
+    node.group = text(header.groupname_bytes());
+    node.mtime = header
+        .mtime()
+        .ok()
+        .and_then(|mtime| Utc.timestamp_opt(mtime as i64, 0).single());
+
+    node
+}
+
+/// Read every entry of a tarball, unknown entries are skipped
+pub fn read_entries(data: &[u8]) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    let mut archive = tar::Archive::new(data);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path_bytes().to_vec();
+        let Some((kind, path, block)) = parse_name(&name) else {
+            debug!("Skipping tar entry {}", String::from_utf8_lossy(&name));
+            continue;
+        };
+
+        let link_name = entry.link_name_bytes().map(|name| name.to_vec());
+        let node = to_tree_node(entry.header(), &path, link_name.as_deref());
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+
+        entries.push(Entry {
+            kind,
+            block,
+            node,
+            data,
+        });
+    }
+
+    Ok(entries)
+}
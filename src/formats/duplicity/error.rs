@@ -0,0 +1,29 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Password required")]
+    PasswordRequired,
+    #[error("Invalid password")]
+    InvalidPassword,
+    #[error("Invalid OpenPGP message: {0}")]
+    InvalidOpenPgp(String),
+    #[error("Unsupported OpenPGP packet: tag {0}")]
+    UnsupportedPacket(u8),
+    #[error("Unsupported OpenPGP cipher: {0}")]
+    UnsupportedCipher(u8),
+    #[error("Unsupported OpenPGP hash algorithm: {0}")]
+    UnsupportedHash(u8),
+    #[error("Unsupported OpenPGP string-to-key type: {0}")]
+    UnsupportedS2k(u8),
+    #[error("Unsupported OpenPGP compression: {0}")]
+    UnsupportedCompression(u8),
+    #[error("Integrity check failed for {0}")]
+    IntegrityCheckFailed(String),
+    #[error("Invalid manifest {0}: {1}")]
+    InvalidManifest(String, String),
+    #[error("Invalid rdiff delta for {0}")]
+    InvalidDelta(String),
+    #[error("{1} not found in backup set {0}")]
+    EntryNotFound(String, String),
+}
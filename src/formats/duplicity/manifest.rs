@@ -0,0 +1,125 @@
+//! The manifest of a backup set: which range of paths every volume holds and its hash
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use super::error::Error;
+use crate::error::Result;
+
+#[derive(Debug, Default)]
+pub struct VolumeInfo {
+    /// First and last path, a file split across volumes is both the end of one and the start of
+    /// the next
+    pub start: PathBuf,
+    pub end: PathBuf,
+    /// SHA-1 of the uploaded file, hex encoded
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Manifest {
+    pub hostname: Option<String>,
+    pub local_dir: Option<String>,
+    pub volumes: BTreeMap<u32, VolumeInfo>,
+}
+
+/// Paths are quoted if they contain spaces, quotes or unprintable bytes, which are escaped as
+/// `\xNN`
+fn unquote(value: &str) -> Vec<u8> {
+    let Some(value) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.as_bytes().to_vec();
+    };
+
+    let bytes = value.as_bytes();
+    let mut unquoted = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            if let Some(byte) = bytes
+                .get(i + 2..i + 4)
+                .filter(|_| bytes.get(i + 1) == Some(&b'x'))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                unquoted.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        unquoted.push(bytes[i]);
+        i += 1;
+    }
+
+    unquoted
+}
+
+/// `.` is the root of the backup
+fn to_path(value: &str) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+
+    match unquote(value) {
+        path if path == b"." => PathBuf::new(),
+        path => PathBuf::from(std::ffi::OsString::from_vec(path)),
+    }
+}
+
+/// Split off the quoted or unquoted value at the start of a line
+fn split_value(line: &str) -> &str {
+    let line = line.trim_start();
+    match line.strip_prefix('"') {
+        Some(rest) => match rest.find('"') {
+            Some(end) => &line[..end + 2],
+            None => line,
+        },
+        None => line.split_whitespace().next().unwrap_or_default(),
+    }
+}
+
+impl Manifest {
+    /// Parse a manifest, `name` is only used for errors
+    pub fn parse(data: &[u8], name: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidManifest(name.to_string(), reason.to_string());
+        let text = String::from_utf8_lossy(data);
+
+        let mut manifest = Manifest::default();
+        let mut volume = None;
+        for line in text.lines() {
+            let trimmed = line.trim_start();
+            let (key, value) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+
+            match key {
+                "Hostname" => manifest.hostname = Some(value.trim().to_string()),
+                "Localdir" => {
+                    let local_dir = unquote(split_value(value));
+                    manifest.local_dir = Some(String::from_utf8_lossy(&local_dir).to_string());
+                }
+                "Volume" => {
+                    let number = value
+                        .trim()
+                        .strip_suffix(':')
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(|| invalid("invalid volume number"))?;
+                    manifest.volumes.insert(number, VolumeInfo::default());
+                    volume = Some(number);
+                }
+                "StartingPath" | "EndingPath" | "Hash" => {
+                    let info = volume
+                        .and_then(|n| manifest.volumes.get_mut(&n))
+                        .ok_or_else(|| invalid(&format!("{key} outside of a volume")))?;
+                    match key {
+                        "StartingPath" => info.start = to_path(split_value(value)),
+                        "EndingPath" => info.end = to_path(split_value(value)),
+                        _ => match value.split_whitespace().collect::<Vec<_>>().as_slice() {
+                            ["SHA1", hash] => info.hash = Some(hash.to_lowercase()),
+                            _ => debug!("Ignoring hash {value} of {name}"),
+                        },
+                    }
+                }
+                // the changed paths of newer versions, they're in the signatures too
+                "Filelist" => volume = None,
+                _ => {}
+            }
+        }
+
+        Ok(manifest)
+    }
+}
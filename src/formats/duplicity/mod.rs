@@ -0,0 +1,426 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Read,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use chrono::{DateTime, Utc};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, ChunkRef, NodeKind, SnapshotInfo},
+    storage::Storage,
+};
+use difftar::{Entry, EntryKind};
+use error::Error;
+use manifest::Manifest;
+use naming::{BackupFile, FileKind};
+
+mod difftar;
+mod manifest;
+mod naming;
+mod openpgp;
+mod rdiff;
+
+pub mod error;
+
+/// Number of decoded volumes kept in memory, files split across volumes need two
+const CACHED_VOLUMES: usize = 2;
+
+/// A file of the backup destination and what its name says about it
+#[derive(Debug)]
+struct StoredFile {
+    path: PathBuf,
+    info: BackupFile,
+}
+
+/// A full or incremental backup: its manifest, volumes and signatures
+#[derive(Debug)]
+struct BackupSet {
+    /// Time of the backup set this one is based on, `None` for full backups
+    start: Option<String>,
+    end: String,
+    time: DateTime<Utc>,
+    manifest_file: Option<StoredFile>,
+    manifest: Manifest,
+    volumes: BTreeMap<u32, StoredFile>,
+    signatures: Option<StoredFile>,
+    /// Paths in the order they were backed up and their metadata, `None` if they were deleted
+    changes: Vec<(PathBuf, Option<tree::Node>)>,
+    /// Files whose contents are stored in this set
+    changed: HashSet<PathBuf>,
+}
+
+impl BackupSet {
+    fn files(&self) -> impl Iterator<Item = &StoredFile> {
+        self.manifest_file
+            .iter()
+            .chain(self.volumes.values())
+            .chain(self.signatures.iter())
+    }
+}
+
+#[derive(Debug)]
+pub struct Duplicity {
+    pub path: PathBuf,
+    storage: Rc<dyn Storage>,
+    password: Option<String>,
+
+    /// A full backup followed by the incremental backups based on it
+    chains: Vec<Vec<BackupSet>>,
+    /// Sets that aren't part of a chain yet
+    sets: Vec<BackupSet>,
+    /// Recently decoded volumes, the most recent last
+    volume_cache: RefCell<Vec<(PathBuf, Rc<Vec<Entry>>)>>,
+}
+
+impl Duplicity {
+    /// Group the files of a backup destination into backup sets, the password is only needed
+    /// for encrypted backups
+    pub fn from_folder(
+        storage: Rc<dyn Storage>,
+        path: impl Into<PathBuf>,
+        password: impl Into<Option<String>>,
+    ) -> Result<Self> {
+        let path = path.into();
+
+        let mut sets: HashMap<(Option<String>, String), BackupSet> = HashMap::new();
+        for entry in storage.list(&path)? {
+            let Some(info) = BackupFile::parse(&entry.name) else {
+                continue;
+            };
+
+            let set = sets
+                .entry((info.start.clone(), info.end.clone()))
+                .or_insert_with(|| BackupSet {
+                    start: info.start.clone(),
+                    end: info.end.clone(),
+                    time: naming::parse_time(&info.end).unwrap_or_default(),
+                    manifest_file: None,
+                    manifest: Manifest::default(),
+                    volumes: BTreeMap::new(),
+                    signatures: None,
+                    changes: Vec::new(),
+                    changed: HashSet::new(),
+                });
+            let kind = info.kind;
+            let stored = StoredFile {
+                path: entry.path,
+                info,
+            };
+            match kind {
+                FileKind::Manifest => set.manifest_file = Some(stored),
+                FileKind::Volume(number) => {
+                    set.volumes.insert(number, stored);
+                }
+                FileKind::Signatures => set.signatures = Some(stored),
+            }
+        }
+
+        let mut sets: Vec<BackupSet> = sets.into_values().collect();
+        sets.sort_by_key(|set| set.time);
+
+        Ok(Self {
+            path,
+            storage,
+            password: password.into(),
+            chains: Vec::new(),
+            sets,
+            volume_cache: RefCell::default(),
+        })
+    }
+
+    /// Read the manifests and signatures of every backup set and chain the incremental backups
+    /// to the backup they're based on. Sets without a manifest haven't been finished.
+    pub fn load_all_snapshots(&mut self) -> Result<()> {
+        for mut set in std::mem::take(&mut self.sets) {
+            let Some(manifest_file) = &set.manifest_file else {
+                debug!("Skipping unfinished backup set {}", set.end);
+                continue;
+            };
+            trace!("Loading backup set {}", set.end);
+
+            set.manifest = Manifest::parse(
+                &self.read_file(manifest_file)?,
+                &manifest_file.path.to_string_lossy(),
+            )?;
+            trace!(
+                "Manifest: host {:?}, directory {:?}, {} volumes",
+                set.manifest.hostname,
+                set.manifest.local_dir,
+                set.manifest.volumes.len()
+            );
+            self.load_changes(&mut set)?;
+
+            match &set.start {
+                None => self.chains.push(vec![set]),
+                Some(start) => match self
+                    .chains
+                    .iter_mut()
+                    .find(|chain| chain.last().is_some_and(|last| &last.end == start))
+                {
+                    Some(chain) => chain.push(set),
+                    None => debug!("Skipping backup set {} without a chain", set.end),
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load everything
+    pub fn load_all(&mut self) -> Result<()> {
+        self.load_all_snapshots()?;
+        Ok(())
+    }
+
+    /// What changed in a backup set, from its signatures or, if they're missing, from its
+    /// volumes
+    fn load_changes(&self, set: &mut BackupSet) -> Result<()> {
+        let entries = match &set.signatures {
+            Some(signatures) => difftar::read_entries(&self.read_file(signatures)?)?,
+            None => {
+                debug!("No signatures for backup set {}, reading volumes", set.end);
+                let mut entries = Vec::new();
+                for volume in set.volumes.values() {
+                    entries.extend(difftar::read_entries(&self.read_file(volume)?)?);
+                }
+                entries
+            }
+        };
+
+        for entry in entries {
+            // split files are listed once, diffs don't tell the size of the new version
+            if entry.block.is_some_and(|block| block > 1) {
+                continue;
+            }
+            let mut node = entry.node;
+            node.size = match (entry.kind, entry.block, &node.kind) {
+                (EntryKind::Snapshot, None, NodeKind::File) => Some(entry.data.len() as u64),
+                _ => None,
+            };
+
+            match entry.kind {
+                EntryKind::Deleted => set.changes.push((node.path, None)),
+                _ => {
+                    if node.kind == NodeKind::File {
+                        set.changed.insert(node.path.clone());
+                    }
+                    set.changes.push((node.path.clone(), Some(node)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a file of the backup, decrypting and decompressing it
+    fn read_file(&self, file: &StoredFile) -> Result<Vec<u8>> {
+        let mut data = self.storage.read(&file.path)?;
+        if file.info.encrypted {
+            let password = self.password.as_deref().ok_or(Error::PasswordRequired)?;
+            data = openpgp::decrypt(&data, password)?;
+        }
+        if file.info.compressed {
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(&data[..]).read_to_end(&mut decompressed)?;
+            data = decompressed;
+        }
+
+        Ok(data)
+    }
+
+    /// The entries of a volume, after checking its hash against the manifest
+    fn volume(&self, set: &BackupSet, number: u32) -> Result<Rc<Vec<Entry>>> {
+        let file = &set.volumes[&number];
+        let mut cache = self.volume_cache.borrow_mut();
+        if let Some(i) = cache.iter().position(|(path, _)| *path == file.path) {
+            let cached = cache.remove(i);
+            let entries = cached.1.clone();
+            cache.push(cached);
+            return Ok(entries);
+        }
+
+        trace!("Decoding volume {}", file.path.display());
+        if let Some(hash) = set
+            .manifest
+            .volumes
+            .get(&number)
+            .and_then(|volume| volume.hash.as_ref())
+        {
+            if hex::encode(Sha1::digest(self.storage.read(&file.path)?)) != *hash {
+                return Err(
+                    Error::IntegrityCheckFailed(file.path.to_string_lossy().to_string()).into(),
+                );
+            }
+        }
+
+        let entries = Rc::new(difftar::read_entries(&self.read_file(file)?)?);
+        if cache.len() >= CACHED_VOLUMES {
+            cache.remove(0);
+        }
+        cache.push((file.path.clone(), entries.clone()));
+
+        Ok(entries)
+    }
+
+    /// The snapshot or diff of a file stored in a backup set, blocks of split files are joined
+    fn stored_entry(&self, set: &BackupSet, path: &Path) -> Result<(EntryKind, Vec<u8>)> {
+        // the manifest tells which volumes a path could be in
+        let mut numbers: Vec<u32> = set
+            .manifest
+            .volumes
+            .iter()
+            .filter(|(_, volume)| volume.start.as_path() <= path && path <= volume.end.as_path())
+            .map(|(number, _)| *number)
+            .filter(|number| set.volumes.contains_key(number))
+            .collect();
+        if numbers.is_empty() {
+            numbers = set.volumes.keys().copied().collect();
+        }
+
+        let mut blocks = Vec::new();
+        for number in numbers {
+            for entry in self.volume(set, number)?.iter() {
+                if entry.node.path == path
+                    && matches!(entry.kind, EntryKind::Snapshot | EntryKind::Diff)
+                {
+                    blocks.push((entry.block, entry.kind, entry.data.clone()));
+                }
+            }
+        }
+        blocks.sort_by_key(|(block, _, _)| *block);
+
+        let kind = blocks.first().map(|(_, kind, _)| *kind).ok_or_else(|| {
+            Error::EntryNotFound(set.end.clone(), path.to_string_lossy().to_string())
+        })?;
+        Ok((
+            kind,
+            blocks.into_iter().flat_map(|(_, _, data)| data).collect(),
+        ))
+    }
+
+    /// Contents of a file in a backup set, deltas are applied to the previous version
+    fn file_contents(&self, chain: &[BackupSet], path: &Path) -> Result<Vec<u8>> {
+        let (set, previous) = chain.split_last().unwrap();
+        let (kind, data) = self.stored_entry(set, path)?;
+        if kind == EntryKind::Snapshot {
+            return Ok(data);
+        }
+
+        let basis = match previous.iter().rposition(|set| set.changed.contains(path)) {
+            Some(i) => self.file_contents(&chain[..=i], path)?,
+            None => Vec::new(),
+        };
+        Ok(rdiff::patch(&basis, &data)
+            .ok_or_else(|| Error::InvalidDelta(path.to_string_lossy().to_string()))?)
+    }
+
+    /// A chain up to and including the backup set with the given end time
+    fn chain_until(&self, end: &str) -> Option<&[BackupSet]> {
+        self.chains.iter().find_map(|chain| {
+            let i = chain.iter().position(|set| set.end == end)?;
+            Some(&chain[..=i])
+        })
+    }
+
+    /// Chunks are the files of a backup set, `<end time>/<path>`
+    fn parse_chunk_id<'a>(&self, id: &'a str) -> Result<(&[BackupSet], &'a Path)> {
+        let not_found = || Error::EntryNotFound(String::new(), id.to_string());
+        let (end, path) = id.split_once('/').ok_or_else(not_found)?;
+        let chain = self.chain_until(end).ok_or_else(not_found)?;
+
+        Ok((chain, Path::new(path)))
+    }
+}
+
+impl Backup for Duplicity {
+    /// Backup sets are identified by their time
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        let mut snapshots: Vec<SnapshotInfo> = self
+            .chains
+            .iter()
+            .flatten()
+            .map(|set| SnapshotInfo {
+                id: set.end.clone(),
+                group: String::new(),
+                time: set.time,
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+
+        snapshots
+    }
+
+    /// Replay the changes of every backup set of the chain up to the snapshot
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let id = self.find_snapshot(snapshot)?.id;
+        let chain = self.chain_until(&id).unwrap();
+
+        let mut nodes: BTreeMap<PathBuf, tree::Node> = BTreeMap::new();
+        for set in chain {
+            for (path, node) in &set.changes {
+                let Some(node) = node else {
+                    // paths are ordered by their components, descendants follow their parent
+                    let removed: Vec<PathBuf> = nodes
+                        .range(path.clone()..)
+                        .map(|(p, _)| p)
+                        .take_while(|p| p.starts_with(path))
+                        .cloned()
+                        .collect();
+                    for path in removed {
+                        nodes.remove(&path);
+                    }
+                    continue;
+                };
+
+                let mut node = node.clone();
+                if node.kind == NodeKind::File {
+                    let id = format!("{}/{}", set.end, path.to_string_lossy());
+                    node.chunks = vec![ChunkRef::whole(id)];
+                }
+                nodes.insert(path.clone(), node);
+            }
+        }
+
+        Ok(nodes
+            .into_values()
+            .filter(|node| !node.path.as_os_str().is_empty())
+            .collect())
+    }
+
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        let (chain, path) = self.parse_chunk_id(id)?;
+        self.file_contents(chain, path)
+    }
+
+    /// The size of the snapshot or diff in the volume tarballs, they're compressed as a whole
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        let (chain, path) = self.parse_chunk_id(id)?;
+        Ok(self.stored_entry(chain.last().unwrap(), path)?.1.len() as u64)
+    }
+
+    /// Every file is stored at the top of the destination
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
+    /// Every file of a backup set that's part of a chain, unfinished sets and incremental sets
+    /// whose base is gone are orphans
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        Ok(self
+            .chains
+            .iter()
+            .flatten()
+            .flat_map(BackupSet::files)
+            .map(|file| file.path.clone())
+            .collect())
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+}
@@ -0,0 +1,75 @@
+//! The names of the files duplicity uploads, e.g. `duplicity-inc.<start>.to.<end>.vol1.difftar.gpg`
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Manifest,
+    /// Volumes are numbered from 1
+    Volume(u32),
+    Signatures,
+}
+
+#[derive(Debug, Clone)]
+pub struct BackupFile {
+    pub kind: FileKind,
+    /// Time of the backup set an incremental one is based on, `None` for full backups
+    pub start: Option<String>,
+    pub end: String,
+    pub encrypted: bool,
+    pub compressed: bool,
+}
+
+impl BackupFile {
+    /// Parse a file name, an optional prefix is allowed. Partial uploads and unrelated files
+    /// are ignored.
+    pub fn parse(name: &str) -> Option<Self> {
+        let (name, encrypted) = match name.strip_suffix(".gpg") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let (name, compressed) = match name.strip_suffix(".gz") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+
+        let name = &name[name.find("duplicity-")? + "duplicity-".len()..];
+        let parts: Vec<&str> = name.split('.').collect();
+
+        let (start, end, rest) = match parts.as_slice() {
+            ["full" | "full-signatures", time, rest @ ..] => (None, *time, rest),
+            ["inc" | "new-signatures", start, "to", end, rest @ ..] => {
+                (Some(start.to_string()), *end, rest)
+            }
+            _ => return None,
+        };
+        let signatures = parts[0].ends_with("-signatures");
+
+        let kind = match rest {
+            ["sigtar"] if signatures => FileKind::Signatures,
+            ["manifest"] if !signatures => FileKind::Manifest,
+            [volume, "difftar"] if !signatures => {
+                FileKind::Volume(volume.strip_prefix("vol")?.parse().ok()?)
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            kind,
+            start,
+            end: end.to_string(),
+            encrypted,
+            compressed,
+        })
+    }
+}
+
+/// Times are `20240101T100000Z`, old versions used W3C datetimes with the local offset
+pub fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    match NaiveDateTime::parse_from_str(time, "%Y%m%dT%H%M%SZ") {
+        Ok(time) => Some(Utc.from_utc_datetime(&time)),
+        Err(_) => DateTime::parse_from_rfc3339(time)
+            .ok()
+            .map(|time| time.with_timezone(&Utc)),
+    }
+}
@@ -0,0 +1,383 @@
+//! Just enough OpenPGP (RFC 4880) to decrypt what `gpg --symmetric` produces: symmetric-key
+//! encrypted session keys, encrypted data with and without MDC, compressed and literal data
+
+use std::io::Read;
+
+use cfb_mode::cipher::{AsyncStreamCipher, BlockCipher, BlockEncryptMut, KeyInit, KeyIvInit};
+use sha1::{Digest, Sha1};
+use sha2::digest::DynDigest;
+
+use super::error::Error;
+use crate::error::Result;
+
+const TAG_SKESK: u8 = 3;
+const TAG_COMPRESSED: u8 = 8;
+const TAG_SED: u8 = 9;
+const TAG_MARKER: u8 = 10;
+const TAG_LITERAL: u8 = 11;
+const TAG_SEIPD: u8 = 18;
+
+/// Length of the modification detection code packet, header included
+const MDC_SIZE: usize = 22;
+
+/// Approximate size of the repeated salt and passphrase handed to the hash at once
+const S2K_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+enum Cipher {
+    TripleDes,
+    Cast5,
+    Blowfish,
+    Aes128,
+    Aes192,
+    Aes256,
+    Twofish,
+}
+
+impl Cipher {
+    fn from_id(id: u8) -> Result<Self> {
+        match id {
+            2 => Ok(Cipher::TripleDes),
+            3 => Ok(Cipher::Cast5),
+            4 => Ok(Cipher::Blowfish),
+            7 => Ok(Cipher::Aes128),
+            8 => Ok(Cipher::Aes192),
+            9 => Ok(Cipher::Aes256),
+            10 => Ok(Cipher::Twofish),
+            id => Err(Error::UnsupportedCipher(id).into()),
+        }
+    }
+
+    fn key_size(&self) -> usize {
+        match self {
+            Cipher::Cast5 | Cipher::Blowfish | Cipher::Aes128 => 16,
+            Cipher::TripleDes | Cipher::Aes192 => 24,
+            Cipher::Aes256 | Cipher::Twofish => 32,
+        }
+    }
+
+    fn block_size(&self) -> usize {
+        match self {
+            Cipher::TripleDes | Cipher::Cast5 | Cipher::Blowfish => 8,
+            _ => 16,
+        }
+    }
+
+    /// Decrypt in CFB mode, in place
+    fn decrypt(&self, key: &[u8], iv: &[u8], data: &mut [u8]) -> Result<()> {
+        match self {
+            Cipher::TripleDes => cfb_decrypt::<des::TdesEde3>(key, iv, data),
+            Cipher::Cast5 => cfb_decrypt::<cast5::Cast5>(key, iv, data),
+            Cipher::Blowfish => cfb_decrypt::<blowfish::Blowfish>(key, iv, data),
+            Cipher::Aes128 => cfb_decrypt::<aes::Aes128>(key, iv, data),
+            Cipher::Aes192 => cfb_decrypt::<aes::Aes192>(key, iv, data),
+            Cipher::Aes256 => cfb_decrypt::<aes::Aes256>(key, iv, data),
+            Cipher::Twofish => cfb_decrypt::<twofish::Twofish>(key, iv, data),
+        }
+    }
+}
+
+fn cfb_decrypt<C: BlockEncryptMut + BlockCipher + KeyInit>(
+    key: &[u8],
+    iv: &[u8],
+    data: &mut [u8],
+) -> Result<()> {
+    cfb_mode::Decryptor::<C>::new_from_slices(key, iv)
+        .map_err(|_| Error::InvalidOpenPgp("invalid key length".into()))?
+        .decrypt(data);
+    Ok(())
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidOpenPgp(reason.to_string())
+}
+
+/// Split a message into its packets, the bodies of packets with partial lengths are joined
+fn packets(data: &[u8]) -> Result<Vec<(u8, Vec<u8>)>> {
+    let mut packets = Vec::new();
+    let mut pos = 0;
+
+    let byte = |pos: usize| {
+        data.get(pos)
+            .copied()
+            .ok_or_else(|| invalid("truncated packet"))
+    };
+    let be = |start: usize, len: usize| -> Result<usize> {
+        let bytes = data
+            .get(start..start + len)
+            .ok_or_else(|| invalid("truncated packet"))?;
+        Ok(bytes.iter().fold(0, |v, b| v << 8 | *b as usize))
+    };
+    let body = |start: usize, len: usize| {
+        data.get(start..start + len)
+            .ok_or_else(|| invalid("truncated packet"))
+    };
+
+    while pos < data.len() {
+        let header = byte(pos)?;
+        if header & 0x80 == 0 {
+            return Err(invalid("invalid packet header").into());
+        }
+        pos += 1;
+
+        // new format headers: length octets, partial lengths continue with another length
+        if header & 0x40 != 0 {
+            let tag = header & 0x3f;
+            let mut contents = Vec::new();
+            loop {
+                let first = byte(pos)? as usize;
+                let (len, partial, skip) = match first {
+                    0..=191 => (first, false, 1),
+                    192..=223 => (
+                        ((first - 192) << 8) + byte(pos + 1)? as usize + 192,
+                        false,
+                        2,
+                    ),
+                    255 => (be(pos + 1, 4)?, false, 5),
+                    _ => (1 << (first & 0x1f), true, 1),
+                };
+                pos += skip;
+                contents.extend_from_slice(body(pos, len)?);
+                pos += len;
+                if !partial {
+                    break;
+                }
+            }
+            packets.push((tag, contents));
+            continue;
+        }
+
+        // old format headers: the length type is in the header, 3 extends to the end
+        let tag = (header >> 2) & 0x0f;
+        let (len, skip) = match header & 0x03 {
+            0 => (be(pos, 1)?, 1),
+            1 => (be(pos, 2)?, 2),
+            2 => (be(pos, 4)?, 4),
+            _ => (data.len() - pos, 0),
+        };
+        pos += skip;
+        packets.push((tag, body(pos, len)?.to_vec()));
+        pos += len;
+    }
+
+    Ok(packets)
+}
+
+fn hasher(id: u8) -> Result<Box<dyn DynDigest>> {
+    match id {
+        1 => Ok(Box::new(md5::Md5::default())),
+        2 => Ok(Box::new(Sha1::default())),
+        8 => Ok(Box::new(sha2::Sha256::default())),
+        9 => Ok(Box::new(sha2::Sha384::default())),
+        10 => Ok(Box::new(sha2::Sha512::default())),
+        11 => Ok(Box::new(sha2::Sha224::default())),
+        id => Err(Error::UnsupportedHash(id).into()),
+    }
+}
+
+/// Derive a key from the passphrase, returns the key and the length of the specifier
+fn string_to_key(spec: &[u8], password: &[u8], key_size: usize) -> Result<(Vec<u8>, usize)> {
+    let truncated = || invalid("truncated string-to-key specifier");
+    let (kind, hash) = match spec {
+        [kind, hash, ..] => (*kind, *hash),
+        _ => return Err(truncated().into()),
+    };
+
+    // simple | salted | iterated and salted, the count is encoded as a tiny float
+    let (salt, count, len) = match kind {
+        0 => (&[][..], 0, 2),
+        1 => (spec.get(2..10).ok_or_else(truncated)?, 0, 10),
+        3 => {
+            let c = *spec.get(10).ok_or_else(truncated)? as usize;
+            (
+                spec.get(2..10).ok_or_else(truncated)?,
+                (16 + (c & 15)) << ((c >> 4) + 6),
+                11,
+            )
+        }
+        kind => return Err(Error::UnsupportedS2k(kind).into()),
+    };
+
+    let mut input = salt.to_vec();
+    input.extend_from_slice(password);
+    // at least the salt and the password are hashed once
+    let count = count.max(input.len());
+    // iterated specifiers hash megabytes, feed them in large pieces
+    let repeated = input.repeat((S2K_BUFFER_SIZE / input.len()).max(1));
+
+    // longer keys hash the same input again, preloaded with a growing number of zeros
+    let mut key = Vec::new();
+    let mut preload = 0;
+    while key.len() < key_size {
+        let mut hasher = hasher(hash)?;
+        hasher.update(&vec![0u8; preload]);
+
+        let mut remaining = count;
+        while remaining > 0 {
+            let len = remaining.min(repeated.len());
+            hasher.update(&repeated[..len]);
+            remaining -= len;
+        }
+
+        key.extend_from_slice(&hasher.finalize());
+        preload += 1;
+    }
+    key.truncate(key_size);
+
+    Ok((key, len))
+}
+
+/// The session keys a symmetric-key encrypted session key packet could hold, there's no way to
+/// tell whether the password was right until the data is decrypted
+fn session_key(packet: &[u8], password: &str) -> Result<Option<(Cipher, Vec<u8>)>> {
+    // version | cipher | string-to-key specifier | optional encrypted session key
+    let (version, cipher) = match packet {
+        [version, cipher, ..] => (*version, Cipher::from_id(*cipher)?),
+        _ => return Err(invalid("truncated session key packet").into()),
+    };
+    if version != 4 {
+        return Err(invalid(&format!("session key packet version {version}")).into());
+    }
+
+    let (key, len) = string_to_key(&packet[2..], password.as_bytes(), cipher.key_size())?;
+    let encrypted = &packet[2 + len..];
+    if encrypted.is_empty() {
+        return Ok(Some((cipher, key)));
+    }
+
+    // the first byte is the cipher of the session key
+    let mut decrypted = encrypted.to_vec();
+    cipher.decrypt(&key, &vec![0u8; cipher.block_size()], &mut decrypted)?;
+    let Ok(session_cipher) = Cipher::from_id(decrypted[0]) else {
+        return Ok(None);
+    };
+    if decrypted.len() - 1 != session_cipher.key_size() {
+        return Ok(None);
+    }
+
+    Ok(Some((session_cipher, decrypted[1..].to_vec())))
+}
+
+/// Encrypted data starts with a random block, its last two bytes repeated. `None` if they
+/// don't match, the key is wrong.
+fn decrypt_data(tag: u8, data: &[u8], cipher: Cipher, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let bs = cipher.block_size();
+
+    match tag {
+        // version | ciphertext, followed by a SHA-1 of the plaintext
+        TAG_SEIPD => {
+            if data.first() != Some(&1) || data.len() < 1 + bs + 2 + MDC_SIZE {
+                return Err(invalid("invalid encrypted data packet").into());
+            }
+
+            let mut plaintext = data[1..].to_vec();
+            cipher.decrypt(key, &vec![0u8; bs], &mut plaintext)?;
+            if plaintext[bs - 2..bs] != plaintext[bs..bs + 2] {
+                return Ok(None);
+            }
+
+            let (message, mdc) = plaintext.split_at(plaintext.len() - 20);
+            if !message.ends_with(&[0xd3, 0x14]) || Sha1::digest(message)[..] != *mdc {
+                return Err(Error::IntegrityCheckFailed("OpenPGP message".into()).into());
+            }
+
+            Ok(Some(message[bs + 2..message.len() - 2].to_vec()))
+        }
+        // no integrity protection, the cipher is resynchronized after the random prefix
+        _ => {
+            if data.len() < bs + 2 {
+                return Err(invalid("invalid encrypted data packet").into());
+            }
+
+            let mut prefix = data[..bs + 2].to_vec();
+            cipher.decrypt(key, &vec![0u8; bs], &mut prefix)?;
+            if prefix[bs - 2..bs] != prefix[bs..] {
+                return Ok(None);
+            }
+
+            let mut plaintext = data[bs + 2..].to_vec();
+            cipher.decrypt(key, &data[2..bs + 2], &mut plaintext)?;
+            Ok(Some(plaintext))
+        }
+    }
+}
+
+/// The contents of the literal data packet of a message, decompressing along the way
+fn literal_data(message: &[u8]) -> Result<Vec<u8>> {
+    for (tag, body) in packets(message)? {
+        match tag {
+            TAG_COMPRESSED => {
+                let (algorithm, compressed) = body
+                    .split_first()
+                    .ok_or_else(|| invalid("empty compressed data packet"))?;
+
+                let mut decompressed = Vec::new();
+                match algorithm {
+                    0 => decompressed = compressed.to_vec(),
+                    1 => {
+                        flate2::read::DeflateDecoder::new(compressed)
+                            .read_to_end(&mut decompressed)?;
+                    }
+                    2 => {
+                        flate2::read::ZlibDecoder::new(compressed)
+                            .read_to_end(&mut decompressed)?;
+                    }
+                    3 => {
+                        bzip2::read::BzDecoder::new(compressed).read_to_end(&mut decompressed)?;
+                    }
+                    algorithm => return Err(Error::UnsupportedCompression(*algorithm).into()),
+                }
+
+                return literal_data(&decompressed);
+            }
+            // format | file name length | file name | date | data
+            TAG_LITERAL => {
+                let name_len = *body
+                    .get(1)
+                    .ok_or_else(|| invalid("truncated literal data"))?;
+                return Ok(body
+                    .get(2 + name_len as usize + 4..)
+                    .ok_or_else(|| invalid("truncated literal data"))?
+                    .to_vec());
+            }
+            // signatures of signed backups
+            _ => {}
+        }
+    }
+
+    Err(invalid("no literal data"))?
+}
+
+/// Decrypt a message encrypted with a passphrase
+pub fn decrypt(data: &[u8], password: &str) -> Result<Vec<u8>> {
+    let mut session_keys = Vec::new();
+    let mut encrypted = None;
+
+    for (tag, body) in packets(data)? {
+        match tag {
+            TAG_SKESK => session_keys.push(body),
+            TAG_SED | TAG_SEIPD => {
+                encrypted = Some((tag, body));
+                break;
+            }
+            TAG_MARKER => {}
+            // public key encrypted session keys, AEAD encrypted data, ...
+            tag => return Err(Error::UnsupportedPacket(tag).into()),
+        }
+    }
+    let (tag, encrypted) = encrypted.ok_or_else(|| invalid("no encrypted data"))?;
+
+    // without a session key packet the key is derived with MD5 and used with IDEA, which isn't
+    // supported
+    for packet in &session_keys {
+        let Some((cipher, key)) = session_key(packet, password)? else {
+            continue;
+        };
+
+        if let Some(message) = decrypt_data(tag, &encrypted, cipher, &key)? {
+            return literal_data(&message);
+        }
+    }
+
+    Err(Error::InvalidPassword)?
+}
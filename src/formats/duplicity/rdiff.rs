@@ -0,0 +1,91 @@
+//! librsync deltas, the changes of a file since its previous version
+
+const DELTA_MAGIC: u64 = 0x7273_0236;
+
+const OP_END: u8 = 0x00;
+const OP_LITERAL_N1: u8 = 0x41;
+const OP_COPY_N1_N1: u8 = 0x45;
+const OP_COPY_N8_N8: u8 = 0x54;
+
+const WIDTHS: [usize; 4] = [1, 2, 4, 8];
+
+fn take<'a>(delta: &'a [u8], pos: &mut usize, len: usize) -> Option<&'a [u8]> {
+    let bytes = delta.get(*pos..pos.checked_add(len)?)?;
+    *pos += len;
+    Some(bytes)
+}
+
+fn read_be(delta: &[u8], pos: &mut usize, len: usize) -> Option<usize> {
+    let bytes = take(delta, pos, len)?;
+    usize::try_from(bytes.iter().fold(0u64, |v, b| v << 8 | *b as u64)).ok()
+}
+
+/// Apply a delta to the previous version of a file, `None` if the delta is invalid
+pub fn patch(basis: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    if read_be(delta, &mut pos, 4)? as u64 != DELTA_MAGIC {
+        return None;
+    }
+
+    let mut output = Vec::new();
+    loop {
+        let op = *take(delta, &mut pos, 1)?.first()?;
+        match op {
+            OP_END => return Some(output),
+            // literals with the length in the command, then with a 1, 2, 4 or 8 byte length
+            0x01..=0x40 => output.extend_from_slice(take(delta, &mut pos, op as usize)?),
+            OP_LITERAL_N1..OP_COPY_N1_N1 => {
+                let len = read_be(delta, &mut pos, WIDTHS[(op - OP_LITERAL_N1) as usize])?;
+                output.extend_from_slice(take(delta, &mut pos, len)?);
+            }
+            // copies from the basis, every combination of offset and length widths
+            OP_COPY_N1_N1..=OP_COPY_N8_N8 => {
+                let index = (op - OP_COPY_N1_N1) as usize;
+                let offset = read_be(delta, &mut pos, WIDTHS[index / 4])?;
+                let len = read_be(delta, &mut pos, WIDTHS[index % 4])?;
+                output.extend_from_slice(basis.get(offset..offset.checked_add(len)?)?);
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASIS: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+    /// Copies with one and two byte offsets, a literal in the command and one with a length
+    fn delta() -> Vec<u8> {
+        [
+            &b"\x72\x73\x02\x36"[..],
+            b"\x45\x00\x0a",
+            b"\x03red",
+            b"\x49\x00\x0f\x1c",
+            b"\x41\x01!",
+            b"\x00",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn copies_and_literals() {
+        assert_eq!(
+            patch(BASIS, &delta()).unwrap(),
+            b"The quick red fox jumps over the lazy dog!"
+        );
+    }
+
+    #[test]
+    fn invalid_delta() {
+        let mut delta = delta();
+        delta[0] = 0;
+        assert!(patch(BASIS, &delta).is_none());
+
+        // copying past the end of the basis
+        assert!(patch(BASIS, b"\x72\x73\x02\x36\x45\x20\x20\x00").is_none());
+        // no end command
+        assert!(patch(BASIS, b"\x72\x73\x02\x36\x03red").is_none());
+    }
+}
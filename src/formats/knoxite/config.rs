@@ -3,8 +3,9 @@ use std::path::Path;
 use serde::Deserialize;
 
 use super::decoder::Decoder;
-use crate::error::Result;
+use crate::{error::Result, storage::Storage};
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
@@ -14,6 +15,7 @@ pub struct Config {
     pub key: String,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Volume {
@@ -24,8 +26,12 @@ pub struct Volume {
 }
 
 impl Config {
-    pub fn from_file(path: impl AsRef<Path>, password: impl AsRef<str>) -> Result<Self> {
-        let file = std::fs::read(path)?;
+    pub fn from_file(
+        storage: &dyn Storage,
+        path: impl AsRef<Path>,
+        password: impl AsRef<str>,
+    ) -> Result<Self> {
+        let file = storage.read(path.as_ref())?;
 
         let data = Decoder::new_password(password).decrypt(&file)?;
 
@@ -3,7 +3,7 @@ use std::{collections::HashMap, path::Path};
 use serde::Deserialize;
 
 use super::{config::Config, decoder::Decoder};
-use crate::error::Result;
+use crate::{error::Result, storage::Storage};
 
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
@@ -22,8 +22,12 @@ pub struct IndexItem {
 }
 
 impl Index {
-    pub fn from_file(config: &Config, path: impl AsRef<Path>) -> Result<Index> {
-        let file = std::fs::read(path)?;
+    pub fn from_file(
+        storage: &dyn Storage,
+        config: &Config,
+        path: impl AsRef<Path>,
+    ) -> Result<Index> {
+        let file = storage.read(path.as_ref())?;
 
         let data = Decoder::new_key(&config.key).decrypt_and_decompress(&file)?;
 
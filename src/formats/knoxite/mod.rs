@@ -1,16 +1,21 @@
 use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    rc::Rc,
 };
 
-use crate::error::Result;
+use chrono::{DateTime, Utc};
+
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, SnapshotInfo},
+    storage::Storage,
+};
 use config::Config;
 use index::Index;
 use raw_chunk::RawChunk;
 use snapshot::Snapshot;
 
-use self::snapshot::ArchiveType;
-
 mod config;
 mod decoder;
 mod index;
@@ -20,42 +25,45 @@ mod snapshot;
 #[derive(Debug)]
 pub struct Knoxite {
     pub path: PathBuf,
+    storage: Rc<dyn Storage>,
     pub config: Config,
     pub index: Index,
-    pub password: String,
     snapshots: HashMap<String, Snapshot>,
 }
 
 impl Knoxite {
-    pub fn from_folder(path: impl Into<PathBuf>, password: impl Into<String>) -> Result<Self> {
+    pub fn from_folder(
+        storage: Rc<dyn Storage>,
+        path: impl Into<PathBuf>,
+        password: impl Into<String>,
+    ) -> Result<Self> {
         let path = path.into();
         let password = password.into();
 
-        let config = Config::from_file(path.join("repository.knoxite"), &password)?;
+        let config = Config::from_file(&*storage, path.join("repository.knoxite"), &password)?;
 
         // chunk index
-        let index = Index::from_file(&config, path.join("chunks").join("index"))?;
+        let index = Index::from_file(&*storage, &config, path.join("chunks").join("index"))?;
 
         Ok(Self {
             path,
+            storage,
             config,
             index,
-            password,
             snapshots: HashMap::new(),
         })
     }
 
-    pub fn load_latest_snapshots(&mut self) -> Result<()> {
-        // read the latest snapshot for each volume listed in the config
+    pub fn load_all_snapshots(&mut self) -> Result<()> {
+        // read every snapshot of each volume listed in the config
         for volume in &self.config.volumes {
-            let latest = volume.snapshots.last();
-
-            if let Some(snapshot_id) = latest {
+            for snapshot_id in &volume.snapshots {
                 let snapshot = Snapshot::from_file(
+                    &*self.storage,
                     &self.config,
-                    &self.path.join("snapshots").join(snapshot_id),
+                    self.path.join("snapshots").join(snapshot_id),
                 )?;
-                self.snapshots.insert(volume.name.to_string(), snapshot);
+                self.snapshots.insert(snapshot_id.to_string(), snapshot);
             }
         }
 
@@ -63,43 +71,7 @@ impl Knoxite {
     }
 
     pub fn load_all(&mut self) -> Result<()> {
-        self.load_latest_snapshots()?;
-        Ok(())
-    }
-
-    // Dump all files and folders referenced by the latest snapshots of each volume
-    pub fn dump_all_files(&self, output_dir: impl AsRef<Path>) -> Result<()> {
-        for snapshot in self.snapshots.values() {
-            for archive in snapshot.archives.values() {
-                let mut archive_path = PathBuf::from(&archive.path);
-                archive_path = archive_path.strip_prefix("/").unwrap().to_path_buf();
-
-                match archive.archive_type {
-                    ArchiveType::Directory => {
-                        std::fs::create_dir_all(output_dir.as_ref().join(&archive_path))?;
-                    }
-                    ArchiveType::File => {
-                        if let Some(chunks) = &archive.chunks {
-                            let mut contents = Vec::new();
-                            for chunk in chunks {
-                                let raw = RawChunk::from_file(
-                                    &self.config,
-                                    self.resolve_path(&chunk.hash),
-                                )?;
-                                contents.extend(raw.0);
-                            }
-
-                            // write files
-                            std::fs::write(output_dir.as_ref().join(&archive_path), &contents)?;
-                        }
-                    }
-                    ArchiveType::Symlink => {
-                        // ignore
-                    }
-                }
-            }
-        }
-
+        self.load_all_snapshots()?;
         Ok(())
     }
 
@@ -115,3 +87,239 @@ impl Knoxite {
         path
     }
 }
+
+impl Backup for Knoxite {
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        let mut snapshots = Vec::new();
+        for volume in &self.config.volumes {
+            for snapshot_id in &volume.snapshots {
+                let Some(snapshot) = self.snapshots.get(snapshot_id) else {
+                    continue;
+                };
+
+                snapshots.push(SnapshotInfo {
+                    id: snapshot_id.clone(),
+                    group: volume.name.clone(),
+                    time: DateTime::parse_from_rfc3339(&snapshot.date)
+                        .expect("Failed to parse snapshot date")
+                        .with_timezone(&Utc),
+                });
+            }
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+
+        snapshots
+    }
+
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let id = self.find_snapshot(snapshot)?.id;
+        let snapshot = &self.snapshots[&id];
+
+        let mut nodes: Vec<tree::Node> = snapshot
+            .archives
+            .values()
+            .map(|archive| archive.to_tree_node())
+            .collect();
+
+        // archives are stored in a map, sorting by path lists parents before their children
+        nodes.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(nodes)
+    }
+
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        let raw = RawChunk::from_file(&*self.storage, &self.config, self.resolve_path(id))?;
+        Ok(raw.0)
+    }
+
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        // the index has the size of the processed chunk, before it's split into parts
+        if let Some(item) = self.index.chunks.get(id) {
+            if let Ok(size) = u64::try_from(item.size) {
+                let data_parts = item.data_parts.max(1) as u64;
+                let parts = data_parts + item.parity_parts.unwrap_or(0) as u64;
+                return Ok(size * parts / data_parts);
+            }
+        }
+
+        Ok(self.storage.stat(&self.resolve_path(id))?.size)
+    }
+
+    fn logical_size(&self, snapshot: &str) -> Option<u64> {
+        Some(self.snapshots.get(snapshot)?.stats.size)
+    }
+
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        vec![self.path.join("chunks")]
+    }
+
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        // the chunk index lives next to the chunks
+        let mut referenced = HashSet::from([self.path.join("chunks").join("index")]);
+
+        // the index lists the snapshots using each chunk, chunks only deleted snapshots used
+        // are garbage
+        let chunks = self.index.chunks.values().filter(|item| {
+            item.snapshots
+                .iter()
+                .any(|snapshot| self.snapshots.contains_key(snapshot))
+        });
+        for chunk in chunks {
+            // every data and parity part is stored in its own file
+            let parts = chunk.data_parts + chunk.parity_parts.unwrap_or(0);
+            for part in 0..parts {
+                let mut path = self.resolve_path(&chunk.hash);
+                path.set_extension(format!("{part}_{parts}"));
+                referenced.insert(path);
+            }
+        }
+
+        Ok(referenced)
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+
+    fn guesses_hard_links(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::storage::Local;
+
+    fn archive(path: &str, chunks: &[(&str, u32)]) -> serde_json::Value {
+        let size: u32 = chunks.iter().map(|(_, size)| size).sum();
+        let chunks: Vec<_> = chunks
+            .iter()
+            .enumerate()
+            .map(|(num, (hash, original_size))| {
+                json!({
+                    "data": [], "data_parts": 1, "parity_parts": 0,
+                    "original_size": original_size, "size": 0,
+                    "decrypted_hash": "", "hash": hash, "num": num,
+                })
+            })
+            .collect();
+
+        json!({
+            "path": path, "points_to": null, "mode": 0o644, "mod_time": 0,
+            "size": size, "storage_size": 0, "uid": 0, "gid": 0, "chunks": chunks,
+            "encrypted": 1, "compressed": 1, "type": 0,
+        })
+    }
+
+    fn snapshot(id: &str, size: u64, archives: &[serde_json::Value]) -> Snapshot {
+        let archives: serde_json::Map<_, _> = archives
+            .iter()
+            .map(|archive| {
+                (
+                    archive["path"].as_str().unwrap().to_string(),
+                    archive.clone(),
+                )
+            })
+            .collect();
+        serde_json::from_value(json!({
+            "id": id, "date": "2024-01-01T00:00:00Z", "description": null,
+            "stats": {
+                "files": archives.len(), "dirs": 0, "symlinks": 0, "size": size,
+                "storage_size": 0, "transferred": 0, "errors": 0,
+            },
+            "archives": archives,
+        }))
+        .unwrap()
+    }
+
+    /// Stored sizes come from the index alone, there are no chunk files to stat
+    fn knoxite() -> Knoxite {
+        let config = serde_json::from_value(json!({
+            "version": 4, "paths": [], "key": "",
+            "volumes": [{
+                "id": "v", "name": "volume", "description": null, "snapshots": ["s1", "s2"],
+            }],
+        }))
+        .unwrap();
+        let index = serde_json::from_value(json!({
+            "chunks": {
+                "aaaaaa": {
+                    "hash": "aaaaaa", "data_parts": 1, "parity_parts": 0, "size": 80,
+                    "snapshots": ["s1", "s2"],
+                },
+                "bbbbbb": {
+                    "hash": "bbbbbb", "data_parts": 2, "parity_parts": 1, "size": 40,
+                    "snapshots": ["s1"],
+                },
+                "cccccc": {
+                    "hash": "cccccc", "data_parts": 1, "parity_parts": null, "size": 10,
+                    "snapshots": ["deleted"],
+                },
+            },
+        }))
+        .unwrap();
+        let snapshots = HashMap::from([
+            (
+                "s1".to_string(),
+                snapshot(
+                    "s1",
+                    150,
+                    &[archive("/a", &[("aaaaaa", 100), ("bbbbbb", 50)])],
+                ),
+            ),
+            (
+                "s2".to_string(),
+                snapshot("s2", 100, &[archive("/b", &[("aaaaaa", 100)])]),
+            ),
+        ]);
+
+        Knoxite {
+            path: PathBuf::from("/nonexistent"),
+            storage: Rc::new(Local),
+            config,
+            index,
+            snapshots,
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let report = crate::stats::stats(&knoxite()).unwrap();
+
+        let s1 = &report.snapshots[0];
+        assert_eq!(s1.snapshot, "s1");
+        assert_eq!(s1.stats.logical_size, 150);
+        // parity parts add half the size of the second chunk
+        assert_eq!(s1.stats.stored_size, 80 + 60);
+        assert_eq!(s1.stats.average_chunk_size, Some(75.0));
+        assert_eq!(s1.exclusive_chunks, 1);
+        assert_eq!(s1.exclusive_stored_size, 60);
+
+        assert_eq!(report.snapshots[1].stats.logical_size, 100);
+        assert_eq!(report.snapshots[1].exclusive_chunks, 0);
+
+        assert_eq!(report.total.logical_size, 250);
+        assert_eq!(report.total.stored_size, 140);
+        assert_eq!(report.total.chunks, 2);
+        assert_eq!(report.total.dedup_ratio, Some(250.0 / 150.0));
+        assert_eq!(report.total.compression_ratio, Some(150.0 / 140.0));
+    }
+
+    #[test]
+    fn referenced_objects() {
+        let knoxite = knoxite();
+        let chunks = knoxite.path.join("chunks");
+        let mut expected = vec![chunks.join("index"), chunks.join("aa/aa/aaaaaa.0_1")];
+        for part in 0..3 {
+            expected.push(chunks.join(format!("bb/bb/bbbbbb.{part}_3")));
+        }
+
+        let mut referenced: Vec<_> = knoxite.referenced_objects().unwrap().into_iter().collect();
+        referenced.sort();
+        expected.sort();
+        assert_eq!(referenced, expected);
+    }
+}
@@ -1,13 +1,17 @@
 use std::path::Path;
 
 use super::{config::Config, decoder::Decoder};
-use crate::error::Result;
+use crate::{error::Result, storage::Storage};
 
 pub struct RawChunk(pub Vec<u8>);
 
 impl RawChunk {
-    pub fn from_file(config: &Config, path: impl AsRef<Path>) -> Result<Self> {
-        let file = std::fs::read(path)?;
+    pub fn from_file(
+        storage: &dyn Storage,
+        config: &Config,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let file = storage.read(path.as_ref())?;
 
         let data = Decoder::new_key(&config.key).decrypt(&file)?;
 
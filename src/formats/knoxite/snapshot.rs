@@ -1,64 +1,53 @@
 use std::{collections::HashMap, path::Path};
 
+use chrono::{TimeZone, Utc};
 use serde::Deserialize;
 use serde_repr::Deserialize_repr;
 
 use super::config::Config;
-use crate::{error::Result, formats::knoxite::decoder::Decoder, utils::from_truthy};
+use crate::{
+    error::Result,
+    formats::{
+        knoxite::decoder::Decoder,
+        tree::{self, ChunkRef, NodeKind},
+    },
+    storage::Storage,
+    utils::from_go_mode,
+};
 
+/// Only the fields that are read are declared, knoxite stores more about snapshots, archives
+/// and chunks
 #[derive(Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
 pub struct Snapshot {
-    pub id: String,
     pub date: String,
-    pub description: Option<String>,
     pub stats: Stats,
     pub archives: HashMap<String, Archive>,
 }
 
+/// Counters of the snapshot, only the total size of its files is used
 #[derive(Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
 pub struct Stats {
-    pub files: u64,
-    pub dirs: u64,
-    pub symlinks: u64,
     pub size: u64,
-    pub storage_size: u64,
-    pub transferred: u64,
-    pub errors: u64,
 }
 
 #[derive(Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
 pub struct Archive {
     pub path: String,
     pub points_to: Option<String>,
     pub mode: u64,
     pub mod_time: i64,
     pub size: u64,
-    pub storage_size: u64,
     pub uid: u32,
     pub gid: u32,
     pub chunks: Option<Vec<Chunk>>,
-    #[serde(deserialize_with = "from_truthy")]
-    pub encrypted: bool,
-    #[serde(deserialize_with = "from_truthy")]
-    pub compressed: bool,
     #[serde(rename = "type")]
     pub archive_type: ArchiveType,
 }
 
 #[derive(Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
 pub struct Chunk {
-    pub data: Vec<u8>,
-    pub data_parts: u32,
-    pub parity_parts: u32,
     pub original_size: i32,
-    pub size: i32,
-    pub decrypted_hash: String,
     pub hash: String,
-    pub num: u32,
 }
 
 #[derive(Deserialize_repr, PartialEq, Debug)]
@@ -69,9 +58,48 @@ pub enum ArchiveType {
     Symlink = 2,
 }
 
+impl Archive {
+    /// Convert into the format independent representation
+    pub fn to_tree_node(&self) -> tree::Node {
+        let kind = match self.archive_type {
+            ArchiveType::File => NodeKind::File,
+            ArchiveType::Directory => NodeKind::Dir,
+            ArchiveType::Symlink => NodeKind::Symlink {
+                target: self.points_to.clone().unwrap_or_default(),
+            },
+        };
+
+        // archive paths are absolute
+        let mut node = tree::Node::new(self.path.trim_start_matches('/'), kind);
+        node.mode = Some(from_go_mode(self.mode));
+        node.uid = Some(self.uid);
+        node.gid = Some(self.gid);
+        node.mtime = Utc.timestamp_opt(self.mod_time, 0).single();
+
+        if node.kind == NodeKind::File {
+            node.size = Some(self.size);
+            node.chunks = self
+                .chunks
+                .iter()
+                .flatten()
+                .map(|chunk| ChunkRef {
+                    size: usize::try_from(chunk.original_size).ok(),
+                    ..ChunkRef::whole(&chunk.hash)
+                })
+                .collect();
+        }
+
+        node
+    }
+}
+
 impl Snapshot {
-    pub fn from_file(config: &Config, path: impl AsRef<Path>) -> Result<Snapshot> {
-        let file = std::fs::read(path)?;
+    pub fn from_file(
+        storage: &dyn Storage,
+        config: &Config,
+        path: impl AsRef<Path>,
+    ) -> Result<Snapshot> {
+        let file = storage.read(path.as_ref())?;
 
         let data = Decoder::new_key(&config.key).decrypt_and_decompress(&file)?;
 
@@ -0,0 +1,148 @@
+//! Compressed data starts with a big endian header ID naming the compressor
+
+use std::io::Read;
+
+use super::error::Error;
+use crate::error::Result;
+
+/// Decompress data including its header
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < 4 {
+        return Err(Error::InvalidCompressedData.into());
+    }
+    let (header, payload) = data.split_at(4);
+    let header = u32::from_be_bytes(header.try_into().unwrap());
+
+    let mut decompressed = Vec::new();
+    // the low byte picks the compression level
+    match header >> 8 {
+        // gzip and pgzip
+        0x10 | 0x13 => {
+            flate2::read::GzDecoder::new(payload).read_to_end(&mut decompressed)?;
+        }
+        0x11 => decompressed = zstd::stream::decode_all(payload)?,
+        0x12 => decompressed = decompress_s2(payload)?,
+        0x14 => {
+            lz4_flex::frame::FrameDecoder::new(payload).read_to_end(&mut decompressed)?;
+        }
+        0x15 => {
+            flate2::read::DeflateDecoder::new(payload).read_to_end(&mut decompressed)?;
+        }
+        _ => return Err(Error::UnsupportedCompression(header).into()),
+    }
+
+    Ok(decompressed)
+}
+
+/// S2 uses the snappy framing format: chunk type | length (3 bytes) | data
+fn decompress_s2(mut data: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return Err(Error::InvalidCompressedData.into());
+        }
+        let chunk_type = data[0];
+        let length = u32::from_le_bytes([data[1], data[2], data[3], 0]) as usize;
+        let chunk = data
+            .get(4..4 + length)
+            .ok_or(Error::InvalidCompressedData)?;
+        data = &data[4 + length..];
+
+        // compressed and uncompressed chunks start with a checksum of the data
+        match chunk_type {
+            0x00 => decompress_s2_block(
+                chunk.get(4..).ok_or(Error::InvalidCompressedData)?,
+                &mut decompressed,
+            )?,
+            0x01 => decompressed.extend(chunk.get(4..).ok_or(Error::InvalidCompressedData)?),
+            // stream identifier, padding and skippable chunks
+            0x80..=0xff => {}
+            _ => return Err(Error::InvalidCompressedData.into()),
+        }
+    }
+
+    Ok(decompressed)
+}
+
+/// A snappy block, with S2's repeat offsets: copies with a zero offset reuse the last offset
+fn decompress_s2_block(block: &[u8], output: &mut Vec<u8>) -> Result<()> {
+    let invalid = || Error::InvalidCompressedData;
+
+    let mut block = block;
+    let mut next = |count: usize| -> Result<&[u8]> {
+        if block.len() < count {
+            return Err(invalid().into());
+        }
+        let (bytes, rest) = block.split_at(count);
+        block = rest;
+        Ok(bytes)
+    };
+    let le = |bytes: &[u8]| bytes.iter().rev().fold(0usize, |v, b| v << 8 | *b as usize);
+
+    // uncompressed length as a varint
+    let mut length = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = next(1)?[0];
+        length |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let start = output.len();
+    let mut offset = 0;
+
+    while output.len() - start < length {
+        let tag = next(1)?[0];
+        let copy_length = match tag & 0x03 {
+            // literal, lengths above 60 are stored in the following bytes
+            0x00 => {
+                let literal_length = match (tag >> 2) as usize {
+                    n @ 0..=59 => n + 1,
+                    n => le(next(n - 59)?) + 1,
+                };
+                output.extend(next(literal_length)?);
+                continue;
+            }
+            0x01 => {
+                let byte = next(1)?[0] as usize;
+                let copy_offset = ((tag as usize & 0xe0) << 3) | byte;
+                let copy_length = (tag as usize >> 2) & 0x07;
+                if copy_offset == 0 {
+                    let repeat_length = match copy_length {
+                        5 => le(next(1)?) + 4,
+                        6 => le(next(2)?) + (1 << 8),
+                        7 => le(next(3)?) + (1 << 16),
+                        n => n,
+                    };
+                    repeat_length + 4
+                } else {
+                    offset = copy_offset;
+                    copy_length + 4
+                }
+            }
+            0x02 => {
+                offset = le(next(2)?);
+                (tag as usize >> 2) + 1
+            }
+            _ => {
+                offset = le(next(4)?);
+                (tag as usize >> 2) + 1
+            }
+        };
+
+        if offset == 0 || offset > output.len() - start {
+            return Err(invalid().into());
+        }
+        // copies may overlap what they write
+        for _ in 0..copy_length {
+            output.push(output[output.len() - offset]);
+        }
+    }
+
+    if output.len() - start != length {
+        return Err(invalid().into());
+    }
+
+    Ok(())
+}
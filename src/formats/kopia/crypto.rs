@@ -0,0 +1,215 @@
+//! Content hashing and encryption, both keyed by secrets from the repository config
+
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, KeyInit, Payload},
+    Aes256Gcm,
+};
+use blake2::{
+    digest::consts::{U16, U32},
+    Blake2bMac, Blake2sMac,
+};
+use chacha20poly1305::{
+    aead::{Aead as _, NewAead},
+    ChaCha20Poly1305,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Sha224, Sha256};
+
+use super::{error::Error, format::RepositoryConfig};
+use crate::error::Result;
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// Contents and blobs are encrypted with the last 16 bytes of their hash as the IV
+const IV_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+enum HashAlgorithm {
+    HmacSha256,
+    HmacSha224,
+    Blake2b256,
+    Blake2s256,
+    Blake2s128,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Encryption {
+    None,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+#[derive(Debug)]
+pub struct Crypter {
+    hash: HashAlgorithm,
+    /// Hashes may be truncated, e.g. `BLAKE2B-256-128`
+    hash_len: usize,
+    hmac_secret: Vec<u8>,
+
+    encryption: Encryption,
+    /// Per content keys are derived from this
+    key_derivation_secret: Vec<u8>,
+}
+
+impl Crypter {
+    pub fn new(config: &RepositoryConfig) -> Result<Self> {
+        let (hash, hash_len) = match config.hash.as_str() {
+            "HMAC-SHA256" => (HashAlgorithm::HmacSha256, 32),
+            "HMAC-SHA256-128" => (HashAlgorithm::HmacSha256, 16),
+            "HMAC-SHA224" => (HashAlgorithm::HmacSha224, 28),
+            "BLAKE2B-256" => (HashAlgorithm::Blake2b256, 32),
+            "BLAKE2B-256-128" => (HashAlgorithm::Blake2b256, 16),
+            "BLAKE2S-256" => (HashAlgorithm::Blake2s256, 32),
+            "BLAKE2S-128" => (HashAlgorithm::Blake2s128, 16),
+            hash => return Err(Error::UnsupportedHash(hash.to_string()).into()),
+        };
+
+        let encryption = match config.encryption.as_str() {
+            "NONE" => Encryption::None,
+            "AES256-GCM-HMAC-SHA256" => Encryption::Aes256Gcm,
+            "CHACHA20-POLY1305-HMAC-SHA256" => Encryption::ChaCha20Poly1305,
+            encryption => return Err(Error::UnsupportedEncryption(encryption.to_string()).into()),
+        };
+
+        // the purpose is used as the salt
+        let mut key_derivation_secret = vec![0u8; 32];
+        Hkdf::<Sha256>::new(Some(b"encryption"), &config.master_key)
+            .expand(&[], &mut key_derivation_secret)
+            .unwrap();
+
+        Ok(Self {
+            hash,
+            hash_len,
+            hmac_secret: config.hmac_secret.clone(),
+            encryption,
+            key_derivation_secret,
+        })
+    }
+
+    /// Hash of some plaintext, content IDs are the hex encoded hash
+    pub fn hash(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut hash = match self.hash {
+            HashAlgorithm::HmacSha256 => mac::<Hmac<Sha256>>(&self.hmac_secret, data)?,
+            HashAlgorithm::HmacSha224 => mac::<Hmac<Sha224>>(&self.hmac_secret, data)?,
+            HashAlgorithm::Blake2b256 => mac::<Blake2bMac<U32>>(&self.hmac_secret, data)?,
+            HashAlgorithm::Blake2s256 => mac::<Blake2sMac<U32>>(&self.hmac_secret, data)?,
+            HashAlgorithm::Blake2s128 => mac::<Blake2sMac<U16>>(&self.hmac_secret, data)?,
+        };
+        hash.truncate(self.hash_len);
+
+        Ok(hash)
+    }
+
+    /// Length of the hashes in bytes, after truncation
+    pub fn hash_len(&self) -> usize {
+        self.hash_len
+    }
+
+    /// Decrypt content or a blob, `hash` is the hash of the plaintext and `name` is only used
+    /// for errors
+    pub fn decrypt(&self, data: &[u8], hash: &[u8], name: &str) -> Result<Vec<u8>> {
+        let integrity_error = || Error::IntegrityCheckFailed(name.to_string());
+        let iv = &hash[hash.len().saturating_sub(IV_SIZE)..];
+
+        let plaintext = match self.encryption {
+            Encryption::None => data.to_vec(),
+            encryption => {
+                if data.len() < NONCE_SIZE + TAG_SIZE {
+                    return Err(integrity_error().into());
+                }
+
+                // every content has its own key, nonce | ciphertext | tag
+                let key = mac::<Hmac<Sha256>>(&self.key_derivation_secret, iv)?;
+                let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+                let payload = Payload {
+                    msg: ciphertext,
+                    aad: iv,
+                };
+
+                match encryption {
+                    Encryption::Aes256Gcm => Aes256Gcm::new(GenericArray::from_slice(&key))
+                        .decrypt(GenericArray::from_slice(nonce), payload)
+                        .map_err(|_| integrity_error())?,
+                    _ => ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key))
+                        .decrypt(
+                            chacha20poly1305::Nonce::from_slice(nonce),
+                            chacha20poly1305::aead::Payload {
+                                msg: payload.msg,
+                                aad: payload.aad,
+                            },
+                        )
+                        .map_err(|_| integrity_error())?,
+                }
+            }
+        };
+
+        Ok(plaintext)
+    }
+
+    /// Check the plaintext against the hash it's stored under
+    pub fn verify(&self, data: &[u8], hash: &[u8], name: &str) -> Result<()> {
+        if self.hash(data)? != hash {
+            return Err(Error::IntegrityCheckFailed(name.to_string()).into());
+        }
+
+        Ok(())
+    }
+}
+
+fn mac<M: Mac + KeyInit>(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut mac = <M as KeyInit>::new_from_slice(key)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crypter() -> Crypter {
+        Crypter::new(&RepositoryConfig {
+            hash: "HMAC-SHA256".to_string(),
+            encryption: "AES256-GCM-HMAC-SHA256".to_string(),
+            ecc: String::new(),
+            ecc_overhead_percent: 0,
+            hmac_secret: (0..32).collect(),
+            master_key: (32..64).collect(),
+            version: 2,
+            index_version: 2,
+        })
+        .unwrap()
+    }
+
+    /// "kopia known answer", the IV is the second half of its hash
+    const HASH: &str = "a21204ac39a90357315e9ef184f1fcee546d5706f4333d387ad46afca0170170";
+    const CONTENT: &str = concat!(
+        "f0f1f2f3f4f5f6f7f8f9fafb36f0caf036fb43ffdb68bf131962e0486c5ef677",
+        "00185b4485490c3a58e006b03fb0",
+    );
+
+    #[test]
+    fn aes_gcm_content() {
+        let crypter = crypter();
+        let hash = hex::decode(HASH).unwrap();
+        assert_eq!(crypter.hash(b"kopia known answer").unwrap(), hash);
+
+        let data = crypter
+            .decrypt(&hex::decode(CONTENT).unwrap(), &hash, HASH)
+            .unwrap();
+        assert_eq!(data, b"kopia known answer");
+        crypter.verify(&data, &hash, HASH).unwrap();
+    }
+
+    #[test]
+    fn truncated_hash() {
+        let crypter = crypter();
+        let hash = hex::decode(HASH).unwrap();
+        assert!(crypter
+            .decrypt(&hex::decode(CONTENT).unwrap(), &hash[..16], HASH)
+            .is_err());
+        assert!(crypter
+            .verify(b"kopia known answer", &hash[16..], HASH)
+            .is_err());
+    }
+}
@@ -0,0 +1,35 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Password required")]
+    PasswordRequired,
+    #[error("Invalid password")]
+    InvalidPassword,
+    #[error("Unsupported key derivation algorithm: {0}")]
+    UnsupportedKeyDerivation(String),
+    #[error("Unsupported hash algorithm: {0}")]
+    UnsupportedHash(String),
+    #[error("Unsupported encryption algorithm: {0}")]
+    UnsupportedEncryption(String),
+    #[error("Unsupported error correction: {0}")]
+    UnsupportedEcc(String),
+    #[error("Unsupported compression: {0:#06x}")]
+    UnsupportedCompression(u32),
+    #[error("Invalid compressed data")]
+    InvalidCompressedData,
+    #[error("Unsupported index version: {0}")]
+    UnsupportedIndexVersion(u8),
+    #[error("Invalid index blob: {0}")]
+    InvalidIndex(String),
+    #[error("Invalid object ID: {0}")]
+    InvalidObjectId(String),
+    #[error("Blob not found in repository: {0}")]
+    BlobNotFound(String),
+    #[error("Content not found in index: {0}")]
+    ContentNotFound(String),
+    #[error("Integrity check failed for {0}")]
+    IntegrityCheckFailed(String),
+    #[error("Unexpected object stream {1:?} in {0}")]
+    UnexpectedStream(String, String),
+}
@@ -0,0 +1,141 @@
+//! `kopia.repository`: the repository parameters, encrypted with a key derived from the password
+
+use std::path::Path;
+
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, KeyInit, Payload},
+    Aes256Gcm,
+};
+use hkdf::Hkdf;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use super::error::Error;
+use crate::{error::Result, storage::Storage, utils::from_b64};
+
+/// The unencrypted part of the format blob
+#[derive(Deserialize, Debug)]
+struct FormatBlob {
+    #[serde(rename = "uniqueID", deserialize_with = "from_b64")]
+    unique_id: Vec<u8>,
+    #[serde(rename = "keyAlgo")]
+    key_algorithm: String,
+    #[serde(
+        rename = "encryptedBlockFormat",
+        default,
+        deserialize_with = "from_b64"
+    )]
+    encrypted_format: Vec<u8>,
+    /// Only used by repositories that were created without encryption
+    #[serde(rename = "blockFormat")]
+    format: Option<RepositoryConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EncryptedRepositoryConfig {
+    format: RepositoryConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RepositoryConfig {
+    pub hash: String,
+    pub encryption: String,
+    #[serde(default)]
+    pub ecc: String,
+    #[serde(default)]
+    pub ecc_overhead_percent: u32,
+    /// Key of the keyed content hash
+    #[serde(rename = "secret", default, deserialize_with = "from_b64")]
+    pub hmac_secret: Vec<u8>,
+    /// Only set if the password can be changed, otherwise the key derived from the password is
+    /// the master key
+    #[serde(default, deserialize_with = "from_b64")]
+    pub master_key: Vec<u8>,
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub index_version: u32,
+}
+
+impl RepositoryConfig {
+    pub fn from_file(
+        storage: &dyn Storage,
+        path: impl AsRef<Path>,
+        password: Option<&str>,
+    ) -> Result<Self> {
+        let file = storage.read(path.as_ref())?;
+
+        // local copies of the blob carry a checksum after the JSON, only read the first value
+        let blob: FormatBlob = serde_json::Deserializer::from_slice(&file)
+            .into_iter()
+            .next()
+            .ok_or(Error::IntegrityCheckFailed("kopia.repository".into()))??;
+
+        if let Some(config) = blob.format {
+            return Ok(config);
+        }
+
+        let password = password.ok_or(Error::PasswordRequired)?;
+        let key = derive_key(&blob.key_algorithm, password, &blob.unique_id)?;
+
+        // AES-256-GCM: nonce | ciphertext | tag, keys derived from the master key and the
+        // repository ID
+        let derive = |purpose: &[u8]| {
+            let mut output = [0u8; 32];
+            Hkdf::<Sha256>::new(Some(&blob.unique_id), &key)
+                .expand(purpose, &mut output)
+                .unwrap();
+            output
+        };
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&derive(b"AES")));
+        if blob.encrypted_format.len() < 12 {
+            return Err(Error::IntegrityCheckFailed("kopia.repository".into()).into());
+        }
+        let (nonce, ciphertext) = blob.encrypted_format.split_at(12);
+        let plaintext = cipher
+            .decrypt(
+                GenericArray::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &derive(b"CHECKSUM"),
+                },
+            )
+            .map_err(|_| Error::InvalidPassword)?;
+
+        let mut config = serde_json::from_slice::<EncryptedRepositoryConfig>(&plaintext)?.format;
+        if config.master_key.is_empty() {
+            config.master_key = key;
+        }
+        trace!(
+            "Format: hash {}, encryption {}, version {}, index version {}",
+            config.hash,
+            config.encryption,
+            config.version,
+            config.index_version
+        );
+
+        if !config.ecc.is_empty() && config.ecc_overhead_percent > 0 {
+            return Err(Error::UnsupportedEcc(config.ecc).into());
+        }
+
+        Ok(config)
+    }
+}
+
+/// Derive the key protecting the format blob, the repository ID is the salt
+fn derive_key(algorithm: &str, password: &str, salt: &[u8]) -> Result<Vec<u8>> {
+    let mut key = vec![0u8; 32];
+    match algorithm {
+        "scrypt-65536-8-1" => {
+            let params = scrypt::Params::new(16, 8, 1, key.len())?;
+            scrypt::scrypt(password.as_bytes(), salt, &params, &mut key)?;
+        }
+        "pbkdf2-sha256-600000" => {
+            pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, 600_000, &mut key);
+        }
+        algorithm => return Err(Error::UnsupportedKeyDerivation(algorithm.to_string()).into()),
+    }
+
+    Ok(key)
+}
@@ -0,0 +1,193 @@
+//! Index blobs map every content to the pack blob it's stored in
+
+use std::collections::HashMap;
+
+use super::error::Error;
+use crate::error::Result;
+
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub timestamp: i64,
+    pub deleted: bool,
+    pub pack: String,
+    pub offset: u32,
+    pub packed_length: u32,
+    /// Header ID of the compressor, 0 if the content isn't compressed
+    pub compression: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Index {
+    entries: HashMap<String, IndexEntry>,
+}
+
+impl Index {
+    /// Merge an index blob, the newest entry of a content wins
+    pub fn add_blob(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let entries = match data.first() {
+            Some(1) => parse_v1(name, data)?,
+            Some(2) => parse_v2(name, data)?,
+            Some(version) => return Err(Error::UnsupportedIndexVersion(*version).into()),
+            None => return Err(Error::InvalidIndex(name.to_string()).into()),
+        };
+
+        for (id, entry) in entries {
+            match self.entries.get(&id) {
+                // deleted entries lose against live ones of the same age
+                Some(existing)
+                    if (existing.timestamp, !existing.deleted)
+                        >= (entry.timestamp, !entry.deleted) => {}
+                _ => {
+                    self.entries.insert(id, entry);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Look up a content that hasn't been deleted
+    pub fn get(&self, id: &str) -> Option<&IndexEntry> {
+        self.entries.get(id).filter(|entry| !entry.deleted)
+    }
+
+    /// IDs of the contents that haven't been deleted
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| !entry.deleted)
+            .map(|(id, _)| id.as_str())
+    }
+}
+
+fn be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |v, b| v << 8 | *b as u64)
+}
+
+/// Keys are the hash, prefixed with the ID prefix or a zero byte
+fn content_id(key: &[u8]) -> String {
+    match key.split_first() {
+        Some((0, hash)) => hex::encode(hash),
+        Some((prefix, hash)) => format!("{}{}", *prefix as char, hex::encode(hash)),
+        None => String::new(),
+    }
+}
+
+/// Fixed size entries: key | timestamp (6 bytes) | format version | pack name length | pack name
+/// offset | pack offset and deleted flag | packed length
+fn parse_v1(name: &str, data: &[u8]) -> Result<Vec<(String, IndexEntry)>> {
+    let invalid = || Error::InvalidIndex(name.to_string());
+
+    let header = data.get(..8).ok_or_else(invalid)?;
+    let key_size = header[1] as usize;
+    let entry_size = be(&header[2..4]) as usize;
+    let count = be(&header[4..8]) as usize;
+    if entry_size < 20 {
+        return Err(invalid().into());
+    }
+
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let start = 8 + i * (key_size + entry_size);
+        let record = data
+            .get(start..start + key_size + entry_size)
+            .ok_or_else(invalid)?;
+        let (key, entry) = record.split_at(key_size);
+
+        let name_length = entry[7] as usize;
+        let name_offset = be(&entry[8..12]) as usize;
+        let pack = data
+            .get(name_offset..name_offset + name_length)
+            .ok_or_else(invalid)?;
+
+        entries.push((
+            content_id(key),
+            IndexEntry {
+                timestamp: be(&entry[..6]) as i64,
+                deleted: entry[12] & 0x80 != 0,
+                pack: String::from_utf8_lossy(pack).to_string(),
+                offset: be(&entry[12..16]) as u32 & 0x7fff_ffff,
+                packed_length: be(&entry[16..20]) as u32,
+                compression: 0,
+            },
+        ));
+    }
+
+    Ok(entries)
+}
+
+/// Entries are followed by the pack names and the content formats, entries refer to both by
+/// their position
+fn parse_v2(name: &str, data: &[u8]) -> Result<Vec<(String, IndexEntry)>> {
+    let invalid = || Error::InvalidIndex(name.to_string());
+
+    // version | key size | entry size | entry count | pack count | format count | base timestamp
+    let header = data.get(..17).ok_or_else(invalid)?;
+    let key_size = header[1] as usize;
+    let entry_size = be(&header[2..4]) as usize;
+    let count = be(&header[4..8]) as usize;
+    let pack_count = be(&header[8..12]) as usize;
+    let format_count = header[12] as usize;
+    let base_timestamp = be(&header[13..17]) as i64;
+    if entry_size < 16 {
+        return Err(invalid().into());
+    }
+
+    let packs_offset = 17 + count * (key_size + entry_size);
+    let formats_offset = packs_offset + pack_count * 5;
+
+    // each pack is name length | name offset
+    let packs = (0..pack_count)
+        .map(|i| {
+            let pack = data
+                .get(packs_offset + i * 5..packs_offset + (i + 1) * 5)
+                .ok_or_else(invalid)?;
+            let offset = be(&pack[1..]) as usize;
+            let name = data
+                .get(offset..offset + pack[0] as usize)
+                .ok_or_else(invalid)?;
+            Ok(String::from_utf8_lossy(name).to_string())
+        })
+        .collect::<Result<Vec<String>>>()?;
+
+    // each format is compression header | format version | encryption key ID
+    let formats = (0..format_count)
+        .map(|i| {
+            let format = data
+                .get(formats_offset + i * 6..formats_offset + (i + 1) * 6)
+                .ok_or_else(invalid)?;
+            Ok(be(&format[..4]) as u32)
+        })
+        .collect::<Result<Vec<u32>>>()?;
+
+    // timestamp | pack offset and deleted flag | original length (3 bytes) | packed length
+    // (3 bytes) | pack index (2 bytes), optionally followed by the format index, the high bits
+    // of the pack index and the high bits of the lengths (original in the upper nibble)
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let start = 17 + i * (key_size + entry_size);
+        let record = data
+            .get(start..start + key_size + entry_size)
+            .ok_or_else(invalid)?;
+        let (key, entry) = record.split_at(key_size);
+
+        let format = entry.get(16).copied().unwrap_or(0) as usize;
+        let pack =
+            be(&entry[14..16]) as usize | (entry.get(17).copied().unwrap_or(0) as usize) << 16;
+        let high_bits = entry.get(18).copied().unwrap_or(0) as u32;
+
+        entries.push((
+            content_id(key),
+            IndexEntry {
+                timestamp: base_timestamp + be(&entry[..4]) as i64,
+                deleted: entry[4] & 0x80 != 0,
+                pack: packs.get(pack).ok_or_else(invalid)?.clone(),
+                offset: be(&entry[4..8]) as u32 & 0x7fff_ffff,
+                packed_length: be(&entry[11..14]) as u32 | (high_bits & 0x0f) << 24,
+                compression: formats.get(format).copied().unwrap_or(0),
+            },
+        ));
+    }
+
+    Ok(entries)
+}
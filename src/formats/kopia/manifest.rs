@@ -0,0 +1,99 @@
+//! Manifests hold snapshots and policies, they're stored as gzipped JSON in `m` contents
+
+use std::{collections::HashMap, io::Read};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::Deserialize;
+
+use super::object::DirEntry;
+use crate::{error::Result, utils::from_datetime};
+
+#[derive(Deserialize, Debug)]
+struct ManifestContent {
+    entries: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ManifestEntry {
+    pub id: String,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(deserialize_with = "from_datetime")]
+    pub modified: DateTime<FixedOffset>,
+    #[serde(default)]
+    pub deleted: bool,
+    /// Missing for deleted entries
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Source {
+    pub host: String,
+    pub user_name: String,
+    pub path: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotManifest {
+    pub source: Source,
+    #[serde(deserialize_with = "from_datetime")]
+    pub start_time: DateTime<FixedOffset>,
+    pub root_entry: Option<DirEntry>,
+}
+
+#[derive(Debug)]
+pub struct Snapshot {
+    pub id: String,
+    pub source: Source,
+    pub time: DateTime<Utc>,
+    pub root: DirEntry,
+}
+
+/// Parse a manifest content, older ones aren't compressed
+pub fn parse_entries(data: &[u8]) -> Result<Vec<ManifestEntry>> {
+    let json = if data.starts_with(&[0x1f, 0x8b]) {
+        let mut json = Vec::new();
+        flate2::read::GzDecoder::new(data).read_to_end(&mut json)?;
+        json
+    } else {
+        data.to_vec()
+    };
+
+    Ok(serde_json::from_slice::<ManifestContent>(&json)?.entries)
+}
+
+/// Manifest entries are rewritten when manifests are compacted, only the latest version of an
+/// entry counts
+pub fn latest_entries(entries: Vec<ManifestEntry>) -> Vec<ManifestEntry> {
+    let mut latest: HashMap<String, ManifestEntry> = HashMap::new();
+    for entry in entries {
+        match latest.get(&entry.id) {
+            Some(existing) if existing.modified >= entry.modified => {}
+            _ => {
+                latest.insert(entry.id.clone(), entry);
+            }
+        }
+    }
+
+    latest
+        .into_values()
+        .filter(|entry| !entry.deleted)
+        .collect()
+}
+
+impl Snapshot {
+    /// Snapshots without a root entry never finished and are skipped
+    pub fn from_entry(entry: &ManifestEntry) -> Result<Option<Self>> {
+        let manifest: SnapshotManifest = serde_json::from_value(entry.data.clone())?;
+
+        Ok(manifest.root_entry.map(|root| Snapshot {
+            id: entry.id.clone(),
+            source: manifest.source,
+            time: manifest.start_time.with_timezone(&Utc),
+            root,
+        }))
+    }
+}
@@ -0,0 +1,449 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, ChunkRef, SnapshotInfo},
+    storage::Storage,
+};
+use crypto::Crypter;
+use error::Error;
+use format::RepositoryConfig;
+use index::Index;
+use manifest::Snapshot;
+use object::{content_hash, DirEntry, Directory, IndirectObject, ObjectId};
+
+mod compression;
+mod crypto;
+mod format;
+mod index;
+mod manifest;
+mod object;
+
+pub mod error;
+
+/// Prefixes of the blobs holding the content index: legacy, epoch, compacted and checkpoints
+const INDEX_PREFIXES: [&str; 4] = ["n", "xn", "xs", "xr"];
+
+#[derive(Debug)]
+pub struct Kopia {
+    pub path: PathBuf,
+    storage: Rc<dyn Storage>,
+
+    /// Blob IDs and the files storing them
+    blobs: HashMap<String, PathBuf>,
+    crypter: Crypter,
+    index: Index,
+
+    snapshots: Vec<Snapshot>,
+    /// Decoded directory objects, most directories are shared between snapshots
+    directories: RefCell<HashMap<String, Rc<Directory>>>,
+}
+
+impl Kopia {
+    pub fn from_folder(
+        storage: Rc<dyn Storage>,
+        path: impl Into<PathBuf>,
+        password: impl Into<Option<String>>,
+    ) -> Result<Self> {
+        let path = path.into();
+        let password = password.into();
+
+        let config = RepositoryConfig::from_file(
+            &*storage,
+            path.join("kopia.repository.f"),
+            password.as_deref(),
+        )?;
+        let crypter = Crypter::new(&config)?;
+
+        let mut blobs = HashMap::new();
+        list_blobs(&*storage, &path, "", &mut blobs)?;
+
+        let mut index = Index::default();
+        for (id, blob_path) in &blobs {
+            if !INDEX_PREFIXES.iter().any(|prefix| id.starts_with(prefix)) {
+                continue;
+            }
+            trace!("Loading index blob {id}");
+
+            // the blob ID ends with the hash of its plaintext, followed by an optional session
+            let name = id.split('-').next().unwrap_or_default();
+            let hash = name
+                .len()
+                .checked_sub(crypter.hash_len() * 2)
+                .and_then(|start| hex::decode(&name[start..]).ok())
+                .ok_or_else(|| Error::InvalidIndex(id.clone()))?;
+
+            let data = crypter.decrypt(&storage.read(blob_path)?, &hash, id)?;
+            crypter.verify(&data, &hash, id)?;
+            index.add_blob(id, &data)?;
+        }
+
+        Ok(Self {
+            path,
+            storage,
+            blobs,
+            crypter,
+            index,
+            snapshots: Vec::new(),
+            directories: RefCell::default(),
+        })
+    }
+
+    /// Load every snapshot manifest
+    pub fn load_all_snapshots(&mut self) -> Result<()> {
+        let mut entries = Vec::new();
+        for id in self.index.ids().filter(|id| id.starts_with('m')) {
+            entries.extend(manifest::parse_entries(&self.read_content(id)?)?);
+        }
+
+        for entry in manifest::latest_entries(entries) {
+            if entry.labels.get("type").map(String::as_str) != Some("snapshot") {
+                continue;
+            }
+
+            if let Some(snapshot) = Snapshot::from_entry(&entry)? {
+                self.snapshots.push(snapshot);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load everything
+    pub fn load_all(&mut self) -> Result<()> {
+        self.load_all_snapshots()?;
+        Ok(())
+    }
+
+    /// Read a content from its pack, decrypt, decompress and verify it
+    fn read_content(&self, id: &str) -> Result<Vec<u8>> {
+        let entry = self
+            .index
+            .get(id)
+            .ok_or_else(|| Error::ContentNotFound(id.to_string()))?;
+        let path = self
+            .blobs
+            .get(&entry.pack)
+            .ok_or_else(|| Error::BlobNotFound(entry.pack.clone()))?;
+
+        let data =
+            self.storage
+                .read_range(path, entry.offset as u64, entry.packed_length as u64)?;
+
+        let hash = content_hash(id)?;
+        let mut data = self.crypter.decrypt(&data, &hash, id)?;
+        if entry.compression != 0 {
+            data = compression::decompress(&data)?;
+        }
+        self.crypter.verify(&data, &hash, id)?;
+
+        Ok(data)
+    }
+
+    /// Read a whole object, following indirect objects to their parts
+    fn read_object(&self, id: &ObjectId) -> Result<Vec<u8>> {
+        match id {
+            ObjectId::Direct {
+                content,
+                compressed,
+            } => {
+                let data = self.read_content(content)?;
+                match compressed {
+                    true => compression::decompress(&data),
+                    false => Ok(data),
+                }
+            }
+            ObjectId::Indirect(_) => {
+                let mut data = Vec::new();
+                for part in self.indirect_parts(id)? {
+                    data.extend(self.read_object(&ObjectId::parse(&part.object)?)?);
+                }
+                Ok(data)
+            }
+        }
+    }
+
+    /// The parts of an indirect object, in order
+    fn indirect_parts(&self, id: &ObjectId) -> Result<Vec<object::IndirectEntry>> {
+        let ObjectId::Indirect(list) = id else {
+            return Ok(Vec::new());
+        };
+
+        let indirect: IndirectObject = serde_json::from_slice(&self.read_object(list)?)?;
+        if indirect.stream != "kopia:indirect" {
+            return Err(Error::UnexpectedStream(id.to_string(), indirect.stream).into());
+        }
+
+        Ok(indirect.entries)
+    }
+
+    /// Load a directory object, directories are cached so walking many snapshots only decodes
+    /// each once
+    fn load_directory(&self, id: &str) -> Result<Rc<Directory>> {
+        if let Some(directory) = self.directories.borrow().get(id) {
+            return Ok(directory.clone());
+        }
+
+        let directory: Directory =
+            serde_json::from_slice(&self.read_object(&ObjectId::parse(id)?)?)?;
+        if directory.stream != "kopia:directory" {
+            return Err(Error::UnexpectedStream(id.to_string(), directory.stream).into());
+        }
+
+        let directory = Rc::new(directory);
+        self.directories
+            .borrow_mut()
+            .insert(id.to_string(), directory.clone());
+        Ok(directory)
+    }
+
+    /// Chunks of a file object, the parts of indirect objects become the chunks
+    fn file_chunks(&self, entry: &DirEntry) -> Result<Vec<ChunkRef>> {
+        let id = ObjectId::parse(&entry.obj)?;
+        if let ObjectId::Direct { .. } = id {
+            let mut chunk = ChunkRef::whole(entry.obj.clone());
+            chunk.size = entry.size.map(|size| size as usize);
+            return Ok(vec![chunk]);
+        }
+
+        let mut chunks = Vec::new();
+        for part in self.indirect_parts(&id)? {
+            match ObjectId::parse(&part.object)? {
+                ObjectId::Direct { .. } => {
+                    let mut chunk = ChunkRef::whole(part.object);
+                    chunk.size = Some(part.length as usize);
+                    chunks.push(chunk);
+                }
+                // nested indirection, only used by huge files
+                _ => chunks.extend(self.file_chunks(&DirEntry {
+                    obj: part.object,
+                    size: Some(part.length),
+                    ..entry.clone()
+                })?),
+            }
+        }
+
+        Ok(chunks)
+    }
+
+    /// Convert a directory entry, reading the target of symlinks
+    fn to_tree_node(&self, entry: &DirEntry, path: PathBuf) -> Result<tree::Node> {
+        let link_target = match entry.is_symlink() {
+            true => Some(
+                String::from_utf8_lossy(&self.read_object(&ObjectId::parse(&entry.obj)?)?)
+                    .to_string(),
+            ),
+            false => None,
+        };
+
+        let mut node = entry.to_tree_node(path, link_target);
+        if node.kind == tree::NodeKind::File {
+            node.chunks = self.file_chunks(entry)?;
+        }
+
+        Ok(node)
+    }
+
+    /// Walk a directory object and all of its subdirectories, depth first
+    fn walk_directory(&self, id: &str, parent: &Path, nodes: &mut Vec<tree::Node>) -> Result<()> {
+        let directory = self.load_directory(id)?;
+
+        for entry in &directory.entries {
+            let path = parent.join(&entry.name);
+            nodes.push(self.to_tree_node(entry, path.clone())?);
+
+            if entry.is_dir() {
+                self.walk_directory(&entry.obj, &path, nodes)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Collect the contents an object is made of, including the lists of indirect objects
+    fn collect_object(&self, id: &ObjectId, contents: &mut HashSet<String>) -> Result<()> {
+        match id {
+            ObjectId::Direct { content, .. } => {
+                contents.insert(content.clone());
+            }
+            ObjectId::Indirect(list) => {
+                self.collect_object(list, contents)?;
+                for part in self.indirect_parts(id)? {
+                    self.collect_object(&ObjectId::parse(&part.object)?, contents)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Collect the contents of a directory and of everything below it, directories that have
+    /// already been visited are skipped
+    fn collect_directory(
+        &self,
+        id: &str,
+        contents: &mut HashSet<String>,
+        visited: &mut HashSet<String>,
+    ) -> Result<()> {
+        if !visited.insert(id.to_string()) {
+            return Ok(());
+        }
+
+        self.collect_object(&ObjectId::parse(id)?, contents)?;
+        for entry in &self.load_directory(id)?.entries {
+            match entry.is_dir() {
+                true => self.collect_directory(&entry.obj, contents, visited)?,
+                false => self.collect_object(&ObjectId::parse(&entry.obj)?, contents)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The storage splits long blob IDs into nested directories, the ID is the concatenated path
+/// without the `.f` suffix
+fn list_blobs(
+    storage: &dyn Storage,
+    dir: &Path,
+    prefix: &str,
+    blobs: &mut HashMap<String, PathBuf>,
+) -> Result<()> {
+    for entry in storage.list(dir)? {
+        if entry.stat.is_dir {
+            list_blobs(
+                storage,
+                &entry.path,
+                &format!("{prefix}{}", entry.name),
+                blobs,
+            )?;
+        } else if let Some(id) = entry.name.strip_suffix(".f") {
+            blobs.insert(format!("{prefix}{id}"), entry.path);
+        }
+    }
+
+    Ok(())
+}
+
+impl Backup for Kopia {
+    /// Snapshots are identified by their manifest ID, grouped by their source
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        let mut snapshots: Vec<SnapshotInfo> = self
+            .snapshots
+            .iter()
+            .map(|snapshot| SnapshotInfo {
+                id: snapshot.id.clone(),
+                group: format!(
+                    "{}@{}:{}",
+                    snapshot.source.user_name, snapshot.source.host, snapshot.source.path
+                ),
+                time: snapshot.time,
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+
+        snapshots
+    }
+
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let id = self.find_snapshot(snapshot)?.id;
+        let snapshot = self.snapshots.iter().find(|s| s.id == id).unwrap();
+
+        let mut nodes = Vec::new();
+        match snapshot.root.is_dir() {
+            true => self.walk_directory(&snapshot.root.obj, Path::new(""), &mut nodes)?,
+            // snapshots of a single file
+            false => {
+                let name = Path::new(&snapshot.source.path)
+                    .file_name()
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| PathBuf::from(&snapshot.root.name));
+                nodes.push(self.to_tree_node(&snapshot.root, name)?);
+            }
+        }
+
+        Ok(nodes)
+    }
+
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        self.read_object(&ObjectId::parse(id)?)
+    }
+
+    /// Contents share pack blobs, only the content itself is counted
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        let mut contents = HashSet::new();
+        self.collect_object(&ObjectId::parse(id)?, &mut contents)?;
+
+        contents
+            .iter()
+            .map(|content| {
+                let entry = self
+                    .index
+                    .get(content)
+                    .ok_or_else(|| Error::ContentNotFound(content.to_string()))?;
+                Ok(entry.packed_length as u64)
+            })
+            .sum()
+    }
+
+    /// Pack blobs, `p` for file contents and `q` for metadata, the other blobs are repository
+    /// metadata
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = self
+            .storage
+            .list(&self.path)
+            .into_iter()
+            .flatten()
+            .filter(|entry| entry.stat.is_dir)
+            .filter(|entry| entry.name.starts_with('p') || entry.name.starts_with('q'))
+            .map(|entry| entry.path)
+            .collect();
+        dirs.sort();
+
+        dirs
+    }
+
+    /// A pack is referenced as long as one of its contents is, manifests are always referenced
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        let mut contents: HashSet<String> = self
+            .index
+            .ids()
+            .filter(|id| id.starts_with('m'))
+            .map(String::from)
+            .collect();
+
+        let mut visited = HashSet::new();
+        for snapshot in &self.snapshots {
+            match snapshot.root.is_dir() {
+                true => self.collect_directory(&snapshot.root.obj, &mut contents, &mut visited)?,
+                false => {
+                    self.collect_object(&ObjectId::parse(&snapshot.root.obj)?, &mut contents)?
+                }
+            }
+        }
+
+        contents
+            .iter()
+            .map(|content| {
+                let entry = self
+                    .index
+                    .get(content)
+                    .ok_or_else(|| Error::ContentNotFound(content.to_string()))?;
+                let path = self
+                    .blobs
+                    .get(&entry.pack)
+                    .ok_or_else(|| Error::BlobNotFound(entry.pack.clone()))?;
+                Ok(path.clone())
+            })
+            .collect()
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+}
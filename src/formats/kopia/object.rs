@@ -0,0 +1,145 @@
+//! Objects are files and directory listings, small ones are stored in a single content while
+//! large ones are split and listed in an indirect object
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use super::error::Error;
+use crate::{
+    error::Result,
+    formats::tree::{self, NodeKind},
+    utils::from_go_mode,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ObjectId {
+    /// A single content, optionally compressed at the object level
+    Direct { content: String, compressed: bool },
+    /// The listed object holds the list of parts
+    Indirect(Box<ObjectId>),
+}
+
+impl ObjectId {
+    /// `I` marks each level of indirection, `Z` compression and `D` is a legacy directory marker
+    pub fn parse(id: &str) -> Result<Self> {
+        if let Some(rest) = id.strip_prefix('I') {
+            return Ok(ObjectId::Indirect(Box::new(ObjectId::parse(rest)?)));
+        }
+
+        let (compressed, rest) = match id.strip_prefix('Z') {
+            Some(rest) => (true, rest),
+            None => (false, id),
+        };
+        let content = rest.strip_prefix('D').unwrap_or(rest);
+
+        if content.is_empty() || content_hash(content).is_err() {
+            return Err(Error::InvalidObjectId(id.to_string()).into());
+        }
+
+        Ok(ObjectId::Direct {
+            content: content.to_string(),
+            compressed,
+        })
+    }
+}
+
+impl std::fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectId::Direct {
+                content,
+                compressed: true,
+            } => write!(f, "Z{content}"),
+            ObjectId::Direct { content, .. } => write!(f, "{content}"),
+            ObjectId::Indirect(list) => write!(f, "I{list}"),
+        }
+    }
+}
+
+/// Hash part of a content ID, which is an optional single letter prefix followed by the hex
+/// encoded hash
+pub fn content_hash(content: &str) -> Result<Vec<u8>> {
+    let hash = match content.len() % 2 {
+        1 => &content[1..],
+        _ => content,
+    };
+    Ok(hex::decode(hash)?)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IndirectObject {
+    pub stream: String,
+    pub entries: Vec<IndirectEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IndirectEntry {
+    #[serde(rename = "l")]
+    pub length: u64,
+    #[serde(rename = "o")]
+    pub object: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Directory {
+    pub stream: String,
+    pub entries: Vec<DirEntry>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub entry_type: String,
+    /// Octal go `fs.FileMode` permission bits
+    pub mode: Option<String>,
+    pub size: Option<u64>,
+    pub mtime: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub obj: String,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.entry_type == "d"
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.entry_type == "s"
+    }
+
+    /// Convert into the format independent representation, symlink targets are stored in their
+    /// object and have to be passed in
+    pub fn to_tree_node(&self, path: PathBuf, link_target: Option<String>) -> tree::Node {
+        let kind = match self.entry_type.as_str() {
+            "f" => NodeKind::File,
+            "d" => NodeKind::Dir,
+            "s" => NodeKind::Symlink {
+                target: link_target.unwrap_or_default(),
+            },
+            _ => NodeKind::Special,
+        };
+
+        let mut node = tree::Node::new(path, kind.clone());
+        node.mode = self
+            .mode
+            .as_deref()
+            .and_then(|mode| u64::from_str_radix(mode, 8).ok())
+            .map(from_go_mode);
+        node.uid = self.uid;
+        node.gid = self.gid;
+        node.mtime = self
+            .mtime
+            .as_deref()
+            .and_then(|mtime| DateTime::parse_from_rfc3339(mtime).ok())
+            .map(|mtime| mtime.with_timezone(&Utc));
+        if kind == NodeKind::File {
+            node.size = self.size;
+        }
+
+        node
+    }
+}
@@ -1,9 +1,29 @@
 pub mod blobbackup;
+pub mod borg;
+pub mod bup;
+pub mod bupstash;
+pub mod casync;
+pub mod detect;
 pub mod duplicacy;
+pub mod duplicati;
+pub mod duplicity;
 pub mod knoxite;
+pub mod kopia;
+pub mod pbs;
 pub mod restic;
+pub mod tree;
 
 pub use blobbackup::BlobBackup;
+pub use borg::Borg;
+pub use bup::Bup;
+pub use bupstash::Bupstash;
+pub use casync::Casync;
+pub use detect::BackupFormat;
 pub use duplicacy::Duplicacy;
+pub use duplicati::Duplicati;
+pub use duplicity::Duplicity;
 pub use knoxite::Knoxite;
+pub use kopia::Kopia;
+pub use pbs::Pbs;
 pub use restic::Restic;
+pub use tree::Backup;
@@ -0,0 +1,93 @@
+//! Data blobs: the container of chunks and of the small files of a snapshot, optionally
+//! compressed and encrypted
+
+use super::{
+    error::Error,
+    key::{self, CryptConfig},
+};
+use crate::error::Result;
+
+// the first 8 bytes of the SHA-256 of a description, e.g. `Proxmox Backup uncompressed blob v1.0`
+const UNCOMPRESSED_BLOB_MAGIC: [u8; 8] = [66, 171, 56, 7, 190, 131, 112, 161];
+const COMPRESSED_BLOB_MAGIC: [u8; 8] = [49, 185, 88, 66, 111, 182, 163, 127];
+const ENCRYPTED_BLOB_MAGIC: [u8; 8] = [123, 103, 133, 190, 34, 45, 76, 240];
+const ENCR_COMPR_BLOB_MAGIC: [u8; 8] = [230, 89, 27, 191, 11, 191, 216, 11];
+
+/// Whether the blob can only be read with the key
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(&ENCRYPTED_BLOB_MAGIC) || data.starts_with(&ENCR_COMPR_BLOB_MAGIC)
+}
+
+/// Decode a blob: magic | CRC32 of the payload, followed by the IV and tag if it's encrypted.
+/// `name` is only used for errors.
+pub fn decode(data: &[u8], crypt: Option<&CryptConfig>, name: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::InvalidBlob(name.to_string());
+
+    let magic = data.get(..8).ok_or_else(invalid)?;
+    let (compressed, encrypted) = match magic.try_into().unwrap() {
+        UNCOMPRESSED_BLOB_MAGIC => (false, false),
+        COMPRESSED_BLOB_MAGIC => (true, false),
+        ENCRYPTED_BLOB_MAGIC => (false, true),
+        ENCR_COMPR_BLOB_MAGIC => (true, true),
+        _ => return Err(invalid().into()),
+    };
+
+    let header_size = match encrypted {
+        true => 44,
+        false => 12,
+    };
+    let payload = data.get(header_size..).ok_or_else(invalid)?;
+    let crc = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    if crc32fast::hash(payload) != crc {
+        return Err(Error::IntegrityCheckFailed(name.to_string()).into());
+    }
+
+    let mut plaintext = match encrypted {
+        true => {
+            let crypt = crypt.ok_or_else(|| Error::KeyfileNotFound(name.to_string()))?;
+            key::decrypt(&crypt.enc_key, &data[12..28], &data[28..44], payload)
+                .ok_or_else(|| Error::IntegrityCheckFailed(name.to_string()))?
+        }
+        false => payload.to_vec(),
+    };
+    if compressed {
+        plaintext = zstd::stream::decode_all(&plaintext[..])?;
+    }
+
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "pbs known answer" compressed with zstd and encrypted with a key of counting bytes
+    const BLOB: &str = concat!(
+        "e6591bbf0bbfd80b8dba38a5e0e1e2e3e4e5e6e7e8e9eaebecedeeefaa338452",
+        "49b4dacd465026dfb35123349cbdcdc0d97b2e5055c5c2e85ec5210f5f6fd9b8",
+        "e1d0d0d448",
+    );
+    const DIGEST: &str = "fa292780ae2e848cb10123f679304980d8d05af86719810bb2a206ab0ea65c9a";
+
+    #[test]
+    fn encrypted_compressed() {
+        let crypt = CryptConfig::new((0..32).collect());
+        let data = hex::decode(BLOB).unwrap();
+        assert!(is_encrypted(&data));
+
+        let plaintext = decode(&data, Some(&crypt), DIGEST).unwrap();
+        assert_eq!(plaintext, b"pbs known answer");
+        assert_eq!(hex::encode(crypt.compute_digest(&plaintext)), DIGEST);
+    }
+
+    #[test]
+    fn corrupted() {
+        let crypt = CryptConfig::new((0..32).collect());
+        let mut data = hex::decode(BLOB).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decode(&data, Some(&crypt), DIGEST),
+            Err(crate::error::Error::Pbs(Error::IntegrityCheckFailed(_)))
+        ));
+    }
+}
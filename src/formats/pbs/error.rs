@@ -0,0 +1,23 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Password required")]
+    PasswordRequired,
+    #[error("Invalid password")]
+    InvalidPassword,
+    #[error("No keyfile found for encrypted data in {0}, set PBS_KEYFILE to point to it")]
+    KeyfileNotFound(String),
+    #[error("Invalid keyfile: {0}")]
+    InvalidKeyfile(String),
+    #[error("Invalid blob: {0}")]
+    InvalidBlob(String),
+    #[error("Invalid index: {0}")]
+    InvalidIndex(String),
+    #[error("Integrity check failed for {0}")]
+    IntegrityCheckFailed(String),
+    #[error("Invalid pxar archive {0}: {1}")]
+    InvalidArchive(String, String),
+    #[error("Unexpected item {1:#018x} in pxar archive {0}")]
+    UnexpectedItem(String, u64),
+}
@@ -0,0 +1,140 @@
+//! Index files list the chunks of an archive: `.didx` for variable sized chunks of a stream,
+//! `.fidx` for fixed sized chunks of an image
+
+use std::path::Path;
+
+use super::error::Error;
+use crate::{error::Result, formats::tree::ChunkRef, storage::Storage};
+
+const FIXED_SIZED_CHUNK_INDEX_MAGIC: [u8; 8] = [47, 127, 65, 237, 145, 253, 15, 205];
+const DYNAMIC_SIZED_CHUNK_INDEX_MAGIC: [u8; 8] = [28, 145, 78, 165, 25, 186, 179, 205];
+
+/// Both headers are padded to 4096 bytes
+const HEADER_SIZE: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct DynamicIndex {
+    /// End offset of each chunk in the stream and its digest
+    pub entries: Vec<(u64, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FixedIndex {
+    pub size: u64,
+    pub chunk_size: u64,
+    pub digests: Vec<String>,
+}
+
+/// magic | UUID | creation time | checksum of the entries, followed by format specific fields
+fn read_header(storage: &dyn Storage, path: &Path, magic: [u8; 8]) -> Result<(Vec<u8>, String)> {
+    let name = path.display().to_string();
+    let data = storage.read(path)?;
+    if data.len() < HEADER_SIZE || data[..8] != magic {
+        return Err(Error::InvalidIndex(name).into());
+    }
+
+    Ok((data, name))
+}
+
+impl DynamicIndex {
+    /// Entries are the little endian end offset, followed by the digest
+    pub fn from_file(storage: &dyn Storage, path: &Path) -> Result<Self> {
+        let (data, name) = read_header(storage, path, DYNAMIC_SIZED_CHUNK_INDEX_MAGIC)?;
+
+        let entries = &data[HEADER_SIZE..];
+        if entries.len() % 40 != 0 {
+            return Err(Error::InvalidIndex(name).into());
+        }
+
+        Ok(Self {
+            entries: entries
+                .chunks(40)
+                .map(|entry| {
+                    (
+                        u64::from_le_bytes(entry[..8].try_into().unwrap()),
+                        hex::encode(&entry[8..]),
+                    )
+                })
+                .collect(),
+        })
+    }
+
+    /// Size of the whole stream
+    pub fn size(&self) -> u64 {
+        self.entries.last().map_or(0, |(end, _)| *end)
+    }
+
+    /// Start and end of a chunk in the stream
+    pub fn chunk_range(&self, index: usize) -> (u64, u64) {
+        let start = match index {
+            0 => 0,
+            _ => self.entries[index - 1].0,
+        };
+
+        (start, self.entries[index].0)
+    }
+
+    /// The chunk containing an offset of the stream
+    pub fn chunk_at(&self, offset: u64) -> Option<usize> {
+        let index = self.entries.partition_point(|(end, _)| *end <= offset);
+        (index < self.entries.len()).then_some(index)
+    }
+
+    /// The parts of the chunks holding a range of the stream
+    pub fn chunk_refs(&self, start: u64, end: u64) -> Vec<ChunkRef> {
+        let mut chunks = Vec::new();
+        let Some(first) = self.chunk_at(start).filter(|_| start < end) else {
+            return chunks;
+        };
+
+        for index in first..self.entries.len() {
+            let (chunk_start, chunk_end) = self.chunk_range(index);
+            if chunk_start >= end {
+                break;
+            }
+
+            chunks.push(ChunkRef {
+                id: self.entries[index].1.clone(),
+                start: (start.max(chunk_start) - chunk_start) as usize,
+                end: Some((end.min(chunk_end) - chunk_start) as usize),
+                size: Some((chunk_end - chunk_start) as usize),
+            });
+        }
+
+        chunks
+    }
+}
+
+impl FixedIndex {
+    /// The header continues with the image size and chunk size, the entries are the digests
+    pub fn from_file(storage: &dyn Storage, path: &Path) -> Result<Self> {
+        let (data, name) = read_header(storage, path, FIXED_SIZED_CHUNK_INDEX_MAGIC)?;
+
+        let size = u64::from_le_bytes(data[64..72].try_into().unwrap());
+        let chunk_size = u64::from_le_bytes(data[72..80].try_into().unwrap());
+        let entries = &data[HEADER_SIZE..];
+        if chunk_size == 0 || entries.len() != size.div_ceil(chunk_size) as usize * 32 {
+            return Err(Error::InvalidIndex(name).into());
+        }
+
+        Ok(Self {
+            size,
+            chunk_size,
+            digests: entries.chunks(32).map(hex::encode).collect(),
+        })
+    }
+
+    /// Every chunk is full, except for the last one
+    pub fn chunk_refs(&self) -> Vec<ChunkRef> {
+        self.digests
+            .iter()
+            .enumerate()
+            .map(|(i, digest)| {
+                let mut chunk = ChunkRef::whole(digest.clone());
+                chunk.size =
+                    Some(self.chunk_size.min(self.size - i as u64 * self.chunk_size) as usize);
+                chunk
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,129 @@
+//! Encryption keys: the keyfile of the client and the secrets derived from the key
+
+use std::path::PathBuf;
+
+use aes_gcm::{
+    aead::{consts::U16, generic_array::GenericArray, Aead, KeyInit, Payload},
+    AesGcm,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::error::Error;
+use crate::{error::Result, utils::from_b64};
+
+/// AES-256-GCM with the 16 byte IVs PBS uses everywhere
+pub type Aes256Gcm16 = AesGcm<aes::Aes256, U16>;
+
+#[derive(Deserialize, Debug)]
+enum KeyDerivation {
+    Scrypt {
+        n: u64,
+        r: u32,
+        p: u32,
+        #[serde(deserialize_with = "from_b64")]
+        salt: Vec<u8>,
+    },
+    #[serde(rename = "PBKDF2")]
+    Pbkdf2 {
+        iter: u32,
+        #[serde(deserialize_with = "from_b64")]
+        salt: Vec<u8>,
+    },
+}
+
+/// `encryption-key.json`, the key is protected by a passphrase unless `kdf` is null
+#[derive(Deserialize, Debug)]
+struct Keyfile {
+    kdf: Option<KeyDerivation>,
+    #[serde(deserialize_with = "from_b64")]
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct CryptConfig {
+    pub enc_key: Vec<u8>,
+    /// Appended to the plaintext when hashing chunks, so their digests don't leak their contents
+    id_key: Vec<u8>,
+}
+
+impl CryptConfig {
+    pub fn new(enc_key: Vec<u8>) -> Self {
+        let mut id_key = vec![0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(&enc_key, b"_id_key", 10, &mut id_key);
+
+        Self { enc_key, id_key }
+    }
+
+    /// Load the keyfile from `PBS_KEYFILE` or the default location of the client, `None` if
+    /// there's none
+    pub fn load(password: Option<&str>) -> Result<Option<Self>> {
+        let path = match std::env::var_os("PBS_KEYFILE") {
+            Some(path) => PathBuf::from(path),
+            None => std::env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| {
+                    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+                })
+                .unwrap_or_default()
+                .join("proxmox-backup")
+                .join("encryption-key.json"),
+        };
+        if !path.is_file() {
+            return Ok(None);
+        }
+        trace!("Loading keyfile {path:?}");
+
+        let keyfile: Keyfile = serde_json::from_slice(&std::fs::read(&path)?)?;
+        let Some(kdf) = keyfile.kdf else {
+            return Ok(Some(Self::new(keyfile.data)));
+        };
+
+        let password = password.ok_or(Error::PasswordRequired)?;
+        let mut key = vec![0u8; 32];
+        match kdf {
+            KeyDerivation::Scrypt { n, r, p, salt } => {
+                let params = scrypt::Params::new(n.trailing_zeros() as u8, r, p, key.len())?;
+                scrypt::scrypt(password.as_bytes(), &salt, &params, &mut key)?;
+            }
+            KeyDerivation::Pbkdf2 { iter, salt } => {
+                pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iter, &mut key);
+            }
+        }
+
+        // IV | tag | ciphertext
+        if keyfile.data.len() < 32 {
+            return Err(Error::InvalidKeyfile(path.display().to_string()).into());
+        }
+        let (iv, rest) = keyfile.data.split_at(16);
+        let (tag, ciphertext) = rest.split_at(16);
+        let enc_key = decrypt(&key, iv, tag, ciphertext).ok_or(Error::InvalidPassword)?;
+
+        Ok(Some(Self::new(enc_key)))
+    }
+
+    /// Chunk digests are the SHA-256 of the plaintext, followed by the ID key
+    pub fn compute_digest(&self, data: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hasher.update(&self.id_key);
+        hasher.finalize().into()
+    }
+}
+
+/// Decrypt AES-256-GCM without associated data, `None` if the tag doesn't match
+pub fn decrypt(key: &[u8], iv: &[u8], tag: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    // the tag is expected at the end of the ciphertext
+    let mut message = ciphertext.to_vec();
+    message.extend_from_slice(tag);
+
+    Aes256Gcm16::new(GenericArray::from_slice(key))
+        .decrypt(
+            GenericArray::from_slice(iv),
+            Payload {
+                msg: &message,
+                aad: &[],
+            },
+        )
+        .ok()
+}
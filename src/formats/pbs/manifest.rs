@@ -0,0 +1,104 @@
+//! Backup groups, their snapshots and the manifest listing the archives of a snapshot
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Deserialize;
+
+use super::{blob, key::CryptConfig};
+use crate::{error::Result, storage::Storage};
+
+/// Group directories are grouped by the type of the backup
+const BACKUP_TYPES: [&str; 3] = ["vm", "ct", "host"];
+
+/// `index.json.blob`, it's signed but never encrypted
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Manifest {
+    pub backup_time: i64,
+    pub files: Vec<FileInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileInfo {
+    pub filename: String,
+}
+
+#[derive(Debug)]
+pub struct Snapshot {
+    /// Path relative to the datastore, e.g. `host/myhost/2024-01-01T10:00:00Z`
+    pub id: String,
+    /// `<type>/<id>`, prefixed with the namespace
+    pub group: String,
+    pub time: DateTime<Utc>,
+    pub path: PathBuf,
+    pub manifest: Manifest,
+}
+
+/// Find the snapshots of a namespace, `<type>/<id>/<time>`, and of the namespaces below it in
+/// `ns/<name>`. Snapshots without a manifest haven't been finished.
+pub fn find_snapshots(
+    storage: &dyn Storage,
+    datastore: &Path,
+    namespace: &Path,
+    crypt: Option<&CryptConfig>,
+    snapshots: &mut Vec<Snapshot>,
+) -> Result<()> {
+    for backup_type in BACKUP_TYPES {
+        for group in subdirectories(storage, &namespace.join(backup_type))? {
+            for dir in subdirectories(storage, &group)? {
+                let manifest_path = dir.join("index.json.blob");
+                let id = dir
+                    .strip_prefix(datastore)
+                    .unwrap_or(&dir)
+                    .to_string_lossy()
+                    .to_string();
+                if !storage.is_file(&manifest_path) {
+                    debug!("Skipping unfinished snapshot {id}");
+                    continue;
+                }
+                trace!("Loading snapshot {id}");
+
+                let data = blob::decode(&storage.read(&manifest_path)?, crypt, &id)?;
+                let manifest: Manifest = serde_json::from_slice(&data)?;
+                snapshots.push(Snapshot {
+                    group: group
+                        .strip_prefix(datastore)
+                        .unwrap_or(&group)
+                        .to_string_lossy()
+                        .to_string(),
+                    time: Utc
+                        .timestamp_opt(manifest.backup_time, 0)
+                        .single()
+                        .unwrap_or_default(),
+                    id,
+                    path: dir,
+                    manifest,
+                });
+            }
+        }
+    }
+
+    for namespace in subdirectories(storage, &namespace.join("ns"))? {
+        find_snapshots(storage, datastore, &namespace, crypt, snapshots)?;
+    }
+
+    Ok(())
+}
+
+fn subdirectories(storage: &dyn Storage, dir: &Path) -> Result<Vec<PathBuf>> {
+    if !storage.is_dir(dir) {
+        return Ok(Vec::new());
+    }
+
+    let mut dirs = Vec::new();
+    for entry in storage.list(dir)? {
+        if entry.stat.is_dir {
+            dirs.push(entry.path);
+        }
+    }
+    dirs.sort();
+
+    Ok(dirs)
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::HashSet,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, ChunkCache, ChunkRef, NodeKind, SnapshotInfo},
+    storage::Storage,
+};
+use error::Error;
+use index::{DynamicIndex, FixedIndex};
+use key::CryptConfig;
+use manifest::Snapshot;
+use pxar::Decoder;
+
+mod blob;
+mod index;
+mod key;
+mod manifest;
+mod pxar;
+
+pub mod error;
+
+#[derive(Debug)]
+pub struct Pbs {
+    pub path: PathBuf,
+    storage: Rc<dyn Storage>,
+
+    /// Only needed for encrypted backups
+    crypt: Option<CryptConfig>,
+    snapshots: Vec<Snapshot>,
+    /// The payload of many small files fits into one chunk
+    chunks: ChunkCache,
+}
+
+impl Pbs {
+    /// Open a datastore, the key is read from the client's keyfile if there is one
+    pub fn from_folder(
+        storage: Rc<dyn Storage>,
+        path: impl Into<PathBuf>,
+        password: impl Into<Option<String>>,
+    ) -> Result<Self> {
+        let password = password.into();
+
+        Ok(Self {
+            path: path.into(),
+            storage,
+            crypt: CryptConfig::load(password.as_deref())?,
+            snapshots: Vec::new(),
+            chunks: ChunkCache::default(),
+        })
+    }
+
+    /// Load the manifest of every snapshot, in every namespace
+    pub fn load_all_snapshots(&mut self) -> Result<()> {
+        manifest::find_snapshots(
+            &*self.storage,
+            &self.path,
+            &self.path,
+            self.crypt.as_ref(),
+            &mut self.snapshots,
+        )
+    }
+
+    /// Load everything
+    pub fn load_all(&mut self) -> Result<()> {
+        self.load_all_snapshots()?;
+        Ok(())
+    }
+
+    /// Chunks are stored in `.chunks/<first 4 digits of the digest>/<digest>`
+    fn chunk_path(&self, digest: &str) -> PathBuf {
+        self.path
+            .join(".chunks")
+            .join(digest.get(..4).unwrap_or_default())
+            .join(digest)
+    }
+
+    /// Read, decode and verify a chunk, the digest of encrypted chunks is keyed
+    fn read_chunk(&self, digest: &str) -> Result<Vec<u8>> {
+        let data = self.storage.read(&self.chunk_path(digest))?;
+        let plaintext = blob::decode(&data, self.crypt.as_ref(), digest)?;
+
+        let actual: [u8; 32] = match (blob::is_encrypted(&data), &self.crypt) {
+            (true, Some(crypt)) => crypt.compute_digest(&plaintext),
+            _ => Sha256::digest(&plaintext).into(),
+        };
+        if hex::encode(actual) != digest {
+            return Err(Error::IntegrityCheckFailed(digest.to_string()).into());
+        }
+
+        Ok(plaintext)
+    }
+
+    /// Snapshots are made of archives: pxar archives become directories, everything else a file
+    /// named after the archive
+    fn archive_nodes(
+        &self,
+        snapshot: &Snapshot,
+        filename: &str,
+        nodes: &mut Vec<tree::Node>,
+    ) -> Result<()> {
+        let path = snapshot.path.join(filename);
+
+        if let Some(name) = filename.strip_suffix(".didx") {
+            // the payload of split archives is decoded along with the metadata
+            if name.ends_with(".ppxar") {
+                return Ok(());
+            }
+
+            let index = DynamicIndex::from_file(&*self.storage, &path)?;
+            if name.ends_with(".pxar") {
+                let stream = IndexStream::new(self, &index);
+                Decoder::new(stream, filename, &index).decode(Path::new(name), nodes)?;
+            } else if let Some(base) = name.strip_suffix(".mpxar") {
+                let payload = DynamicIndex::from_file(
+                    &*self.storage,
+                    &snapshot.path.join(format!("{base}.ppxar.didx")),
+                )?;
+                let stream = IndexStream::new(self, &index);
+                Decoder::new(stream, filename, &payload)
+                    .decode(Path::new(&format!("{base}.pxar")), nodes)?;
+            } else {
+                let mut node = self.archive_node(snapshot, name);
+                node.size = Some(index.size());
+                node.chunks = index.chunk_refs(0, index.size());
+                nodes.push(node);
+            }
+        } else if let Some(name) = filename.strip_suffix(".fidx") {
+            // disk images of virtual machines
+            let index = FixedIndex::from_file(&*self.storage, &path)?;
+            let mut node = self.archive_node(snapshot, name);
+            node.size = Some(index.size);
+            node.chunks = index.chunk_refs();
+            nodes.push(node);
+        } else if let Some(name) = filename.strip_suffix(".blob") {
+            // small files like the guest config, the chunk is the blob itself
+            let mut node = self.archive_node(snapshot, name);
+            node.chunks = vec![ChunkRef::whole(format!("{}/{filename}", snapshot.id))];
+            nodes.push(node);
+        }
+
+        Ok(())
+    }
+
+    fn archive_node(&self, snapshot: &Snapshot, name: &str) -> tree::Node {
+        let mut node = tree::Node::new(name, NodeKind::File);
+        node.mtime = Some(snapshot.time);
+        node
+    }
+
+    fn find(&self, snapshot: &str) -> Result<&Snapshot> {
+        let id = self.find_snapshot(snapshot)?.id;
+        Ok(self.snapshots.iter().find(|s| s.id == id).unwrap())
+    }
+}
+
+/// Chunks are named after their digest, blobs after their path in the datastore
+fn is_digest(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Reads the stream of a dynamic index, only the chunks that are actually read are loaded
+struct IndexStream<'a> {
+    pbs: &'a Pbs,
+    index: &'a DynamicIndex,
+    position: u64,
+    chunk: Option<(usize, Vec<u8>)>,
+}
+
+impl<'a> IndexStream<'a> {
+    fn new(pbs: &'a Pbs, index: &'a DynamicIndex) -> Self {
+        Self {
+            pbs,
+            index,
+            position: 0,
+            chunk: None,
+        }
+    }
+}
+
+impl Read for IndexStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(index) = self.index.chunk_at(self.position) else {
+            return Ok(0);
+        };
+
+        if self.chunk.as_ref().map(|(cached, _)| *cached) != Some(index) {
+            let data = self
+                .pbs
+                .read_chunk(&self.index.entries[index].1)
+                .map_err(std::io::Error::other)?;
+            self.chunk = Some((index, data));
+        }
+
+        let (start, end) = self.index.chunk_range(index);
+        let data = &self.chunk.as_ref().unwrap().1;
+        if data.len() as u64 != end - start {
+            return Err(std::io::Error::other(Error::InvalidIndex(
+                self.index.entries[index].1.clone(),
+            )));
+        }
+
+        let offset = (self.position - start) as usize;
+        let len = buf.len().min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl Seek for IndexStream<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.index.size().checked_add_signed(offset),
+        };
+
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start")
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl Backup for Pbs {
+    /// Snapshots are identified by their path in the datastore, grouped by backup type and ID
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        let mut snapshots: Vec<SnapshotInfo> = self
+            .snapshots
+            .iter()
+            .map(|snapshot| SnapshotInfo {
+                id: snapshot.id.clone(),
+                group: snapshot.group.clone(),
+                time: snapshot.time,
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+
+        snapshots
+    }
+
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let snapshot = self.find(snapshot)?;
+
+        let mut nodes = Vec::new();
+        for file in &snapshot.manifest.files {
+            self.archive_nodes(snapshot, &file.filename, &mut nodes)?;
+        }
+
+        Ok(nodes)
+    }
+
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        match is_digest(id) {
+            true => self.chunks.get_or_load(id, || self.read_chunk(id)),
+            false => blob::decode(
+                &self.storage.read(&self.path.join(id))?,
+                self.crypt.as_ref(),
+                id,
+            ),
+        }
+    }
+
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        let path = match is_digest(id) {
+            true => self.chunk_path(id),
+            false => self.path.join(id),
+        };
+
+        Ok(self.storage.stat(&path)?.size)
+    }
+
+    /// Snapshot directories only hold indexes and small blobs
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        vec![self.path.join(".chunks")]
+    }
+
+    /// Every chunk listed in an index of a snapshot
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        let mut digests = HashSet::new();
+        for snapshot in &self.snapshots {
+            for file in &snapshot.manifest.files {
+                let path = snapshot.path.join(&file.filename);
+                if file.filename.ends_with(".didx") {
+                    let index = DynamicIndex::from_file(&*self.storage, &path)?;
+                    digests.extend(index.entries.into_iter().map(|(_, digest)| digest));
+                } else if file.filename.ends_with(".fidx") {
+                    digests.extend(FixedIndex::from_file(&*self.storage, &path)?.digests);
+                }
+            }
+        }
+
+        Ok(digests
+            .iter()
+            .map(|digest| self.chunk_path(digest))
+            .collect())
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+}
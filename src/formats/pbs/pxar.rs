@@ -0,0 +1,312 @@
+//! pxar archives: a depth first stream of file entries, each followed by its metadata and
+//! contents. Split archives (`.mpxar` and `.ppxar`) store the file contents in a separate stream.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+
+use chrono::{TimeZone, Utc};
+
+use super::{error::Error, index::DynamicIndex};
+use crate::{
+    error::Result,
+    formats::tree::{self, Inode, NodeKind},
+};
+
+const PXAR_FORMAT_VERSION: u64 = 0x730f6c75df16a40d;
+const PXAR_PRELUDE: u64 = 0xe309d79d9f7b771b;
+const PXAR_ENTRY: u64 = 0xd5956474e588acef;
+const PXAR_ENTRY_V1: u64 = 0x11da850a1c1cceff;
+const PXAR_FILENAME: u64 = 0x16701121063917b3;
+const PXAR_SYMLINK: u64 = 0x27f971e7dbf5dc5f;
+const PXAR_DEVICE: u64 = 0x9fc9e906586d5ce9;
+const PXAR_XATTR: u64 = 0x0dab0229b57dcd03;
+const PXAR_ACL_USER: u64 = 0x2ce8540a457d55b8;
+const PXAR_ACL_GROUP: u64 = 0x136e3eceb04c03ab;
+const PXAR_ACL_GROUP_OBJ: u64 = 0x10868031e9582876;
+const PXAR_ACL_DEFAULT: u64 = 0xbbbb13415a6896f5;
+const PXAR_ACL_DEFAULT_USER: u64 = 0xc89357b40532cd1f;
+const PXAR_ACL_DEFAULT_GROUP: u64 = 0xf90a8a5816038ffe;
+const PXAR_FCAPS: u64 = 0x2da9dd9db5f7fb67;
+const PXAR_QUOTA_PROJID: u64 = 0xe07540e82f7d1cbb;
+const PXAR_HARDLINK: u64 = 0x51269c8422bd7275;
+const PXAR_PAYLOAD: u64 = 0x28147a1b0b7c1a25;
+const PXAR_PAYLOAD_REF: u64 = 0x419d3d6bc4ba977e;
+const PXAR_GOODBYE: u64 = 0x2fec4fa642d5731d;
+
+/// Every item starts with its type and its size, including the header
+const HEADER_SIZE: u64 = 16;
+
+const S_IFMT: u64 = 0o170000;
+const S_IFDIR: u64 = 0o040000;
+const S_IFREG: u64 = 0o100000;
+const S_IFLNK: u64 = 0o120000;
+
+pub struct Decoder<'a, R> {
+    reader: R,
+    name: &'a str,
+    /// The stream holding the file contents, the archive itself unless it's split
+    payload: &'a DynamicIndex,
+    /// Node indices by their path inside the archive, hard links refer to their target by path
+    paths: HashMap<PathBuf, usize>,
+}
+
+impl<'a, R: Read + Seek> Decoder<'a, R> {
+    pub fn new(reader: R, name: &'a str, payload: &'a DynamicIndex) -> Self {
+        Self {
+            reader,
+            name,
+            payload,
+            paths: HashMap::new(),
+        }
+    }
+
+    /// Decode the whole archive, its root directory becomes `root`
+    pub fn decode(mut self, root: &Path, nodes: &mut Vec<tree::Node>) -> Result<()> {
+        // newer archives start with their version, optionally followed by a prelude
+        loop {
+            let (kind, size) = self.read_header()?;
+            match kind {
+                PXAR_FORMAT_VERSION | PXAR_PRELUDE => self.skip(size)?,
+                _ => {
+                    self.unread()?;
+                    break;
+                }
+            }
+        }
+
+        self.decode_entry(root, Path::new(""), nodes)
+    }
+
+    fn invalid(&self, reason: &str) -> Error {
+        Error::InvalidArchive(self.name.to_string(), reason.to_string())
+    }
+
+    /// Type and size of the contents of the next item
+    fn read_header(&mut self) -> Result<(u64, u64)> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        self.reader.read_exact(&mut header)?;
+
+        let kind = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let size = u64::from_le_bytes(header[8..].try_into().unwrap());
+        if size < HEADER_SIZE {
+            return Err(self.invalid("item smaller than its header").into());
+        }
+
+        Ok((kind, size - HEADER_SIZE))
+    }
+
+    /// Go back to the start of the header that was just read
+    fn unread(&mut self) -> Result<()> {
+        self.reader.seek(SeekFrom::Current(-(HEADER_SIZE as i64)))?;
+        Ok(())
+    }
+
+    fn skip(&mut self, size: u64) -> Result<()> {
+        self.reader.seek(SeekFrom::Current(size as i64))?;
+        Ok(())
+    }
+
+    fn read_vec(&mut self, size: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; size as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Names and link targets are NUL terminated
+    fn read_string(&mut self, size: u64) -> Result<String> {
+        let mut data = self.read_vec(size)?;
+        if data.pop() != Some(0) {
+            return Err(self.invalid("string without NUL terminator").into());
+        }
+
+        Ok(String::from_utf8_lossy(&data).to_string())
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut data = [0u8; 8];
+        self.reader.read_exact(&mut data)?;
+        Ok(u64::from_le_bytes(data))
+    }
+
+    /// Decode an entry, its metadata, its contents and, for directories, everything below it
+    fn decode_entry(
+        &mut self,
+        root: &Path,
+        relative: &Path,
+        nodes: &mut Vec<tree::Node>,
+    ) -> Result<()> {
+        let (kind, size) = self.read_header()?;
+        let stat = match kind {
+            PXAR_ENTRY | PXAR_ENTRY_V1 => self.read_vec(size)?,
+            kind => return Err(Error::UnexpectedItem(self.name.to_string(), kind).into()),
+        };
+        if stat.len() < 32 {
+            return Err(self.invalid("truncated entry").into());
+        }
+
+        // mode | flags | uid | gid | mtime, either as nanoseconds or as seconds and nanoseconds
+        let mode = u64::from_le_bytes(stat[..8].try_into().unwrap());
+        let uid = u32::from_le_bytes(stat[16..20].try_into().unwrap());
+        let gid = u32::from_le_bytes(stat[20..24].try_into().unwrap());
+        let mtime = match kind {
+            PXAR_ENTRY if stat.len() >= 36 => Utc
+                .timestamp_opt(
+                    i64::from_le_bytes(stat[24..32].try_into().unwrap()),
+                    u32::from_le_bytes(stat[32..36].try_into().unwrap()),
+                )
+                .single(),
+            _ => Some(Utc.timestamp_nanos(i64::from_le_bytes(stat[24..32].try_into().unwrap()))),
+        };
+
+        // joining an empty path would add a trailing separator
+        let path = match relative.as_os_str().is_empty() {
+            true => root.to_path_buf(),
+            false => root.join(relative),
+        };
+        let mut node = tree::Node::new(
+            path,
+            match mode & S_IFMT {
+                S_IFDIR => NodeKind::Dir,
+                S_IFREG => NodeKind::File,
+                S_IFLNK => NodeKind::Symlink {
+                    target: String::new(),
+                },
+                _ => NodeKind::Special,
+            },
+        );
+        node.mode = Some((mode & 0o7777) as u32);
+        node.uid = Some(uid);
+        node.gid = Some(gid);
+        node.mtime = mtime;
+
+        // metadata items, followed by the contents unless it's a directory, fifo or socket
+        loop {
+            let (kind, size) = self.read_header()?;
+            match kind {
+                PXAR_XATTR => {
+                    let xattr = self.read_vec(size)?;
+                    let Some(separator) = xattr.iter().position(|b| *b == 0) else {
+                        return Err(self.invalid("xattr without name").into());
+                    };
+                    node.xattrs.push((
+                        String::from_utf8_lossy(&xattr[..separator]).to_string(),
+                        xattr[separator + 1..].to_vec(),
+                    ));
+                }
+                PXAR_ACL_USER
+                | PXAR_ACL_GROUP
+                | PXAR_ACL_GROUP_OBJ
+                | PXAR_ACL_DEFAULT
+                | PXAR_ACL_DEFAULT_USER
+                | PXAR_ACL_DEFAULT_GROUP
+                | PXAR_FCAPS
+                | PXAR_QUOTA_PROJID => self.skip(size)?,
+                PXAR_PAYLOAD => {
+                    let start = self.reader.stream_position()?;
+                    node.size = Some(size);
+                    node.chunks = self.payload.chunk_refs(start, start + size);
+                    self.skip(size)?;
+                    break;
+                }
+                // offset of the payload item in the payload stream | size of the contents
+                PXAR_PAYLOAD_REF => {
+                    let start = self.read_u64()? + HEADER_SIZE;
+                    let length = self.read_u64()?;
+                    self.skip(size.saturating_sub(16))?;
+                    node.size = Some(length);
+                    node.chunks = self.payload.chunk_refs(start, start + length);
+                    break;
+                }
+                PXAR_SYMLINK => {
+                    node.kind = NodeKind::Symlink {
+                        target: self.read_string(size)?,
+                    };
+                    break;
+                }
+                PXAR_DEVICE => {
+                    self.skip(size)?;
+                    break;
+                }
+                PXAR_FILENAME | PXAR_GOODBYE => {
+                    self.unread()?;
+                    break;
+                }
+                kind => return Err(Error::UnexpectedItem(self.name.to_string(), kind).into()),
+            }
+        }
+
+        let is_dir = node.kind == NodeKind::Dir;
+        self.paths.insert(relative.to_path_buf(), nodes.len());
+        nodes.push(node);
+        if !is_dir {
+            return Ok(());
+        }
+
+        // the children, each preceded by its name, followed by the goodbye table of the
+        // directory, a hash table used for lookups
+        loop {
+            let (kind, size) = self.read_header()?;
+            match kind {
+                PXAR_FILENAME => {
+                    let name = self.read_string(size)?;
+                    let child = relative.join(&name);
+                    if !matches!(child.components().next_back(), Some(Component::Normal(_)))
+                        || name.contains('/')
+                    {
+                        return Err(self.invalid(&format!("invalid file name {name:?}")).into());
+                    }
+
+                    let (kind, size) = self.read_header()?;
+                    match kind {
+                        PXAR_HARDLINK => self.decode_hardlink(root, &child, size, nodes)?,
+                        _ => {
+                            self.unread()?;
+                            self.decode_entry(root, &child, nodes)?;
+                        }
+                    }
+                }
+                PXAR_GOODBYE => {
+                    self.skip(size)?;
+                    return Ok(());
+                }
+                kind => return Err(Error::UnexpectedItem(self.name.to_string(), kind).into()),
+            }
+        }
+    }
+
+    /// Hard links only store the offset and the path of the file they link to, they share its
+    /// metadata and contents
+    fn decode_hardlink(
+        &mut self,
+        root: &Path,
+        relative: &Path,
+        size: u64,
+        nodes: &mut Vec<tree::Node>,
+    ) -> Result<()> {
+        let _offset = self.read_u64()?;
+        let target = self.read_string(size.saturating_sub(8))?;
+        let target = PathBuf::from(target.trim_start_matches('/'));
+
+        let Some(&index) = self.paths.get(&target) else {
+            return Err(self
+                .invalid(&format!("hard link to unknown file {target:?}"))
+                .into());
+        };
+
+        let inode = nodes[index].inode.get_or_insert(Inode {
+            device: 0,
+            inode: index as u64,
+            links: 1,
+        });
+        inode.links += 1;
+
+        let mut node = nodes[index].clone();
+        node.path = root.join(relative);
+        nodes.push(node);
+
+        Ok(())
+    }
+}
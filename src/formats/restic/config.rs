@@ -3,8 +3,9 @@ use std::{self, path::Path};
 use serde::Deserialize;
 
 use super::{decoder::Decoder, keys::Masterkey};
-use crate::error::Result;
+use crate::{error::Result, storage::Storage};
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
@@ -14,8 +15,12 @@ pub struct Config {
 }
 
 impl Config {
-    pub fn from_file(masterkey: &Masterkey, path: impl AsRef<Path>) -> Result<Self> {
-        let file = std::fs::read(path)?;
+    pub fn from_file(
+        storage: &dyn Storage,
+        masterkey: &Masterkey,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let file = storage.read(path.as_ref())?;
 
         let decoder = Decoder::new(masterkey);
         let decoded = decoder.decrypt(&file)?;
@@ -6,4 +6,14 @@ pub enum Error {
     InvalidPassword,
     #[error("Invalid blob type: {0}")]
     InvalidBlobType(u8),
+    #[error("Blob not found in index: {0}")]
+    BlobNotFound(String),
+    #[error("Unexpected blob type for blob {0}")]
+    UnexpectedBlobType(String),
+    #[error("Unsupported index type: {0}")]
+    UnknownIndexType(String),
+    #[error("Blob {0} is shorter than its index entry")]
+    TruncatedBlob(String),
+    #[error("Integrity check failed for blob {0}")]
+    IntegrityCheckFailed(String),
 }
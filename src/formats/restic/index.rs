@@ -1,9 +1,9 @@
-use std::path::Path;
+use std::{collections::HashMap, path::Path};
 
 use serde::Deserialize;
 
 use super::{decoder::Decoder, keys::Masterkey};
-use crate::error::Result;
+use crate::{error::Result, storage::Storage};
 
 #[derive(Deserialize, Debug, Default)]
 #[serde(deny_unknown_fields)]
@@ -11,6 +11,9 @@ pub struct Index {
     #[serde(default)]
     pub supersedes: Vec<String>,
     pub packs: Vec<PackIndex>,
+    /// Position of every blob in `packs`, built once the indexes are merged
+    #[serde(skip)]
+    blobs: HashMap<String, (usize, usize)>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -33,8 +36,12 @@ pub struct BlobIndex {
 
 impl Index {
     // Load a single index file
-    pub fn from_file(masterkey: &Masterkey, path: impl AsRef<Path>) -> Result<Self> {
-        let file = std::fs::read(path)?;
+    pub fn from_file(
+        storage: &dyn Storage,
+        masterkey: &Masterkey,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let file = storage.read(path.as_ref())?;
 
         let decoder = Decoder::new(masterkey);
         let decoded = decoder.decrypt_and_decompress(&file)?;
@@ -46,12 +53,14 @@ impl Index {
     }
 
     // Load all the index files and merge them together
-    pub fn from_folder(masterkey: &Masterkey, path: impl AsRef<Path>) -> Result<Self> {
+    pub fn from_folder(
+        storage: &dyn Storage,
+        masterkey: &Masterkey,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
         let mut indexes = Vec::new();
-        for index in std::fs::read_dir(path)? {
-            let index = index?;
-
-            let index = Index::from_file(masterkey, index.path())?;
+        for index in storage.list(path.as_ref())? {
+            let index = Index::from_file(storage, masterkey, index.path)?;
 
             indexes.push(index);
         }
@@ -64,18 +73,30 @@ impl Index {
             index.packs.extend(idx.packs);
         }
 
+        for (pack_pos, pack) in index.packs.iter().enumerate() {
+            for (blob_pos, blob) in pack.blobs.iter().enumerate() {
+                index.blobs.insert(blob.id.clone(), (pack_pos, blob_pos));
+            }
+        }
+
         Ok(index)
     }
 
     // Search the index for the pack that contains the blob with the given ID
     pub fn find_pack(&self, id: &str) -> Option<(&PackIndex, &BlobIndex)> {
-        for pack in &self.packs {
-            for blob in &pack.blobs {
-                if blob.id == id {
-                    return Some((pack, blob));
-                }
-            }
-        }
-        None
+        let (pack_pos, blob_pos) = *self.blobs.get(id)?;
+        let pack = &self.packs[pack_pos];
+
+        Some((pack, &pack.blobs[blob_pos]))
+    }
+}
+
+impl BlobIndex {
+    /// Size of the blob once decrypted and decompressed, `None` if the entry is too short to
+    /// hold one
+    pub fn data_len(&self) -> Option<usize> {
+        // uncompressed blobs are only prefixed with the IV and followed by the MAC
+        self.uncompressed_length
+            .or_else(|| self.length.checked_sub(32))
     }
 }
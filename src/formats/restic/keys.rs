@@ -7,14 +7,10 @@ use aes256ctr_poly1305aes::{
 use serde::Deserialize;
 
 use super::error::Error;
-use crate::{error::Result, utils::from_b64};
+use crate::{error::Result, storage::Storage, utils::from_b64};
 
 #[derive(Deserialize, Debug)]
 pub struct Key {
-    pub created: String,
-    pub username: String,
-    pub hostname: String,
-    pub kdf: String,
     #[serde(rename = "N")]
     pub n: u32,
     pub r: u32,
@@ -41,17 +37,18 @@ pub struct Mac {
 }
 
 impl Key {
-    pub fn from_folder(path: impl AsRef<Path>, password: String) -> Result<Masterkey> {
-        for key in std::fs::read_dir(path)? {
-            let key = key?;
-            let key_path = key.path();
-
-            if !key_path.is_file() {
+    pub fn from_folder(
+        storage: &dyn Storage,
+        path: impl AsRef<Path>,
+        password: String,
+    ) -> Result<Masterkey> {
+        for key in storage.list(path.as_ref())? {
+            if key.stat.is_dir {
                 continue;
             }
 
             // we found a working key
-            if let Ok(k) = Key::from_file(key_path, &password) {
+            if let Ok(k) = Key::from_file(storage, key.path, &password) {
                 return Ok(k);
             }
         }
@@ -60,8 +57,12 @@ impl Key {
         Err(Error::InvalidPassword)?
     }
 
-    pub fn from_file(path: impl AsRef<Path>, password: impl AsRef<[u8]>) -> Result<Masterkey> {
-        let file = std::fs::read(path.as_ref())?;
+    pub fn from_file(
+        storage: &dyn Storage,
+        path: impl AsRef<Path>,
+        password: impl AsRef<[u8]>,
+    ) -> Result<Masterkey> {
+        let file = storage.read(path.as_ref())?;
 
         let json: Key = serde_json::from_slice(&file)?;
 
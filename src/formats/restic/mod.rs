@@ -1,13 +1,22 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    cell::RefCell,
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
+    rc::Rc,
 };
 
-use crate::{error::Result, formats::restic::pack::NodeType};
+use chrono::Utc;
+
+use crate::{
+    error::Result,
+    formats::tree::{self, Backup, SnapshotInfo},
+    storage::Storage,
+};
 use config::Config;
+use error::Error;
 use index::Index;
 use keys::Key;
-use pack::Blob;
+use pack::{Blob, Tree};
 use snapshot::Snapshot;
 
 mod config;
@@ -22,38 +31,43 @@ pub mod error;
 #[derive(Debug)]
 pub struct Restic {
     pub path: PathBuf,
-    pub config: Config,
+    storage: Rc<dyn Storage>,
 
     pub masterkey: keys::Masterkey,
     index: Index,
 
     snapshots: Vec<Snapshot>,
-    blobs: HashMap<String, Blob>,
+    /// Decrypted tree blobs, most trees are shared between snapshots
+    trees: RefCell<HashMap<String, Rc<Tree>>>,
 }
 
 impl Restic {
-    pub fn from_folder(path: impl Into<PathBuf>, password: impl Into<String>) -> Result<Self> {
+    pub fn from_folder(
+        storage: Rc<dyn Storage>,
+        path: impl Into<PathBuf>,
+        password: impl Into<String>,
+    ) -> Result<Self> {
         let path = path.into();
 
-        let masterkey = Key::from_folder(path.join("keys"), password.into())?;
-        let config = Config::from_file(&masterkey, path.join("config"))?;
-        let index = Index::from_folder(&masterkey, path.join("index"))?;
+        let masterkey = Key::from_folder(&*storage, path.join("keys"), password.into())?;
+        // nothing in the config is needed, but a repository without one is broken
+        Config::from_file(&*storage, &masterkey, path.join("config"))?;
+        let index = Index::from_folder(&*storage, &masterkey, path.join("index"))?;
 
         Ok(Self {
             path,
-            config,
+            storage,
             masterkey,
             index,
             snapshots: Vec::new(),
-            blobs: HashMap::new(),
+            trees: RefCell::default(),
         })
     }
 
     /// Load all the snapshots
     pub fn load_all_snapshots(&mut self) -> Result<()> {
-        for snapshot in std::fs::read_dir(self.path.join("snapshots"))? {
-            let snapshot = snapshot?;
-            let snap = Snapshot::from_file(&self.masterkey, snapshot.path())?;
+        for snapshot in self.storage.list(&self.path.join("snapshots"))? {
+            let snap = Snapshot::from_file(&*self.storage, &self.masterkey, snapshot.path)?;
             self.snapshots.push(snap);
         }
 
@@ -66,87 +80,73 @@ impl Restic {
         Ok(())
     }
 
-    /// Extract and recreate the files present in the latest snapshot - directories are ignored
-    pub fn dump_all_files(&mut self, output_dir: impl AsRef<Path>) -> Result<()> {
-        // find the latest snapshot
-        let latest = self
-            .snapshots
-            .iter()
-            .max_by_key(|x| x.time)
-            .expect("No snapshot found");
+    /// Find the pack containing a blob and load it
+    fn load_blob(&self, id: &str) -> Result<Blob> {
+        let (pack_index, blob_index) = self
+            .index
+            .find_pack(id)
+            .ok_or_else(|| Error::BlobNotFound(id.to_string()))?;
+
+        Blob::from_file_blobindex(
+            &*self.storage,
+            &self.masterkey,
+            self.resolve_path(&pack_index.id),
+            blob_index,
+        )
+    }
 
-        // create initial output directory
-        std::fs::create_dir_all(output_dir.as_ref())?;
+    /// Load a tree blob, trees are cached so walking many snapshots only decrypts each once
+    fn load_tree(&self, id: &str) -> Result<Rc<Tree>> {
+        if let Some(tree) = self.trees.borrow().get(id) {
+            return Ok(tree.clone());
+        }
 
-        // start going through the tree and loading the referenced blobs
-        let mut nodes: VecDeque<String> = VecDeque::new();
-        nodes.push_back(latest.tree.to_owned());
+        let Blob::Tree(tree) = self.load_blob(id)? else {
+            return Err(Error::UnexpectedBlobType(id.to_string()).into());
+        };
 
-        loop {
-            // find the pack that contains the needed blob
-            let (pack_index, blob_index) = self
-                .index
-                .find_pack(&nodes.pop_front().unwrap())
-                .expect("Snapshot tree not found in index");
-
-            // load the pack that contains the tree blob
-            let blob = Blob::from_file_blobindex(
-                &self.masterkey,
-                &self.resolve_path(&pack_index.id),
-                blob_index,
-            )?;
-
-            // parse the blob and add subtrees to be parsed
-            if let Blob::Tree(tree) = &blob {
-                for node in &tree.nodes {
-                    if let Some(subtree) = &node.subtree {
-                        nodes.push_back(subtree.to_owned());
-                    }
-
-                    if let Some(content) = &node.content {
-                        // is there an extend method that we can use? unsure
-                        for c in content {
-                            nodes.push_back(c.to_owned());
-                        }
-                    }
-                }
-            }
+        let tree = Rc::new(tree);
+        self.trees.borrow_mut().insert(id.to_string(), tree.clone());
+        Ok(tree)
+    }
+
+    /// Walk a tree blob and all of its subtrees, depth first
+    fn walk_tree(&self, id: &str, parent: &Path, nodes: &mut Vec<tree::Node>) -> Result<()> {
+        let tree = self.load_tree(id)?;
+
+        for node in &tree.nodes {
+            let path = parent.join(&node.name);
 
-            // cache blob into our blob map
-            self.blobs.insert(blob_index.id.clone(), blob);
+            let mut tree_node = node.to_tree_node(path.clone());
+            for chunk in &mut tree_node.chunks {
+                chunk.size = self
+                    .index
+                    .find_pack(&chunk.id)
+                    .and_then(|(_, blob)| blob.data_len());
+            }
+            nodes.push(tree_node);
 
-            if nodes.is_empty() {
-                // we're done
-                break;
+            if let Some(subtree) = &node.subtree {
+                self.walk_tree(subtree, &path, nodes)?;
             }
         }
 
-        //trace!("Blobs parsed so far: {:#?}", self.blobs);
-
-        // dump the files out and ignore the directory structure - much easier to do it this way
-        for (_hash, blob) in self.blobs.iter() {
-            if let Blob::Tree(tree) = blob {
-                for node in &tree.nodes {
-                    match node.node_type {
-                        NodeType::Dir => {
-                            // ignore this for now
-                        }
-                        NodeType::File => {
-                            // create a file
-                            if let Some(content) = &node.content {
-                                let mut fulldata = Vec::new();
-                                for hash in content {
-                                    let data = self.blobs.get(hash).unwrap();
-                                    if let Blob::Data(bytes) = data {
-                                        fulldata.extend(bytes);
-                                    }
-                                }
-
-                                std::fs::write(output_dir.as_ref().join(&node.name), fulldata)?;
-                            }
-                        }
-                    }
-                }
+        Ok(())
+    }
+
+    /// Collect the IDs of a tree blob and of every blob it references, trees that have already
+    /// been visited are skipped
+    fn collect_blobs(&self, id: &str, blobs: &mut HashSet<String>) -> Result<()> {
+        if !blobs.insert(id.to_string()) {
+            return Ok(());
+        }
+
+        let tree = self.load_tree(id)?;
+        for node in &tree.nodes {
+            blobs.extend(node.content.iter().flatten().cloned());
+
+            if let Some(subtree) = &node.subtree {
+                self.collect_blobs(subtree, blobs)?;
             }
         }
 
@@ -159,3 +159,74 @@ impl Restic {
         path
     }
 }
+
+impl Backup for Restic {
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        let mut snapshots: Vec<SnapshotInfo> = self
+            .snapshots
+            .iter()
+            .map(|snapshot| SnapshotInfo {
+                id: snapshot.id.clone(),
+                group: snapshot.hostname.clone(),
+                time: snapshot.time.with_timezone(&Utc),
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+
+        snapshots
+    }
+
+    fn tree(&self, snapshot: &str) -> Result<Vec<tree::Node>> {
+        let id = self.find_snapshot(snapshot)?.id;
+        let snapshot = self.snapshots.iter().find(|s| s.id == id).unwrap();
+
+        let mut nodes = Vec::new();
+        self.walk_tree(&snapshot.tree, Path::new(""), &mut nodes)?;
+
+        Ok(nodes)
+    }
+
+    fn chunk(&self, id: &str) -> Result<Vec<u8>> {
+        match self.load_blob(id)? {
+            Blob::Data(data) => Ok(data),
+            Blob::Tree(_) => Err(Error::UnexpectedBlobType(id.to_string()))?,
+        }
+    }
+
+    /// Blobs share pack files, only the blob itself is counted
+    fn chunk_stored_size(&self, id: &str) -> Result<u64> {
+        let (_, blob) = self
+            .index
+            .find_pack(id)
+            .ok_or_else(|| Error::BlobNotFound(id.to_string()))?;
+
+        Ok(blob.length as u64)
+    }
+
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        vec![self.path.join("data")]
+    }
+
+    /// A pack is referenced as long as one of its blobs is
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>> {
+        let mut blobs = HashSet::new();
+        for snapshot in &self.snapshots {
+            self.collect_blobs(&snapshot.tree, &mut blobs)?;
+        }
+
+        let mut referenced = HashSet::new();
+        for blob in &blobs {
+            let (pack, _) = self
+                .index
+                .find_pack(blob)
+                .ok_or_else(|| Error::BlobNotFound(blob.to_string()))?;
+            referenced.insert(self.resolve_path(&pack.id));
+        }
+
+        Ok(referenced)
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+}
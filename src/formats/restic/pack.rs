@@ -3,15 +3,24 @@
 use std::{
     collections::HashMap,
     io::{Cursor, Read},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use byteorder::{LittleEndian, ReadBytesExt};
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use sha2::{Digest, Sha256};
 
 use super::{error::Error, index::BlobIndex, keys::Masterkey};
-use crate::{error::Result, formats::restic::decoder::Decoder};
+use crate::{
+    error::Result,
+    formats::{
+        restic::decoder::Decoder,
+        tree::{self, ChunkRef, Inode, NodeKind},
+    },
+    storage::Storage,
+    utils::{from_b64, from_go_mode},
+};
 
 #[derive(Debug)]
 pub enum Blob {
@@ -42,10 +51,20 @@ pub struct Node {
     pub inode: u64,
     pub device_id: u64,
     pub size: Option<u64>,
-    pub extended_attributes: Option<Vec<HashMap<String, String>>>,
+    pub extended_attributes: Option<Vec<ExtendedAttribute>>,
     pub content: Option<Vec<String>>,
     pub subtree: Option<String>,
     pub links: Option<u64>,
+    pub linktarget: Option<String>,
+    pub device: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtendedAttribute {
+    pub name: String,
+    #[serde(deserialize_with = "from_b64")]
+    pub value: Vec<u8>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -53,6 +72,52 @@ pub struct Node {
 pub enum NodeType {
     Dir,
     File,
+    Symlink,
+    Dev,
+    Chardev,
+    Fifo,
+    Socket,
+}
+
+impl Node {
+    /// Convert into the format independent representation, `path` is the full path of the node
+    pub fn to_tree_node(&self, path: PathBuf) -> tree::Node {
+        let kind = match self.node_type {
+            NodeType::Dir => NodeKind::Dir,
+            NodeType::File => NodeKind::File,
+            NodeType::Symlink => NodeKind::Symlink {
+                target: self.linktarget.clone().unwrap_or_default(),
+            },
+            NodeType::Dev | NodeType::Chardev | NodeType::Fifo | NodeType::Socket => {
+                NodeKind::Special
+            }
+        };
+
+        let mut node = tree::Node::new(path, kind);
+        node.mode = Some(from_go_mode(self.mode as u64));
+        node.uid = Some(self.uid);
+        node.gid = Some(self.gid);
+        node.user = Some(self.user.clone());
+        node.group = Some(self.group.clone());
+        node.mtime = DateTime::parse_from_rfc3339(&self.mtime)
+            .ok()
+            .map(|time| time.with_timezone(&Utc));
+        node.size = self.size;
+        node.xattrs = self
+            .extended_attributes
+            .iter()
+            .flatten()
+            .map(|xattr| (xattr.name.clone(), xattr.value.clone()))
+            .collect();
+        node.inode = Some(Inode {
+            device: self.device_id,
+            inode: self.inode,
+            links: self.links.unwrap_or(1),
+        });
+        node.chunks = self.content.iter().flatten().map(ChunkRef::whole).collect();
+
+        node
+    }
 }
 
 #[derive(Debug)]
@@ -79,65 +144,62 @@ enum BlobType {
 
 impl Blob {
     pub fn from_file_blobindex(
+        storage: &dyn Storage,
         masterkey: &Masterkey,
         file: impl AsRef<Path>,
         index: &BlobIndex,
     ) -> Result<Self> {
-        let file = std::fs::read(file)?;
-
-        // reference the blob we're interested in
-        let blob_bytes = &file[index.offset..index.offset + index.length];
+        // only read the blob we're interested in
+        let blob_bytes =
+            &storage.read_range(file.as_ref(), index.offset as u64, index.length as u64)?[..];
 
         // make sure our blob is the correct length
-        assert_eq!(blob_bytes.len(), index.length);
+        if blob_bytes.len() != index.length {
+            return Err(Error::TruncatedBlob(index.id.clone()).into());
+        }
 
         let blob = if let Some(uncompressed_len) = index.uncompressed_length {
             // blob is compressed - decrypt & decompress
             let decoder = Decoder::new(masterkey);
             let blob = decoder.decrypt_and_decompress_packed(blob_bytes)?;
 
-            // verify
-            assert_eq!(blob.len(), uncompressed_len);
-            let mut hasher = Sha256::new();
-            hasher.update(&blob);
-            let hash = hasher.finalize();
-            assert_eq!(hex::encode(hash), index.id);
-
+            if blob.len() != uncompressed_len {
+                return Err(Error::IntegrityCheckFailed(index.id.clone()).into());
+            }
             blob
         } else {
             // blob is not compressed - decrypt only
             let decoder = Decoder::new(masterkey);
-            let blob = decoder.decrypt(blob_bytes)?;
-
-            // verify
-            let mut hasher = Sha256::new();
-            hasher.update(&blob);
-            let hash = hasher.finalize();
-            assert_eq!(hex::encode(hash), index.id);
-
-            blob
+            decoder.decrypt(blob_bytes)?
         };
 
-        return match index.data_type.as_str() {
+        // verify
+        let mut hasher = Sha256::new();
+        hasher.update(&blob);
+        let hash = hasher.finalize();
+        if hex::encode(hash) != index.id {
+            return Err(Error::IntegrityCheckFailed(index.id.clone()).into());
+        }
+
+        match index.data_type.as_str() {
             "data" => Ok(Blob::Data(blob)),
             "tree" => {
                 trace!("Blob tree JSON: {}", String::from_utf8_lossy(&blob));
                 let tree: Tree = serde_json::from_slice(&blob)?;
                 Ok(Blob::Tree(tree))
             }
-            t => {
-                panic!("Unsupported index type: {t}")
-            }
-        };
+            t => Err(Error::UnknownIndexType(t.to_string()).into()),
+        }
     }
 
     /// Read an entire pack file using the header.
     /// Pack structure: `EncryptedBlob1 || ... || EncryptedBlobN || EncryptedHeader || Header_Length`
     pub fn from_file_header(
+        storage: &dyn Storage,
         masterkey: &Masterkey,
         file: impl AsRef<Path>,
     ) -> Result<HashMap<String, Self>> {
-        let file = std::fs::read(file)?;
+        let file = storage.read(file.as_ref())?;
 
         // read the header length (last 4 bytes) as u32 little-endian
         let header_length = u32::from_le_bytes(file[file.len() - 4..].try_into().unwrap());
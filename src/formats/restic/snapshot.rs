@@ -4,11 +4,15 @@ use chrono::prelude::*;
 use serde::Deserialize;
 
 use super::{decoder::Decoder, keys::Masterkey};
-use crate::{error::Result, utils::from_datetime};
+use crate::{error::Result, storage::Storage, utils::from_datetime};
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Snapshot {
+    /// The snapshot ID is the name of the file, it isn't part of the JSON
+    #[serde(skip)]
+    pub id: String,
     #[serde(deserialize_with = "from_datetime")]
     pub time: DateTime<FixedOffset>,
     pub tree: String,
@@ -19,17 +23,30 @@ pub struct Snapshot {
     pub gid: u32,
     pub tags: Option<Vec<String>>,
     pub original: Option<String>,
+    pub parent: Option<String>,
+    pub excludes: Option<Vec<String>>,
+    pub program_version: Option<String>,
 }
 
 impl Snapshot {
-    pub fn from_file(masterkey: &Masterkey, path: impl AsRef<Path>) -> Result<Self> {
-        let file = std::fs::read(path.as_ref())?;
+    pub fn from_file(
+        storage: &dyn Storage,
+        masterkey: &Masterkey,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let file = storage.read(path.as_ref())?;
 
         let decoder = Decoder::new(masterkey);
         let decoded = decoder.decrypt_and_decompress(&file)?;
 
         trace!("Snapshot JSON: {}", String::from_utf8_lossy(&decoded));
-        let snapshot: Snapshot = serde_json::from_slice(&decoded)?;
+        let mut snapshot: Snapshot = serde_json::from_slice(&decoded)?;
+        snapshot.id = path
+            .as_ref()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
 
         Ok(snapshot)
     }
@@ -0,0 +1,462 @@
+//! Format independent view of a backup: its snapshots, their file trees and the chunks holding the
+//! file contents. Every format converts its own metadata into this, so restoring and exporting
+//! only has to be written once.
+
+use std::{
+    cell::RefCell,
+    collections::{HashSet, VecDeque},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use blake2::{digest::consts::U32, digest::DynDigest, Blake2b};
+use chrono::{DateTime, Utc};
+use sha2::Sha256;
+
+use crate::{
+    error::{Error, Result},
+    storage::Storage,
+};
+
+pub trait Backup {
+    /// List every snapshot in the repository, sorted by time
+    fn snapshots(&self) -> Vec<SnapshotInfo>;
+
+    /// Walk the file tree of a snapshot, parents are always listed before their children
+    fn tree(&self, snapshot: &str) -> Result<Vec<Node>>;
+
+    /// Read, decrypt and decompress a single chunk
+    fn chunk(&self, id: &str) -> Result<Vec<u8>>;
+
+    /// Space a chunk takes up in the repository, after compression and encryption
+    fn chunk_stored_size(&self, id: &str) -> Result<u64>;
+
+    /// Total size of the files of a snapshot, if the format records it
+    fn logical_size(&self, _snapshot: &str) -> Option<u64> {
+        None
+    }
+
+    /// Directories of the repository holding chunks, packs and other objects - everything in
+    /// them is expected to be referenced by a snapshot
+    fn object_dirs(&self) -> Vec<PathBuf>;
+
+    /// Every file in the object directories the snapshots depend on, directly or indirectly
+    fn referenced_objects(&self) -> Result<HashSet<PathBuf>>;
+
+    /// Where the files of the repository are read from
+    fn storage(&self) -> &dyn Storage;
+
+    /// Whether files without inodes can be grouped into hard links by their contents and
+    /// metadata when asked to. Only formats keeping enough metadata for the guess to be
+    /// reasonable opt in.
+    fn guesses_hard_links(&self) -> bool {
+        false
+    }
+
+    /// Find a snapshot by ID, abbreviated IDs are accepted as long as they're unambiguous
+    fn find_snapshot(&self, id: &str) -> Result<SnapshotInfo> {
+        let snapshots = self.snapshots();
+        if let Some(snapshot) = snapshots.iter().find(|snapshot| snapshot.id == id) {
+            return Ok(snapshot.clone());
+        }
+
+        let mut matches = snapshots
+            .into_iter()
+            .filter(|snapshot| snapshot.id.starts_with(id));
+        match (matches.next(), matches.next()) {
+            (Some(snapshot), None) => Ok(snapshot),
+            _ => Err(Error::SnapshotNotFound(id.to_string())),
+        }
+    }
+
+    /// Find a single entry of a snapshot, `path` is relative to the root of the snapshot
+    fn find_node(&self, snapshot: &str, path: &Path) -> Result<Node> {
+        let path = path.strip_prefix("/").unwrap_or(path);
+
+        self.tree(snapshot)?
+            .into_iter()
+            .find(|node| node.path == path)
+            .ok_or_else(|| Error::PathNotFound(path.to_path_buf()))
+    }
+
+    /// The most recent snapshot in the repository
+    fn latest_snapshot(&self) -> Result<SnapshotInfo> {
+        self.snapshots()
+            .into_iter()
+            .max_by_key(|snapshot| snapshot.time)
+            .ok_or(Error::NoSnapshots)
+    }
+
+    /// The most recent snapshot of each group
+    fn latest_snapshots(&self) -> Vec<SnapshotInfo> {
+        let mut latest: Vec<SnapshotInfo> = Vec::new();
+        for snapshot in self.snapshots() {
+            match latest.iter_mut().find(|s| s.group == snapshot.group) {
+                Some(existing) if existing.time < snapshot.time => *existing = snapshot,
+                Some(_) => {}
+                None => latest.push(snapshot),
+            }
+        }
+
+        latest
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SnapshotInfo {
+    pub id: String,
+    /// Snapshots of the same source, e.g. duplicacy snapshot IDs or knoxite volumes
+    pub group: String,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    /// Path relative to the root of the snapshot
+    pub path: PathBuf,
+    pub kind: NodeKind,
+    /// Unix permission bits, including setuid/setgid/sticky
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub mtime: Option<DateTime<Utc>>,
+    /// Size of the file contents, some formats only know it once the chunks are read
+    pub size: Option<u64>,
+    pub xattrs: Vec<(String, Vec<u8>)>,
+    /// Only available if the format keeps track of inodes
+    pub inode: Option<Inode>,
+    /// Hash of the whole file contents, if the format stores one
+    pub hash: Option<ContentHash>,
+    pub chunks: Vec<ChunkRef>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Dir,
+    Symlink {
+        target: String,
+    },
+    /// Devices, fifos and sockets - listed, but never restored
+    Special,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Inode {
+    pub device: u64,
+    pub inode: u64,
+    pub links: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChunkRef {
+    /// Format specific ID passed to [`Backup::chunk`]
+    pub id: String,
+    /// Offset of the file contents inside the chunk
+    pub start: usize,
+    /// End of the file contents inside the chunk, `None` if it extends to the end of the chunk
+    pub end: Option<usize>,
+    /// Decoded size of the whole chunk, if the format stores it outside of the chunk itself
+    pub size: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ContentHash {
+    Blake2b256(Vec<u8>),
+    Sha256(Vec<u8>),
+}
+
+/// Identifies the files belonging to the same hard link group
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HardLinkKey {
+    Inode {
+        device: u64,
+        inode: u64,
+    },
+    /// Formats without inodes only allow guessing, files that shared an inode have identical
+    /// contents and metadata - there's no way to tell them apart from identical copies though
+    Metadata {
+        hash: Option<ContentHash>,
+        /// Only used if there's no hash, identical files can be stored in different chunks
+        chunks: Vec<ChunkRef>,
+        size: Option<u64>,
+        mtime: Option<DateTime<Utc>>,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    },
+}
+
+impl ChunkRef {
+    /// Reference an entire chunk
+    pub fn whole(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            start: 0,
+            end: None,
+            size: None,
+        }
+    }
+
+    /// Length of the file contents inside the chunk, if it's known without reading the chunk
+    pub fn data_len(&self) -> Option<usize> {
+        Some(self.end.or(self.size)? - self.start)
+    }
+}
+
+impl std::fmt::Display for ContentHash {
+    /// `<algorithm>:<hex>`, stable across formats
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContentHash::Blake2b256(hash) => write!(f, "blake2b-256:{}", hex::encode(hash)),
+            ContentHash::Sha256(hash) => write!(f, "sha256:{}", hex::encode(hash)),
+        }
+    }
+}
+
+impl ContentHash {
+    fn hasher(&self) -> Box<dyn DynDigest> {
+        match self {
+            ContentHash::Blake2b256(_) => Box::new(Blake2b::<U32>::default()),
+            ContentHash::Sha256(_) => Box::new(Sha256::default()),
+        }
+    }
+
+    fn expected(&self) -> &[u8] {
+        match self {
+            ContentHash::Blake2b256(hash) | ContentHash::Sha256(hash) => hash,
+        }
+    }
+}
+
+impl Node {
+    pub fn new(path: impl Into<PathBuf>, kind: NodeKind) -> Self {
+        Self {
+            path: path.into(),
+            kind,
+            mode: None,
+            uid: None,
+            gid: None,
+            user: None,
+            group: None,
+            mtime: None,
+            size: None,
+            xattrs: Vec::new(),
+            inode: None,
+            hash: None,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Key used to find the other files of the same hard link group, `None` if the file can't
+    /// be part of one. Without an inode, files are only grouped by their metadata if `guess` is
+    /// set, see [`Backup::guesses_hard_links`].
+    pub fn hard_link_key(&self, guess: bool) -> Option<HardLinkKey> {
+        if self.kind != NodeKind::File || self.chunks.is_empty() {
+            return None;
+        }
+
+        match self.inode {
+            Some(inode) if inode.links > 1 => Some(HardLinkKey::Inode {
+                device: inode.device,
+                inode: inode.inode,
+            }),
+            Some(_) => None,
+            None if !guess => None,
+            None => Some(HardLinkKey::Metadata {
+                hash: self.hash.clone(),
+                chunks: match self.hash {
+                    Some(_) => Vec::new(),
+                    None => self.chunks.clone(),
+                },
+                size: self.size,
+                mtime: self.mtime,
+                mode: self.mode,
+                uid: self.uid,
+                gid: self.gid,
+            }),
+        }
+    }
+
+    /// Stream the file contents chunk by chunk
+    pub fn reader<'a>(&'a self, backup: &'a dyn Backup) -> FileReader<'a> {
+        self.range_reader(backup, 0, None)
+    }
+
+    /// Stream part of the file contents, starting at `offset` and stopping after `length` bytes.
+    /// Chunks of known size before the offset are skipped without reading them. Partial reads
+    /// can't be verified, the hash is only checked if the whole file is read.
+    pub fn range_reader<'a>(
+        &'a self,
+        backup: &'a dyn Backup,
+        offset: u64,
+        length: Option<u64>,
+    ) -> FileReader<'a> {
+        let whole = offset == 0 && length.is_none();
+
+        FileReader {
+            backup,
+            path: &self.path,
+            chunks: self.chunks.iter(),
+            buffer: Vec::new(),
+            position: 0,
+            skip: offset,
+            remaining: length,
+            verify: self
+                .hash
+                .as_ref()
+                .filter(|_| whole)
+                .map(|hash| (hash.hasher(), hash)),
+        }
+    }
+}
+
+/// The most recently decoded chunks, for formats packing the contents of many small files into
+/// one chunk - without it every file decodes the shared chunk again
+#[derive(Debug, Default)]
+pub struct ChunkCache {
+    /// Least recently used first
+    chunks: RefCell<VecDeque<(String, Vec<u8>)>>,
+}
+
+impl ChunkCache {
+    const CAPACITY: usize = 4;
+
+    /// Return a copy of a cached chunk, or decode it with `load` and cache it
+    pub fn get_or_load(&self, id: &str, load: impl FnOnce() -> Result<Vec<u8>>) -> Result<Vec<u8>> {
+        let mut chunks = self.chunks.borrow_mut();
+        if let Some(index) = chunks.iter().position(|(cached, _)| cached == id) {
+            let entry = chunks.remove(index).unwrap();
+            let data = entry.1.clone();
+            chunks.push_back(entry);
+            return Ok(data);
+        }
+
+        let data = load()?;
+        if chunks.len() == Self::CAPACITY {
+            chunks.pop_front();
+        }
+        chunks.push_back((id.to_string(), data.clone()));
+
+        Ok(data)
+    }
+}
+
+/// Reads the contents of a file, loading its chunks one at a time. The contents are verified
+/// against the file hash once everything has been read, if the format stores one.
+pub struct FileReader<'a> {
+    backup: &'a dyn Backup,
+    path: &'a Path,
+    chunks: std::slice::Iter<'a, ChunkRef>,
+    buffer: Vec<u8>,
+    position: usize,
+    /// Bytes left to drop before the requested range starts
+    skip: u64,
+    /// Bytes left to return, `None` reads until the end of the file
+    remaining: Option<u64>,
+    verify: Option<(Box<dyn DynDigest>, &'a ContentHash)>,
+}
+
+impl FileReader<'_> {
+    fn next_chunk(&mut self) -> Result<bool> {
+        let chunk = loop {
+            let Some(chunk) = self.chunks.next() else {
+                // we're done, check the hash
+                if let Some((hasher, expected)) = self.verify.take() {
+                    if *hasher.finalize() != *expected.expected() {
+                        return Err(Error::MismatchedHash(self.path.to_path_buf()));
+                    }
+                }
+
+                return Ok(false);
+            };
+
+            match chunk.data_len() {
+                Some(len) if (len as u64) <= self.skip => self.skip -= len as u64,
+                _ => break chunk,
+            }
+        };
+
+        let mut data = self.backup.chunk(&chunk.id)?;
+        let end = chunk.end.unwrap_or(data.len());
+        if chunk.start > end || end > data.len() {
+            return Err(Error::TruncatedChunk(chunk.id.clone(), data.len()));
+        }
+        data.truncate(end);
+        data.drain(..chunk.start);
+
+        if let Some((hasher, _)) = &mut self.verify {
+            hasher.update(&data);
+        }
+
+        // the size of the chunk might not have been known in advance
+        let skip = self.skip.min(data.len() as u64);
+        data.drain(..skip as usize);
+        self.skip -= skip;
+
+        self.buffer = data;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == Some(0) {
+            return Ok(0);
+        }
+
+        while self.position == self.buffer.len() {
+            if !self.next_chunk().map_err(std::io::Error::other)? {
+                return Ok(0);
+            }
+        }
+
+        let mut len = buf.len().min(self.buffer.len() - self.position);
+        if let Some(remaining) = &mut self.remaining {
+            len = len.min(*remaining as usize);
+            *remaining -= len as u64;
+        }
+        buf[..len].copy_from_slice(&self.buffer[self.position..self.position + len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn chunk_cache() {
+        let cache = ChunkCache::default();
+        let loads = Cell::new(0);
+        let load = |id: &str| {
+            cache
+                .get_or_load(id, || {
+                    loads.set(loads.get() + 1);
+                    Ok(id.as_bytes().to_vec())
+                })
+                .unwrap()
+        };
+
+        assert_eq!(load("a"), b"a");
+        assert_eq!(load("a"), b"a");
+        assert_eq!(loads.get(), 1);
+
+        // "a" is used again before the cache is full, so "b" is evicted first
+        for id in ["b", "c", "d", "a", "e"] {
+            load(id);
+        }
+        assert_eq!(loads.get(), 5);
+        load("a");
+        assert_eq!(loads.get(), 5);
+        load("b");
+        assert_eq!(loads.get(), 6);
+    }
+}
@@ -0,0 +1,108 @@
+//! Search the contents of the files of a snapshot without restoring them
+
+use std::io::{BufRead, BufReader, Read, Write};
+
+use regex::bytes::Regex;
+
+use crate::{
+    error::Result,
+    find::PathPattern,
+    formats::{
+        tree::{Node, NodeKind},
+        Backup,
+    },
+};
+
+#[derive(Debug, Default)]
+pub struct GrepOptions {
+    /// Only files with matching paths are searched
+    pub paths: Option<PathPattern>,
+    /// Skip files larger than this, files of unknown size are only searched up to it
+    pub max_size: Option<u64>,
+}
+
+/// Search every file of a snapshot for lines matching `pattern`, writing them as
+/// `path:line:contents`. Files are streamed chunk by chunk, nothing is written to disk.
+/// Returns the number of matching lines.
+pub fn grep(
+    backup: &dyn Backup,
+    snapshot: &str,
+    pattern: &Regex,
+    options: &GrepOptions,
+    mut writer: impl Write,
+) -> Result<usize> {
+    let mut matches = 0;
+
+    for node in backup.tree(snapshot)? {
+        if node.kind != NodeKind::File {
+            continue;
+        }
+        if let Some(paths) = &options.paths {
+            if !paths.is_match(&node.path) {
+                continue;
+            }
+        }
+        if let (Some(size), Some(max_size)) = (node.size, options.max_size) {
+            if size > max_size {
+                debug!("Skipping {:?}, it's larger than the size limit", node.path);
+                continue;
+            }
+        }
+
+        trace!("Searching {:?}", node.path);
+        match grep_file(backup, &node, pattern, options, &mut writer) {
+            Ok(found) => matches += found,
+            // a single unreadable file shouldn't stop the search
+            Err(e) => warn!("Failed to search {:?}: {e}", node.path),
+        }
+    }
+
+    writer.flush()?;
+    Ok(matches)
+}
+
+fn grep_file(
+    backup: &dyn Backup,
+    node: &Node,
+    pattern: &Regex,
+    options: &GrepOptions,
+    writer: &mut impl Write,
+) -> Result<usize> {
+    let reader = node.reader(backup);
+    let mut reader: Box<dyn BufRead> = match options.max_size {
+        Some(max_size) => Box::new(BufReader::new(reader.take(max_size))),
+        None => Box::new(BufReader::new(reader)),
+    };
+
+    let mut matches = 0;
+    let mut line = Vec::new();
+    let mut number = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        number += 1;
+
+        if !pattern.is_match(&line) {
+            continue;
+        }
+        matches += 1;
+
+        // same as grep, don't dump binary garbage into the terminal
+        if line.contains(&0) {
+            writeln!(writer, "Binary file {} matches", node.path.display())?;
+            break;
+        }
+
+        let text = String::from_utf8_lossy(&line);
+        writeln!(
+            writer,
+            "{}:{number}:{}",
+            node.path.display(),
+            text.trim_end_matches(['\n', '\r'])
+        )?;
+    }
+
+    Ok(matches)
+}
@@ -0,0 +1,77 @@
+//! Versions of a single path across all snapshots
+
+use std::{io::Write, path::Path};
+
+use crate::{
+    diff,
+    error::Result,
+    formats::{
+        tree::{Node, SnapshotInfo},
+        Backup,
+    },
+};
+
+/// A version of the path, kept unchanged by one or more consecutive snapshots
+#[derive(Debug)]
+pub struct Version {
+    pub node: Node,
+    pub snapshots: Vec<SnapshotInfo>,
+}
+
+/// List the versions of a path, oldest first. Consecutive snapshots with identical contents and
+/// metadata are collapsed into one version, a snapshot without the path always starts a new one.
+pub fn history(backup: &dyn Backup, path: &Path) -> Result<Vec<Version>> {
+    let mut versions: Vec<Version> = Vec::new();
+    let mut previous_found = false;
+
+    for snapshot in backup.snapshots() {
+        let node = match backup.find_node(&snapshot.id, path) {
+            Ok(node) => node,
+            Err(crate::error::Error::PathNotFound(_)) => {
+                previous_found = false;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        match versions.last_mut() {
+            Some(version) if previous_found && diff::compare(&version.node, &node).is_none() => {
+                version.snapshots.push(snapshot)
+            }
+            _ => versions.push(Version {
+                node,
+                snapshots: vec![snapshot],
+            }),
+        }
+        previous_found = true;
+    }
+
+    Ok(versions)
+}
+
+/// Write one line per version: version number, first and last snapshot, number of snapshots,
+/// size, mtime and content hash
+pub fn write_history(versions: &[Version], mut writer: impl Write) -> Result<()> {
+    for (number, version) in versions.iter().enumerate() {
+        let node = &version.node;
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            number + 1,
+            version.snapshots.first().unwrap().id,
+            version.snapshots.last().unwrap().id,
+            version.snapshots.len(),
+            node.size.map(|size| size.to_string()).unwrap_or_default(),
+            node.mtime
+                .map(|mtime| mtime.to_rfc3339())
+                .unwrap_or_default(),
+            node.hash
+                .as_ref()
+                .map(|hash| hash.to_string())
+                .unwrap_or_default(),
+        )?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
@@ -1,87 +1,519 @@
-#![feature(cursor_remaining, iter_advance_by)]
-
 #[macro_use]
 extern crate tracing;
 
-use clap::{Parser, ValueEnum};
+use std::{fs::File, io::BufWriter, path::PathBuf, rc::Rc};
+
+use clap::{Args as ClapArgs, Parser, Subcommand};
 use tracing::Level;
 use tracing_subscriber::{filter, prelude::*};
 
+mod diff;
 mod error;
+mod export;
+mod find;
 mod formats;
+mod grep;
+mod history;
+mod manifest;
+mod orphans;
+mod restore;
+mod stats;
+mod storage;
 mod utils;
 
-use formats::{BlobBackup, Duplicacy, Knoxite, Restic};
+use diff::DiffFormat;
+use export::{Compression, ExportFormat, ExportOptions};
+use find::PathPattern;
+use formats::{
+    tree::NodeKind, Backup, BackupFormat, BlobBackup, Borg, Bup, Bupstash, Casync, Duplicacy,
+    Duplicati, Duplicity, Knoxite, Kopia, Pbs, Restic,
+};
+use grep::GrepOptions;
+use manifest::ManifestFormat;
+use restore::{FileWriter, RestoreOptions, RestoreReport, XattrNamespace};
+use stats::StatsFormat;
+use storage::Storage;
 
 #[derive(Parser, Debug)]
 struct Args {
-    /// Format
+    /// Format, detected from the repository layout if not given
     #[arg(value_enum, short, long)]
-    format: BackupFormat,
+    format: Option<BackupFormat>,
 
     /// Repository
     #[arg(short, long)]
     repository: String,
 
+    /// Password
+    #[arg(short, long)]
+    password: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the snapshots in the repository
+    List,
+    /// Restore snapshots into a directory
+    Restore(RestoreArgs),
+    /// Export a snapshot as a single archive
+    Export(ExportArgs),
+    /// List every entry of a snapshot with its metadata and chunks
+    Manifest(ManifestArgs),
+    /// Show what changed between two snapshots
+    Diff(DiffArgs),
+    /// Write the contents of a single file to stdout
+    Cat(CatArgs),
+    /// Search every snapshot for matching paths
+    Find(FindArgs),
+    /// List the versions of a file across all snapshots
+    History(HistoryArgs),
+    /// Search the contents of the files of a snapshot
+    Grep(GrepArgs),
+    /// Show size and deduplication statistics of every snapshot and the whole repository
+    Stats(StatsArgs),
+    /// List files in the repository no snapshot references, without deleting them
+    Orphans,
+}
+
+#[derive(ClapArgs, Debug)]
+struct RestoreArgs {
     /// Output directory
     #[arg(short, long)]
-    output_dir: String,
+    output_dir: PathBuf,
 
-    /// Password
+    /// Snapshot to restore, defaults to the latest snapshot of each source, each one restored
+    /// into its own subdirectory if there's more than one
     #[arg(short, long)]
-    password: Option<String>,
+    snapshot: Option<String>,
+
+    /// Restore extended attributes and ACLs, optionally only from the given namespaces
+    #[arg(long, value_enum, num_args = 0.., value_delimiter = ',')]
+    xattrs: Option<Vec<XattrNamespace>>,
+
+    /// Restore files that shared an inode as separate copies instead of hard links
+    #[arg(long)]
+    no_hard_links: bool,
+
+    /// Link files with identical contents and metadata in formats without inode numbers,
+    /// independent copies get linked too
+    #[arg(long, conflicts_with = "no_hard_links")]
+    guess_hard_links: bool,
+
+    /// Leave holes for blocks of zeros instead of writing them out
+    #[arg(long)]
+    sparse: bool,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
-enum BackupFormat {
-    Duplicacy,
-    Restic,
-    Knoxite,
-    BlobBackup,
+#[derive(ClapArgs, Debug)]
+struct ExportArgs {
+    /// Archive format
+    #[arg(value_enum, long)]
+    format: ExportFormat,
+
+    /// Compression used for the entries of zip archives
+    #[arg(value_enum, long, default_value_t)]
+    compression: Compression,
+
+    /// Store files that shared an inode as separate copies instead of hard links
+    #[arg(long)]
+    no_hard_links: bool,
+
+    /// Link files with identical contents and metadata in formats without inode numbers,
+    /// independent copies get linked too
+    #[arg(long, conflicts_with = "no_hard_links")]
+    guess_hard_links: bool,
+
+    /// Snapshot to export, defaults to the latest one
+    #[arg(short, long)]
+    snapshot: Option<String>,
+
+    /// Output file, defaults to stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
 }
 
-fn main() -> miette::Result<()> {
-    // initialize logging
-    let filter = filter::Targets::new().with_target("backup_dumper", Level::TRACE);
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
-        .with(filter)
-        .init();
+#[derive(ClapArgs, Debug)]
+struct ManifestArgs {
+    /// Output format
+    #[arg(value_enum, long, default_value_t)]
+    format: ManifestFormat,
 
-    let args = Args::parse();
+    /// Snapshot to list, defaults to the latest one
+    #[arg(short, long)]
+    snapshot: Option<String>,
+
+    /// Output file, defaults to stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct DiffArgs {
+    /// Older snapshot
+    from: String,
+
+    /// Newer snapshot
+    to: String,
+
+    /// Output format
+    #[arg(value_enum, long, default_value_t)]
+    format: DiffFormat,
+}
+
+#[derive(ClapArgs, Debug)]
+struct CatArgs {
+    /// Snapshot containing the file
+    snapshot: String,
+
+    /// Path of the file inside the snapshot
+    path: PathBuf,
+
+    /// Skip this many bytes at the start of the file
+    #[arg(long, default_value_t = 0)]
+    offset: u64,
+
+    /// Stop after this many bytes, defaults to the end of the file
+    #[arg(long)]
+    length: Option<u64>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct FindArgs {
+    /// Glob matched against the file name, or the whole path if it contains a `/`
+    pattern: String,
+
+    /// Treat the pattern as a regular expression matched against the whole path
+    #[arg(long)]
+    regex: bool,
+}
+
+#[derive(ClapArgs, Debug)]
+struct HistoryArgs {
+    /// Path inside the snapshots
+    path: PathBuf,
+
+    /// Restore this version instead of listing them, numbered as in the listing
+    #[arg(long, requires = "output")]
+    restore: Option<usize>,
+
+    /// File the restored version is written to
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct GrepArgs {
+    /// Regular expression matched against each line
+    pattern: String,
+
+    /// Snapshot to search
+    snapshot: String,
+
+    /// Only search files matching this glob, see `find`
+    path: Option<String>,
 
-    match args.format {
+    /// Skip files larger than this many bytes
+    #[arg(long)]
+    max_size: Option<u64>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct StatsArgs {
+    /// Output format
+    #[arg(value_enum, long, default_value_t)]
+    format: StatsFormat,
+}
+
+/// Open the repository and load all of its snapshots
+fn open(
+    format: BackupFormat,
+    storage: Rc<dyn Storage>,
+    repository: PathBuf,
+    password: Option<String>,
+) -> miette::Result<Box<dyn Backup>> {
+    let backup: Box<dyn Backup> = match format {
         BackupFormat::Duplicacy => {
-            let mut duplicacy = Duplicacy::from_folder(args.repository, args.password)?;
+            let mut duplicacy = Duplicacy::from_folder(storage, repository, password)?;
             duplicacy.load_all()?;
-            duplicacy.dump_all_files(args.output_dir)?;
+            Box::new(duplicacy)
         }
         BackupFormat::Restic => {
             let mut restic = Restic::from_folder(
-                args.repository,
-                args.password
-                    .expect("Password is required for restic repositories"),
+                storage,
+                repository,
+                password.expect("Password is required for restic repositories"),
             )?;
             restic.load_all()?;
-            restic.dump_all_files(args.output_dir)?;
+            Box::new(restic)
         }
         BackupFormat::Knoxite => {
             let mut knoxite = Knoxite::from_folder(
-                args.repository,
-                args.password
-                    .expect("Password is required for knoxite repositories"),
+                storage,
+                repository,
+                password.expect("Password is required for knoxite repositories"),
             )?;
             knoxite.load_all()?;
-            knoxite.dump_all_files(args.output_dir)?;
+            Box::new(knoxite)
         }
         BackupFormat::BlobBackup => {
             let mut blobbackup = BlobBackup::from_folder(
-                args.repository,
-                args.password
-                    .expect("Password is required for blob-backup repositories"),
+                storage,
+                repository,
+                password.expect("Password is required for blob-backup repositories"),
             )?;
             blobbackup.load_all()?;
-            blobbackup.dump_all_files(args.output_dir)?;
+            Box::new(blobbackup)
+        }
+        BackupFormat::Borg => {
+            let mut borg = Borg::from_folder(storage, repository, password)?;
+            borg.load_all()?;
+            Box::new(borg)
+        }
+        BackupFormat::Kopia => {
+            let mut kopia = Kopia::from_folder(storage, repository, password)?;
+            kopia.load_all()?;
+            Box::new(kopia)
+        }
+        BackupFormat::Duplicati => {
+            let mut duplicati = Duplicati::from_folder(storage, repository, password)?;
+            duplicati.load_all()?;
+            Box::new(duplicati)
+        }
+        BackupFormat::Pbs => {
+            let mut pbs = Pbs::from_folder(storage, repository, password)?;
+            pbs.load_all()?;
+            Box::new(pbs)
+        }
+        BackupFormat::Duplicity => {
+            let mut duplicity = Duplicity::from_folder(storage, repository, password)?;
+            duplicity.load_all()?;
+            Box::new(duplicity)
+        }
+        BackupFormat::Bupstash => {
+            let mut bupstash = Bupstash::from_folder(storage, repository)?;
+            bupstash.load_all()?;
+            Box::new(bupstash)
+        }
+        BackupFormat::Casync => {
+            let mut casync = Casync::from_folder(storage, repository)?;
+            casync.load_all()?;
+            Box::new(casync)
+        }
+        BackupFormat::Bup => {
+            let mut bup = Bup::from_folder(storage, repository)?;
+            bup.load_all()?;
+            Box::new(bup)
+        }
+    };
+
+    Ok(backup)
+}
+
+fn main() -> miette::Result<()> {
+    // initialize logging - stdout is reserved for output, e.g. exported archives
+    let filter = filter::Targets::new().with_target("backup_dumper", Level::TRACE);
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(filter)
+        .init();
+
+    let args = Args::parse();
+
+    let (storage, repository) = storage::open(&args.repository)?;
+
+    let format = match args.format {
+        Some(format) => format,
+        None => {
+            let format = formats::detect::detect(&*storage, &repository)?;
+            info!("Detected {format:?} repository");
+            format
+        }
+    };
+
+    let backup = open(format, storage, repository, args.password)?;
+
+    match args.command {
+        Command::List => {
+            for snapshot in backup.snapshots() {
+                println!(
+                    "{}\t{}\t{}",
+                    snapshot.id,
+                    snapshot.time.to_rfc3339(),
+                    snapshot.group
+                );
+            }
+        }
+        Command::Restore(restore_args) => {
+            let options = RestoreOptions {
+                xattrs: restore_args.xattrs,
+                hard_links: !restore_args.no_hard_links,
+                guess_hard_links: restore_args.guess_hard_links,
+                sparse: restore_args.sparse,
+            };
+
+            let snapshots = match restore_args.snapshot {
+                Some(id) => vec![backup.find_snapshot(&id)?],
+                None => backup.latest_snapshots(),
+            };
+
+            let mut report = RestoreReport::default();
+            for snapshot in &snapshots {
+                let output_dir = if snapshots.len() > 1 {
+                    restore_args.output_dir.join(&snapshot.group)
+                } else {
+                    restore_args.output_dir.clone()
+                };
+
+                info!("Restoring snapshot {} into {output_dir:?}", snapshot.id);
+                restore::restore(
+                    backup.as_ref(),
+                    &snapshot.id,
+                    output_dir,
+                    &options,
+                    &mut report,
+                )?;
+            }
+            report.log();
+        }
+        Command::Export(export_args) => {
+            let snapshot = match export_args.snapshot {
+                Some(id) => backup.find_snapshot(&id)?,
+                None => backup.latest_snapshot()?,
+            };
+
+            let options = ExportOptions {
+                format: export_args.format,
+                compression: export_args.compression,
+                hard_links: !export_args.no_hard_links,
+                guess_hard_links: export_args.guess_hard_links,
+            };
+
+            info!("Exporting snapshot {}", snapshot.id);
+            match export_args.output {
+                Some(path) => {
+                    let file = BufWriter::new(File::create(path).map_err(error::Error::from)?);
+                    export::export(backup.as_ref(), &snapshot.id, file, &options)?;
+                }
+                None => {
+                    let stdout = BufWriter::new(std::io::stdout().lock());
+                    export::export(backup.as_ref(), &snapshot.id, stdout, &options)?;
+                }
+            }
+        }
+        Command::Manifest(manifest_args) => {
+            let snapshot = match manifest_args.snapshot {
+                Some(id) => backup.find_snapshot(&id)?,
+                None => backup.latest_snapshot()?,
+            };
+
+            match manifest_args.output {
+                Some(path) => {
+                    let file = BufWriter::new(File::create(path).map_err(error::Error::from)?);
+                    manifest::write_manifest(
+                        backup.as_ref(),
+                        &snapshot.id,
+                        manifest_args.format,
+                        file,
+                    )?;
+                }
+                None => {
+                    let stdout = BufWriter::new(std::io::stdout().lock());
+                    manifest::write_manifest(
+                        backup.as_ref(),
+                        &snapshot.id,
+                        manifest_args.format,
+                        stdout,
+                    )?;
+                }
+            }
+        }
+        Command::Diff(diff_args) => {
+            let from = backup.find_snapshot(&diff_args.from)?;
+            let to = backup.find_snapshot(&diff_args.to)?;
+
+            info!("Comparing snapshot {} to {}", from.id, to.id);
+            let changes = diff::diff(backup.as_ref(), &from.id, &to.id)?;
+            diff::write_diff(&changes, diff_args.format, std::io::stdout().lock())?;
+        }
+        Command::Cat(cat_args) => {
+            let snapshot = backup.find_snapshot(&cat_args.snapshot)?;
+            let node = backup.find_node(&snapshot.id, &cat_args.path)?;
+            if node.kind != NodeKind::File {
+                return Err(error::Error::NotAFile(node.path).into());
+            }
+
+            let mut reader = node.range_reader(backup.as_ref(), cat_args.offset, cat_args.length);
+            std::io::copy(&mut reader, &mut std::io::stdout().lock())
+                .map_err(error::Error::from)?;
+        }
+        Command::Find(find_args) => {
+            let pattern = match find_args.regex {
+                true => PathPattern::regex(&find_args.pattern)?,
+                false => PathPattern::glob(&find_args.pattern)?,
+            };
+
+            let matches = find::find(backup.as_ref(), &pattern, std::io::stdout().lock())?;
+            info!("Found {matches} matches");
+        }
+        Command::History(history_args) => {
+            let versions = history::history(backup.as_ref(), &history_args.path)?;
+
+            match (history_args.restore, history_args.output) {
+                (Some(number), Some(output)) => {
+                    let version = number
+                        .checked_sub(1)
+                        .and_then(|i| versions.get(i))
+                        .ok_or(error::Error::VersionNotFound(number))?;
+                    let node = &version.node;
+                    if node.kind != NodeKind::File {
+                        return Err(error::Error::NotAFile(node.path.clone()).into());
+                    }
+
+                    info!(
+                        "Restoring version {number} from snapshot {} to {output:?}",
+                        version.snapshots[0].id
+                    );
+                    let mut writer = FileWriter::create(&output, &RestoreOptions::default())
+                        .map_err(error::Error::from)?;
+                    std::io::copy(&mut node.reader(backup.as_ref()), &mut writer)
+                        .map_err(error::Error::from)?;
+                    writer.finish().map_err(error::Error::from)?;
+                }
+                _ => history::write_history(&versions, std::io::stdout().lock())?,
+            }
+        }
+        Command::Grep(grep_args) => {
+            let snapshot = backup.find_snapshot(&grep_args.snapshot)?;
+            let pattern =
+                regex::bytes::Regex::new(&grep_args.pattern).map_err(error::Error::from)?;
+            let options = GrepOptions {
+                paths: grep_args
+                    .path
+                    .as_deref()
+                    .map(PathPattern::glob)
+                    .transpose()?,
+                max_size: grep_args.max_size,
+            };
+
+            let matches = grep::grep(
+                backup.as_ref(),
+                &snapshot.id,
+                &pattern,
+                &options,
+                std::io::stdout().lock(),
+            )?;
+            info!("Found {matches} matching lines");
+        }
+        Command::Stats(stats_args) => {
+            let report = stats::stats(backup.as_ref())?;
+            stats::write_report(&report, stats_args.format, std::io::stdout().lock())?;
+        }
+        Command::Orphans => {
+            let orphans = orphans::orphans(backup.as_ref())?;
+            orphans::write_orphans(&orphans, std::io::stdout().lock())?;
         }
     }
 
@@ -0,0 +1,138 @@
+//! Machine readable listing of every entry of a snapshot, with the same schema for all formats
+
+use std::io::Write;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    error::Result,
+    formats::{
+        tree::{Node, NodeKind},
+        Backup,
+    },
+};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug, Default)]
+pub enum ManifestFormat {
+    /// One JSON object per line
+    #[default]
+    Jsonl,
+    /// Chunk IDs are separated by spaces
+    Csv,
+}
+
+/// A single manifest line, fields the format doesn't store are left empty
+#[derive(Serialize, Debug)]
+pub struct ManifestEntry {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub node_type: &'static str,
+    pub size: Option<u64>,
+    /// Octal permission bits, e.g. `0644`
+    pub mode: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// RFC 3339
+    pub mtime: Option<String>,
+    /// `<algorithm>:<hex>`
+    pub hash: Option<String>,
+    pub link_target: Option<String>,
+    pub chunks: Vec<String>,
+}
+
+const CSV_HEADER: [&str; 12] = [
+    "path",
+    "type",
+    "size",
+    "mode",
+    "uid",
+    "gid",
+    "user",
+    "group",
+    "mtime",
+    "hash",
+    "link_target",
+    "chunks",
+];
+
+impl From<&Node> for ManifestEntry {
+    fn from(node: &Node) -> Self {
+        let (node_type, link_target) = match &node.kind {
+            NodeKind::File => ("file", None),
+            NodeKind::Dir => ("dir", None),
+            NodeKind::Symlink { target } => ("symlink", Some(target.clone())),
+            NodeKind::Special => ("special", None),
+        };
+
+        Self {
+            path: node.path.to_string_lossy().to_string(),
+            node_type,
+            size: node.size,
+            mode: node.mode.map(|mode| format!("{mode:04o}")),
+            uid: node.uid,
+            gid: node.gid,
+            user: node.user.clone(),
+            group: node.group.clone(),
+            mtime: node.mtime.map(|mtime| mtime.to_rfc3339()),
+            hash: node.hash.as_ref().map(|hash| hash.to_string()),
+            link_target,
+            chunks: node.chunks.iter().map(|chunk| chunk.id.clone()).collect(),
+        }
+    }
+}
+
+impl ManifestEntry {
+    fn to_csv_record(&self) -> [String; 12] {
+        fn opt<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map(T::to_string).unwrap_or_default()
+        }
+
+        [
+            self.path.clone(),
+            self.node_type.to_string(),
+            opt(&self.size),
+            opt(&self.mode),
+            opt(&self.uid),
+            opt(&self.gid),
+            opt(&self.user),
+            opt(&self.group),
+            opt(&self.mtime),
+            opt(&self.hash),
+            opt(&self.link_target),
+            self.chunks.join(" "),
+        ]
+    }
+}
+
+/// Write the manifest of a snapshot, only metadata is read
+pub fn write_manifest(
+    backup: &dyn Backup,
+    snapshot: &str,
+    format: ManifestFormat,
+    mut writer: impl Write,
+) -> Result<()> {
+    let nodes = backup.tree(snapshot)?;
+
+    match format {
+        ManifestFormat::Jsonl => {
+            for node in &nodes {
+                serde_json::to_writer(&mut writer, &ManifestEntry::from(node))?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        ManifestFormat::Csv => {
+            let mut csv = csv::Writer::from_writer(writer);
+            csv.write_record(CSV_HEADER)?;
+            for node in &nodes {
+                csv.write_record(ManifestEntry::from(node).to_csv_record())?;
+            }
+            csv.flush()?;
+        }
+    }
+
+    Ok(())
+}
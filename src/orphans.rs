@@ -0,0 +1,66 @@
+//! Find files in the repository that no snapshot references. Nothing is ever deleted, the
+//! files are only listed.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::Result, formats::Backup, storage::Storage};
+
+#[derive(Debug)]
+pub struct Orphan {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// List every file in `dir` and its subdirectories along with its size
+fn walk(storage: &dyn Storage, dir: &Path, files: &mut Vec<Orphan>) -> Result<()> {
+    for entry in storage.list(dir)? {
+        if entry.stat.is_dir {
+            walk(storage, &entry.path, files)?;
+        } else {
+            files.push(Orphan {
+                path: entry.path,
+                size: entry.stat.size,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Compare the objects referenced by the snapshots with the ones in the repository
+pub fn orphans(backup: &dyn Backup) -> Result<Vec<Orphan>> {
+    let referenced = backup.referenced_objects()?;
+    debug!("Snapshots reference {} objects", referenced.len());
+
+    let mut files = Vec::new();
+    let storage = backup.storage();
+    for dir in backup.object_dirs() {
+        if storage.is_dir(&dir) {
+            walk(storage, &dir, &mut files)?;
+        }
+    }
+
+    let mut orphans: Vec<Orphan> = files
+        .into_iter()
+        .filter(|file| !referenced.contains(&file.path))
+        .collect();
+    orphans.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(orphans)
+}
+
+/// Write one line per orphan with its size and path, followed by the total
+pub fn write_orphans(orphans: &[Orphan], mut writer: impl Write) -> Result<()> {
+    for orphan in orphans {
+        writeln!(writer, "{}\t{}", orphan.size, orphan.path.display())?;
+    }
+
+    let total: u64 = orphans.iter().map(|orphan| orphan.size).sum();
+    writeln!(writer, "{} orphaned files, {total} bytes", orphans.len())?;
+
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,330 @@
+//! Options and helpers shared by the formats when restoring files to disk
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+
+use crate::{
+    error::Result,
+    formats::{
+        tree::{HardLinkKey, NodeKind},
+        Backup,
+    },
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct RestoreOptions {
+    /// Extended attribute namespaces to restore, nothing is restored if `None`.
+    /// An empty list restores every supported namespace.
+    pub xattrs: Option<Vec<XattrNamespace>>,
+    /// Recreate files that shared an inode as hard links instead of duplicating their contents
+    pub hard_links: bool,
+    /// Also link files without inodes that have the same contents and metadata, which links
+    /// identical copies as well
+    pub guess_hard_links: bool,
+    /// Seek past blocks of zeros instead of writing them, leaving holes in the restored files
+    pub sparse: bool,
+}
+
+/// Holes are only created for whole blocks of zeros, this matches the block size of most
+/// filesystems so smaller runs wouldn't save any space anyway
+const SPARSE_BLOCK_SIZE: u64 = 4096;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum XattrNamespace {
+    /// `user.*`
+    User,
+    /// `security.*`, e.g. SELinux labels and file capabilities
+    Security,
+    /// `system.posix_acl_*`, access and default POSIX ACLs
+    Acl,
+}
+
+impl XattrNamespace {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            XattrNamespace::User => name.starts_with("user."),
+            XattrNamespace::Security => name.starts_with("security."),
+            XattrNamespace::Acl => name.starts_with("system.posix_acl_"),
+        }
+    }
+}
+
+/// Summary of a restore, including everything that went wrong but wasn't worth aborting for
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub failed_xattrs: Vec<FailedXattr>,
+    pub hard_links: usize,
+}
+
+#[derive(Debug)]
+pub struct FailedXattr {
+    pub path: PathBuf,
+    pub name: String,
+    pub error: std::io::Error,
+}
+
+impl RestoreOptions {
+    fn wants_xattr(&self, name: &str) -> bool {
+        match &self.xattrs {
+            None => false,
+            Some(namespaces) if namespaces.is_empty() => XattrNamespace::value_variants()
+                .iter()
+                .any(|ns| ns.matches(name)),
+            Some(namespaces) => namespaces.iter().any(|ns| ns.matches(name)),
+        }
+    }
+
+    /// Write the extended attributes of an already restored file, any failures are added to the
+    /// report instead of stopping the restore
+    pub fn apply_xattrs<'a>(
+        &self,
+        path: impl AsRef<Path>,
+        xattrs: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+        report: &mut RestoreReport,
+    ) {
+        for (name, value) in xattrs {
+            if !self.wants_xattr(name) {
+                trace!("Skipping xattr {name} on {:?}", path.as_ref());
+                continue;
+            }
+
+            // don't follow symlinks, the attributes belong to the entry itself
+            if let Err(error) = xattr::set(path.as_ref(), name, value) {
+                report.failed_xattrs.push(FailedXattr {
+                    path: path.as_ref().to_path_buf(),
+                    name: name.to_string(),
+                    error,
+                });
+            }
+        }
+    }
+}
+
+/// Remembers where the first file of each hard link group was restored
+#[derive(Debug, Default)]
+pub struct HardLinks {
+    restored: HashMap<HardLinkKey, PathBuf>,
+}
+
+impl HardLinks {
+    /// Link `path` to a previously restored file of the same group, returns `false` if this is
+    /// the first file of the group (or linking failed) and its contents need to be written out
+    pub fn link(
+        &mut self,
+        key: HardLinkKey,
+        path: impl AsRef<Path>,
+        report: &mut RestoreReport,
+    ) -> bool {
+        let path = path.as_ref();
+
+        let Some(original) = self.restored.get(&key) else {
+            self.restored.insert(key, path.to_path_buf());
+            return false;
+        };
+
+        // restores overwrite existing files, links have to do the same
+        if path.exists() {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Failed to remove {path:?} before linking it: {e}");
+                return false;
+            }
+        }
+
+        match std::fs::hard_link(original, path) {
+            Ok(()) => {
+                trace!("Linked {path:?} to {original:?}");
+                report.hard_links += 1;
+                true
+            }
+            Err(e) => {
+                // e.g. the filesystem doesn't support hard links, fall back to a copy
+                warn!("Failed to link {path:?} to {original:?}: {e}");
+                false
+            }
+        }
+    }
+}
+
+/// Restore the file tree of a snapshot into `output_dir`
+pub fn restore(
+    backup: &dyn Backup,
+    snapshot: &str,
+    output_dir: impl AsRef<Path>,
+    options: &RestoreOptions,
+    report: &mut RestoreReport,
+) -> Result<()> {
+    let output_dir = output_dir.as_ref();
+    std::fs::create_dir_all(output_dir)?;
+
+    let mut hard_links = HardLinks::default();
+
+    for node in backup.tree(snapshot)? {
+        let path = output_dir.join(&node.path);
+
+        match &node.kind {
+            NodeKind::Dir => {
+                // directories are part of the tree, recreate them even if they're empty
+                debug!("Creating directory {:?}", node.path);
+                std::fs::create_dir_all(&path)?;
+            }
+            NodeKind::File => {
+                // parents are listed first, but not every format stores its directories
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+
+                if options.hard_links
+                    && node
+                        .hard_link_key(options.guess_hard_links && backup.guesses_hard_links())
+                        .is_some_and(|key| hard_links.link(key, &path, report))
+                {
+                    continue;
+                }
+
+                debug!("Dumping {:?}", node.path);
+
+                let mut writer = FileWriter::create(&path, options)?;
+                if let Err(e) = std::io::copy(&mut node.reader(backup), &mut writer) {
+                    // don't leave corrupted files behind
+                    drop(writer);
+                    std::fs::remove_file(&path)?;
+                    return Err(e.into());
+                }
+                writer.finish()?;
+            }
+            NodeKind::Symlink { target } => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+
+                // restores overwrite existing files, symlinks have to do the same
+                if path.symlink_metadata().is_ok() {
+                    std::fs::remove_file(&path)?;
+                }
+
+                debug!("Linking {:?} -> {target}", node.path);
+                std::os::unix::fs::symlink(target, &path)?;
+            }
+            NodeKind::Special => {
+                trace!("Skipping special file {:?}", node.path);
+                continue;
+            }
+        }
+
+        options.apply_xattrs(
+            &path,
+            node.xattrs
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_slice())),
+            report,
+        );
+    }
+
+    Ok(())
+}
+
+/// Writes a restored file chunk by chunk, so the whole file never has to be kept in memory
+pub struct FileWriter {
+    file: File,
+    sparse: bool,
+    /// Apparent size of the file so far
+    len: u64,
+    /// Length of the zero run we've skipped over but haven't seeked past yet
+    hole: u64,
+}
+
+impl FileWriter {
+    pub fn create(path: impl AsRef<Path>, options: &RestoreOptions) -> std::io::Result<Self> {
+        let file = File::create(path)?;
+
+        Ok(Self {
+            file,
+            sparse: options.sparse,
+            len: 0,
+            hole: 0,
+        })
+    }
+
+    /// Flush any pending hole and set the final size of the file, a file ending with a hole
+    /// would otherwise be truncated
+    pub fn finish(mut self) -> std::io::Result<()> {
+        if self.hole > 0 {
+            self.file.set_len(self.len)?;
+        }
+        self.file.flush()
+    }
+
+    fn write_block(&mut self, block: &[u8]) -> std::io::Result<()> {
+        if block.iter().all(|b| *b == 0) {
+            self.hole += block.len() as u64;
+        } else {
+            if self.hole > 0 {
+                self.file.seek(SeekFrom::Current(self.hole as i64))?;
+                self.hole = 0;
+            }
+            self.file.write_all(block)?;
+        }
+
+        self.len += block.len() as u64;
+        Ok(())
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.sparse {
+            let written = self.file.write(buf)?;
+            self.len += written as u64;
+            return Ok(written);
+        }
+
+        // keep the blocks aligned to the file offset rather than the chunk boundaries, so every
+        // zero block we skip is one the filesystem doesn't have to allocate
+        let mut buf_rest = buf;
+        let unaligned = ((SPARSE_BLOCK_SIZE - self.len % SPARSE_BLOCK_SIZE) % SPARSE_BLOCK_SIZE)
+            .min(buf.len() as u64) as usize;
+        if unaligned > 0 {
+            let (head, tail) = buf_rest.split_at(unaligned);
+            self.write_block(head)?;
+            buf_rest = tail;
+        }
+
+        for block in buf_rest.chunks(SPARSE_BLOCK_SIZE as usize) {
+            self.write_block(block)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl RestoreReport {
+    pub fn log(&self) {
+        if self.hard_links > 0 {
+            info!("Recreated {} hard links", self.hard_links);
+        }
+
+        for failed in &self.failed_xattrs {
+            warn!(
+                "Failed to set xattr {} on {:?}: {}",
+                failed.name, failed.path, failed.error
+            );
+        }
+
+        if !self.failed_xattrs.is_empty() {
+            warn!(
+                "{} extended attributes couldn't be applied",
+                self.failed_xattrs.len()
+            );
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Size and deduplication statistics, computed from metadata only - no chunk is read
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    error::Result,
+    formats::{tree::NodeKind, Backup},
+};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug, Default)]
+pub enum StatsFormat {
+    /// A block per snapshot followed by the totals
+    #[default]
+    Text,
+    /// A single JSON object
+    Json,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Stats {
+    /// Total size of the files, as they'd be restored
+    pub logical_size: u64,
+    /// Space taken up by the distinct chunks
+    pub stored_size: u64,
+    /// Number of distinct chunks
+    pub chunks: usize,
+    /// Average decoded size of the chunks, only chunks of known size are counted
+    pub average_chunk_size: Option<f64>,
+    /// Logical size divided by the decoded size of the distinct chunks
+    pub dedup_ratio: Option<f64>,
+    /// Decoded size divided by the stored size of the distinct chunks
+    pub compression_ratio: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SnapshotStats {
+    pub snapshot: String,
+    #[serde(flatten)]
+    pub stats: Stats,
+    /// Chunks no other snapshot references, i.e. what deleting the snapshot would free
+    pub exclusive_chunks: usize,
+    pub exclusive_stored_size: u64,
+    /// Share of the chunks of the snapshot that are exclusive to it
+    pub exclusive_share: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RepositoryStats {
+    pub snapshots: Vec<SnapshotStats>,
+    pub total: Stats,
+}
+
+/// What's known about a chunk without reading it
+#[derive(Debug, Default, Clone, Copy)]
+struct ChunkSizes {
+    decoded: Option<u64>,
+    stored: u64,
+    /// Number of snapshots referencing the chunk
+    snapshots: usize,
+}
+
+fn ratio(numerator: u64, denominator: u64) -> Option<f64> {
+    (denominator > 0).then(|| numerator as f64 / denominator as f64)
+}
+
+fn summarize<'a>(
+    logical_size: u64,
+    chunk_ids: impl IntoIterator<Item = &'a String>,
+    chunks: &HashMap<String, ChunkSizes>,
+) -> Stats {
+    let mut stats = Stats {
+        logical_size,
+        ..Default::default()
+    };
+
+    let mut known_count = 0;
+    let mut known_decoded = 0;
+    let mut known_stored = 0;
+    for id in chunk_ids {
+        let sizes = chunks[id];
+        stats.chunks += 1;
+        stats.stored_size += sizes.stored;
+
+        if let Some(decoded) = sizes.decoded {
+            known_count += 1;
+            known_decoded += decoded;
+            known_stored += sizes.stored;
+        }
+    }
+
+    stats.average_chunk_size = ratio(known_decoded, known_count);
+    stats.compression_ratio = ratio(known_decoded, known_stored);
+    // only meaningful if every chunk's size is known
+    if known_count == stats.chunks as u64 {
+        stats.dedup_ratio = ratio(logical_size, known_decoded);
+    }
+
+    stats
+}
+
+/// Collect the statistics of every snapshot and of the whole repository
+pub fn stats(backup: &dyn Backup) -> Result<RepositoryStats> {
+    let mut chunks: HashMap<String, ChunkSizes> = HashMap::new();
+    let mut snapshots = Vec::new();
+    let mut total_logical = 0;
+
+    for snapshot in backup.snapshots() {
+        debug!("Collecting chunks of snapshot {}", snapshot.id);
+
+        let mut logical_size = 0;
+        let mut snapshot_chunks = HashSet::new();
+        for node in backup.tree(&snapshot.id)? {
+            if node.kind != NodeKind::File {
+                continue;
+            }
+
+            logical_size += node.size.unwrap_or_else(|| {
+                node.chunks
+                    .iter()
+                    .filter_map(|chunk| chunk.data_len())
+                    .sum::<usize>() as u64
+            });
+
+            for chunk in &node.chunks {
+                let sizes = chunks.entry(chunk.id.clone()).or_default();
+                // chunks without a stored size are at least as large as the data we know is in
+                // them
+                let decoded = chunk.size.or(chunk.end).map(|size| size as u64);
+                sizes.decoded = sizes.decoded.max(decoded);
+
+                if snapshot_chunks.insert(chunk.id.clone()) {
+                    sizes.snapshots += 1;
+                }
+            }
+        }
+
+        let logical_size = backup.logical_size(&snapshot.id).unwrap_or(logical_size);
+        total_logical += logical_size;
+        snapshots.push((snapshot.id, logical_size, snapshot_chunks));
+    }
+
+    for (id, sizes) in &mut chunks {
+        sizes.stored = backup.chunk_stored_size(id)?;
+    }
+
+    let snapshots = snapshots
+        .into_iter()
+        .map(|(id, logical_size, snapshot_chunks)| {
+            let exclusive: Vec<&String> = snapshot_chunks
+                .iter()
+                .filter(|id| chunks[*id].snapshots == 1)
+                .collect();
+
+            SnapshotStats {
+                snapshot: id,
+                stats: summarize(logical_size, &snapshot_chunks, &chunks),
+                exclusive_chunks: exclusive.len(),
+                exclusive_stored_size: exclusive.iter().map(|id| chunks[*id].stored).sum(),
+                exclusive_share: ratio(exclusive.len() as u64, snapshot_chunks.len() as u64),
+            }
+        })
+        .collect();
+
+    let total = summarize(total_logical, chunks.keys(), &chunks);
+
+    Ok(RepositoryStats { snapshots, total })
+}
+
+fn format_ratio(ratio: Option<f64>) -> String {
+    ratio
+        .map(|r| format!("{r:.2}"))
+        .unwrap_or_else(|| "-".into())
+}
+
+fn write_stats(writer: &mut impl Write, stats: &Stats) -> std::io::Result<()> {
+    writeln!(writer, "  Logical size:      {}", stats.logical_size)?;
+    writeln!(writer, "  Stored size:       {}", stats.stored_size)?;
+    writeln!(writer, "  Chunks:            {}", stats.chunks)?;
+    writeln!(
+        writer,
+        "  Average chunk:     {}",
+        stats
+            .average_chunk_size
+            .map(|size| format!("{size:.0}"))
+            .unwrap_or_else(|| "-".into())
+    )?;
+    writeln!(
+        writer,
+        "  Dedup ratio:       {}",
+        format_ratio(stats.dedup_ratio)
+    )?;
+    writeln!(
+        writer,
+        "  Compression ratio: {}",
+        format_ratio(stats.compression_ratio)
+    )
+}
+
+pub fn write_report(
+    report: &RepositoryStats,
+    format: StatsFormat,
+    mut writer: impl Write,
+) -> Result<()> {
+    match format {
+        StatsFormat::Text => {
+            for snapshot in &report.snapshots {
+                writeln!(writer, "Snapshot {}", snapshot.snapshot)?;
+                write_stats(&mut writer, &snapshot.stats)?;
+                writeln!(
+                    writer,
+                    "  Exclusive chunks:  {} ({} stored, {})",
+                    snapshot.exclusive_chunks,
+                    snapshot.exclusive_stored_size,
+                    snapshot
+                        .exclusive_share
+                        .map(|share| format!("{:.1}%", share * 100.0))
+                        .unwrap_or_else(|| "-".into())
+                )?;
+                writeln!(writer)?;
+            }
+
+            writeln!(writer, "Repository")?;
+            write_stats(&mut writer, &report.total)?;
+        }
+        StatsFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, report)?;
+            writeln!(writer)?;
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! Files on a local disk, paths are used as they are
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use super::{DirEntry, Stat, Storage};
+use crate::error::Result;
+
+#[derive(Debug)]
+pub struct Local;
+
+impl From<std::fs::Metadata> for Stat {
+    fn from(metadata: std::fs::Metadata) -> Self {
+        Self {
+            size: metadata.len(),
+            is_dir: metadata.is_dir(),
+            modified: metadata.modified().ok(),
+        }
+    }
+}
+
+impl Storage for Local {
+    fn list(&self, path: &Path) -> Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            // broken symlinks are listed as the link itself
+            let metadata = std::fs::metadata(entry.path()).or_else(|_| entry.metadata())?;
+            entries.push(DirEntry {
+                path: entry.path(),
+                name: entry.file_name().to_string_lossy().to_string(),
+                stat: metadata.into(),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        Ok(std::fs::read(path)?)
+    }
+
+    fn read_range(&self, path: &Path, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = Vec::new();
+        file.take(length).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn stat(&self, path: &Path) -> Result<Stat> {
+        Ok(std::fs::metadata(path)?.into())
+    }
+}
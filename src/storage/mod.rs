@@ -0,0 +1,75 @@
+//! Where repositories are read from. Formats only ever see paths, the storage decides what they
+//! point to: files on a local disk, entries of an archive or files on a remote server.
+
+use std::{
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
+};
+
+use crate::error::{Error, Result};
+
+pub mod local;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+
+pub use local::Local;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub size: u64,
+    pub is_dir: bool,
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    /// Path of the entry, the listed directory joined with its name
+    pub path: PathBuf,
+    pub name: String,
+    pub stat: Stat,
+}
+
+pub trait Storage: std::fmt::Debug {
+    /// The entries of a directory, in no particular order
+    fn list(&self, path: &Path) -> Result<Vec<DirEntry>>;
+
+    /// Read a whole file
+    fn read(&self, path: &Path) -> Result<Vec<u8>>;
+
+    /// Read `length` bytes starting at `offset`, fewer if the file ends before that
+    fn read_range(&self, path: &Path, offset: u64, length: u64) -> Result<Vec<u8>>;
+
+    /// Size, type and modification time of a file or directory, following symlinks
+    fn stat(&self, path: &Path) -> Result<Stat>;
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.stat(path).is_ok_and(|stat| !stat.is_dir)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.stat(path).is_ok_and(|stat| stat.is_dir)
+    }
+
+    fn read_to_string(&self, path: &Path) -> Result<String> {
+        String::from_utf8(self.read(path)?).map_err(|err| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, err.utf8_error()).into()
+        })
+    }
+}
+
+/// Pick the storage a repository location refers to, and the path of the repository in it.
+/// Locations are local paths unless they start with a URL scheme.
+pub fn open(location: &str) -> Result<(Rc<dyn Storage>, PathBuf)> {
+    match location.split_once("://") {
+        #[cfg(feature = "sftp")]
+        Some(("sftp", rest)) => {
+            let (sftp, path) = sftp::Sftp::connect(rest)?;
+            Ok((Rc::new(sftp), path))
+        }
+        Some((scheme, _)) if scheme.bytes().all(|b| b.is_ascii_alphanumeric()) => {
+            Err(Error::UnsupportedLocation(location.to_string()))
+        }
+        _ => Ok((Rc::new(Local), PathBuf::from(location))),
+    }
+}
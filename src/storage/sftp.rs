@@ -0,0 +1,622 @@
+//! Files on a remote server, read over version 3 of the SFTP protocol. The connection is left to
+//! `ssh`: host keys have to be in `known_hosts` already and only public key authentication is
+//! tried, with the keys of the agent or `~/.ssh/config`.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    ffi::OsStr,
+    io::{BufReader, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    time::{Duration, SystemTime},
+};
+
+use byteorder::{ReadBytesExt, BE};
+
+use super::{DirEntry, Stat, Storage};
+use crate::error::{Error, Result};
+
+const VERSION: u32 = 3;
+
+// packet types
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_READ: u8 = 5;
+const FXP_OPENDIR: u8 = 11;
+const FXP_READDIR: u8 = 12;
+const FXP_STAT: u8 = 17;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_DATA: u8 = 103;
+const FXP_NAME: u8 = 104;
+const FXP_ATTRS: u8 = 105;
+
+// status codes
+const FX_EOF: u32 = 1;
+const FX_NO_SUCH_FILE: u32 = 2;
+const FX_PERMISSION_DENIED: u32 = 3;
+
+const FXF_READ: u32 = 1;
+
+// which fields of the attributes are present
+const ATTR_SIZE: u32 = 0x1;
+const ATTR_UIDGID: u32 = 0x2;
+const ATTR_PERMISSIONS: u32 = 0x4;
+const ATTR_ACMODTIME: u32 = 0x8;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Servers may answer with less, every server handles at least 32 KiB
+const CHUNK_SIZE: u64 = 64 * 1024;
+/// Reads sent before waiting for the first answer, to not pay the latency for every chunk
+const READ_AHEAD: usize = 16;
+/// Larger packets are a desynchronized stream rather than a real answer
+const MAX_PACKET_SIZE: u32 = 4 * 1024 * 1024;
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("SFTP: {message}"))
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_string(buffer: &mut Vec<u8>, value: &[u8]) {
+    put_u32(buffer, value.len() as u32);
+    buffer.extend_from_slice(value);
+}
+
+fn read_string(reader: &mut &[u8]) -> std::io::Result<Vec<u8>> {
+    let length = reader.read_u32::<BE>()? as usize;
+    if reader.len() < length {
+        return Err(invalid_data("truncated string"));
+    }
+    let (string, rest) = reader.split_at(length);
+    *reader = rest;
+
+    Ok(string.to_vec())
+}
+
+/// Only the size, the type and the modification time are kept
+fn read_attrs(reader: &mut &[u8]) -> std::io::Result<(Stat, u32)> {
+    let flags = reader.read_u32::<BE>()?;
+    let size = match flags & ATTR_SIZE {
+        0 => 0,
+        _ => reader.read_u64::<BE>()?,
+    };
+    if flags & ATTR_UIDGID != 0 {
+        reader.read_u64::<BE>()?;
+    }
+    let mode = match flags & ATTR_PERMISSIONS {
+        0 => 0,
+        _ => reader.read_u32::<BE>()?,
+    };
+    let mut modified = None;
+    if flags & ATTR_ACMODTIME != 0 {
+        let _atime = reader.read_u32::<BE>()?;
+        let mtime = reader.read_u32::<BE>()?;
+        modified = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime as u64));
+    }
+    if flags & ATTR_EXTENDED != 0 {
+        for _ in 0..reader.read_u32::<BE>()? * 2 {
+            read_string(reader)?;
+        }
+    }
+
+    let stat = Stat {
+        size,
+        is_dir: mode & S_IFMT == S_IFDIR,
+        modified,
+    };
+    Ok((stat, mode))
+}
+
+/// A failed request, `EOF` is expected and handled by the callers
+fn status_error(path: &Path, mut body: &[u8]) -> std::io::Error {
+    let Ok(code) = body.read_u32::<BE>() else {
+        return invalid_data("truncated status");
+    };
+    let message = read_string(&mut body).unwrap_or_default();
+
+    let kind = match code {
+        FX_NO_SUCH_FILE => std::io::ErrorKind::NotFound,
+        FX_PERMISSION_DENIED => std::io::ErrorKind::PermissionDenied,
+        _ => std::io::ErrorKind::Other,
+    };
+    std::io::Error::new(
+        kind,
+        format!("{}: {}", path.display(), String::from_utf8_lossy(&message)),
+    )
+}
+
+#[derive(Debug)]
+struct Channel {
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_id: u32,
+    /// Requests sent and not answered yet, with the answers that arrived before they were
+    /// asked for
+    outstanding: HashMap<u32, Option<(u8, Vec<u8>)>>,
+}
+
+impl Channel {
+    fn new(stdin: ChildStdin, stdout: ChildStdout) -> Self {
+        Self {
+            stdin,
+            stdout: BufReader::new(stdout),
+            next_id: 0,
+            outstanding: HashMap::new(),
+        }
+    }
+
+    /// length | type | body
+    fn write_packet(&mut self, kind: u8, body: &[u8]) -> std::io::Result<()> {
+        let mut packet = Vec::with_capacity(5 + body.len());
+        put_u32(&mut packet, body.len() as u32 + 1);
+        packet.push(kind);
+        packet.extend_from_slice(body);
+
+        self.stdin.write_all(&packet)
+    }
+
+    fn read_packet(&mut self) -> std::io::Result<(u8, Vec<u8>)> {
+        let length = self.stdout.read_u32::<BE>()?;
+        if length == 0 || length > MAX_PACKET_SIZE {
+            return Err(invalid_data(&format!("invalid packet length {length}")));
+        }
+        let kind = self.stdout.read_u8()?;
+
+        let mut body = vec![0u8; length as usize - 1];
+        self.stdout.read_exact(&mut body)?;
+        Ok((kind, body))
+    }
+
+    /// Send a request, its ID is prepended to the body
+    fn send(&mut self, kind: u8, body: &[u8]) -> std::io::Result<u32> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(body);
+        self.write_packet(kind, &packet)?;
+        self.outstanding.insert(id, None);
+
+        Ok(id)
+    }
+
+    /// The answer to a request. Servers may answer in any order, answers to other requests
+    /// are kept until they're asked for.
+    fn receive(&mut self, id: u32) -> std::io::Result<(u8, Vec<u8>)> {
+        loop {
+            match self.outstanding.get(&id) {
+                Some(Some(_)) => return Ok(self.outstanding.remove(&id).flatten().unwrap()),
+                Some(None) => {}
+                None => return Err(invalid_data("waiting for an answer to an unknown request")),
+            }
+
+            let (kind, body) = self.read_packet()?;
+            let Some(answer_id) = body
+                .get(..4)
+                .map(|id| u32::from_be_bytes(id.try_into().unwrap()))
+            else {
+                return Err(invalid_data("truncated answer"));
+            };
+            match self.outstanding.get_mut(&answer_id) {
+                Some(answer @ None) => *answer = Some((kind, body[4..].to_vec())),
+                _ => return Err(invalid_data("answer to an unexpected request")),
+            }
+        }
+    }
+
+    fn request(&mut self, kind: u8, body: &[u8]) -> std::io::Result<(u8, Vec<u8>)> {
+        let id = self.send(kind, body)?;
+        self.receive(id)
+    }
+
+    /// Open a file or a directory, and get its handle
+    fn open_handle(&mut self, kind: u8, path: &Path) -> std::io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        put_string(&mut body, path.as_os_str().as_bytes());
+        if kind == FXP_OPEN {
+            put_u32(&mut body, FXF_READ);
+            // no attributes, they're only used when creating files
+            put_u32(&mut body, 0);
+        }
+
+        match self.request(kind, &body)? {
+            (FXP_HANDLE, handle) => read_string(&mut &handle[..]),
+            (FXP_STATUS, status) => Err(status_error(path, &status)),
+            _ => Err(invalid_data("unexpected answer to open")),
+        }
+    }
+
+    fn close(&mut self, path: &Path, handle: &[u8]) -> std::io::Result<()> {
+        let mut body = Vec::new();
+        put_string(&mut body, handle);
+
+        match self.request(FXP_CLOSE, &body)? {
+            (FXP_STATUS, status) if status.starts_with(&[0; 4]) => Ok(()),
+            (FXP_STATUS, status) => Err(status_error(path, &status)),
+            _ => Err(invalid_data("unexpected answer to close")),
+        }
+    }
+
+    /// Read a range of an open file, the reads still in flight when it fails are waited for so
+    /// the handle can be closed
+    fn read_handle(
+        &mut self,
+        path: &Path,
+        handle: &[u8],
+        offset: u64,
+        length: u64,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut pending = VecDeque::new();
+        let data = self.read_pipelined(path, handle, offset, length, &mut pending);
+        for (id, _) in pending {
+            self.receive(id)?;
+        }
+
+        data
+    }
+
+    /// Keep up to `READ_AHEAD` reads in flight. The server may return less than asked for, the
+    /// reads after a short one are then dropped and sent again from where it stopped.
+    fn read_pipelined(
+        &mut self,
+        path: &Path,
+        handle: &[u8],
+        offset: u64,
+        length: u64,
+        pending: &mut VecDeque<(u32, u64)>,
+    ) -> std::io::Result<Vec<u8>> {
+        let end = offset.saturating_add(length);
+        let mut data = Vec::new();
+        let mut requested = offset;
+
+        loop {
+            while pending.len() < READ_AHEAD && requested < end {
+                let size = (end - requested).min(CHUNK_SIZE);
+                let mut body = Vec::new();
+                put_string(&mut body, handle);
+                body.extend_from_slice(&requested.to_be_bytes());
+                put_u32(&mut body, size as u32);
+
+                pending.push_back((self.send(FXP_READ, &body)?, size));
+                requested += size;
+            }
+            let Some((id, size)) = pending.pop_front() else {
+                break;
+            };
+
+            let (short, eof) = match self.receive(id)? {
+                (FXP_DATA, body) => {
+                    let chunk = read_string(&mut &body[..])?;
+                    if chunk.len() as u64 > size {
+                        return Err(invalid_data("read more than requested"));
+                    }
+                    data.extend_from_slice(&chunk);
+                    ((chunk.len() as u64) < size, false)
+                }
+                (FXP_STATUS, status) if status.starts_with(&FX_EOF.to_be_bytes()) => (true, true),
+                (FXP_STATUS, status) => return Err(status_error(path, &status)),
+                _ => return Err(invalid_data("unexpected answer to read")),
+            };
+            if short {
+                for (id, _) in pending.drain(..) {
+                    self.receive(id)?;
+                }
+                requested = offset + data.len() as u64;
+            }
+            if eof {
+                break;
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Every entry of an open directory, and which of them are symlinks
+    fn read_dir(
+        &mut self,
+        path: &Path,
+        handle: &[u8],
+    ) -> std::io::Result<(Vec<DirEntry>, Vec<usize>)> {
+        let mut entries = Vec::new();
+        let mut symlinks = Vec::new();
+
+        let mut body = Vec::new();
+        put_string(&mut body, handle);
+        // each answer holds some of the entries, until the end of the directory
+        loop {
+            let names = match self.request(FXP_READDIR, &body)? {
+                (FXP_NAME, names) => names,
+                (FXP_STATUS, status) if status.starts_with(&FX_EOF.to_be_bytes()) => break,
+                (FXP_STATUS, status) => return Err(status_error(path, &status)),
+                _ => return Err(invalid_data("unexpected answer to readdir")),
+            };
+
+            let mut names = &names[..];
+            for _ in 0..names.read_u32::<BE>()? {
+                let name = read_string(&mut names)?;
+                let _long_name = read_string(&mut names)?;
+                let (stat, mode) = read_attrs(&mut names)?;
+                if name == b"." || name == b".." {
+                    continue;
+                }
+
+                if mode & S_IFMT == S_IFLNK {
+                    symlinks.push(entries.len());
+                }
+                entries.push(DirEntry {
+                    path: path.join(OsStr::from_bytes(&name)),
+                    name: String::from_utf8_lossy(&name).to_string(),
+                    stat,
+                });
+            }
+        }
+
+        Ok((entries, symlinks))
+    }
+}
+
+#[derive(Debug)]
+pub struct Sftp {
+    child: Child,
+    channel: RefCell<Channel>,
+}
+
+impl Sftp {
+    /// Connect to `[user@]host[:port]/path`, the path is absolute unless it starts with `~/`,
+    /// then it's relative to the home directory of the user
+    pub fn connect(location: &str) -> Result<(Self, PathBuf)> {
+        let invalid = || Error::InvalidLocation(format!("sftp://{location}"));
+
+        let (authority, path) = location.split_once('/').unwrap_or((location, ""));
+        let path = match path.strip_prefix('~') {
+            Some("") => PathBuf::from("."),
+            Some(relative) => PathBuf::from(relative.strip_prefix('/').ok_or_else(invalid)?),
+            None => Path::new("/").join(path),
+        };
+
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (Some(user), host),
+            None => (None, authority),
+        };
+        // IPv6 addresses are in brackets, to tell them apart from the port
+        let (host, port) = match host.strip_prefix('[') {
+            Some(host) => match host.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => (host, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+                None => return Err(invalid()),
+            },
+            None => match host.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (host, None),
+            },
+        };
+        if host.is_empty() || port.is_some_and(|port| port.parse::<u16>().is_err()) {
+            return Err(invalid());
+        }
+
+        // `SFTP_SERVER` runs a local sftp-server instead, like `sftp -D`
+        let command = match std::env::var_os("SFTP_SERVER") {
+            Some(server) => Command::new(server),
+            None => {
+                let mut command = Command::new("ssh");
+                command.args([
+                    "-o",
+                    "BatchMode=yes",
+                    "-o",
+                    "StrictHostKeyChecking=yes",
+                    "-o",
+                    "PreferredAuthentications=publickey",
+                    "-a",
+                    "-x",
+                    "-T",
+                ]);
+                if let Some(port) = port {
+                    command.args(["-p", port]);
+                }
+                if let Some(user) = user {
+                    command.args(["-l", user]);
+                }
+                command.args(["-s", "--", host, "sftp"]);
+                command
+            }
+        };
+        debug!("Connecting to {authority}: {command:?}");
+
+        // ssh already explained why it failed on stderr
+        let sftp = Self::spawn(command).map_err(|err| {
+            debug!("SFTP handshake failed: {err}");
+            Error::SftpConnection(authority.to_string())
+        })?;
+
+        Ok((sftp, path))
+    }
+
+    /// Run a command speaking SFTP on its stdin and stdout, and agree on the version
+    fn spawn(mut command: Command) -> std::io::Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let channel = Channel::new(child.stdin.take().unwrap(), child.stdout.take().unwrap());
+        let mut sftp = Self {
+            child,
+            channel: RefCell::new(channel),
+        };
+
+        let version = sftp.handshake()?;
+        debug!("Server speaks SFTP version {version}");
+
+        Ok(sftp)
+    }
+
+    /// The client sends the highest version it supports, and the server the one it'll use
+    fn handshake(&mut self) -> std::io::Result<u32> {
+        let channel = self.channel.get_mut();
+        channel.write_packet(FXP_INIT, &VERSION.to_be_bytes())?;
+
+        match channel.read_packet()? {
+            (FXP_VERSION, body) => match (&body[..]).read_u32::<BE>()? {
+                VERSION => Ok(VERSION),
+                version => Err(invalid_data(&format!("unsupported version {version}"))),
+            },
+            _ => Err(invalid_data("unexpected answer to init")),
+        }
+    }
+}
+
+impl Drop for Sftp {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Storage for Sftp {
+    fn list(&self, path: &Path) -> Result<Vec<DirEntry>> {
+        let (mut entries, symlinks) = {
+            let mut channel = self.channel.borrow_mut();
+            let handle = channel.open_handle(FXP_OPENDIR, path)?;
+            let listed = channel.read_dir(path, &handle);
+            channel.close(path, &handle)?;
+            listed?
+        };
+
+        // listings describe symlinks themselves, broken ones are left that way
+        for index in symlinks {
+            if let Ok(stat) = self.stat(&entries[index].path) {
+                entries[index].stat = stat;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        self.read_range(path, 0, u64::MAX)
+    }
+
+    fn read_range(&self, path: &Path, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut channel = self.channel.borrow_mut();
+        let handle = channel.open_handle(FXP_OPEN, path)?;
+        let data = channel.read_handle(path, &handle, offset, length);
+        channel.close(path, &handle)?;
+
+        Ok(data?)
+    }
+
+    fn stat(&self, path: &Path) -> Result<Stat> {
+        let mut body = Vec::new();
+        put_string(&mut body, path.as_os_str().as_bytes());
+
+        let stat = match self.channel.borrow_mut().request(FXP_STAT, &body)? {
+            (FXP_ATTRS, attrs) => read_attrs(&mut &attrs[..])?.0,
+            (FXP_STATUS, status) => return Err(status_error(path, &status).into()),
+            _ => return Err(invalid_data("unexpected answer to stat").into()),
+        };
+
+        Ok(stat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The sftp-server OpenSSH runs as its subsystem, serving the local filesystem
+    fn server() -> Sftp {
+        let path = std::env::var_os("SFTP_SERVER")
+            .map(PathBuf::from)
+            .expect("SFTP_SERVER should be the path of an sftp-server");
+        assert!(path.exists(), "{path:?} doesn't exist");
+
+        Sftp::spawn(Command::new(path)).unwrap()
+    }
+
+    fn is_not_found<T>(result: Result<T>) -> bool {
+        matches!(result, Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound)
+    }
+
+    #[test]
+    #[ignore = "needs an sftp-server, run with SFTP_SERVER set and --ignored"]
+    fn local_server() {
+        let sftp = server();
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        let data: Vec<u8> = (0..CHUNK_SIZE as usize * 3 + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(&file, &data).unwrap();
+        std::fs::create_dir(dir.path().join("dir")).unwrap();
+        std::os::unix::fs::symlink("file", dir.path().join("link")).unwrap();
+        std::os::unix::fs::symlink("missing", dir.path().join("broken")).unwrap();
+
+        // symlinks are followed, unless they're broken
+        let mut entries = sftp.list(dir.path()).unwrap();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let listed: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.stat.is_dir, entry.stat.size))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("broken", false, 7),
+                ("dir", true, entries[1].stat.size),
+                ("file", false, data.len() as u64),
+                ("link", false, data.len() as u64),
+            ]
+        );
+        assert_eq!(entries[2].path, file);
+        assert!(entries[2].stat.modified.is_some());
+
+        assert_eq!(sftp.read(&file).unwrap(), data);
+        assert_eq!(sftp.read(&dir.path().join("link")).unwrap(), data);
+
+        // across chunks, and past the end of the file
+        let offset = CHUNK_SIZE as usize - 10;
+        assert_eq!(
+            sftp.read_range(&file, offset as u64, 100).unwrap(),
+            data[offset..offset + 100]
+        );
+        assert_eq!(
+            sftp.read_range(&file, 1, CHUNK_SIZE * 2).unwrap(),
+            data[1..CHUNK_SIZE as usize * 2 + 1]
+        );
+        let offset = data.len() - 50;
+        assert_eq!(
+            sftp.read_range(&file, offset as u64, CHUNK_SIZE).unwrap(),
+            data[offset..]
+        );
+        assert!(sftp
+            .read_range(&file, data.len() as u64 + 10, 10)
+            .unwrap()
+            .is_empty());
+        assert!(sftp.read_range(&file, 0, 0).unwrap().is_empty());
+
+        let stat = sftp.stat(&file).unwrap();
+        assert_eq!((stat.size, stat.is_dir), (data.len() as u64, false));
+        assert!(sftp.stat(&dir.path().join("dir")).unwrap().is_dir);
+        assert!(sftp.is_file(&dir.path().join("link")));
+        assert!(!sftp.is_file(&dir.path().join("broken")));
+
+        // failed requests leave the connection usable
+        assert!(is_not_found(sftp.stat(&dir.path().join("missing"))));
+        assert!(is_not_found(sftp.read(&dir.path().join("missing"))));
+        assert!(is_not_found(sftp.list(&dir.path().join("missing"))));
+        assert!(sftp.read(&dir.path().join("dir")).is_err());
+        assert_eq!(sftp.read_range(&file, 0, 10).unwrap(), data[..10]);
+    }
+}
@@ -30,10 +30,23 @@ pub fn from_datetime<'de, D: Deserializer<'de>>(
     DateTime::parse_from_rfc3339(&time).map_err(serde::de::Error::custom)
 }
 
-pub fn from_truthy<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<bool, D::Error> {
-    let value = u64::deserialize(d)?;
-    match value {
-        0 => Ok(false),
-        _ => Ok(true),
+/// Convert go's `fs.FileMode` into unix permission bits, the type bits are dropped
+/// see https://pkg.go.dev/io/fs#FileMode
+pub fn from_go_mode(mode: u64) -> u32 {
+    const MODE_SETUID: u64 = 1 << 23;
+    const MODE_SETGID: u64 = 1 << 22;
+    const MODE_STICKY: u64 = 1 << 20;
+
+    let mut unix = (mode & 0o777) as u32;
+    if mode & MODE_SETUID != 0 {
+        unix |= 0o4000;
+    }
+    if mode & MODE_SETGID != 0 {
+        unix |= 0o2000;
     }
+    if mode & MODE_STICKY != 0 {
+        unix |= 0o1000;
+    }
+
+    unix
 }